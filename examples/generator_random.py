@@ -21,7 +21,9 @@ def generate(options: dict[str, str]) \
     """This function returns an Iterator which yields tuples that
     describe packets to send. The inner tuple sets the send time
     relative to the previous packet in seconds and nanoseconds, the
-    final `int` the size of the packet in bytes.
+    final `int` the size of the packet in bytes. Optionally a third
+    element `True` marks the time as an absolute departure time
+    instead of a delay.
 
     The `options` dict contains the generator options passed on the
     command line, if any.
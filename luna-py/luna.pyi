@@ -1,10 +1,31 @@
+from collections.abc import Awaitable, Iterator
 from decimal import Decimal
-from typing import Self, final
+from typing import Literal, Self, TypedDict, final
 
-__all__ = ['Client', 'MIN_SIZE', 'PacketRecord', 'Server']
+__all__ = ['Client', 'MIN_SIZE', 'PacketRecord', 'RawRecords', 'Server']
 MIN_SIZE: int
 
 
+class ResourceUsage(TypedDict):
+    user_time: float
+    system_time: float
+    major_page_faults: int
+    minor_page_faults: int
+    voluntary_context_switches: int
+    involuntary_context_switches: int
+    sched_policy: str
+    sched_priority: int
+    memlock_limit: int | None
+
+
+class EchoStats(TypedDict):
+    received: int
+    lost: int
+    reordered: int
+    duplicated: int
+    late: int
+
+
 @final
 class PacketRecord:
     source: str
@@ -12,45 +33,96 @@ class PacketRecord:
     size: int
     sequence: int
     timestamp: Decimal
+    latency: Decimal
+    flow: int
+    fragment_size: int | None
+    clock: Literal['realtime', 'monotonic', 'tai']
     def __str__(self) -> str: ...
 
 
+@final
+class RawRecords(Iterator[tuple[int, int, int, int, int, str]]):
+    def __iter__(self) -> Self: ...
+    def __next__(self) -> tuple[int, int, int, int, int, str]: ...
+
+
 @final
 class Server:
     buffer_size: int
     bind: str
+    label: str | None
+    keep_recent: int
     running: bool
 
-    def __new__(cls, bind: str, port: int = 7800, buffer_size: int = 1500) \
-            -> Self:
+    def __new__(
+            cls, bind: str, port: int = 7800, buffer_size: int = 1500,
+            label: str | None = None, keep_recent: int = 0) -> Self:
+        ...
+
+    @staticmethod
+    def from_fd(
+            fd: int, buffer_size: int = 1500,
+            label: str | None = None, keep_recent: int = 0) -> Server:
         ...
 
     def start(self) -> None: ...
     def stop(self) -> None: ...
     def join(self) -> None: ...
+    def resource_usage(self) -> ResourceUsage | None: ...
+    def recent(self, n: int) -> list[PacketRecord]: ...
+    def raw_records(self) -> RawRecords: ...
     def __enter__(self) -> Self: ...
     def __exit__(self, exception_type, exception_value, traceback) -> bool: ...
     def __iter__(self) -> Self: ...
     def __next__(self) -> PacketRecord: ...
+    def __aiter__(self) -> Self: ...
+    def __anext__(self) -> Awaitable[PacketRecord]: ...
 
 
 @final
 class Client:
     buffer_size: int
+    clock: Literal['realtime', 'monotonic', 'tai']
     echo: bool
+    label: str | None
+    flow: int | None
+    keep_recent: int
+    keepalive: float | None
+    paused: bool
     running: bool
     server: str
 
     def __new__(
-            cls, server: str, buffer_size: int = 1500, echo: bool = True) \
-            -> Self:
+            cls, server: str, buffer_size: int = 1500, echo: bool = True,
+            label: str | None = None,
+            family: Literal['auto', 'inet', 'inet6'] = 'auto',
+            flow: int | None = None, keep_recent: int = 0) -> Self:
         ...
 
     def start(self) -> None: ...
-    def put(self, delay: tuple[int, int], size: int) -> None: ...
+    def run_to_completion(
+            self, generator: str = 'default',
+            options: dict[str, str] | None = None) -> ResourceUsage:
+        ...
+    def put(
+            self, delay: tuple[int, int], size: int,
+            absolute: bool = False) -> None:
+        ...
+    def put_bytes(
+            self, delay: tuple[int, int], payload: bytes,
+            absolute: bool = False) -> None:
+        ...
     def close(self) -> None: ...
+    def pause(self) -> None: ...
+    def resume(self) -> None: ...
     def join(self) -> None: ...
+    def resource_usage(self) -> ResourceUsage | None: ...
+    def echo_stats(self) -> EchoStats | None: ...
+    def recent(self, n: int) -> list[PacketRecord]: ...
+    def raw_records(self) -> RawRecords: ...
     def __enter__(self) -> Self: ...
     def __exit__(self, exception_type, exception_value, traceback) -> bool: ...
     def __iter__(self) -> Self: ...
     def __next__(self) -> PacketRecord: ...
+    def __aiter__(self) -> Self: ...
+    def __anext__(self) -> Awaitable[PacketRecord]: ...
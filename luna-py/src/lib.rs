@@ -1,26 +1,53 @@
 use std::{
-	net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
-	sync::{mpsc::{self, RecvError}, Mutex},
-	thread
+	collections::HashMap,
+	net::SocketAddr,
+	os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd},
+	sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Mutex},
+	thread,
+	time::Duration
 };
 
-use luna_rs::{client, server, PacketData, ReceivedPacket, MIN_SIZE};
-use nix::{errno::Errno, sys::{socket::SockaddrStorage, time::TimeSpec}};
+use luna_rs::{
+	addr, client, generator::Generator, server, sink, stats::{RunUsage, SequenceGaps}, MemlockLimit, PacketData,
+	ReceivedPacket,
+	SendClock, CLOCK_SIZE, MIN_SIZE
+};
+use nix::{errno::Errno, sys::{socket::{self, SockaddrStorage}, time::TimeSpec}};
 use pyo3::{
-	exceptions::{PyException, PyOSError, PyValueError},
+	PyClass,
+	exceptions::{PyException, PyOSError, PyStopAsyncIteration, PyValueError},
 	prelude::*,
+	pyclass::boolean_struct::True,
 	sync::PyOnceLock,
-	types::{PyTraceback, PyType}
+	types::{PyCFunction, PyDict, PyTraceback, PyType}
 };
 
 
-fn timespec_to_decimal<'py>(
-	py: Python<'py>, time: &TimeSpec)
+/// Nanoseconds of a TimeSpec, in i128 so timestamps from packets
+/// cannot overflow.
+fn timespec_nanos(time: &TimeSpec) -> i128 {
+	i128::from(time.tv_sec()) * 1_000_000_000 + i128::from(time.tv_nsec())
+}
+
+
+/// Convert nanoseconds to decimal.Decimal in seconds, exactly.
+fn nanos_to_decimal<'py>(
+	py: Python<'py>, nanos: i128)
 	-> PyResult<Bound<'py, PyAny>>
 {
 	static DECIMAL: PyOnceLock<Py<PyType>> = PyOnceLock::new();
+	let sign = if nanos < 0 { "-" } else { "" };
+	let abs = nanos.unsigned_abs();
 	DECIMAL.import(py, "decimal", "Decimal")?
-		.call1((format!("{}.{}", time.tv_sec(), time.tv_nsec()),))
+		.call1((format!("{sign}{}.{:09}", abs / 1_000_000_000, abs % 1_000_000_000),))
+}
+
+
+fn timespec_to_decimal<'py>(
+	py: Python<'py>, time: &TimeSpec)
+	-> PyResult<Bound<'py, PyAny>>
+{
+	nanos_to_decimal(py, timespec_nanos(time))
 }
 
 
@@ -64,6 +91,40 @@ impl PacketRecord {
 		timespec_to_decimal(py, &self.packet.timestamp)
 	}
 
+	/// Receive time minus send timestamp, as decimal.Decimal in
+	/// seconds: the round trip time for echo packets received by the
+	/// client, the one-way delay for packets received by the server.
+	/// The one-way delay is only meaningful if the clocks of both
+	/// hosts are synchronized, with clock skew it may be negative.
+	#[getter]
+	fn latency<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+		nanos_to_decimal(
+			py,
+			timespec_nanos(&self.packet.receive_time) - timespec_nanos(&self.packet.timestamp))
+	}
+
+	/// Flow ID of the packet, 0 if it carries none.
+	#[getter]
+	fn flow(&self) -> u32 {
+		self.packet.flow
+	}
+
+	/// Size of the largest fragment if the packet arrived
+	/// fragmented, None otherwise.
+	#[getter]
+	fn fragment_size(&self) -> Option<usize> {
+		self.packet.fragment_size
+	}
+
+	/// Clock the send timestamp was read from: "realtime",
+	/// "monotonic", or "tai". Latency is only meaningful for
+	/// "realtime", or for echoes received by the client, which
+	/// reads the receive time from the same clock.
+	#[getter]
+	fn clock(&self) -> String {
+		self.packet.clock.to_string()
+	}
+
 	fn __str__(&self) -> String {
 		format!("{}", self.packet)
 	}
@@ -74,6 +135,144 @@ impl PacketRecord {
 }
 
 
+/// Convert resource usage of a run to a dict for Python.
+fn usage_dict<'py>(py: Python<'py>, usage: &RunUsage) -> PyResult<Bound<'py, PyDict>> {
+	let r = &usage.resources;
+	let d = PyDict::new(py);
+	d.set_item("user_time", r.user_time.as_secs_f64())?;
+	d.set_item("system_time", r.system_time.as_secs_f64())?;
+	d.set_item("major_page_faults", r.major_page_faults)?;
+	d.set_item("minor_page_faults", r.minor_page_faults)?;
+	d.set_item("voluntary_context_switches", r.voluntary_context_switches)?;
+	d.set_item("involuntary_context_switches", r.involuntary_context_switches)?;
+	d.set_item("sched_policy", usage.scheduling.policy_name())?;
+	d.set_item("sched_priority", usage.scheduling.priority)?;
+	d.set_item("memlock_limit", match usage.memlock {
+		MemlockLimit::Bytes(b) => Some(b),
+		MemlockLimit::Unlimited => None,
+	})?;
+	Ok(d)
+}
+
+
+/// Convert echo counts by sequence number to a dict for Python.
+fn gaps_dict<'py>(py: Python<'py>, gaps: &SequenceGaps) -> PyResult<Bound<'py, PyDict>> {
+	let d = PyDict::new(py);
+	d.set_item("received", gaps.received())?;
+	d.set_item("lost", gaps.lost())?;
+	d.set_item("reordered", gaps.reordered())?;
+	d.set_item("duplicated", gaps.duplicated())?;
+	d.set_item("late", gaps.late())?;
+	Ok(d)
+}
+
+
+/// Packet log of a client or server, `None` until started.
+type Log = Arc<Mutex<Option<mpsc::Receiver<ReceivedPacket>>>>;
+
+
+/// Most recent packets of the log, if enabled with `keep_recent`.
+type Recent = Mutex<Option<Arc<sink::Ring<ReceivedPacket>>>>;
+
+
+/// Create the channel for the packet log of a run. With
+/// `keep_recent` > 0 packets pass through a ring sink on the way,
+/// which is stored in `recent`.
+fn log_channel(keep_recent: usize, recent: &Recent)
+	-> std::io::Result<(mpsc::Sender<ReceivedPacket>, mpsc::Receiver<ReceivedPacket>)>
+{
+	let (sender, receiver) = mpsc::channel();
+	if keep_recent == 0 {
+		return Ok((sender, receiver));
+	}
+	let (ring_sender, ring) = sink::to_ring(keep_recent, Some(sender))?;
+	*recent.lock().unwrap() = Some(ring);
+	Ok((ring_sender, receiver))
+}
+
+
+/// Wait for the next packet in the log, `None` after the log has
+/// ended. Must be called with the GIL released.
+fn next_packet(log: &Mutex<Option<mpsc::Receiver<ReceivedPacket>>>) -> Option<ReceivedPacket> {
+	let guard = log.lock().unwrap();
+	guard.as_ref().and_then(|r| r.recv().ok())
+}
+
+
+/// Client and server both provide their packet log for iteration.
+trait PacketLog {
+	fn log(&self) -> &Log;
+	fn recent_ring(&self) -> &Recent;
+
+	/// The last `n` packets of the ring sink, oldest first.
+	fn recent_records(&self, n: usize) -> Vec<PacketRecord> {
+		let recent = self.recent_ring().lock().unwrap();
+		let packets = recent.as_ref().map(|r| r.snapshot()).unwrap_or_default();
+		let skip = packets.len().saturating_sub(n);
+		packets.into_iter().skip(skip).map(|packet| PacketRecord { packet }).collect()
+	}
+
+	/// Wait for the next log record, `None` after the log has
+	/// ended. Must be called with the GIL released.
+	fn next_record(&self) -> Option<PacketRecord> {
+		next_packet(self.log()).map(|packet| PacketRecord { packet })
+	}
+}
+
+
+/// Iterator over a packet log that yields tuples of primitives
+/// instead of PacketRecord objects, which is cheaper at high packet
+/// rates, see `raw_records()` of Client and Server.
+#[pyclass(frozen, module = "luna")]
+struct RawRecords {
+	log: Log,
+}
+
+#[pymethods]
+impl RawRecords {
+	fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+		slf
+	}
+
+	/// Tuple of sequence number, receive time and send timestamp (as
+	/// integer nanoseconds), size, flags, and source address.
+	fn __next__(&self, py: Python<'_>) -> Option<(u32, i128, i128, usize, u8, String)> {
+		py.detach(|| next_packet(&self.log)).map(|p| (
+			p.sequence,
+			timespec_nanos(&p.receive_time),
+			timespec_nanos(&p.timestamp),
+			p.size,
+			p.flags.bits(),
+			format!("{}", p.source),
+		))
+	}
+}
+
+
+/// Awaitable for the next log record, for `__anext__`. The blocking
+/// receive runs in the default executor of the running asyncio event
+/// loop, so the loop is not blocked. Note that cancelling the
+/// awaitable does not interrupt the receive, the executor thread
+/// stays busy until the next record arrives or the log ends.
+fn anext_record<'py, T>(slf: Bound<'py, T>) -> PyResult<Bound<'py, PyAny>>
+where
+	T: PacketLog + PyClass<Frozen = True> + Sync
+{
+	static GET_RUNNING_LOOP: PyOnceLock<Py<PyAny>> = PyOnceLock::new();
+	let py = slf.py();
+	let obj = slf.unbind();
+	let recv = PyCFunction::new_closure(
+		py, None, None,
+		move |args, _kwargs| -> PyResult<PacketRecord> {
+			args.py().detach(|| obj.get().next_record())
+				.ok_or_else(|| PyStopAsyncIteration::new_err(()))
+		})?;
+	GET_RUNNING_LOOP.import(py, "asyncio", "get_running_loop")?
+		.call0()?
+		.call_method1("run_in_executor", (py.None(), recv))
+}
+
+
 #[pyclass(frozen, module = "luna")]
 struct Client {
 	server: SocketAddr,
@@ -81,31 +280,87 @@ struct Client {
 	buffer_size: usize,
 	#[pyo3(get)]
 	echo: bool,
+	#[pyo3(get)]
+	label: Option<String>,
+	#[pyo3(get)]
+	flow: Option<u32>,
+	#[pyo3(get)]
+	keep_recent: usize,
+	/// see client::Options::clock, applies from the next start
+	clock: Mutex<SendClock>,
+	/// seconds, see client::Options::keepalive, applies from the
+	/// next start
+	keepalive: Mutex<Option<f64>>,
 	generator: Mutex<Option<mpsc::Sender<PacketData>>>,
-	running: Mutex<Option<thread::JoinHandle<Result<(), String>>>>,
-	log: Mutex<Option<mpsc::Receiver<ReceivedPacket>>>,
+	/// set by pause(), see client::Options::pause
+	pause: Arc<AtomicBool>,
+	running: Mutex<Option<thread::JoinHandle<Result<client::Summary, String>>>>,
+	/// summary of the finished run, set by join()
+	summary: Mutex<Option<client::Summary>>,
+	log: Log,
+	recent: Recent,
+}
+
+impl Client {
+	/// Pass a packet to the running client.
+	fn enqueue(
+		&self, py: Python<'_>, delay: (i64, i64), absolute: bool, size: usize,
+		payload: Option<Vec<u8>>)
+		-> PyResult<()>
+	{
+		py.detach(|| {
+			let r = self.generator.lock().unwrap();
+			if let Some(s) = r.as_ref() {
+				let time = TimeSpec::new(delay.0, delay.1);
+				let _ = s.send(if absolute {
+					PacketData {
+						delay: TimeSpec::new(0, 0),
+						size,
+						departure: Some(time),
+						payload,
+					}
+				} else {
+					PacketData { delay: time, size, departure: None, payload }
+				});
+				Ok(())
+			} else {
+				Err("client is not running")
+			}
+		}).map_err(|e| PyException::new_err(e))
+	}
 }
 
 #[pymethods]
 impl Client {
 	#[new]
-	#[pyo3(signature = (server, buffer_size=1500, echo=true))]
-	fn new(server: &str, buffer_size: usize, echo: bool) -> PyResult<Self> {
-		let server_addr = match server.to_socket_addrs() {
-			Err(_) => return Err(PyValueError::new_err("could not resolve address")),
-			Ok(mut s) => match s.next() {
-				None => return Err(
-					PyValueError::new_err("could not resolve address")),
-				Some(s) => s,
-			}
-		};
+	#[pyo3(signature = (
+		server, buffer_size=1500, echo=true, label=None, family="auto", flow=None,
+		keep_recent=0))]
+	fn new(
+		server: &str, buffer_size: usize, echo: bool, label: Option<String>,
+		family: &str, flow: Option<u32>, keep_recent: usize)
+		-> PyResult<Self>
+	{
+		let family: addr::Family = family.parse()
+			.map_err(PyValueError::new_err)?;
+		let server_addr = addr::resolve(server, family)
+			.map_err(|e| PyValueError::new_err(
+				format!("could not resolve address: {e}")))?;
 		Ok(Client {
 			server: server_addr,
 			buffer_size,
 			echo,
+			label,
+			flow,
+			keep_recent,
+			clock: Mutex::new(SendClock::Realtime),
+			keepalive: Mutex::new(None),
 			generator: Mutex::new(None),
+			pause: Arc::new(AtomicBool::new(false)),
 			running: Mutex::new(None),
-			log: Mutex::new(None),
+			summary: Mutex::new(None),
+			log: Arc::new(Mutex::new(None)),
+			recent: Mutex::new(None),
 		})
 	}
 
@@ -114,6 +369,42 @@ impl Client {
 		format!("{}", self.server)
 	}
 
+	/// Clock the client paces packets on and reads send timestamps
+	/// from, see `PacketRecord.clock`. Applies from the next start.
+	#[getter]
+	fn clock(&self) -> String {
+		self.clock.lock().unwrap().to_string()
+	}
+
+	#[setter]
+	fn set_clock(&self, clock: &str) -> PyResult<()> {
+		let clock: SendClock = clock.parse()
+			.map_err(PyValueError::new_err)?;
+		if clock != SendClock::Realtime && self.buffer_size < CLOCK_SIZE {
+			return Err(PyValueError::new_err(format!(
+				"the {clock} clock needs a buffer of at least {CLOCK_SIZE} bytes")));
+		}
+		*self.clock.lock().unwrap() = clock;
+		Ok(())
+	}
+
+	/// Send a keepalive packet when nothing was sent for this many
+	/// seconds, `None` (the default) to disable. Applies from the
+	/// next start.
+	#[getter]
+	fn keepalive(&self) -> Option<f64> {
+		*self.keepalive.lock().unwrap()
+	}
+
+	#[setter]
+	fn set_keepalive(&self, keepalive: Option<f64>) -> PyResult<()> {
+		if keepalive.is_some_and(|k| !(k.is_finite() && k > 0.0)) {
+			return Err(PyValueError::new_err("keepalive must be a positive number of seconds"));
+		}
+		*self.keepalive.lock().unwrap() = keepalive;
+		Ok(())
+	}
+
 	fn start(&self, py: Python<'_>) -> PyResult<()> {
 		py.detach(|| {
 			let gen_receiver = {
@@ -127,15 +418,25 @@ impl Client {
 					Some(_) => return Err("already running"),
 					None => (),
 				};
-				let (log_sender, log_receiver) = mpsc::channel::<ReceivedPacket>();
+				let (log_sender, log_receiver) = log_channel(self.keep_recent, &self.recent)
+					.map_err(|_| "could not start packet log thread")?;
 				let (s, buf_size, echo) = (self.server.clone(), self.buffer_size, self.echo);
+				let clock = *self.clock.lock().unwrap();
+				self.pause.store(false, Ordering::Relaxed);
+				let options = client::Options {
+					label: self.label.clone(),
+					flow: self.flow,
+					pause: Some(Arc::clone(&self.pause)),
+					keepalive: self.keepalive.lock().unwrap().map(Duration::from_secs_f64),
+					clock,
+					header_length: clock != SendClock::Realtime,
+					..Default::default()
+				};
 				let t = thread::spawn(move || {
-					if let Err(e) = client::run(
-						s, buf_size, echo, gen_receiver, None, Some(log_sender))
-					{
-						return Err(format!("client run failed: {e}"));
-					}
-					Ok(())
+					client::run(
+						s, buf_size, echo, gen_receiver, None, Some(log_sender),
+						options)
+						.map_err(|e| format!("client run failed: {e}"))
 				});
 				*r = Some(t);
 				log_receiver
@@ -148,7 +449,12 @@ impl Client {
 		}).map_err(|e| PyException::new_err(e))
 	}
 
-	fn put(&self, py: Python<'_>, delay: (i64, i64), size: usize) -> PyResult<()> {
+	#[pyo3(signature = (delay, size, absolute=false))]
+	fn put(
+		&self, py: Python<'_>, delay: (i64, i64), size: usize,
+		absolute: bool)
+		-> PyResult<()>
+	{
 		if size > self.buffer_size {
 			return Err(PyValueError::new_err(
 				"size too large, increase buffer_size"));
@@ -156,27 +462,62 @@ impl Client {
 			return Err(PyValueError::new_err(
 				format!("size smaller than minimum ({MIN_SIZE})")));
 		}
-		py.detach(|| {
-			let r = self.generator.lock().unwrap();
-			if let Some(s) = r.as_ref() {
-				let _ = s.send(PacketData {
-					delay: TimeSpec::new(delay.0, delay.1),
-					size,
-				});
-				Ok(())
-			} else {
-				Err("client is not running")
-			}
-		}).map_err(|e| PyException::new_err(e))
+		self.enqueue(py, delay, absolute, size, None)
 	}
 
+	/// Like put(), but send exactly the given payload after the
+	/// packet header instead of zeros. The packet size follows from
+	/// the payload length.
+	#[pyo3(signature = (delay, payload, absolute=false))]
+	fn put_bytes(
+		&self, py: Python<'_>, delay: (i64, i64), payload: &[u8],
+		absolute: bool)
+		-> PyResult<()>
+	{
+		// where the client writes the payload, see start()
+		let clock = *self.clock.lock().unwrap();
+		let header = client::Options {
+			flow: self.flow,
+			clock,
+			header_length: clock != SendClock::Realtime,
+			..Default::default()
+		}.payload_start(self.echo, self.buffer_size);
+		let size = header + payload.len();
+		if size > self.buffer_size {
+			return Err(PyValueError::new_err(format!(
+				"{header} bytes header plus {} bytes payload too large, increase buffer_size",
+				payload.len())));
+		}
+		self.enqueue(py, delay, absolute, size, Some(payload.to_vec()))
+	}
+
+	/// End the packet queue. Queued packets are still sent, a pause
+	/// ends so the client can finish.
 	fn close(&self, py: Python<'_>) {
+		self.pause.store(false, Ordering::Relaxed);
 		py.detach(|| {
 			let mut r = self.generator.lock().unwrap();
 			r.take();
 		});
 	}
 
+	/// Stop sending before the next packet until resume() is called.
+	/// Echoes are still received, packets put meanwhile are queued.
+	fn pause(&self) {
+		self.pause.store(true, Ordering::Relaxed);
+	}
+
+	/// Continue sending after pause(), relative delays count from
+	/// now.
+	fn resume(&self) {
+		self.pause.store(false, Ordering::Relaxed);
+	}
+
+	#[getter]
+	fn paused(&self) -> bool {
+		self.pause.load(Ordering::Relaxed)
+	}
+
 	#[getter]
 	fn running(&self, py: Python<'_>) -> bool {
 		py.detach(|| {
@@ -194,12 +535,129 @@ impl Client {
 			match r.take().map(|t| t.join()) {
 				None => Ok(()),
 				Some(e) => e
-					.map(|_| ())
+					.map(|r| if let Ok(s) = r {
+						*self.summary.lock().unwrap() = Some(s);
+					})
 					.map_err(|_| "panic in client thread")
 			}
 		}).map_err(|e| PyException::new_err(e))
 	}
 
+	/// Resource usage and scheduling of the send thread as a dict,
+	/// `None` until the run has finished and been joined.
+	fn resource_usage<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyDict>>> {
+		let usage = py.detach(|| self.summary.lock().unwrap().as_ref().map(|s| s.usage.clone()));
+		usage.map(|u| usage_dict(py, &u)).transpose()
+	}
+
+	/// Echo counts by sequence number as a dict with the keys
+	/// `received`, `lost`, `reordered`, `duplicated`, and `late`, `None`
+	/// until the run has finished and been joined, or if the client
+	/// did not request echoes.
+	fn echo_stats<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyDict>>> {
+		let gaps = py.detach(|| {
+			self.summary.lock().unwrap().as_ref().and_then(|s| s.echoes.clone())
+		});
+		gaps.map(|g| gaps_dict(py, &g)).transpose()
+	}
+
+	/// Run a built-in generator with the given options (like `-O`
+	/// on the command line) until it ends and pending echoes have
+	/// arrived, and return the run statistics as a dict: `echoes`
+	/// (like `echo_stats()`), `send_calls`, and `usage` (like
+	/// `resource_usage()`). Echoes can be read from the packet log
+	/// afterwards. A KeyboardInterrupt (or other exception raised by
+	/// a signal handler) stops sending, waits for the run to end,
+	/// and is then raised.
+	#[pyo3(signature = (generator="default", options=None))]
+	fn run_to_completion<'py>(
+		&self, py: Python<'py>, generator: &str,
+		options: Option<HashMap<String, String>>)
+		-> PyResult<Bound<'py, PyDict>>
+	{
+		let generator: Generator = generator.parse()
+			.map_err(PyValueError::new_err)?;
+		let go = options.unwrap_or_default();
+		let stop = Arc::new(AtomicBool::new(false));
+		let (done_sender, done) = mpsc::channel::<()>();
+		let done = Mutex::new(done);
+		py.detach(|| {
+			let mut r = self.running.lock().unwrap();
+			if r.is_some() {
+				return Err(String::from("already running"));
+			}
+			let planned_count = generator.planned_count(&go).map_err(|e| e.to_string())?;
+			// the send log only goes to a generator that adjusts to it
+			let (send_log, feedback) = if generator.uses_feedback() {
+				let (s, r) = mpsc::channel();
+				(Some(s), Some(r))
+			} else {
+				(None, None)
+			};
+			let gen_receiver = generator.run_with_feedback(go, feedback)
+				.map_err(|e| e.to_string())?;
+			let (log_sender, log_receiver) = log_channel(self.keep_recent, &self.recent)
+				.map_err(|e| e.to_string())?;
+			let (s, buf_size, echo) = (self.server, self.buffer_size, self.echo);
+			let clock = *self.clock.lock().unwrap();
+			let options = client::Options {
+				label: self.label.clone(),
+				flow: self.flow,
+				planned_count,
+				send_log,
+				stop: Some(Arc::clone(&stop)),
+				pause: Some(Arc::clone(&self.pause)),
+				keepalive: self.keepalive.lock().unwrap().map(Duration::from_secs_f64),
+				clock,
+				header_length: clock != SendClock::Realtime,
+				..Default::default()
+			};
+			let t = thread::spawn(move || {
+				// dropped when the run ends
+				let _done = done_sender;
+				client::run(
+					s, buf_size, echo, gen_receiver, Some(client::EchoWait::default()),
+					Some(log_sender), options)
+					.map_err(|e| format!("client run failed: {e}"))
+			});
+			*r = Some(t);
+			*self.log.lock().unwrap() = Some(log_receiver);
+			Ok(())
+		}).map_err(PyValueError::new_err)?;
+
+		// Wake up regularly so signal handlers (KeyboardInterrupt)
+		// can run, they need the GIL.
+		let interrupted = loop {
+			let finished = py.detach(|| !matches!(
+				done.lock().unwrap().recv_timeout(Duration::from_millis(100)),
+				Err(mpsc::RecvTimeoutError::Timeout)));
+			if finished {
+				break None;
+			}
+			if let Err(e) = py.check_signals() {
+				stop.store(true, Ordering::Relaxed);
+				break Some(e);
+			}
+		};
+		let summary = py.detach(|| {
+			let t = self.running.lock().unwrap().take();
+			match t.map(|t| t.join()) {
+				None => Err(String::from("client is not running")),
+				Some(Err(_)) => Err(String::from("panic in client thread")),
+				Some(Ok(r)) => r.inspect(|s| *self.summary.lock().unwrap() = Some(s.clone())),
+			}
+		});
+		if let Some(e) = interrupted {
+			return Err(e);
+		}
+		let summary = summary.map_err(PyException::new_err)?;
+		let d = PyDict::new(py);
+		d.set_item("echoes", summary.echoes.as_ref().map(|g| gaps_dict(py, g)).transpose()?)?;
+		d.set_item("send_calls", summary.send_calls)?;
+		d.set_item("usage", usage_dict(py, &summary.usage)?)?;
+		Ok(d)
+	}
+
 	fn __enter__<'py>(
 		slf: PyRef<'py, Self>, py: Python<'py>)
 		-> PyResult<PyRef<'py, Self>>
@@ -225,16 +683,39 @@ impl Client {
 	}
 
 	fn __next__(&self, py: Python<'_>) -> Option<PacketRecord> {
-		py.detach(|| {
-			let guard = self.log.lock().unwrap();
-			match guard.as_ref()
-				.map(|r| r.recv())
-				.unwrap_or(Err(RecvError))
-			{
-				Err(RecvError) => None,
-				Ok(record) => Some(PacketRecord {packet: record}),
-			}
-		})
+		py.detach(|| self.next_record())
+	}
+
+	/// The `n` most recently received packets, oldest first, at
+	/// most `keep_recent` as given to the constructor. Independent of
+	/// iteration, which still yields all packets.
+	fn recent(&self, py: Python<'_>, n: usize) -> Vec<PacketRecord> {
+		py.detach(|| self.recent_records(n))
+	}
+
+	/// Iterate over the packet log as tuples of primitives, see
+	/// RawRecords. Records are taken from the same log as with
+	/// regular iteration.
+	fn raw_records(&self) -> RawRecords {
+		RawRecords { log: Arc::clone(&self.log) }
+	}
+
+	fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+		slf
+	}
+
+	fn __anext__<'py>(slf: Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+		anext_record(slf)
+	}
+}
+
+impl PacketLog for Client {
+	fn log(&self) -> &Log {
+		&self.log
+	}
+
+	fn recent_ring(&self) -> &Recent {
+		&self.recent
 	}
 }
 
@@ -244,30 +725,74 @@ struct Server {
 	bind: Mutex<SockaddrStorage>,
 	#[pyo3(get)]
 	buffer_size: usize,
+	#[pyo3(get)]
+	label: Option<String>,
 	handle: Mutex<Option<server::CloseHandle>>,
-	running: Mutex<Option<thread::JoinHandle<Result<(), String>>>>,
-	log: Mutex<Option<mpsc::Receiver<ReceivedPacket>>>,
+	running: Mutex<Option<thread::JoinHandle<Result<server::Summary, String>>>>,
+	/// resource usage of the finished run, set by join()
+	usage: Mutex<Option<RunUsage>>,
+	log: Log,
+	/// pre-bound socket to use on start instead of binding
+	fd: Mutex<Option<OwnedFd>>,
+	#[pyo3(get)]
+	keep_recent: usize,
+	recent: Recent,
 }
 
 #[pymethods]
 impl Server {
 	#[new]
-	#[pyo3(signature = (bind, port=7800, buffer_size=1500))]
-	fn new(bind: &str, port: u16, buffer_size: usize) -> PyResult<Self> {
-		let bind_ip: IpAddr = match bind.parse() {
-			Ok(i) => i,
-			Err(e) => { return Err(PyValueError::new_err(e)); },
-		};
-		let bind_addr = match bind_ip {
-			IpAddr::V6(i) => SockaddrStorage::from(SocketAddrV6::new(i, port, 0, 0)),
-			IpAddr::V4(i) => SockaddrStorage::from(SocketAddrV4::new(i, port)),
-		};
+	#[pyo3(signature = (bind, port=7800, buffer_size=1500, label=None, keep_recent=0))]
+	fn new(
+		bind: &str, port: u16, buffer_size: usize, label: Option<String>,
+		keep_recent: usize)
+		-> PyResult<Self>
+	{
+		let bind_addr = addr::bind_addr(bind, port, addr::Family::Auto)
+			.map_err(|e| PyValueError::new_err(
+				format!("invalid bind address: {e}")))?;
 		Ok(Server {
 			bind: Mutex::new(bind_addr),
 			buffer_size,
+			label,
 			handle: Mutex::new(None),
 			running: Mutex::new(None),
-			log: Mutex::new(None),
+			usage: Mutex::new(None),
+			log: Arc::new(Mutex::new(None)),
+			fd: Mutex::new(None),
+			keep_recent,
+			recent: Mutex::new(None),
+		})
+	}
+
+	/// Create a server using an already bound datagram socket. The
+	/// file descriptor is duplicated, the caller keeps ownership of
+	/// the original.
+	#[staticmethod]
+	#[pyo3(signature = (fd, buffer_size=1500, label=None, keep_recent=0))]
+	fn from_fd(
+		fd: RawFd, buffer_size: usize, label: Option<String>, keep_recent: usize)
+		-> PyResult<Self>
+	{
+		if fd < 0 {
+			return Err(PyValueError::new_err("invalid file descriptor"));
+		}
+		// SAFETY: the descriptor is only borrowed for the duration
+		// of the dup, an fd that is not open fails with EBADF.
+		let sock = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+		let bind = socket::getsockname::<SockaddrStorage>(sock.as_raw_fd())
+			.map_err(|e| PyOSError::new_err(e.desc()))?;
+		Ok(Server {
+			bind: Mutex::new(bind),
+			buffer_size,
+			label,
+			handle: Mutex::new(None),
+			running: Mutex::new(None),
+			usage: Mutex::new(None),
+			log: Arc::new(Mutex::new(None)),
+			fd: Mutex::new(Some(sock)),
+			keep_recent,
+			recent: Mutex::new(None),
 		})
 	}
 
@@ -281,10 +806,23 @@ impl Server {
 				}
 			}
 			let (ch, jh, logger) = {
-				let (log_sender, logger) = mpsc::channel();
+				let (log_sender, logger) = log_channel(self.keep_recent, &self.recent)
+					.map_err(|e| e.raw_os_error().map_or(Errno::UnknownErrno, Errno::from_raw))?;
 				let mut b = self.bind.lock().unwrap();
-				let mut srv = server::Server::new(*b, self.buffer_size, Some(log_sender));
-				let server_handle = srv.bind()?;
+				let options = server::Options {
+					label: self.label.clone(),
+					..Default::default()
+				};
+				let (srv, server_handle) = match self.fd.lock().unwrap().take() {
+					Some(fd) => server::Server::from_fd(
+						fd, self.buffer_size, Some(log_sender), options)?,
+					None => {
+						let mut srv = server::Server::new(
+							*b, self.buffer_size, Some(log_sender), options);
+						let server_handle = srv.bind()?;
+						(srv, server_handle)
+					},
+				};
 				// address the server is *actually* bound to
 				*b = srv.bound().unwrap().clone();
 				let jh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
@@ -341,12 +879,21 @@ impl Server {
 			match r.take().map(|t| t.join()) {
 				None => Ok(()),
 				Some(e) => e
-					.map(|_| ())
+					.map(|r| if let Ok(s) = r {
+						*self.usage.lock().unwrap() = Some(s.usage);
+					})
 					.map_err(|_| "panic in server thread")
 			}
 		}).map_err(|e| PyException::new_err(e))
 	}
 
+	/// Resource usage and scheduling of the receive thread as a
+	/// dict, `None` until the run has finished and been joined.
+	fn resource_usage<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyDict>>> {
+		let usage = py.detach(|| self.usage.lock().unwrap().clone());
+		usage.map(|u| usage_dict(py, &u)).transpose()
+	}
+
 	fn __enter__<'py>(
 		slf: PyRef<'py, Self>, py: Python<'py>)
 		-> PyResult<PyRef<'py, Self>>
@@ -372,16 +919,39 @@ impl Server {
 	}
 
 	fn __next__(&self, py: Python<'_>) -> Option<PacketRecord> {
-		py.detach(|| {
-			let guard = self.log.lock().unwrap();
-			match guard.as_ref()
-				.map(|r| r.recv())
-				.unwrap_or(Err(RecvError))
-			{
-				Err(RecvError) => None,
-				Ok(record) => Some(PacketRecord {packet: record}),
-			}
-		})
+		py.detach(|| self.next_record())
+	}
+
+	/// The `n` most recently received packets, oldest first, at
+	/// most `keep_recent` as given to the constructor. Independent of
+	/// iteration, which still yields all packets.
+	fn recent(&self, py: Python<'_>, n: usize) -> Vec<PacketRecord> {
+		py.detach(|| self.recent_records(n))
+	}
+
+	/// Iterate over the packet log as tuples of primitives, see
+	/// RawRecords. Records are taken from the same log as with
+	/// regular iteration.
+	fn raw_records(&self) -> RawRecords {
+		RawRecords { log: Arc::clone(&self.log) }
+	}
+
+	fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+		slf
+	}
+
+	fn __anext__<'py>(slf: Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+		anext_record(slf)
+	}
+}
+
+impl PacketLog for Server {
+	fn log(&self) -> &Log {
+		&self.log
+	}
+
+	fn recent_ring(&self) -> &Recent {
+		&self.recent
 	}
 }
 
@@ -392,5 +962,6 @@ fn luna(m: &Bound<'_, PyModule>) -> PyResult<()> {
 	m.add_class::<Client>()?;
 	m.add_class::<Server>()?;
 	m.add_class::<PacketRecord>()?;
+	m.add_class::<RawRecords>()?;
     Ok(())
 }
@@ -1,7 +1,9 @@
+import asyncio
 import itertools
 import luna
 import pytest
 import random
+import socket
 import threading
 from contextlib import ExitStack
 from decimal import Decimal
@@ -82,6 +84,7 @@ def test_full() -> None:
         assert isinstance(record.receive_time, Decimal)
         assert isinstance(record.timestamp, Decimal)
         assert record.receive_time - record.timestamp < diff
+        assert record.latency == record.receive_time - record.timestamp
 
     assert len(server_log) == 10
     # 50ms should be enough for loopback one-way even on slow systems
@@ -98,6 +101,189 @@ def test_full() -> None:
         '<luna.PacketRecord: ReceivedPacket {')
 
 
+def test_run_to_completion():
+    with luna.Server(bind='::1', port=0) as server:
+        client = luna.Client(server.bind, flow=3)
+        stats = client.run_to_completion(
+            'default', {'count': '5', 'interval': '0.001', 'size': '32'})
+        assert stats['echoes'] == client.echo_stats()
+        assert stats['echoes']['received'] == 5
+        assert stats['send_calls'] == 5
+        assert stats['usage']['sched_policy'].startswith('SCHED_')
+        assert [(p.sequence, p.flow) for p in client] \
+            == [(i, 3) for i in range(5)]
+        assert [p.sequence for p in itertools.islice(server, 5)] \
+            == list(range(5))
+    with pytest.raises(ValueError):
+        client.run_to_completion('no-such-generator')
+
+
+def test_clock():
+    with luna.Server(bind='::1', port=0) as server:
+        client = luna.Client(server.bind)
+        assert client.clock == 'realtime'
+        client.clock = 'monotonic'
+        assert client.clock == 'monotonic'
+        client.run_to_completion(
+            'default', {'count': '3', 'interval': '0.001'})
+        assert [p.clock for p in client] == ['monotonic'] * 3
+        assert [p.clock for p in itertools.islice(server, 3)] \
+            == ['monotonic'] * 3
+    client = luna.Client('[::1]:7800', buffer_size=21)
+    with pytest.raises(ValueError):
+        client.clock = 'sundial'
+    with pytest.raises(ValueError, match=r'^the tai clock needs'):
+        client.clock = 'tai'
+    assert client.clock == 'realtime'
+
+
+def test_echo_stats():
+    with luna.Server(bind='::1', port=0) as server:
+        client = luna.Client(server.bind)
+        assert client.echo_stats() is None
+        client.run_to_completion(
+            'default', {'count': '5', 'interval': '0.001'})
+        assert client.echo_stats() == {
+            'received': 5, 'lost': 0, 'reordered': 0, 'duplicated': 0, 'late': 0}
+        client = luna.Client(server.bind, echo=False)
+        stats = client.run_to_completion(
+            'default', {'count': '2', 'interval': '0.001'})
+        assert client.echo_stats() is None
+        assert stats['echoes'] is None
+
+
+def test_recent():
+    n = 5
+    with luna.Server(bind='::1', port=0, keep_recent=n) as server:
+        assert server.recent(n) == []
+        client = luna.Client(server.bind, keep_recent=n)
+        client.run_to_completion(
+            'default', {'count': str(2 * n), 'interval': '0.001'})
+        # the client log has ended, so the ring is complete
+        assert [p.sequence for p in client] == list(range(2 * n))
+        assert [p.sequence for p in client.recent(n)] \
+            == list(range(n, 2 * n))
+        assert [p.sequence for p in client.recent(2)] == [8, 9]
+        # iteration still yields all packets
+        assert [p.sequence for p in itertools.islice(server, 2 * n)] \
+            == list(range(2 * n))
+        assert [p.sequence for p in server.recent(n)] \
+            == list(range(n, 2 * n))
+    assert luna.Client('[::1]:7800').recent(3) == []
+
+
+def test_pause():
+    with luna.Server(bind='::1', port=0, keep_recent=4) as server:
+        client = luna.Client(server.bind)
+        with client:
+            client.put((0, 1000000), luna.MIN_SIZE)
+            client.put((0, 1000000), luna.MIN_SIZE)
+            # echoes arrive, even while paused
+            assert [p.sequence for p in itertools.islice(client, 2)] \
+                == [0, 1]
+            client.pause()
+            assert client.paused
+            client.put((0, 1000000), luna.MIN_SIZE)
+            client.put((0, 1000000), luna.MIN_SIZE)
+            threading.Event().wait(0.2)
+            assert [p.sequence for p in server.recent(4)] == [0, 1]
+            client.resume()
+            client.close()
+        assert [p.sequence for p in client] == [2, 3]
+
+
+def test_pause_close():
+    with luna.Server(bind='::1', port=0) as server:
+        client = luna.Client(server.bind)
+        # start() does not keep a pause from before
+        client.pause()
+        with client:
+            assert not client.paused
+            client.pause()
+            client.put((0, 1000000), luna.MIN_SIZE)
+        # leaving the block ended the pause, the packet was sent
+        assert not client.paused
+        assert [p.sequence for p in client] == [0]
+
+
+def test_keepalive():
+    with socket.socket(socket.AF_INET6, socket.SOCK_DGRAM) as sock:
+        sock.bind(('::1', 0))
+        sock.settimeout(2)
+        port = sock.getsockname()[1]
+        client = luna.Client(f'[::1]:{port}', echo=False)
+        assert client.keepalive is None
+        client.keepalive = 0.02
+        assert client.keepalive == 0.02
+        with client:
+            # nothing to send, only keepalives
+            data = sock.recv(1500)
+            assert len(data) == luna.MIN_SIZE
+            assert data[20] == 0xf0
+            client.put((0, 0), luna.MIN_SIZE)
+            client.close()
+        while (data := sock.recv(1500))[20] == 0xf0:
+            pass
+        assert data[:4] == bytes(4)
+    with pytest.raises(ValueError):
+        client.keepalive = 0
+
+
+def test_put_bytes():
+    with socket.socket(socket.AF_INET6, socket.SOCK_DGRAM) as sock:
+        sock.bind(('::1', 0))
+        sock.settimeout(2)
+        port = sock.getsockname()[1]
+        client = luna.Client(f'[::1]:{port}', buffer_size=64, echo=False)
+        with client:
+            client.put_bytes((0, 1000000), b'luna')
+            client.put((0, 1000000), luna.MIN_SIZE + 6)
+            with pytest.raises(ValueError, match='too large'):
+                client.put_bytes((0, 0), bytes(64))
+            client.close()
+        first = sock.recv(64)
+        second = sock.recv(64)
+    assert len(first) == luna.MIN_SIZE + 4
+    assert first[luna.MIN_SIZE:] == b'luna'
+    # the payload does not leak into the following packet
+    assert second[luna.MIN_SIZE:] == bytes(6)
+
+
+def test_put_bytes_clock():
+    with socket.socket(socket.AF_INET6, socket.SOCK_DGRAM) as sock:
+        sock.bind(('::1', 0))
+        sock.settimeout(2)
+        port = sock.getsockname()[1]
+        client = luna.Client(f'[::1]:{port}', buffer_size=64, echo=False)
+        client.clock = 'monotonic'
+        # header length and clock byte come before the payload
+        header = luna.MIN_SIZE + 2
+        with client:
+            with pytest.raises(ValueError, match='too large'):
+                client.put_bytes((0, 0), bytes(64 - luna.MIN_SIZE))
+            client.put_bytes((0, 1000000), b'x' * (64 - header))
+            client.close()
+        packet = sock.recv(64)
+    assert len(packet) == 64
+    assert packet[header:] == b'x' * (64 - header)
+
+
+def test_latency_negative():
+    server = luna.Server(bind='::1', port=0, buffer_size=luna.MIN_SIZE)
+    with server, socket.socket(socket.AF_INET6, socket.SOCK_DGRAM) as sock:
+        # a send timestamp far in the future, like with a clock
+        # running ahead on the sender
+        sec = 4_000_000_000
+        packet = (7).to_bytes(4) + sec.to_bytes(8) + (5).to_bytes(8) \
+            + bytes(1)
+        sock.sendto(packet, ('::1', int(server.bind.rsplit(':', 1)[1])))
+        record = next(server)
+    assert record.timestamp == Decimal('4000000000.000000005')
+    assert isinstance(record.latency, Decimal)
+    assert record.latency < 0
+    assert record.latency == record.receive_time - record.timestamp
+
+
 def test_client_not_connected():
     client = luna.Client('[::1]:7800')
     with pytest.raises(Exception, match=r'^client is not running'):
@@ -109,6 +295,50 @@ def test_class_name():
     assert repr(client).startswith('<luna.Client object')
 
 
+def test_client_family():
+    assert luna.Client('localhost:7800', family='inet').server \
+        == '127.0.0.1:7800'
+    with pytest.raises(ValueError, match=r'^could not resolve address'):
+        luna.Client('127.0.0.1:7800', family='inet6')
+    with pytest.raises(ValueError):
+        luna.Client('127.0.0.1:7800', family='ipx')
+
+
+def test_server_bind_name():
+    server = luna.Server(bind='localhost', port=0)
+    assert server.bind.startswith(('127.0.0.1:', '[::1]:'))
+    with pytest.raises(ValueError, match=r'^invalid bind address'):
+        luna.Server(bind='no-such-host.invalid')
+
+
+def test_label():
+    assert luna.Client('[::1]:7800').label is None
+    client = luna.Client('[::1]:7800', label='before-change')
+    assert client.label == 'before-change'
+    server = luna.Server(bind='::1', port=0, label='after-change')
+    assert server.label == 'after-change'
+
+
+def test_server_from_fd():
+    with socket.socket(socket.AF_INET6, socket.SOCK_DGRAM) as sock:
+        sock.bind(('::1', 0))
+        port = sock.getsockname()[1]
+        server = luna.Server.from_fd(sock.fileno(), buffer_size=luna.MIN_SIZE)
+    assert server.bind == f'[::1]:{port}'
+    with server:
+        with socket.socket(socket.AF_INET6, socket.SOCK_DGRAM) as sender:
+            sender.sendto(bytes(luna.MIN_SIZE), ('::1', port))
+        record = next(server)
+    assert record.sequence == 0
+    assert record.size == luna.MIN_SIZE
+
+    with socket.socket(socket.AF_INET6, socket.SOCK_STREAM) as sock:
+        sock.bind(('::1', 0))
+        server = luna.Server.from_fd(sock.fileno())
+        with pytest.raises(OSError):
+            server.start()
+
+
 def test_server_double_join():
     with luna.Server(bind='::1', port=0, buffer_size=luna.MIN_SIZE) as server:
         pass
@@ -121,3 +351,76 @@ def test_client_double_join():
         with client:
             client.close()
     client.join()
+
+
+def test_server_async() -> None:
+    packets = 3
+
+    async def receive(server: luna.Server) -> list[luna.PacketRecord]:
+        records = []
+        async for record in server:
+            records.append(record)
+            if len(records) == packets:
+                # end the log, so iteration stops
+                server.stop()
+        return records
+
+    async def main() -> list[luna.PacketRecord]:
+        with luna.Server(bind='::1', port=0, buffer_size=luna.MIN_SIZE) as server:
+            port = int(server.bind.rsplit(':', 1)[1])
+            task = asyncio.create_task(receive(server))
+            with socket.socket(socket.AF_INET6, socket.SOCK_DGRAM) as sender:
+                for seq in range(packets):
+                    sender.sendto(
+                        seq.to_bytes(4) + bytes(luna.MIN_SIZE - 4),
+                        ('::1', port))
+                    # the event loop is not blocked by the receive
+                    await asyncio.sleep(0.01)
+            return await asyncio.wait_for(task, 3.0)
+
+    records = asyncio.run(main())
+    assert [r.sequence for r in records] == list(range(packets))
+
+
+def test_raw_records() -> None:
+    server = luna.Server(bind='::1', port=0, buffer_size=luna.MIN_SIZE)
+    raw = server.raw_records()
+    with server:
+        client = luna.Client(server.bind)
+        with client:
+            client.put((0, 1000000), luna.MIN_SIZE)
+            client.put((0, 1000000), luna.MIN_SIZE + 8)
+            client.close()
+        first = next(raw)
+        record = next(server)
+    rest = list(raw)
+    assert len(rest) == 0
+    assert first[0] == 0
+    assert record.sequence == 1
+    seq, receive_ns, timestamp_ns, size, flags, source = first
+    assert size == luna.MIN_SIZE
+    assert flags >> 4 == 1
+    assert 0 < timestamp_ns <= receive_ns
+    assert source.startswith('[::1]:')
+
+
+def test_resource_usage() -> None:
+    server = luna.Server(bind='::1', port=0, buffer_size=luna.MIN_SIZE)
+    assert server.resource_usage() is None
+    with server:
+        client = luna.Client(server.bind)
+        with client:
+            client.put((0, 1000000), luna.MIN_SIZE)
+            client.close()
+        assert next(server).sequence == 0
+    for usage in (client.resource_usage(), server.resource_usage()):
+        assert usage is not None
+        assert set(usage) == {
+            'user_time', 'system_time', 'major_page_faults',
+            'minor_page_faults', 'voluntary_context_switches',
+            'involuntary_context_switches', 'sched_policy',
+            'sched_priority', 'memlock_limit'}
+        assert usage['major_page_faults'] >= 0
+        assert usage['user_time'] >= 0.0
+        assert usage['sched_policy'] in ('SCHED_OTHER', 'SCHED_RR')
+        assert usage['memlock_limit'] is None or usage['memlock_limit'] >= 0
@@ -0,0 +1,149 @@
+use clap::ValueEnum;
+use nix::sys::socket::SockaddrStorage;
+use std::{
+	io::{Error, ErrorKind},
+	net::{IpAddr, SocketAddr, ToSocketAddrs},
+	str::FromStr,
+};
+
+
+/// Port the server listens on by default.
+pub const DEFAULT_PORT: u16 = 7800;
+
+
+/// Address family to use for a host name that resolves to several
+/// addresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Family {
+	/// use the first address the name resolves to
+	#[default]
+	Auto,
+	/// IPv4 only
+	Inet,
+	/// IPv6 only
+	Inet6,
+}
+
+impl Family {
+	fn matches(&self, addr: &SocketAddr) -> bool {
+		match self {
+			Family::Auto => true,
+			Family::Inet => addr.is_ipv4(),
+			Family::Inet6 => addr.is_ipv6(),
+		}
+	}
+}
+
+impl FromStr for Family {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		<Self as ValueEnum>::from_str(s, false)
+	}
+}
+
+
+/// Resolve host and port, using the first result of the requested
+/// family. IP addresses are used as is, without a lookup.
+fn lookup(host: &str, port: u16, family: Family) -> Result<SocketAddr, Error> {
+	let not_found = || Error::new(
+		ErrorKind::NotFound,
+		format!("no {family:?} address found for {host}"));
+	if let Ok(ip) = host.parse::<IpAddr>() {
+		return Some(SocketAddr::new(ip, port))
+			.filter(|a| family.matches(a))
+			.ok_or_else(not_found);
+	}
+	(host, port).to_socket_addrs()?
+		.find(|a| family.matches(a))
+		.ok_or_else(not_found)
+}
+
+
+/// Resolve the server address for the client. The target is a host
+/// name or IP address, optionally followed by a colon and port
+/// (IPv6 addresses with port in brackets, e.g. `[::1]:7800`). Without
+/// port [`DEFAULT_PORT`] is used.
+pub fn resolve(server: &str, family: Family) -> Result<SocketAddr, Error> {
+	if let Ok(a) = server.parse::<SocketAddr>() {
+		return lookup(&a.ip().to_string(), a.port(), family);
+	}
+	// a bare IPv6 address contains colons, but no port
+	if server.parse::<IpAddr>().is_ok() {
+		return lookup(server, DEFAULT_PORT, family);
+	}
+	match server.rsplit_once(':') {
+		Some((host, port)) => {
+			let port = port.parse().map_err(|_| Error::new(
+				ErrorKind::InvalidInput, format!("invalid port in {server}")))?;
+			lookup(host, port, family)
+		},
+		None => lookup(server, DEFAULT_PORT, family),
+	}
+}
+
+
+/// Build the address for the server to bind to from a host name or
+/// IP address and port. Port 0 lets the kernel pick a free port when
+/// binding.
+pub fn bind_addr(host: &str, port: u16, family: Family) -> Result<SockaddrStorage, Error> {
+	lookup(host, port, family).map(SockaddrStorage::from)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn resolve_family() -> Result<(), Error> {
+		assert_eq!(resolve("[::1]:7800", Family::Auto)?, "[::1]:7800".parse().unwrap());
+		assert_eq!(resolve("[::1]:7800", Family::Inet6)?, "[::1]:7800".parse().unwrap());
+		assert_eq!(
+			resolve("127.0.0.1:7800", Family::Inet)?,
+			"127.0.0.1:7800".parse().unwrap());
+		assert_eq!(
+			resolve("127.0.0.1:7800", Family::Inet6).unwrap_err().kind(),
+			ErrorKind::NotFound);
+		assert_eq!(
+			resolve("[::1]:7800", Family::Inet).unwrap_err().kind(),
+			ErrorKind::NotFound);
+		assert_eq!("inet6".parse::<Family>(), Ok(Family::Inet6));
+		assert!("ipx".parse::<Family>().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn resolve_default_port() -> Result<(), Error> {
+		assert_eq!(resolve("::1", Family::Auto)?, "[::1]:7800".parse().unwrap());
+		assert_eq!(resolve("192.0.2.1", Family::Auto)?, "192.0.2.1:7800".parse().unwrap());
+		assert_eq!(
+			resolve("localhost:x", Family::Auto).unwrap_err().kind(),
+			ErrorKind::InvalidInput);
+		Ok(())
+	}
+
+	#[test]
+	fn resolve_hostname() -> Result<(), Error> {
+		let a = resolve("localhost:7801", Family::Inet)?;
+		assert!(a.ip().is_loopback());
+		assert_eq!(a.port(), 7801);
+		let a = resolve("localhost", Family::Inet)?;
+		assert_eq!(a.port(), DEFAULT_PORT);
+		Ok(())
+	}
+
+	#[test]
+	fn bind_addresses() -> Result<(), Error> {
+		let a = bind_addr("::", 0, Family::Auto)?;
+		assert_eq!(a.as_sockaddr_in6().map(|a| (a.ip().is_unspecified(), a.port())), Some((true, 0)));
+		let a = bind_addr("127.0.0.1", 7800, Family::Auto)?;
+		assert_eq!(a.as_sockaddr_in().map(|a| a.to_string()), Some("127.0.0.1:7800".to_string()));
+		let a = bind_addr("localhost", 0, Family::Inet)?;
+		assert!(a.as_sockaddr_in().is_some_and(|a| a.ip().is_loopback()));
+		assert_eq!(
+			bind_addr("::1", 0, Family::Inet).unwrap_err().kind(),
+			ErrorKind::NotFound);
+		Ok(())
+	}
+}
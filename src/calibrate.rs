@@ -0,0 +1,116 @@
+use crate::{
+	client, server, stats::Latency, PacketData, MIN_SIZE
+};
+use nix::sys::{socket::SockaddrStorage, time::TimeSpec};
+use std::{
+	fmt::{self, Display, Formatter},
+	net::{SocketAddr, SocketAddrV6},
+	sync::mpsc,
+	thread,
+	time::Duration,
+};
+
+
+/// Result of a loopback calibration run, see [`run`].
+#[derive(Clone, Debug)]
+pub struct Calibration {
+	/// round trip times of echoes over loopback
+	pub rtt: Latency,
+	/// whether the client and server threads could enable realtime
+	/// scheduling (CAP_SYS_NICE in the permitted set)
+	pub rt_priority: bool,
+	/// whether memory was locked during the run
+	pub mlock: bool,
+}
+
+
+impl Display for Calibration {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let ms = |ns: Option<i64>| ns.map(|n| n as f64 / 1e6).unwrap_or(f64::NAN);
+		writeln!(f, "loopback round trip times: {} echoes", self.rtt.count())?;
+		writeln!(
+			f, "mean {:.3} ms, stddev {:.3} ms, p50 {:.3} ms, p99 {:.3} ms, max {:.3} ms",
+			ms(self.rtt.mean()), ms(self.rtt.stddev()),
+			ms(self.rtt.percentile(50.0)), ms(self.rtt.percentile(99.0)),
+			ms(self.rtt.percentile(100.0)))?;
+		let yes_no = |b| if b { "yes" } else { "no" };
+		writeln!(f, "realtime priority: {}", yes_no(self.rt_priority))?;
+		write!(f, "memory locked: {}", yes_no(self.mlock))
+	}
+}
+
+
+/// Whether the process currently has locked memory, according to
+/// /proc/self/status.
+fn memory_locked() -> bool {
+	crate::proc_status_bytes("VmLck").is_some_and(|b| b > 0)
+}
+
+
+/// Run a server and a client echoing `count` minimum size packets
+/// at the given interval over the IPv6 loopback interface. The
+/// round trip times show the noise of timestamping and scheduling on
+/// this host, which is a floor below which differences in network
+/// measurements are not meaningful.
+pub fn run(count: usize, interval: Duration)
+	-> Result<Calibration, Box<dyn std::error::Error>>
+{
+	let rt_priority = caps::has_cap(
+		None, caps::CapSet::Permitted, caps::Capability::CAP_SYS_NICE)?;
+
+	let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+	let (server_log, server_logger) = mpsc::channel();
+	let mut srv = server::Server::new(
+		bind_addr, MIN_SIZE, Some(server_log), server::Options::default());
+	let handle = srv.bind()?;
+	let server_addr = SocketAddr::V6(
+		srv.bound().and_then(|b| b.as_sockaddr_in6().copied())
+			.ok_or("server not bound to an IPv6 address")?
+			.into());
+	let st = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+	let (sender, receiver) = mpsc::channel();
+	let delay = TimeSpec::from(interval);
+	for _ in 0..count {
+		sender.send(PacketData { delay, size: MIN_SIZE, departure: None, payload: None })?;
+	}
+	drop(sender);
+	let (echo_log, echo_logger) = mpsc::channel();
+	let client_result = client::run(
+		server_addr, MIN_SIZE, true, receiver,
+		Some(client::EchoWait::default()), Some(echo_log),
+		client::Options::default());
+	// mlockall applies to the whole process and is not undone
+	let mlock = memory_locked();
+
+	handle.close()?;
+	st.join().map_err(|_| "panic in server thread")??;
+	drop(server_logger);
+	client_result?;
+
+	let mut rtt = Latency::new();
+	for echo in echo_logger.iter() {
+		rtt.record(echo.receive_time - echo.timestamp);
+	}
+	Ok(Calibration { rtt, rt_priority, mlock })
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn loopback() -> Result<(), Box<dyn std::error::Error>> {
+		let c = run(20, Duration::from_millis(1))?;
+		assert_eq!(c.rtt.count(), 20);
+		// loopback round trips take less than a second even on a
+		// heavily loaded test host
+		let max = c.rtt.percentile(100.0).unwrap();
+		assert!(max > 0 && max < 1_000_000_000, "max RTT {max} ns");
+		let summary = format!("{c}");
+		assert!(summary.starts_with("loopback round trip times: 20 echoes\n"));
+		assert!(summary.contains("\nrealtime priority: "));
+		Ok(())
+	}
+}
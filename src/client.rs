@@ -1,71 +1,1054 @@
-use crate::{set_rt_prio, PacketData, ReceivedPacket, ECHO_FLAG};
+use crate::{
+	hexdump, recv_frag_size, recv_timestamps, recv_tos, set_rt_prio, set_tos, socket_timeout,
+	ControlMessages, Dscp, Ecn, Flags, LogField, LogFormat, PacketData, ReceivedPacket, Scheduling, SendClock, SentPacket, SocketQueues,
+	TimeFormat, TimestampSource,
+	CLOCK_SIZE, CLOCK_VERSION, HEADER_LENGTH_VERSION,
+	ECHO_FLAG, ECHO_SEQ_FLAG, FLOW_FLAG, FLOW_SIZE, MIN_SIZE
+};
+use crate::stats::{
+	timespec_nanos, Arrival, BurstRtt, FirstEcho, Latency, Live, MemBudget, PathLoss, ReceiveOrder, ResourceUsage, RunUsage,
+	SendGaps, SendRate, SequenceGaps, SizeBuckets, Throughput, ThroughputSummary
+};
 
+use clap::ValueEnum;
 use nix::sys::socket::SockaddrStorage;
 
-use std::io::{Error, ErrorKind, IoSlice, IoSliceMut};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufWriter, Error, ErrorKind, IoSlice, IoSliceMut, Write};
 use std::net::SocketAddr;
-use std::os::fd::AsRawFd;
-use std::sync::mpsc;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::str::FromStr;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use nix::{cmsg_space, sys::{mman, resource, socket, time::TimeSpec}};
+use nix::{errno::Errno, sys::{resource, socket, time::TimeSpec}};
 use nix::time::{ClockId, ClockNanosleepFlags, clock_gettime, clock_nanosleep};
 
-static CLOCK: ClockId = ClockId::CLOCK_REALTIME;
+
+/// How to handle packets larger than the path MTU, see
+/// [`Options::fragment`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Fragment {
+	/// don't set DF, the kernel fragments packets larger than the
+	/// path MTU (`IP_PMTUDISC_WANT`)
+	Allow,
+	/// set DF, sending packets larger than the known path MTU fails
+	/// with `EMSGSIZE`, the client skips and counts them
+	/// (`IP_PMTUDISC_DO`)
+	Deny,
+	/// set DF but ignore the known path MTU, so only the interface
+	/// MTU limits the packet size, for probing the path
+	/// (`IP_PMTUDISC_PROBE`)
+	Probe,
+}
+
+impl Fragment {
+	/// Value of the `IP_MTU_DISCOVER` or `IPV6_MTU_DISCOVER` socket
+	/// option for this mode, the values are the same for both.
+	pub fn pmtudisc(self) -> libc::c_int {
+		match self {
+			Fragment::Allow => libc::IP_PMTUDISC_WANT,
+			Fragment::Deny => libc::IP_PMTUDISC_DO,
+			Fragment::Probe => libc::IP_PMTUDISC_PROBE,
+		}
+	}
+
+	/// Set the mode on an IP socket of the given family.
+	fn apply<F: AsFd>(self, sock: &F, family: socket::AddressFamily) -> Result<(), Errno> {
+		let (level, name) = if family == socket::AddressFamily::Inet6 {
+			(libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER)
+		} else {
+			(libc::IPPROTO_IP, libc::IP_MTU_DISCOVER)
+		};
+		let value = self.pmtudisc();
+		let ret = unsafe {
+			libc::setsockopt(
+				sock.as_fd().as_raw_fd(), level, name,
+				&value as *const libc::c_int as *const libc::c_void,
+				size_of::<libc::c_int>() as libc::socklen_t)
+		};
+		Errno::result(ret).map(drop)
+	}
+}
+
+
+/// Default time to wait for pending echoes after the last packet.
+pub const DEFAULT_ECHO_WAIT: Duration = Duration::from_millis(200);
+
+/// Default limit of the total wait of [`EchoWait::UntilIdle`].
+pub const DEFAULT_IDLE_WAIT_LIMIT: Duration = Duration::from_secs(10);
+
+
+/// How often a paused send loop checks if it should resume, see
+/// [`Options::pause`].
+const PAUSE_CHECK: Duration = Duration::from_millis(5);
+
+
+/// How long [`run`] waits for pending echoes after the last packet
+/// has been sent. The string form is `fixed:SECONDS`, `adaptive`, or
+/// `until-idle:SECONDS[:MAX_SECONDS]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EchoWait {
+	/// wait for the given time
+	Fixed(Duration),
+	/// wait twice the 99th percentile of the round trip times
+	/// measured so far, but at least 10ms to allow for scheduling
+	/// delays, or [`DEFAULT_ECHO_WAIT`] if there are none
+	Adaptive,
+	/// wait until no echo has arrived for the first time, but at
+	/// most the second time in total (default
+	/// [`DEFAULT_IDLE_WAIT_LIMIT`]), so echoes that keep trickling
+	/// in cannot hold the run open forever
+	UntilIdle(Duration, Duration),
+}
+
+impl EchoWait {
+	/// Wait time for the adaptive strategy, given the round trip
+	/// times measured so far.
+	fn adaptive(rtt: &Latency) -> Duration {
+		rtt.percentile(99.0)
+			.map_or(DEFAULT_ECHO_WAIT, |p| Duration::from_nanos(p.max(0) as u64 * 2)
+				.max(Duration::from_millis(10)))
+	}
+}
+
+impl Default for EchoWait {
+	fn default() -> Self {
+		EchoWait::Fixed(DEFAULT_ECHO_WAIT)
+	}
+}
+
+impl FromStr for EchoWait {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let seconds = |v: &str| v.parse::<f64>()
+			.map_err(|e| e.to_string())
+			.and_then(|v| Duration::try_from_secs_f64(v).map_err(|e| e.to_string()))
+			.map_err(|e| format!("invalid duration {v:?} in echo wait strategy: {e}"));
+		match s.split_once(':') {
+			None if s == "adaptive" => Ok(EchoWait::Adaptive),
+			Some(("fixed", v)) => seconds(v).map(EchoWait::Fixed),
+			Some(("until-idle", v)) => match v.split_once(':') {
+				Some((idle, max)) => Ok(EchoWait::UntilIdle(seconds(idle)?, seconds(max)?)),
+				None => seconds(v).map(|idle| EchoWait::UntilIdle(idle, DEFAULT_IDLE_WAIT_LIMIT)),
+			},
+			_ => Err(format!(
+				"invalid echo wait strategy {s:?}, expected fixed:SECONDS, adaptive, or \
+				 until-idle:SECONDS[:MAX_SECONDS]")),
+		}
+	}
+}
+
+
+/// Optional settings for the client, see [`run`].
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+	/// number of packets at the start of the run to exclude from the
+	/// steady state throughput
+	pub warmup: usize,
+	/// if `Some`, report echo round trip times by packet size, the
+	/// values are the inclusive upper size bounds of the buckets
+	pub size_buckets: Option<Vec<usize>>,
+	/// if `Some`, write a JSON statistics snapshot to standard error
+	/// at this interval
+	pub stats_interval: Option<Duration>,
+	/// label to tag the run with in statistics output
+	pub label: Option<String>,
+	/// if `Some`, verify that echo content matches what was sent for
+	/// packets within a window of this many most recent sequence
+	/// numbers
+	pub verify_echo: Option<usize>,
+	/// number of packets the generator is expected to produce, if
+	/// known, for progress reporting
+	pub planned_count: Option<usize>,
+	/// fill the payload after the header with pseudo-random bytes
+	/// that differ for every packet, instead of zeros. Identical
+	/// back-to-back packets may be coalesced by GRO/LRO in ways that
+	/// confuse per-packet timestamping. Note that offloads merge
+	/// flows based on headers, so unique content does not disable
+	/// them, for exact timestamps disable GRO on the receiving
+	/// interface (`ethtool -K <dev> gro off`).
+	pub unique_payload: bool,
+	/// repeat the sequence number in the first four bytes of the
+	/// payload (after the header), in packets large enough, see
+	/// [`crate::payload_sequence`]. Overwrites the start of unique
+	/// or generator supplied payload.
+	pub payload_sequence: bool,
+	/// measure the mean cost of the send syscall over the first
+	/// [`SEND_CALIBRATION`] packets, and afterwards wake up that much
+	/// earlier so packets leave closer to their scheduled time
+	pub compensate_send: bool,
+	/// request echo sequence numbers from the server to split loss
+	/// into forward and reverse path, only for packets of at least
+	/// [`crate::ECHO_SEQ_SIZE`] bytes (one more with
+	/// [`Options::header_length`]), see [`PathLoss`]
+	pub echo_sequence: bool,
+	/// if `Some`, fail the run if buffers, the echo verification
+	/// window, and size bucket samples (8 bytes per echo) would need
+	/// more than this many bytes. Live statistics samples are reset
+	/// each interval and not counted, neither is data queued in the
+	/// echo logger channel.
+	pub mem_budget: Option<usize>,
+	/// if `Some`, write a hex dump of up to this many bytes of each
+	/// sent packet and received echo to standard error, for
+	/// debugging (this slows down sending considerably)
+	pub hexdump: Option<usize>,
+	/// if `Some`, send packets with this ECN codepoint. The server
+	/// reflects ECN capability in echoes, CE marks on echoes are
+	/// counted.
+	pub ecn: Option<Ecn>,
+	/// if `Some`, send packets with this DSCP codepoint, to see how
+	/// the network treats the QoS class
+	pub dscp: Option<Dscp>,
+	/// receive echoes in the send loop after each packet instead of
+	/// in a separate thread. Simpler for low rate probes, but
+	/// receiving delays the following packets, so it is not suitable
+	/// for high rates.
+	pub sync_echo: bool,
+	/// record the gaps between consecutive sends and report their
+	/// distribution, see [`SendGaps`]. Samples count against the
+	/// memory budget (8 bytes per packet).
+	pub send_gaps: bool,
+	/// if `Some`, set how packets larger than the path MTU are
+	/// handled, otherwise the system default applies
+	/// (`net.ipv4.ip_no_pmtu_disc`). Packets the kernel refuses to
+	/// send with `EMSGSIZE` are skipped and counted in any mode.
+	pub fragment: Option<Fragment>,
+	/// if `Some`, send a record of each packet actually sent, with
+	/// the delay since the previous one as measured, so the achieved
+	/// schedule (including overruns) can be replayed later
+	pub send_log: Option<mpsc::Sender<SentPacket>>,
+	/// if `Some`, stop after this many packets (including burst
+	/// packets), even if the generator would produce more
+	pub max_packets: Option<usize>,
+	/// if `Some`, stop sending when this much time has passed since
+	/// the start of sending, even if the generator would produce
+	/// more packets. Checked before each packet, a packet due after
+	/// the end is not sent. Waiting for the generator, a pause, or
+	/// the departure time of the next packet ends at the latest at
+	/// the end. Echoes are waited for as usual afterwards.
+	pub duration: Option<Duration>,
+	/// send this many packets of the full buffer size back to back
+	/// before the packets from the generator, and report their round
+	/// trip times separately, see [`BurstRtt`]. Burst packets are
+	/// excluded from the steady state throughput and size buckets.
+	pub burst: usize,
+	/// send up to this many packets that are due at the same time
+	/// (e.g. burst packets, or packets the loop fell behind on) with
+	/// one `sendmmsg` call, to reach higher packet rates. Each packet
+	/// still gets its own send timestamp, taken when it is added to
+	/// the batch. Packets are batched only if their scheduled send
+	/// time had passed when the first one of the batch was stamped,
+	/// and with [`Options::min_interval`] only burst packets. 0 or 1
+	/// send every packet on its own.
+	pub batch: usize,
+	/// timestamp format for echoes written to standard output
+	pub time_format: TimeFormat,
+	/// if `Some`, the columns of echo logs written to standard output
+	/// in this order, otherwise [`LogFormat::default_fields`]
+	pub fields: Option<Vec<LogField>>,
+	/// format of echoes written to standard output, time format
+	/// applies only to [`LogFormat::Tsv`], fields also to
+	/// [`LogFormat::Json`]
+	pub log_format: LogFormat,
+	/// count echoes whose receive timestamp is earlier than that of
+	/// an echo with lower sequence number, see [`ReceiveOrder`]
+	pub check_timestamps: bool,
+	/// if `Some`, only every n-th echo is logged and included in
+	/// round trip time statistics (live statistics, size buckets,
+	/// burst, echo verification, timestamp order check), to reduce
+	/// the cost per echo at high rates. Counters of received echoes,
+	/// loss, and ECN include all echoes.
+	pub sample: Option<NonZeroUsize>,
+	/// if `Some`, mark packets with this flow ID so logs of several
+	/// streams to the same server can be told apart. Only packets of
+	/// at least [`FLOW_SIZE`] bytes (one more with
+	/// [`Options::header_length`]) carry it, see [`FLOW_FLAG`].
+	pub flow: Option<u32>,
+	/// if `Some`, stop sending once this is set, as if the generator
+	/// had ended. Checked before each packet, so a pending sleep
+	/// until the next departure time is not interrupted.
+	pub stop: Option<Arc<AtomicBool>>,
+	/// if `Some`, do not send while this is set, but keep receiving
+	/// echoes. Checked before each packet like [`Options::stop`],
+	/// which also ends a pause.
+	/// After resuming, relative delays count from the resume time,
+	/// absolute departure times that passed during the pause are
+	/// sent right away.
+	pub pause: Option<Arc<AtomicBool>>,
+	/// if `Some`, send a keepalive packet (see
+	/// [`crate::KEEPALIVE_VERSION`]) whenever no packet has been sent for
+	/// this long while waiting for the generator or paused, so NAT
+	/// and firewall state for the path does not time out. Keepalives
+	/// are not echoed and do not use sequence numbers.
+	pub keepalive: Option<Duration>,
+	/// clock to pace packets on and to read send timestamps from,
+	/// absolute departure times (see [`PacketData::departure`]) must
+	/// be on it, too. Clocks other than [`SendClock::Realtime`] are
+	/// marked in the packets (format version 3, see
+	/// [`CLOCK_VERSION`]), which needs [`Options::header_length`] and
+	/// packets of at least [`CLOCK_SIZE`] bytes, smaller ones are
+	/// padded. Round trip times use receive times read from the same
+	/// clock after receiving, instead of kernel timestamps.
+	pub clock: SendClock,
+	/// request hardware receive timestamps for echoes from the
+	/// network interface, see [`TimestampSource::Hardware`]. Echoes
+	/// the interface did not stamp get software timestamps.
+	pub hardware_timestamps: bool,
+	/// if `Some`, send generator packets at least this long after
+	/// the previous packet, whatever delay the generator requests.
+	/// This caps the packet rate at one per interval, a generator
+	/// that asks for more falls behind its schedule. Burst packets
+	/// are sent back to back regardless.
+	pub min_interval: Option<Duration>,
+	/// if `Some`, the echo receiver thread stops when no echo
+	/// arrived for this long (`SO_RCVTIMEO`) after the last packet
+	/// has been sent, later echoes count as lost. Has no effect with [`Options::sync_echo`], which never
+	/// blocks on receiving.
+	pub recv_timeout: Option<Duration>,
+	/// if `Some`, skip packets whose send blocked for this long
+	/// (`SO_SNDTIMEO`), they count as lost
+	pub send_timeout: Option<Duration>,
+	/// do not warn if realtime priority or memory locking are not
+	/// permitted
+	pub quiet: bool,
+	/// send packets in format version 2, which carry their header
+	/// length, see [`HEADER_LENGTH_VERSION`]. Servers that support
+	/// only version 1 ignore such packets.
+	pub header_length: bool,
+	/// if `Some`, fail the run with [`ThresholdExceeded`] if more
+	/// than this percentage of the sent packets got no echo. Only
+	/// applies with echo.
+	pub fail_if_loss: Option<f64>,
+	/// if `Some`, fail the run with [`ThresholdExceeded`] if the 99th
+	/// percentile of the (sampled) round trip times is above this,
+	/// or there are none. Only applies with echo, samples count
+	/// against the memory budget (8 bytes per echo).
+	pub fail_if_rtt_p99: Option<Duration>,
+	/// accept echoes from any source address, not only from the
+	/// server, for paths through NAT or load balancers that answer
+	/// from a different address. The socket is not connected then,
+	/// so ICMP errors (e.g. port unreachable) are not reported, and
+	/// spoofed packets that look like echoes are accepted.
+	pub no_echo_source_check: bool,
+}
+
+
+impl Options {
+	/// Format version of the packets to send.
+	fn version(&self) -> u8 {
+		if self.clock != SendClock::Realtime {
+			CLOCK_VERSION
+		} else if self.header_length {
+			HEADER_LENGTH_VERSION
+		} else {
+			1
+		}
+	}
+
+	/// Flags of the packets to send, with room for the fields in a
+	/// buffer of `buffer_size` bytes.
+	fn packet_flags(&self, echo: bool, buffer_size: usize) -> Flags {
+		let mut flags = Flags::new(self.version());
+		if echo {
+			flags.set(ECHO_FLAG);
+			if self.echo_sequence {
+				flags.set(ECHO_SEQ_FLAG);
+			}
+		}
+		let flow_size = flags.fields_offset() + 2 * size_of::<u32>();
+		if self.flow.is_some() && buffer_size >= flow_size {
+			flags.set(FLOW_FLAG);
+		}
+		flags
+	}
+
+	/// Where explicit payloads ([`PacketData::payload`]) start in
+	/// packets sent with these options, see [`run`] for `echo` and
+	/// `buffer_size`. The flow ID is not overwritten, with
+	/// [`Options::header_length`] the payload starts after the
+	/// header.
+	pub fn payload_start(&self, echo: bool, buffer_size: usize) -> usize {
+		if self.header_length {
+			self.packet_flags(echo, buffer_size).header_length(buffer_size)
+		} else if self.flow.is_some() {
+			FLOW_SIZE
+		} else {
+			MIN_SIZE
+		}
+	}
+}
+
+
+/// Error returned by [`run`] if the run completed, but its results
+/// are outside the limits set with [`Options::fail_if_loss`] or
+/// [`Options::fail_if_rtt_p99`]. Holds a description of each limit
+/// exceeded.
+#[derive(Debug)]
+pub struct ThresholdExceeded(pub Vec<String>);
+
+impl Display for ThresholdExceeded {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "threshold exceeded: {}", self.0.join(", "))
+	}
+}
+
+impl std::error::Error for ThresholdExceeded {}
+
+
+/// Results of a finished client run, returned by [`run`].
+#[derive(Clone, Debug)]
+pub struct Summary {
+	/// resource usage and scheduling of the send loop
+	pub usage: RunUsage,
+	/// loss, reordering, and duplication of echoes by sequence
+	/// number, `None` if no echoes were requested
+	pub echoes: Option<SequenceGaps>,
+	/// number of send system calls for generator and burst packets,
+	/// fewer than packets if batches were sent, see
+	/// [`Options::batch`]
+	pub send_calls: usize,
+}
 
 
-fn echo_log(
-	sock: i32, max_len: usize, server: SocketAddr,
-	logger: Option<mpsc::Sender<ReceivedPacket>>)
-	-> Result<usize, Error>
+/// Check the results of a run against the limits in `options`,
+/// print the measured values, and return the limits exceeded. Loss
+/// is relative to the `sent` packets, `rtt_p99` in nanoseconds.
+fn check_thresholds(options: &Options, sent: usize, received: usize, rtt_p99: Option<i64>)
+	-> Vec<String>
 {
-	let flags = socket::MsgFlags::empty();
-	let mut buffer = vec![0u8; max_len];
-	let mut cmsgspace = cmsg_space!(TimeSpec);
-	let mut iov = [IoSliceMut::new(&mut buffer)];
-	let server_addr = SockaddrStorage::from(server);
-	let mut count: usize = 0;
+	let mut exceeded = Vec::new();
+	if let Some(limit) = options.fail_if_loss {
+		let lost = sent.saturating_sub(received);
+		let loss = if sent == 0 { 0.0 } else { lost as f64 * 100.0 / sent as f64 };
+		eprintln!("loss: {lost} of {sent} packets ({loss:.2}%), limit {limit}%");
+		if loss > limit {
+			exceeded.push(format!("loss of {loss:.2}% is above {limit}%"));
+		}
+	}
+	if let Some(limit) = options.fail_if_rtt_p99 {
+		match rtt_p99 {
+			Some(p99) => {
+				let p99 = p99 as f64 / 1e9;
+				eprintln!(
+					"99th percentile round trip time: {p99:.6}s, limit {:.6}s",
+					limit.as_secs_f64());
+				if p99 > limit.as_secs_f64() {
+					exceeded.push(format!(
+						"99th percentile round trip time of {p99:.6}s is above {:.6}s",
+						limit.as_secs_f64()));
+				}
+			},
+			None => exceeded.push("no round trip times measured".to_string()),
+		}
+	}
+	exceeded
+}
+
+
+/// Default memory budget of the command line client, see
+/// [`Options::mem_budget`].
+pub const DEFAULT_MEM_BUDGET: usize = 256 << 20;
 
-	// dropping capabilities should always work
-	caps::clear(None, caps::CapSet::Effective)
-		.expect("could not drop effective capabilities");
-	caps::clear(None, caps::CapSet::Permitted)
-		.expect("could not drop effective capabilities");
 
-	if logger.is_none() {
-		println!("{}", ReceivedPacket::header());
+/// State shared between the send loop and the echo receiver.
+#[derive(Clone, Default)]
+struct Shared {
+	live: Option<Arc<Live>>,
+	digests: Option<Arc<EchoDigests>>,
+	budget: Option<Arc<MemBudget>>,
+	/// number of echoes received, for [`EchoWait::UntilIdle`]
+	echoes: Arc<AtomicUsize>,
+	/// round trip times, for [`EchoWait::Adaptive`] and
+	/// [`Options::fail_if_rtt_p99`]
+	rtt: Option<Arc<Mutex<Latency>>>,
+	/// set when the last packet has been sent, only then does
+	/// [`Options::recv_timeout`] end the echo thread
+	sending_done: Arc<AtomicBool>,
+}
+
+
+/// Number of packets to measure the send syscall cost on, see
+/// [`Options::compensate_send`].
+pub const SEND_CALIBRATION: usize = 32;
+
+
+/// Mean cost of the send syscall, measured around the first
+/// [`SEND_CALIBRATION`] sends.
+#[derive(Debug, Default)]
+struct SendCost {
+	total: i64,
+	count: usize,
+}
+
+impl SendCost {
+	fn record(&mut self, before: TimeSpec, after: TimeSpec) {
+		if self.count < SEND_CALIBRATION {
+			self.total += timespec_nanos(&after) - timespec_nanos(&before);
+			self.count += 1;
+		}
 	}
 
-	loop {
+	fn calibrating(&self) -> bool {
+		self.count < SEND_CALIBRATION
+	}
+
+	fn mean(&self) -> Option<i64> {
+		(self.count > 0).then(|| self.total / self.count as i64)
+	}
+
+	/// Sleep target for a packet scheduled at `t`: the mean cost
+	/// earlier once calibration is complete, `t` before.
+	fn wake_time(&self, t: TimeSpec) -> TimeSpec {
+		match self.mean() {
+			Some(m) if !self.calibrating() => add_normalized(t, TimeSpec::new(0, -m)),
+			_ => t,
+		}
+	}
+}
+
+
+/// Statistics collected by the echo receiver.
+#[derive(Debug, Default)]
+struct EchoStats {
+	/// number of echo packets received
+	received: usize,
+	/// round trip times by packet size, if requested
+	size_buckets: Option<SizeBuckets>,
+	/// echoes with content different from the sent packet
+	mismatched: usize,
+	/// echoes that could not be verified because their sequence
+	/// number was outside the verification window
+	unverified: usize,
+	/// echo sequence numbers stamped by the server
+	path_loss: PathLoss,
+	/// sequence numbers of all received echoes
+	sequence: SequenceGaps,
+	/// ECN capable echoes
+	ect: usize,
+	/// CE marked echoes
+	ce: usize,
+	/// echoes with a format version different from the sent packets
+	version_mismatch: usize,
+	/// round trip times of the initial burst, if any
+	burst: Option<BurstRtt>,
+	/// receive timestamp order check, if requested
+	receive_order: Option<ReceiveOrder>,
+	/// echoes that arrived fragmented
+	fragmented: usize,
+	/// largest fragment size of any fragmented echo
+	max_fragment: usize,
+	/// round trip time of the first echo
+	first_echo: FirstEcho,
+}
+
+
+/// FNV-1a hash, used as packet content digest. Bytes in the `skip`
+/// range are excluded.
+fn digest(data: &[u8], skip: &Range<usize>) -> u32 {
+	data.iter()
+		.enumerate()
+		.filter(|(i, _)| !skip.contains(i))
+		.fold(0x811c9dc5, |h, (_, b)| (h ^ *b as u32).wrapping_mul(0x01000193))
+}
+
+
+/// Digests of the most recently sent packets, to verify echo
+/// content. Each slot holds the sequence number (upper 32 bits) and
+/// digest (lower 32 bits) of a packet, so the send loop can update
+/// slots without locking. Memory use is fixed by the window size,
+/// older packets are overwritten.
+struct EchoDigests {
+	slots: Vec<AtomicU64>,
+	/// bytes the server may change in echoes
+	skip: Range<usize>,
+}
+
+impl EchoDigests {
+	fn new(window: usize, skip: Range<usize>) -> Self {
+		// all bits set never matches a recorded sequence number with
+		// its digest in practice
+		EchoDigests {
+			slots: (0..window.max(1)).map(|_| AtomicU64::new(u64::MAX)).collect(),
+			skip,
+		}
+	}
+
+	fn slot(&self, seq: u32) -> &AtomicU64 {
+		&self.slots[seq as usize % self.slots.len()]
+	}
+
+	/// Record the content of a sent packet.
+	fn record(&self, seq: u32, data: &[u8]) {
+		self.slot(seq).store(
+			((seq as u64) << 32) | digest(data, &self.skip) as u64, Ordering::Relaxed);
+	}
+
+	/// Check if the content of an echo matches the sent packet with
+	/// the same sequence number. Returns `None` if the packet is not
+	/// in the window any more.
+	fn verify(&self, seq: u32, data: &[u8]) -> Option<bool> {
+		let v = self.slot(seq).load(Ordering::Relaxed);
+		if (v >> 32) as u32 != seq {
+			return None;
+		}
+		Some(v as u32 == digest(data, &self.skip))
+	}
+}
+
+
+/// Fill the payload with pseudo-random bytes (SplitMix64) seeded
+/// from sequence number and send time, so every packet is
+/// distinguishable on the wire.
+fn fill_unique(payload: &mut [u8], seq: u32, time: TimeSpec) {
+	let mut state = ((seq as u64) << 32)
+		^ (time.tv_sec() as u64).rotate_left(30)
+		^ time.tv_nsec() as u64;
+	for chunk in payload.chunks_mut(size_of::<u64>()) {
+		state = state.wrapping_add(0x9e3779b97f4a7c15);
+		let mut z = state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		z ^= z >> 31;
+		chunk.copy_from_slice(&z.to_be_bytes()[..chunk.len()]);
+	}
+}
+
+
+/// Normalize a TimeSpec so tv_nsec is in [0, 1_000_000_000),
+/// carrying overflow into tv_sec. TimeSpec::new() accepts any values,
+/// but clock_nanosleep() fails with EINVAL for out of range tv_nsec.
+fn normalize(t: TimeSpec) -> TimeSpec {
+	const NSEC_PER_SEC: i64 = 1_000_000_000;
+	TimeSpec::new(
+		t.tv_sec() + t.tv_nsec().div_euclid(NSEC_PER_SEC),
+		t.tv_nsec().rem_euclid(NSEC_PER_SEC))
+}
+
+
+/// Add two TimeSpecs field by field and normalize the result. Unlike
+/// the Add implementation of TimeSpec this does not convert to
+/// nanoseconds, which would overflow for very large intervals.
+fn add_normalized(a: TimeSpec, b: TimeSpec) -> TimeSpec {
+	let (a, b) = (normalize(a), normalize(b));
+	normalize(TimeSpec::new(a.tv_sec() + b.tv_sec(), a.tv_nsec() + b.tv_nsec()))
+}
+
+
+/// Calculate the send time of the next packet. A relative delay is
+/// added to the send time of the previous packet (or the current time
+/// for the first packet). An absolute departure time is used as is,
+/// but must not be earlier than the send time of the previous packet:
+/// packets are sent in the order they are received from the
+/// generator, so a non-monotonic schedule is rejected as invalid.
+fn departure_time(clock: ClockId, prev: Option<TimeSpec>, next: &PacketData)
+	-> Result<TimeSpec, Error>
+{
+	match (prev, next.departure.map(normalize)) {
+		(Some(p), Some(d)) if d < p => Err(Error::new(
+			ErrorKind::InvalidInput,
+			format!(
+				"departure time {}.{:09} is earlier than previous {}.{:09}",
+				d.tv_sec(), d.tv_nsec(), p.tv_sec(), p.tv_nsec()))),
+		(_, Some(d)) => Ok(d),
+		(Some(p), None) => Ok(add_normalized(p, next.delay)),
+		(None, None) => Ok(add_normalized(clock_gettime(clock)?, next.delay)),
+	}
+}
+
+
+/// Receives echoes and collects statistics on them, either in the
+/// echo receiver thread (see [`echo_log`]) or inline in the send loop.
+struct EchoReceiver {
+	sock: i32,
+	buffer: Vec<u8>,
+	cmsgspace: Vec<u8>,
+	server_addr: SockaddrStorage,
+	/// accept echoes only from `server_addr`, see
+	/// [`Options::no_echo_source_check`]
+	check_source: bool,
+	/// format version of the sent packets
+	version: u8,
+	logger: Option<mpsc::Sender<ReceivedPacket>>,
+	hexdump: Option<usize>,
+	time_format: TimeFormat,
+	fields: Vec<LogField>,
+	log_format: LogFormat,
+	/// run label, included in [`LogFormat::Json`] records
+	label: Option<String>,
+	/// echo log on standard output, if there is no `logger`
+	stdout: BufWriter<io::Stdout>,
+	/// send clock, receive times are read from it unless it is
+	/// [`SendClock::Realtime`]
+	clock: SendClock,
+	/// process only every n-th echo in detail, see [`Options::sample`]
+	sample: usize,
+	shared: Shared,
+	stats: EchoStats,
+	/// set when the socket has been shut down or the logger hung up
+	closed: bool,
+}
+
+impl EchoReceiver {
+	fn new(
+		sock: i32, max_len: usize, cmsgs: ControlMessages, server: SocketAddr,
+		logger: Option<mpsc::Sender<ReceivedPacket>>,
+		options: &Options, shared: Shared)
+		-> Result<Self, Error>
+	{
+		let fields = options.fields.clone()
+			.unwrap_or_else(|| options.log_format.default_fields().to_vec());
+		let mut stdout = BufWriter::new(io::stdout());
+		if logger.is_none() {
+			options.log_format.write_header(&mut stdout, &fields)?;
+			stdout.flush()?;
+		}
+		Ok(EchoReceiver {
+			sock,
+			buffer: vec![0u8; max_len],
+			cmsgspace: cmsgs.buffer(),
+			server_addr: SockaddrStorage::from(server),
+			check_source: !options.no_echo_source_check,
+			version: options.version(),
+			logger,
+			hexdump: options.hexdump,
+			time_format: options.time_format,
+			fields,
+			log_format: options.log_format,
+			label: options.label.clone(),
+			stdout,
+			clock: options.clock,
+			sample: options.sample.map_or(1, NonZeroUsize::get),
+			shared,
+			stats: EchoStats {
+				size_buckets: options.size_buckets.clone().map(SizeBuckets::new),
+				burst: (options.burst > 0).then(|| BurstRtt::new(options.burst)),
+				receive_order: options.check_timestamps.then(ReceiveOrder::new),
+				..Default::default()
+			},
+			closed: false,
+		})
+	}
+
+	/// Receive and process one packet. Returns `false` if the socket
+	/// has been shut down for reading or the logger hung up.
+	fn receive(&mut self, flags: socket::MsgFlags) -> Result<bool, Error> {
+		if self.closed {
+			return Ok(false);
+		}
+		let mut iov = [IoSliceMut::new(&mut self.buffer)];
 		let r = socket::recvmsg::<socket::SockaddrStorage>(
-			sock, &mut iov, Some(&mut cmsgspace), flags)?;
+			self.sock, &mut iov, Some(&mut self.cmsgspace), flags)?;
+		// kernel timestamps are on CLOCK_REALTIME
+		let received = (self.clock != SendClock::Realtime)
+			.then(|| clock_gettime(self.clock.id()))
+			.transpose()?;
 		if r.bytes == 0 {
 			// We get a zero bytes packet when the socket has been
 			// shut down for reading.
-			break;
+			self.closed = true;
+			return Ok(false);
+		}
+		let Ok(mut recv) = ReceivedPacket::try_from(r) else {
+			return Ok(true);
+		};
+		if let Some(t) = received {
+			recv.receive_time = t;
+			recv.receive_time_source = TimestampSource::SendClock;
+		}
+		if self.check_source && recv.source != self.server_addr {
+			// wrong source
+			return Ok(true);
+		}
+		if recv.flags.version() != self.version {
+			// not an echo of our packet, or modified by the
+			// reflector, fields might be misinterpreted
+			self.stats.version_mismatch += 1;
+			return Ok(true);
+		}
+		let Shared { live, digests, budget, echoes, rtt: rtt_samples, .. } = &self.shared;
+		let stats = &mut self.stats;
+		if let Some(s) = recv.echo_sequence {
+			stats.path_loss.record(s);
+		}
+		if stats.sequence.record(recv.sequence) == Arrival::Duplicate {
+			// the path replicated the packet, count it only once
+			if stats.sequence.duplicated() == 1 {
+				eprintln!("warning: echo {} arrived more than once", recv.sequence);
+			}
+			return Ok(true);
+		}
+		match recv.ecn() {
+			Some(Ecn::Ect0 | Ecn::Ect1) => stats.ect += 1,
+			Some(Ecn::Ce) => stats.ce += 1,
+			_ => (),
+		}
+		if let Some(f) = recv.fragment_size {
+			stats.fragmented += 1;
+			stats.max_fragment = stats.max_fragment.max(f);
+			if stats.fragmented == 1 {
+				eprintln!(
+					"warning: echo {} ({} bytes) arrived fragmented, largest fragment {f} bytes",
+					recv.sequence, recv.size);
+			}
+		}
+		let sampled = stats.received.is_multiple_of(self.sample);
+		stats.received += 1;
+		echoes.fetch_add(1, Ordering::Relaxed);
+		if !sampled {
+			if let Some(l) = live {
+				l.received_unsampled();
+			}
+			return Ok(true);
 		}
-		if let Ok(recv) = ReceivedPacket::try_from(r) {
-			if recv.source != server_addr {
-				// wrong source
-				continue;
+		let rtt = recv.receive_time - recv.timestamp;
+		// the first echo may include path setup, it is reported
+		// on its own
+		let aggregate = stats.first_echo.record(recv.sequence, rtt);
+		let in_burst = stats.burst.as_mut()
+			.is_some_and(|b| b.record(recv.sequence, rtt));
+		if let Some(b) = stats.size_buckets.as_mut().filter(|_| aggregate && !in_burst) {
+			if let Some(budget) = budget {
+				budget.reserve("size bucket samples", size_of::<i64>())?;
 			}
-			if let Some(sender) = &logger {
-				if let Err(_) = sender.send(recv) {
-					// receiver hung up, no point in listening
+			b.record(recv.size, rtt);
+		}
+		if let Some(l) = live {
+			if aggregate {
+				l.received(rtt);
+			} else {
+				l.received_unsampled();
+			}
+		}
+		if let Some(r) = rtt_samples.as_ref().filter(|_| aggregate) {
+			if let Some(budget) = budget {
+				budget.reserve("round trip time samples", size_of::<i64>())?;
+			}
+			r.lock().unwrap().record(rtt);
+		}
+		if let Some(o) = stats.receive_order.as_mut()
+			&& o.record(recv.sequence, recv.receive_time)
+			&& o.anomalies() == 1
+		{
+			eprintln!(
+				"warning: receive timestamp of echo {} is earlier than that of a lower sequence number",
+				recv.sequence);
+		}
+		if let Some(n) = self.hexdump {
+			eprint!(
+				"received echo {} ({} bytes):\n{}",
+				recv.sequence, recv.size, hexdump(&iov[0][..recv.size], n));
+		}
+		match digests.as_ref()
+			.and_then(|d| d.verify(recv.sequence, &iov[0][..recv.size]))
+		{
+			Some(false) => stats.mismatched += 1,
+			None if digests.is_some() => stats.unverified += 1,
+			_ => (),
+		}
+		if let Some(sender) = &self.logger {
+			if sender.send(recv).is_err() {
+				// receiver hung up, no point in listening
+				self.closed = true;
+				return Ok(false);
+			}
+		} else {
+			self.log_format.write_packet(
+				&mut self.stdout, &recv, self.time_format, &self.fields, self.label.as_deref())?;
+			self.log_format.flush_packet(&mut self.stdout)?;
+		}
+		Ok(true)
+	}
+
+	/// Flush the echo log and return the statistics.
+	fn finish(mut self) -> Result<EchoStats, Error> {
+		self.stdout.flush()?;
+		Ok(self.stats)
+	}
+
+	/// Process all echoes that have already arrived, without
+	/// blocking.
+	fn drain(&mut self) -> Result<(), Error> {
+		loop {
+			match self.receive(socket::MsgFlags::MSG_DONTWAIT) {
+				Ok(true) => (),
+				Ok(false) => return Ok(()),
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) => return Err(e),
+			}
+		}
+	}
+}
+
+
+fn echo_log(mut receiver: EchoReceiver) -> Result<EchoStats, Error> {
+	// dropping capabilities should always work
+	caps::clear(None, caps::CapSet::Effective)
+		.expect("could not drop effective capabilities");
+	caps::clear(None, caps::CapSet::Permitted)
+		.expect("could not drop effective capabilities");
+
+	loop {
+		match receiver.receive(socket::MsgFlags::empty()) {
+			Ok(true) => (),
+			Ok(false) => break,
+			// SO_RCVTIMEO expired, see Options::recv_timeout, a
+			// slow generator is no reason to stop
+			Err(e) if e.kind() == ErrorKind::WouldBlock => {
+				if receiver.shared.sending_done.load(Ordering::Relaxed) {
+					eprintln!("warning: receive timeout expired, no longer waiting for echoes");
 					break;
 				}
-			} else {
-				println!("{recv}");
+			},
+			Err(e) => return Err(e),
+		}
+	}
+	receiver.finish()
+}
+
+
+/// Send a keepalive packet, see [`Options::keepalive`]. A send
+/// timeout (`EAGAIN`) is not an error, the next keepalive will
+/// follow soon enough.
+fn send_keepalive(sock: &impl AsRawFd, destination: Option<&SockaddrStorage>, clock: ClockId)
+	-> Result<(), Errno>
+{
+	let now = clock_gettime(clock)?;
+	let mut packet = [0u8; MIN_SIZE];
+	packet[4..12].copy_from_slice(&now.tv_sec().to_be_bytes());
+	packet[12..20].copy_from_slice(&now.tv_nsec().to_be_bytes());
+	packet[20] = Flags::keepalive().bits();
+	let iov = [IoSlice::new(&packet)];
+	match socket::sendmsg(sock.as_raw_fd(), &iov, &[], socket::MsgFlags::empty(), destination) {
+		Ok(_) | Err(Errno::EAGAIN) => Ok(()),
+		Err(e) => Err(e),
+	}
+}
+
+
+/// A packet waiting in a [`Batch`].
+struct Queued {
+	len: usize,
+	seq: u32,
+	/// scheduled send time
+	scheduled: TimeSpec,
+	/// send timestamp written to the packet
+	current: TimeSpec,
+}
+
+
+/// Packets that were due at the same time, to send with one
+/// `sendmmsg` call, see [`Options::batch`]. Buffers are allocated up
+/// front, so the send loop does not allocate for them.
+struct Batch {
+	buffers: Vec<Vec<u8>>,
+	packets: Vec<Queued>,
+	headers: socket::MultiHeaders<SockaddrStorage>,
+	addrs: Vec<Option<SockaddrStorage>>,
+	/// bytes sent or the error, for each packet of the last send
+	results: Vec<Result<usize, Errno>>,
+	/// system calls made
+	calls: usize,
+}
+
+
+impl Batch {
+	fn new(size: usize, buffer_size: usize, destination: Option<&SockaddrStorage>) -> Self {
+		let size = size.max(1);
+		Batch {
+			buffers: vec![vec![0u8; buffer_size]; size],
+			packets: Vec::with_capacity(size),
+			headers: socket::MultiHeaders::preallocate(size, None),
+			addrs: vec![destination.copied(); size],
+			results: Vec::with_capacity(size),
+			calls: 0,
+		}
+	}
+
+	fn len(&self) -> usize {
+		self.packets.len()
+	}
+
+	fn is_empty(&self) -> bool {
+		self.packets.is_empty()
+	}
+
+	fn is_full(&self) -> bool {
+		self.packets.len() == self.buffers.len()
+	}
+
+	/// Send timestamp of the first packet, later packets join only
+	/// if they were due at that time. The batch must not be empty.
+	fn poll(&self) -> TimeSpec {
+		self.packets[0].current
+	}
+
+	/// Add a copy of the packet `data`, the batch must not be full.
+	fn push(&mut self, data: &[u8], packet: Queued) {
+		self.buffers[self.packets.len()][..data.len()].copy_from_slice(data);
+		self.packets.push(packet);
+	}
+
+	/// Send all packets, a single one with `sendmsg`. If a packet
+	/// fails, the rest are sent with another call. Afterwards
+	/// [`Batch::sent`] has the results.
+	fn send(&mut self, sock: RawFd, flags: socket::MsgFlags) {
+		self.results.clear();
+		if let [p] = self.packets.as_slice() {
+			self.calls += 1;
+			let iov = [IoSlice::new(&self.buffers[0][..p.len])];
+			self.results.push(socket::sendmsg(sock, &iov, &[], flags, self.addrs[0].as_ref()));
+			return;
+		}
+		while self.results.len() < self.packets.len() {
+			let start = self.results.len();
+			self.calls += 1;
+			let iovs: Vec<_> = self.packets[start..].iter().zip(&self.buffers[start..])
+				.map(|(p, b)| [IoSlice::new(&b[..p.len])])
+				.collect();
+			match socket::sendmmsg(
+				sock, &mut self.headers, &iovs, &self.addrs[start..self.packets.len()],
+				[], flags)
+			{
+				// the error of a later packet is reported by the next call
+				Ok(r) => self.results.extend(r.map(|m| Ok(m.bytes))),
+				Err(e) => self.results.push(Err(e)),
 			}
-			count += 1;
 		}
 	}
-	Ok(count)
+
+	/// Packets with their data and send result, after
+	/// [`Batch::send`].
+	fn sent(&self) -> impl Iterator<Item = (&Queued, &[u8], Result<usize, Errno>)> {
+		self.packets.iter().zip(&self.buffers).zip(&self.results)
+			.map(|((p, b), r)| (p, &b[..p.len], *r))
+	}
+
+	fn clear(&mut self) {
+		self.packets.clear();
+		self.results.clear();
+	}
 }
 
 
-/// Run the LUNA client in the current thread. Parameters are:
+/// Shut down the socket, an unconnected socket (see
+/// [`Options::no_echo_source_check`]) reports `ENOTCONN`, but is shut
+/// down all the same.
+fn shutdown<F: AsFd>(sock: &F, how: socket::Shutdown) -> Result<(), Errno> {
+	match socket::shutdown(sock.as_fd().as_raw_fd(), how) {
+		Ok(()) | Err(Errno::ENOTCONN) => Ok(()),
+		Err(e) => Err(e),
+	}
+}
+
+
+/// Run the LUNA client in the current thread, and return a
+/// [`Summary`] with the resource usage of the send loop and echo
+/// sequence statistics. Parameters are:
 ///
 /// * server: address of the server to connect to
 ///
@@ -76,127 +1059,1515 @@ fn echo_log(
 /// * echo: if `true`, request that the server echo packets back to
 ///   the client
 ///
-/// * receiver: read what packets to send from this channel
+/// * receiver: read what packets to send from this channel, packets
+///   with absolute departure times must be in chronological order
 ///
-/// * echo_wait: if `Some`, the duration to wait for pending echo
-///   packets after `receiver` has been closed
+/// * echo_wait: if `Some`, how to wait for pending echo packets
+///   after `receiver` has been closed, see [`EchoWait`]
 ///
 /// * echo_logger: if `Some`, information on received echoes (if
 ///   `echo` is `true` will be sent to this channel, otherwise it will
 ///   be written to standard output.
+///
+/// * options: additional settings, see [`Options`]
 pub fn run(
 	server: SocketAddr, buffer_size: usize, echo: bool,
 	receiver: mpsc::Receiver<PacketData>,
-	echo_wait: Option<Duration>,
-	echo_logger: Option<mpsc::Sender<ReceivedPacket>>)
-	-> Result<(), Box<dyn std::error::Error>>
+	echo_wait: Option<EchoWait>,
+	echo_logger: Option<mpsc::Sender<ReceivedPacket>>,
+	options: Options)
+	-> Result<Summary, Box<dyn std::error::Error>>
 {
+	let start = Instant::now();
+	if options.clock != SendClock::Realtime && !(options.header_length && buffer_size >= CLOCK_SIZE) {
+		return Err(Box::new(Error::new(
+			ErrorKind::InvalidInput,
+			format!(
+				"the {} clock needs the header length field and a buffer of at least {CLOCK_SIZE} bytes",
+				options.clock))));
+	}
+	let clock = options.clock.id();
 	crate::accept_noperm!(
 		crate::with_capability(
 			|| set_rt_prio(20),
 			caps::Capability::CAP_SYS_NICE),
-		"no permission to set realtime priority");
+		"no permission to set realtime priority", options.quiet);
 
+	let family = if server.is_ipv6() {
+		socket::AddressFamily::Inet6
+	} else {
+		socket::AddressFamily::Inet
+	};
 	let sock = socket::socket(
-		if server.is_ipv6() {
-			socket::AddressFamily::Inet6
-		} else {
-			socket::AddressFamily::Inet
-		},
+		family,
 		socket::SockType::Datagram,
 		socket::SockFlag::empty(),
 		None
 	)?;
-	socket::setsockopt(&sock, socket::sockopt::ReceiveTimestampns, &true)?;
-	socket::connect(sock.as_raw_fd(), &SockaddrStorage::from(server))?;
+	recv_timestamps(&sock, options.hardware_timestamps)?;
+	let mut cmsgs = ControlMessages { hardware_timestamps: options.hardware_timestamps, ..Default::default() };
+	if echo {
+		recv_tos(&sock, Some(family))?;
+		cmsgs.tos = true;
+		match recv_frag_size(&sock, Some(family)) {
+			Ok(()) => cmsgs.frag_size = true,
+			Err(e) => eprintln!("cannot detect fragmented echoes: {e}"),
+		}
+	}
+	if options.dscp.is_some() || options.ecn.is_some() {
+		let tos = options.dscp.unwrap_or_default().tos(options.ecn.unwrap_or(Ecn::NotEct));
+		set_tos(&sock, Some(family), tos)?;
+	}
+	if let Some(t) = options.recv_timeout {
+		socket::setsockopt(&sock, socket::sockopt::ReceiveTimeout, &socket_timeout(t))?;
+	}
+	if let Some(t) = options.send_timeout {
+		socket::setsockopt(&sock, socket::sockopt::SendTimeout, &socket_timeout(t))?;
+	}
+	if let Some(f) = options.fragment {
+		f.apply(&sock, family)?;
+	}
+	// Connecting makes the kernel drop datagrams from other sources,
+	// without it packets are sent to the explicit destination.
+	let server_addr = SockaddrStorage::from(server);
+	let destination = if options.no_echo_source_check {
+		Some(&server_addr)
+	} else {
+		socket::connect(sock.as_raw_fd(), &server_addr)?;
+		None
+	};
+
+	let budget = options.mem_budget.map(|b| Arc::new(MemBudget::new(b)));
+	if let Some(b) = &budget {
+		b.reserve("send buffer", buffer_size)?;
+		if echo {
+			b.reserve("echo buffer", buffer_size)?;
+		}
+		if let Some(w) = options.verify_echo.filter(|_| echo) {
+			b.reserve("echo verification window", w * size_of::<AtomicU64>())?;
+		}
+		if echo {
+			b.reserve("burst samples", options.burst * size_of::<Option<TimeSpec>>())?;
+		}
+		if options.batch > 1 {
+			b.reserve("send batch", options.batch * buffer_size)?;
+		}
+	}
+	// burst packets come on top of the generator packets
+	let planned_count = match (options.planned_count.map(|p| p + options.burst), options.max_packets) {
+		(Some(p), Some(m)) => Some(p.min(m)),
+		(p, m) => p.or(m),
+	};
 
 	let flags = socket::MsgFlags::empty();
 	let mut buffer = vec![0u8; buffer_size];
-	if echo {
-		buffer[20] = ECHO_FLAG;
+	let packet_flags = options.packet_flags(echo, buffer_size);
+	// ends of the echo sequence number and flow ID fields
+	let echo_seq_size = packet_flags.fields_offset() + size_of::<u32>();
+	let flow_size = echo_seq_size + size_of::<u32>();
+	if let Some(f) = options.flow.filter(|_| packet_flags.contains(FLOW_FLAG)) {
+		buffer[echo_seq_size..flow_size].copy_from_slice(&f.to_be_bytes());
 	}
+	buffer[20] = packet_flags.bits();
+	if packet_flags.version() >= CLOCK_VERSION {
+		buffer[MIN_SIZE + 1] = options.clock.byte();
+	}
+	let payload_start = options.payload_start(echo, buffer_size);
+	// end of explicit payload written to the buffer, see
+	// PacketData::payload
+	let mut payload_end = 0;
+	// header bytes overwritten by the payload sequence number, see
+	// Options::payload_sequence
+	let mut marker_saved: Option<(usize, [u8; size_of::<u32>()])> = None;
+	// packets sent with echo sequence number request
+	let mut requested: usize = 0;
+
+	let live = options.stats_interval
+		.map(|_| Arc::new(Live::new(options.label.clone(), planned_count, options.clock)));
 
+	let digests = options.verify_echo
+		.filter(|_| echo)
+		.map(|w| Arc::new(EchoDigests::new(
+			w,
+			// the server changes flags and echo sequence number
+			if options.echo_sequence { 20..echo_seq_size } else { 0..0 })));
+
+	let shared = Shared {
+		live: live.clone(),
+		digests: digests.clone(),
+		budget: budget.clone(),
+		echoes: Arc::new(AtomicUsize::new(0)),
+		rtt: (echo_wait == Some(EchoWait::Adaptive) || options.fail_if_rtt_p99.is_some())
+			.then(|| Arc::new(Mutex::new(Latency::new()))),
+		sending_done: Arc::new(AtomicBool::new(false)),
+	};
+	let wait_state = (Arc::clone(&shared.echoes), shared.rtt.clone());
+	let sending_done = Arc::clone(&shared.sending_done);
+	let mut inline_echo = None;
 	let et = if echo {
-		let s = sock.as_raw_fd();
-		Some(thread::Builder::new().name("echo receiver".to_string()).spawn(
-			move || echo_log(s, buffer_size, server, echo_logger))?)
+		let receiver = EchoReceiver::new(
+			sock.as_raw_fd(), buffer_size, cmsgs, server, echo_logger, &options, shared)?;
+		if options.sync_echo {
+			inline_echo = Some(receiver);
+			None
+		} else {
+			Some(thread::Builder::new().name("echo receiver".to_string()).spawn(
+				move || echo_log(receiver))?)
+		}
 	} else {
 		None
 	};
 
-	let mut t = None;
+	// The reporter runs until the stop sender is dropped, at the end
+	// of the run or when returning early on error.
+	let (stats_stop, stats_reporter) = match (options.stats_interval, &live) {
+		(Some(interval), Some(l)) => {
+			let (stop, stopped) = mpsc::channel::<()>();
+			let l = l.clone();
+			// a duplicate stays valid even if the run ends early
+			let queue_sock = sock.try_clone()?;
+			let h = thread::Builder::new().name("stats reporter".to_string()).spawn(
+				move || {
+					while let Err(mpsc::RecvTimeoutError::Timeout) =
+						stopped.recv_timeout(interval)
+					{
+						let queues = SocketQueues::read(&queue_sock).ok();
+						eprintln!("{}", l.snapshot(start.elapsed(), queues));
+					}
+				})?;
+			(Some(stop), Some(h))
+		},
+		_ => (None, None),
+	};
+
+	let mut t: Option<TimeSpec> = None;
 	let mut seq: u32 = 0;
+	let mut throughput = Throughput::new(options.warmup + options.burst);
+	let mut burst_left = options.burst;
+	let mut send_cost = SendCost::default();
+	let mut send_gaps = options.send_gaps.then(SendGaps::new);
+	let mut send_rate = SendRate::new(options.warmup + options.burst);
+	let mut rate_warned = false;
+	// packets that could not be sent because of their size
+	let mut too_large: usize = 0;
+	// packets skipped because the send timeout expired
+	let mut send_timeouts: usize = 0;
+	// send time of the previous packet, for the send log
+	let mut prev_sent: Option<TimeSpec> = None;
+	// whether the generator had more packets than max_packets
+	let mut capped = false;
+	// whether the run was stopped through options.stop
+	let mut stopped = false;
+	// whether sending ended after options.duration
+	let mut expired = false;
+	// pauses through options.pause and their total duration
+	let mut pauses: usize = 0;
+	let mut paused = Duration::ZERO;
+	// end of the most recent pause, until the next packet
+	let mut resumed: Option<TimeSpec> = None;
+	// packets delayed by options.min_interval
+	let mut floored: usize = 0;
+	let min_interval = options.min_interval.map(TimeSpec::from_duration);
+	// keepalives sent, and when the last packet of any kind was
+	let mut keepalives: usize = 0;
+	let mut last_send = Instant::now();
+	let mut batch = Batch::new(options.batch, buffer_size, destination);
+	// next generator packet, taken to check if it can join the batch
+	let mut peeked: Option<PacketData> = None;
 
 	// Prevent swapping, if possible. Needs to be done as late as
 	// possible so all allocations needed for the loop are covered
 	// with MCL_CURRENT.
-	crate::accept_noperm!(
-		crate::with_capability(
-			|| mman::mlockall(mman::MlockAllFlags::MCL_CURRENT),
-			caps::Capability::CAP_IPC_LOCK),
-		"no permission to lock memory");
+	let memlock = crate::lock_memory(options.quiet)?;
 
 	caps::clear(None, caps::CapSet::Effective)?;
 	caps::clear(None, caps::CapSet::Permitted)?;
 
 	let rusage_pre = resource::getrusage(resource::UsageWho::RUSAGE_THREAD)?;
+	// end of sending on the send clock, see options.duration
+	let end = options.duration
+		.map(|d| Ok::<_, Errno>(add_normalized(clock_gettime(clock)?, TimeSpec::from_duration(d))))
+		.transpose()?;
+	// time left until the end, zero if it has passed
+	let remaining = |end: TimeSpec| -> Result<Duration, Errno> {
+		let left = end - clock_gettime(clock)?;
+		Ok(if left.tv_sec() < 0 { Duration::ZERO } else { left.into() })
+	};
 
 	'send: loop {
-		let next = match receiver.recv() {
-			Ok(next) => next,
-			Err(mpsc::RecvError) => {break 'send;}
+		// a pending batch is sent first, see below
+		if batch.is_empty() && options.stop.as_ref().is_some_and(|s| s.load(Ordering::Relaxed)) {
+			stopped = true;
+			break 'send;
+		}
+		if batch.is_empty() && let Some(e) = end && remaining(e)?.is_zero() {
+			expired = true;
+			break 'send;
+		}
+		let in_burst = burst_left > 0;
+		let next = if in_burst {
+			burst_left -= 1;
+			PacketData { delay: TimeSpec::new(0, 0), size: buffer_size, departure: None, payload: None }
+		} else if let Some(p) = peeked.take() {
+			p
+		} else {
+			// wait until the next keepalive or the end of sending is
+			// due, whichever is first
+			loop {
+				let wait = options.keepalive.map(|k| k.saturating_sub(last_send.elapsed()))
+					.into_iter()
+					.chain(end.map(remaining).transpose()?)
+					.min();
+				let r = match wait {
+					None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+					Some(w) => receiver.recv_timeout(w),
+				};
+				match r {
+					Ok(next) => break next,
+					Err(mpsc::RecvTimeoutError::Disconnected) => {break 'send;}
+					Err(mpsc::RecvTimeoutError::Timeout) => {
+						if let Some(e) = end && remaining(e)?.is_zero() {
+							expired = true;
+							break 'send;
+						}
+						if options.keepalive.is_some_and(|k| last_send.elapsed() >= k) {
+							send_keepalive(&sock, destination, clock)?;
+							keepalives += 1;
+							last_send = Instant::now();
+						}
+					},
+				}
+			}
+		};
+		if options.max_packets.is_some_and(|m| seq as usize >= m) {
+			capped = true;
+			break 'send;
+		}
+		if let Some(b) = &budget {
+			b.check()?;
+		}
+		// checked after receiving, so a packet the loop was waiting
+		// for is held back, too
+		if let Some(p) = options.pause.as_ref().filter(|p| batch.is_empty() && p.load(Ordering::Relaxed)) {
+			let start = Instant::now();
+			while p.load(Ordering::Relaxed) {
+				if options.stop.as_ref().is_some_and(|s| s.load(Ordering::Relaxed)) {
+					stopped = true;
+					break 'send;
+				}
+				if let Some(e) = end && remaining(e)?.is_zero() {
+					expired = true;
+					break 'send;
+				}
+				if let Some(r) = inline_echo.as_mut() {
+					r.drain()?;
+				}
+				if options.keepalive.is_some_and(|k| last_send.elapsed() >= k) {
+					send_keepalive(&sock, destination, clock)?;
+					keepalives += 1;
+					last_send = Instant::now();
+				}
+				thread::sleep(PAUSE_CHECK);
+			}
+			pauses += 1;
+			paused += start.elapsed();
+			resumed = Some(clock_gettime(clock)?);
+		}
+		// relative delays count from the end of a pause
+		let prev = match resumed.take() {
+			Some(r) if next.departure.is_none() => t.map(|p| p.max(r)),
+			_ => t,
+		};
+		t = Some(departure_time(clock, prev, &next)?);
+		// the schedule in t stays unchanged, the minimum interval
+		// and send cost compensation only move the wakeup
+		let scheduled = match min_interval.zip(prev_sent).filter(|_| !in_burst) {
+			Some((m, p)) if add_normalized(p, m) > t.unwrap() => {
+				floored += 1;
+				add_normalized(p, m)
+			},
+			_ => t.unwrap(),
+		};
+		// Packets in a batch were due already, a single one must
+		// not leave after the end.
+		if batch.is_empty() && end.is_some_and(|e| scheduled > e) {
+			expired = true;
+			break 'send;
+		}
+		let wake = if options.compensate_send {
+			send_cost.wake_time(scheduled)
+		} else {
+			scheduled
 		};
-		t = t.or_else(|| Some(clock_gettime(CLOCK).unwrap()))
-			.map(|u| u + next.delay);
 
 		loop {
 			match clock_nanosleep(
-				CLOCK, ClockNanosleepFlags::TIMER_ABSTIME, t.as_ref().unwrap())
+				clock, ClockNanosleepFlags::TIMER_ABSTIME, &wake)
 			{
 				Ok(_) => break,
 				// restart sleep if it was interrupted
 				Err(nix::Error::EINTR) => (),
-				Err(e) => return Result::Err(Box::new(e))
+				Err(e) => {
+					let t = wake;
+					return Result::Err(Box::new(Error::new(
+						Error::from(e).kind(),
+						format!(
+							"clock_nanosleep until {}.{:09} failed: {e}",
+							t.tv_sec(), t.tv_nsec()))));
+				}
 			}
 		}
 
 		// write current time to packet
-		let current = clock_gettime(CLOCK)?;
+		let current = clock_gettime(clock)?;
 		buffer.splice(4..12, current.tv_sec().to_be_bytes());
 		buffer.splice(12..20, current.tv_nsec().to_be_bytes());
 
-		let iov = [IoSlice::new(&buffer[..buffer_size.min(next.size)])];
-		socket::sendmsg(
-			sock.as_raw_fd(), &iov, &[], flags,
-			Option::<&SockaddrStorage>::None)?;
-
+		let len = match &next.payload {
+			Some(p) => {
+				let len = buffer_size.min(payload_start + p.len());
+				if len > payload_start {
+					buffer[payload_start..len].copy_from_slice(&p[..len - payload_start]);
+				}
+				payload_end = payload_end.max(len);
+				len
+			},
+			None => {
+				let len = buffer_size.min(next.size);
+				// clear what previous explicit payloads left behind
+				if payload_end > payload_start {
+					buffer[payload_start..payload_end].fill(0);
+					payload_end = 0;
+				}
+				if options.unique_payload && len > payload_start {
+					fill_unique(&mut buffer[payload_start..len], seq, current);
+				}
+				len
+			},
+		};
+		// keep room for the clock byte
+		let len = if packet_flags.version() >= CLOCK_VERSION { len.max(CLOCK_SIZE) } else { len };
+		// the header length depends on which fields fit
+		let header = packet_flags.header_length(len);
+		if options.header_length && len > MIN_SIZE {
+			buffer[MIN_SIZE] = header as u8;
+		}
+		// The payload of a packet too short for some header fields
+		// starts inside them, restore them after the marker of such
+		// a packet.
+		if let Some((start, saved)) = marker_saved.take() {
+			buffer[start..start + size_of::<u32>()].copy_from_slice(&saved);
+		}
+		if options.payload_sequence && len >= header + size_of::<u32>() {
+			let marker = header..header + size_of::<u32>();
+			if header < payload_start {
+				marker_saved = Some((header, buffer[marker.clone()].try_into().unwrap()));
+			}
+			buffer[marker].copy_from_slice(&seq.to_be_bytes());
+		}
+		// digest after filling, so verification covers the
+		// per-packet content
+		let data = &buffer[..len];
+		if let Some(d) = &digests {
+			d.record(seq, data);
+		}
+		batch.push(data, Queued { len, seq, scheduled, current });
 		// prepare next packet
 		seq += 1;
 		buffer.splice(0..4, seq.to_be_bytes());
+
+		// Keep collecting while the next packet was due when the
+		// first one of the batch was stamped. Stop and pause apply
+		// after the batch has been sent.
+		let more = !batch.is_full()
+			&& !options.stop.as_ref().is_some_and(|s| s.load(Ordering::Relaxed))
+			&& !options.pause.as_ref().is_some_and(|p| p.load(Ordering::Relaxed))
+			&& options.max_packets.is_none_or(|m| (seq as usize) < m)
+			&& (burst_left > 0 || min_interval.is_none() && match receiver.try_recv() {
+				Ok(p) => {
+					let due = departure_time(clock, t, &p)? <= batch.poll();
+					peeked = Some(p);
+					due
+				},
+				Err(_) => false,
+			});
+		if more {
+			continue 'send;
+		}
+
+		batch.send(sock.as_raw_fd(), flags);
+		if options.compensate_send && send_cost.calibrating() && batch.len() == 1 {
+			send_cost.record(current, clock_gettime(clock)?);
+		}
+		for (p, data, result) in batch.sent() {
+			let Queued { seq, scheduled, current, .. } = *p;
+			let sent = match result {
+				Ok(sent) => sent,
+				// Larger than the MTU with DF set, or than the
+				// maximum datagram size. The sequence number is
+				// skipped, so the packet shows up as lost.
+				Err(Errno::EMSGSIZE) => {
+					too_large += 1;
+					continue;
+				},
+				// SO_SNDTIMEO expired, see Options::send_timeout
+				Err(Errno::EAGAIN) => {
+					send_timeouts += 1;
+					continue;
+				},
+				Err(e) => return Err(e.into()),
+			};
+			if let Some(n) = options.hexdump {
+				eprint!("sent {seq} ({sent} bytes):\n{}", hexdump(data, n));
+			}
+			if let Some(l) = &options.send_log {
+				let delay = prev_sent.map(|p| current - p).unwrap_or(TimeSpec::new(0, 0));
+				// a dropped receiver only means the log is not needed
+				let _ = l.send(SentPacket { delay, size: sent, flags: packet_flags });
+			}
+			prev_sent = Some(current);
+			last_send = Instant::now();
+			throughput.record(current, sent);
+			send_rate.record(scheduled, current);
+			if !rate_warned && let Some((requested, achieved)) = send_rate.unachievable() {
+				eprintln!(
+					"warning: requested rate of {requested:.1} packets/s is not achievable on this host, estimated maximum {achieved:.1} packets/s");
+				rate_warned = true;
+			}
+			if let Some(g) = send_gaps.as_mut() {
+				if let Some(b) = &budget {
+					b.reserve("send gap samples", size_of::<i64>())?;
+				}
+				g.record(current);
+			}
+			if let Some(l) = &live {
+				l.sent();
+			}
+			if echo && options.echo_sequence && sent >= echo_seq_size {
+				requested += 1;
+			}
+		}
+		batch.clear();
+
+		// Receive time stamps come from the kernel, so processing
+		// echoes late does not affect round trip times.
+		if let Some(r) = inline_echo.as_mut() {
+			r.drain()?;
+		}
 	}
 
 	let rusage_post = resource::getrusage(resource::UsageWho::RUSAGE_THREAD)?;
+	let scheduling = Scheduling::current()?;
 
-	socket::shutdown(sock.as_raw_fd(), socket::Shutdown::Write)?;
+	sending_done.store(true, Ordering::Relaxed);
+	shutdown(&sock, socket::Shutdown::Write)?;
 	// delay so pending echos can arrive
-	if let Some(w) = echo_wait {
-		thread::sleep(w);
+	let (echoes, rtt_samples) = wait_state;
+	match echo_wait {
+		None => (),
+		Some(EchoWait::Fixed(w)) => thread::sleep(w),
+		Some(EchoWait::Adaptive) => {
+			let w = rtt_samples.as_ref()
+				.map_or(DEFAULT_ECHO_WAIT, |r| EchoWait::adaptive(&r.lock().unwrap()));
+			eprintln!("adaptive echo wait: {:.6}s", w.as_secs_f64());
+			thread::sleep(w);
+		},
+		Some(EchoWait::UntilIdle(gap, max)) => {
+			let waiting = Instant::now();
+			loop {
+				let before = echoes.load(Ordering::Relaxed);
+				thread::sleep(gap.min(max.saturating_sub(waiting.elapsed())));
+				if let Some(r) = inline_echo.as_mut() {
+					r.drain()?;
+				}
+				if echoes.load(Ordering::Relaxed) == before {
+					break;
+				}
+				if waiting.elapsed() >= max {
+					eprintln!("echoes still arriving, stopped waiting at the limit");
+					break;
+				}
+			}
+			eprintln!("waited {:.6}s for echoes until idle", waiting.elapsed().as_secs_f64());
+		},
+	}
+	if let Some(r) = inline_echo.as_mut() {
+		r.drain()?;
+	}
+	shutdown(&sock, socket::Shutdown::Read)?;
+	if let Some(label) = &options.label {
+		eprintln!("run label: {label}");
+	}
+	if options.clock != SendClock::Realtime {
+		eprintln!("send clock: {}", options.clock);
+	}
+	let mut echo_stats = match (inline_echo, et) {
+		(Some(r), _) => Some(r.finish()?),
+		(None, Some(t)) => match t.join() {
+			Err(e) => {
+				eprintln!("panic in echo thread: {e:?}");
+				None
+			},
+			Ok(Err(e)) => {
+				eprintln!("error in echo thread: {e:?}");
+				None
+			},
+			Ok(Ok(stats)) => Some(stats),
+		},
+		(None, None) => None,
+	};
+	let echoes_received = echo_stats.as_ref().map_or(0, |s| s.received);
+	if let Some(stats) = echo_stats.as_mut() {
+		// everything up to the last sequence number was sent,
+		// including skipped packets
+		stats.sequence.finish(seq);
 	}
-	socket::shutdown(sock.as_raw_fd(), socket::Shutdown::Read)?;
-	if let Some(t) = et {
-		match t.join() {
-			Err(e) => eprintln!("panic in echo thread: {e:?}"),
-			Ok(r) => match r {
-				Err(e) => eprintln!("error in echo thread: {e:?}"),
-				Ok(count) => eprintln!("received {count} echo packets"),
+	let sequence = echo_stats.as_ref().map(|s| s.sequence.clone());
+	if let Some(stats) = echo_stats {
+		eprintln!("received {} echo packets", stats.received);
+		eprintln!("echo {}", stats.sequence);
+		if stats.first_echo.first().is_some() {
+			eprintln!("{}", stats.first_echo);
+		}
+		if let Some(n) = options.sample {
+			eprintln!(
+				"sampled 1 of every {n} echoes ({} in total) for log and round trip times",
+				stats.received.div_ceil(n.get()));
+		}
+		if stats.version_mismatch > 0 {
+			eprintln!(
+				"ignored {} echoes with mismatched format version",
+				stats.version_mismatch);
+		}
+		if let Some(b) = stats.burst {
+			eprintln!("{b}");
+		}
+		if let Some(o) = stats.receive_order {
+			eprintln!("{o}");
+		}
+		if stats.fragmented > 0 {
+			eprintln!(
+				"reverse path: {} of {} echoes arrived fragmented, largest fragment {} bytes",
+				stats.fragmented, stats.received, stats.max_fragment);
+		}
+		if let Some(b) = stats.size_buckets {
+			eprintln!("{b}");
+		}
+		if digests.is_some() {
+			eprintln!(
+				"echo content mismatches: {} ({} not verified, outside window)",
+				stats.mismatched, stats.unverified);
+		}
+		if options.echo_sequence {
+			let l = &stats.path_loss;
+			eprintln!(
+				"loss: {} forward, {} reverse (of {requested} packets requesting an echo sequence number)",
+				l.forward(requested), l.reverse());
+		}
+		if options.ecn.is_some() {
+			eprintln!(
+				"ECN: {} of {} ECN capable echoes CE marked",
+				stats.ce, stats.ect + stats.ce);
+		}
+	}
+
+	drop(stats_stop);
+	if let Some(Err(e)) = stats_reporter.map(|h| h.join()) {
+		eprintln!("panic in stats reporter thread: {e:?}");
+	}
+
+	eprintln!("{}", ThroughputSummary(&throughput, start.elapsed()));
+	eprintln!("{send_rate}");
+	if floored > 0 {
+		eprintln!("delayed {floored} packets to keep the minimum interval");
+	}
+	if too_large > 0 {
+		eprintln!("skipped {too_large} packets too large to send (EMSGSIZE)");
+	}
+	if send_timeouts > 0 {
+		eprintln!("skipped {send_timeouts} packets because the send timeout expired");
+	}
+	if keepalives > 0 {
+		eprintln!("sent {keepalives} keepalive packets while idle");
+	}
+	if options.batch > 1 {
+		eprintln!("sent {seq} packets with {} send calls", batch.calls);
+	}
+	if pauses > 0 {
+		eprintln!("paused sending {pauses} times, {:.6}s in total", paused.as_secs_f64());
+	}
+	if stopped {
+		eprintln!("stopped on request");
+	}
+	if expired && let Some(d) = options.duration {
+		eprintln!("stopped sending after {:.3}s", d.as_secs_f64());
+	}
+	if let Some(m) = options.max_packets {
+		if capped {
+			eprintln!("stopped at the limit of {m} packets");
+		} else {
+			eprintln!("limit of {m} packets not reached");
+		}
+	}
+	if let Some(planned) = planned_count
+		.filter(|p| *p != throughput.packets())
+	{
+		eprintln!("sent {}/{planned} planned packets", throughput.packets());
+	}
+	if let Some(m) = send_cost.mean() {
+		eprintln!(
+			"mean send cost: {m} ns over {} packets", send_cost.count);
+	}
+	if let Some(g) = send_gaps {
+		eprintln!("{g}");
+	}
+	let resources = ResourceUsage::between(&rusage_pre, &rusage_post);
+	eprintln!("{resources}");
+	eprintln!("locked memory limit: {memlock}");
+	if echo {
+		let rtt_p99 = rtt_samples.and_then(|r| r.lock().unwrap().percentile(99.0));
+		let exceeded = check_thresholds(&options, throughput.packets(), echoes_received, rtt_p99);
+		if !exceeded.is_empty() {
+			return Err(Box::new(ThresholdExceeded(exceeded)));
+		}
+	}
+	Result::Ok(Summary {
+		usage: RunUsage { resources, scheduling, memlock }, echoes: sequence, send_calls: batch.calls,
+	})
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fragment() -> Result<(), Box<dyn std::error::Error>> {
+		let int_opt = |sock: &std::os::fd::OwnedFd, name, value: Option<libc::c_int>| {
+			let mut v = value.unwrap_or_default();
+			let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+			let p = &mut v as *mut libc::c_int as *mut libc::c_void;
+			let ret = unsafe {
+				if value.is_some() {
+					libc::setsockopt(sock.as_raw_fd(), libc::IPPROTO_IPV6, name, p, len)
+				} else {
+					libc::getsockopt(sock.as_raw_fd(), libc::IPPROTO_IPV6, name, p, &mut len)
+				}
+			};
+			Errno::result(ret).map(|_| v)
+		};
+		let receiver = std::net::UdpSocket::bind("[::1]:0")?;
+		receiver.set_read_timeout(Some(Duration::from_millis(500)))?;
+		let target = SockaddrStorage::from(receiver.local_addr()?);
+		let packet = [0u8; 2000];
+		let mut buf = [0u8; 4000];
+
+		for (mode, value, fits) in [
+			(Fragment::Allow, libc::IPV6_PMTUDISC_WANT, true),
+			(Fragment::Deny, libc::IPV6_PMTUDISC_DO, false),
+			// probe ignores the path MTU, but not the MTU of the
+			// socket (or interface)
+			(Fragment::Probe, libc::IPV6_PMTUDISC_PROBE, false),
+		] {
+			let sock = socket::socket(
+				socket::AddressFamily::Inet6, socket::SockType::Datagram,
+				socket::SockFlag::empty(), None)?;
+			mode.apply(&sock, socket::AddressFamily::Inet6)?;
+			assert_eq!(int_opt(&sock, libc::IPV6_MTU_DISCOVER, None)?, value);
+			socket::connect(sock.as_raw_fd(), &target)?;
+			// loopback has a huge MTU, limit it for this socket
+			int_opt(&sock, libc::IPV6_MTU, Some(1280))?;
+			let sent = socket::send(sock.as_raw_fd(), &packet, socket::MsgFlags::empty());
+			if fits {
+				assert_eq!(sent, Ok(packet.len()));
+				assert_eq!(receiver.recv(&mut buf)?, packet.len());
+			} else {
+				assert_eq!(sent, Err(Errno::EMSGSIZE), "{mode:?}");
 			}
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn departure_relative() -> Result<(), Error> {
+		let prev = TimeSpec::new(10, 900_000_000);
+		let next = PacketData {
+			delay: TimeSpec::new(0, 200_000_000),
+			size: 32,
+			departure: None,
+			payload: None,
 		};
+		assert_eq!(
+			departure_time(ClockId::CLOCK_REALTIME, Some(prev), &next)?,
+			TimeSpec::new(11, 100_000_000));
+		// first packet is scheduled relative to the current time
+		let now = clock_gettime(ClockId::CLOCK_REALTIME)?;
+		assert!(departure_time(ClockId::CLOCK_REALTIME, None, &next)? >= now + next.delay);
+		Ok(())
 	}
 
-	eprintln!(
-		"major page faults: {}, minor page faults: {}",
-		rusage_post.major_page_faults() - rusage_pre.major_page_faults(),
-		rusage_post.minor_page_faults() - rusage_pre.minor_page_faults()
-	);
-	Result::Ok(())
+	#[test]
+	fn echo_digests() {
+		let d = EchoDigests::new(4, 0..0);
+		let packet = [1u8, 2, 3, 4, 5];
+		d.record(7, &packet);
+		assert_eq!(d.verify(7, &packet), Some(true));
+		assert_eq!(d.verify(7, &[1u8, 2, 3, 4, 6]), Some(false));
+		assert_eq!(d.verify(7, &packet[..4]), Some(false));
+		// never recorded
+		assert_eq!(d.verify(6, &packet), None);
+		// overwritten by a later packet in the same slot
+		d.record(11, &packet);
+		assert_eq!(d.verify(7, &packet), None);
+		assert_eq!(d.verify(11, &packet), Some(true));
+	}
+
+	#[test]
+	fn unique_payload() {
+		let t = TimeSpec::new(1000, 500);
+		let (mut a, mut b, mut c) = ([0u8; 13], [0u8; 13], [0u8; 13]);
+		fill_unique(&mut a, 1, t);
+		fill_unique(&mut b, 2, t);
+		fill_unique(&mut c, 1, t);
+		assert_ne!(a, [0u8; 13]);
+		assert_ne!(a, b);
+		// deterministic for the same input
+		assert_eq!(a, c);
+	}
+
+	#[test]
+	fn send_gaps_interval() -> Result<(), Error> {
+		let interval = TimeSpec::new(0, 2_000_000);
+		let mut gaps = SendGaps::new();
+		let mut t = clock_gettime(ClockId::CLOCK_REALTIME)?;
+		for _ in 0..50 {
+			t = add_normalized(t, interval);
+			clock_nanosleep(ClockId::CLOCK_REALTIME, ClockNanosleepFlags::TIMER_ABSTIME, &t)?;
+			gaps.record(clock_gettime(ClockId::CLOCK_REALTIME)?);
+		}
+		assert_eq!(gaps.gaps().count(), 49);
+		// absolute wakeups keep the mean on the interval even if
+		// single wakeups are late
+		let mean = gaps.gaps().mean().unwrap();
+		assert!(
+			(mean - timespec_nanos(&interval)).abs() < 200_000,
+			"mean gap {mean} ns");
+		Ok(())
+	}
+
+	/// Echo `count` packets back to their sender, each after the
+	/// given delay, one after the other. If `drop_every` is `Some(n)`,
+	/// every n-th packet is dropped instead, to simulate loss.
+	fn slow_reflector(count: usize, delay: Duration, drop_every: Option<usize>)
+		-> std::io::Result<(SocketAddr, thread::JoinHandle<std::io::Result<()>>)>
+	{
+		let sock = std::net::UdpSocket::bind("[::1]:0")?;
+		let addr = sock.local_addr()?;
+		let h = thread::spawn(move || {
+			let mut buf = [0u8; 64];
+			for i in 1..=count {
+				let (len, from) = sock.recv_from(&mut buf)?;
+				if drop_every.is_some_and(|n| i.is_multiple_of(n)) {
+					continue;
+				}
+				thread::sleep(delay);
+				sock.send_to(&buf[..len], from)?;
+			}
+			Ok(())
+		});
+		Ok((addr, h))
+	}
+
+	/// Send `count` packets at the interval to the reflector with
+	/// echo delay `delay`, return the number of echoes received and
+	/// the duration of the run.
+	fn echo_wait_run(count: usize, interval: TimeSpec, delay: Duration, wait: EchoWait)
+		-> Result<(usize, Duration), Box<dyn std::error::Error>>
+	{
+		let (server, reflector) = slow_reflector(count, delay, None)?;
+		let (sender, receiver) = mpsc::channel();
+		for _ in 0..count {
+			sender.send(PacketData { delay: interval, size: MIN_SIZE, departure: None, payload: None })?;
+		}
+		drop(sender);
+		let (log, echoes) = mpsc::channel();
+		let start = Instant::now();
+		run(server, MIN_SIZE, true, receiver, Some(wait), Some(log), Options::default())?;
+		let elapsed = start.elapsed();
+		reflector.join().unwrap()?;
+		Ok((echoes.iter().count(), elapsed))
+	}
+
+	#[test]
+	fn echo_wait_strategies() -> Result<(), Box<dyn std::error::Error>> {
+		let none = TimeSpec::new(0, 0);
+		// echoes trickle in 60ms apart, a fixed wait misses the
+		// later ones, waiting until idle gets all
+		let (received, _) = echo_wait_run(
+			4, none, Duration::from_millis(60), EchoWait::Fixed(Duration::from_millis(100)))?;
+		assert!(received < 4, "{received}");
+		let (received, elapsed) = echo_wait_run(
+			4, none, Duration::from_millis(60),
+			EchoWait::UntilIdle(Duration::from_millis(100), DEFAULT_IDLE_WAIT_LIMIT))?;
+		assert_eq!(received, 4);
+		// the last echo arrives after 240ms, then the wait ends
+		// after one to two idle periods
+		assert!(elapsed < Duration::from_millis(600), "{elapsed:?}");
+		// echoes that keep coming end the wait at the limit
+		let (received, elapsed) = echo_wait_run(
+			8, none, Duration::from_millis(60),
+			EchoWait::UntilIdle(Duration::from_millis(100), Duration::from_millis(150)))?;
+		assert!(received < 8, "{received}");
+		assert!(elapsed < Duration::from_millis(400), "{elapsed:?}");
+
+		// round trip times of about 20ms, two times p99 covers the
+		// last echo
+		let (received, elapsed) = echo_wait_run(
+			4, TimeSpec::new(0, 30_000_000), Duration::from_millis(20), EchoWait::Adaptive)?;
+		assert_eq!(received, 4);
+		assert!(elapsed < Duration::from_millis(90) + DEFAULT_ECHO_WAIT, "{elapsed:?}");
+		Ok(())
+	}
+
+	#[test]
+	fn recv_timeout_while_sending() -> Result<(), Box<dyn std::error::Error>> {
+		// the receive timeout expires between packets, the echo
+		// thread keeps waiting as long as packets are sent
+		let (server, reflector) = slow_reflector(4, Duration::from_millis(20), None)?;
+		let (sender, receiver) = mpsc::channel();
+		for _ in 0..4 {
+			sender.send(PacketData {
+				delay: TimeSpec::new(0, 100_000_000), size: MIN_SIZE, departure: None, payload: None })?;
+		}
+		drop(sender);
+		let (log, echoes) = mpsc::channel();
+		let options = Options { recv_timeout: Some(Duration::from_millis(50)), ..Default::default() };
+		run(
+			server, MIN_SIZE, true, receiver, Some(EchoWait::Fixed(Duration::from_millis(100))),
+			Some(log), options)?;
+		reflector.join().unwrap()?;
+		// the timeout may still end the wait for the last one
+		let received = echoes.iter().count();
+		assert!(received >= 3, "{received}");
+		Ok(())
+	}
+
+	#[test]
+	fn version_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+		let sock = std::net::UdpSocket::bind("[::1]:0")?;
+		let reflector = std::net::UdpSocket::bind("[::1]:0")?;
+		let options = Options::default();
+		let (log, echoes) = mpsc::channel();
+		let mut receiver = EchoReceiver::new(
+			sock.as_raw_fd(), MIN_SIZE, ControlMessages::default(), reflector.local_addr()?,
+			Some(log), &options, Shared::default())?;
+		let mut packet = [0u8; MIN_SIZE];
+		packet[20] = Flags::new(options.version()).with(ECHO_FLAG).bits();
+		// a reflector that rewrites the version nibble of the
+		// second packet, the echo must not be taken for ours
+		let mut buf = [0u8; 64];
+		for seq in 0..2u32 {
+			packet[..4].copy_from_slice(&seq.to_be_bytes());
+			sock.send_to(&packet, reflector.local_addr()?)?;
+			let (len, from) = reflector.recv_from(&mut buf)?;
+			if seq == 1 {
+				buf[20] = Flags::new(HEADER_LENGTH_VERSION).bits() | (buf[20] & 0x0f);
+			}
+			reflector.send_to(&buf[..len], from)?;
+		}
+		for _ in 0..2 {
+			assert!(receiver.receive(socket::MsgFlags::empty())?);
+		}
+		assert_eq!(receiver.stats.version_mismatch, 1);
+		assert_eq!(receiver.stats.received, 1);
+		drop(receiver);
+		assert_eq!(echoes.iter().map(|e| e.sequence).collect::<Vec<_>>(), [0]);
+		Ok(())
+	}
+
+	#[test]
+	fn pause() -> Result<(), Box<dyn std::error::Error>> {
+		let (server, reflector) = slow_reflector(4, Duration::from_millis(20), None)?;
+		let (sender, receiver) = mpsc::channel();
+		let pause = Arc::new(AtomicBool::new(false));
+		let p = Arc::clone(&pause);
+		let generator = thread::spawn(move || -> Result<(), mpsc::SendError<PacketData>> {
+			let packet = || PacketData { delay: TimeSpec::new(0, 0), size: MIN_SIZE, departure: None, payload: None };
+			sender.send(packet())?;
+			sender.send(packet())?;
+			thread::sleep(Duration::from_millis(20));
+			p.store(true, Ordering::Relaxed);
+			sender.send(packet())?;
+			sender.send(packet())?;
+			thread::sleep(Duration::from_millis(300));
+			p.store(false, Ordering::Relaxed);
+			Ok(())
+		});
+		let (log, echoes) = mpsc::channel();
+		run(server, MIN_SIZE, true, receiver, Some(EchoWait::Fixed(Duration::from_millis(100))), Some(log), Options {
+			pause: Some(pause), ..Default::default()
+		})?;
+		generator.join().unwrap()?;
+		reflector.join().unwrap()?;
+		let echoes: Vec<ReceivedPacket> = echoes.iter().collect();
+		assert_eq!(echoes.iter().map(|e| e.sequence).collect::<Vec<_>>(), [0, 1, 2, 3]);
+		// echoes of the first packets arrived during the pause
+		assert!(echoes[1].receive_time < echoes[2].timestamp);
+		assert!(
+			timespec_nanos(&(echoes[2].timestamp - echoes[1].timestamp)) > 250_000_000,
+			"{echoes:?}");
+		Ok(())
+	}
+
+	/// Echoes sent from a different address than the one packets
+	/// went to are accepted only without the source check.
+	#[test]
+	fn echo_source_check() -> Result<(), Box<dyn std::error::Error>> {
+		let count = 3;
+		for check in [true, false] {
+			let sock = std::net::UdpSocket::bind("[::1]:0")?;
+			let other = std::net::UdpSocket::bind("[::1]:0")?;
+			let server = sock.local_addr()?;
+			let reflector = thread::spawn(move || -> std::io::Result<()> {
+				let mut buf = [0u8; 64];
+				for _ in 0..count {
+					let (len, from) = sock.recv_from(&mut buf)?;
+					other.send_to(&buf[..len], from)?;
+				}
+				Ok(())
+			});
+			let (sender, receiver) = mpsc::channel();
+			for _ in 0..count {
+				sender.send(PacketData {
+					delay: TimeSpec::new(0, 1_000_000), size: MIN_SIZE, departure: None, payload: None })?;
+			}
+			drop(sender);
+			let (log, echoes) = mpsc::channel();
+			run(server, MIN_SIZE, true, receiver, Some(EchoWait::Fixed(Duration::from_millis(100))), Some(log), Options {
+				no_echo_source_check: !check, ..Default::default()
+			})?;
+			reflector.join().unwrap()?;
+			assert_eq!(echoes.iter().count(), if check { 0 } else { count });
+		}
+		Ok(())
+	}
+
+	/// Run 10 packets through a reflector that drops every fourth
+	/// and delays echoes by 20ms, with the given limits.
+	fn threshold_run(options: Options) -> Result<Summary, Box<dyn std::error::Error>> {
+		let count = 10;
+		let (server, reflector) = slow_reflector(count, Duration::from_millis(20), Some(4))?;
+		let (sender, receiver) = mpsc::channel();
+		for _ in 0..count {
+			sender.send(PacketData {
+				delay: TimeSpec::new(0, 1_000_000), size: MIN_SIZE, departure: None, payload: None })?;
+		}
+		drop(sender);
+		let result = run(
+			server, MIN_SIZE, true, receiver,
+			Some(EchoWait::UntilIdle(Duration::from_millis(100), DEFAULT_IDLE_WAIT_LIMIT)),
+			None, options);
+		reflector.join().unwrap()?;
+		result
+	}
+
+	#[test]
+	fn echo_sequence_gaps() -> Result<(), Box<dyn std::error::Error>> {
+		let summary = threshold_run(Options::default())?;
+		let g = summary.echoes.unwrap();
+		// echoes 3 and 7 dropped
+		assert_eq!((g.received(), g.lost(), g.reordered(), g.duplicated()), (8, 2, 0, 0));
+		Ok(())
+	}
+
+	/// Duplicated echoes are counted as such, not as received echoes.
+	#[test]
+	fn duplicate_echoes() -> Result<(), Box<dyn std::error::Error>> {
+		let count = 4;
+		let sock = std::net::UdpSocket::bind("[::1]:0")?;
+		let server = sock.local_addr()?;
+		let reflector = thread::spawn(move || -> std::io::Result<()> {
+			let mut buf = [0u8; 64];
+			for i in 0..count {
+				let (len, from) = sock.recv_from(&mut buf)?;
+				sock.send_to(&buf[..len], from)?;
+				if i == 1 {
+					sock.send_to(&buf[..len], from)?;
+				}
+			}
+			Ok(())
+		});
+		let (sender, receiver) = mpsc::channel();
+		for _ in 0..count {
+			sender.send(PacketData {
+				delay: TimeSpec::new(0, 1_000_000), size: MIN_SIZE, departure: None, payload: None })?;
+		}
+		drop(sender);
+		let (log, echoes) = mpsc::channel();
+		let summary = run(
+			server, MIN_SIZE, true, receiver, Some(EchoWait::Fixed(Duration::from_millis(100))),
+			Some(log), Options::default())?;
+		reflector.join().unwrap()?;
+		assert_eq!(echoes.iter().map(|e| e.sequence).collect::<Vec<_>>(), [0, 1, 2, 3]);
+		let g = summary.echoes.unwrap();
+		assert_eq!((g.received(), g.lost(), g.reordered(), g.duplicated()), (5, 0, 0, 1));
+		Ok(())
+	}
+
+	#[test]
+	fn thresholds() -> Result<(), Box<dyn std::error::Error>> {
+		// 2 of 10 packets lost
+		threshold_run(Options { fail_if_loss: Some(20.0), ..Default::default() })?;
+		let e = threshold_run(Options { fail_if_loss: Some(1.0), ..Default::default() })
+			.unwrap_err();
+		let t = e.downcast_ref::<ThresholdExceeded>().unwrap();
+		assert_eq!(t.0, ["loss of 20.00% is above 1%"]);
+
+		threshold_run(Options {
+			fail_if_rtt_p99: Some(Duration::from_secs(1)), ..Default::default()
+		})?;
+		let e = threshold_run(Options {
+			fail_if_loss: Some(50.0),
+			fail_if_rtt_p99: Some(Duration::from_millis(5)),
+			..Default::default()
+		}).unwrap_err();
+		let t = e.downcast_ref::<ThresholdExceeded>().unwrap();
+		assert_eq!(t.0.len(), 1);
+		// the reflector delays each echo in turn, so later ones
+		// queue up
+		assert!(t.0[0].starts_with("99th percentile round trip time of 0."), "{t}");
+		assert!(t.0[0].ends_with("is above 0.005000s"), "{t}");
+
+		let options = Options { fail_if_rtt_p99: Some(Duration::from_millis(5)), ..Default::default() };
+		assert_eq!(check_thresholds(&options, 5, 0, None), ["no round trip times measured"]);
+		assert!(check_thresholds(&Options::default(), 5, 0, None).is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn echo_wait_parse() {
+		assert_eq!("fixed:0.5".parse(), Ok(EchoWait::Fixed(Duration::from_millis(500))));
+		assert_eq!("adaptive".parse(), Ok(EchoWait::Adaptive));
+		assert_eq!(
+			"until-idle:1".parse(),
+			Ok(EchoWait::UntilIdle(Duration::from_secs(1), DEFAULT_IDLE_WAIT_LIMIT)));
+		assert_eq!(
+			"until-idle:0.5:30".parse(),
+			Ok(EchoWait::UntilIdle(Duration::from_millis(500), Duration::from_secs(30))));
+		for invalid in [
+			"fixed", "adaptive:1", "until-idle:x", "until-idle:1:", "until-idle:1:2:3", "fixed:-1",
+			"sometimes:1",
+		] {
+			assert!(invalid.parse::<EchoWait>().is_err(), "{invalid}");
+		}
+
+		let mut rtt = Latency::new();
+		assert_eq!(EchoWait::adaptive(&rtt), DEFAULT_ECHO_WAIT);
+		for ms in [10, 20, 15] {
+			rtt.record(TimeSpec::new(0, ms * 1_000_000));
+		}
+		assert_eq!(EchoWait::adaptive(&rtt), Duration::from_millis(40));
+		let mut rtt = Latency::new();
+		rtt.record(TimeSpec::new(0, 100_000));
+		assert_eq!(EchoWait::adaptive(&rtt), Duration::from_millis(10));
+	}
+
+	#[test]
+	fn max_packets() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddr>()?);
+		let capture = crate::server::Capture::start(
+			bind_addr, MIN_SIZE, Duration::from_millis(300),
+			crate::server::Options::default())?;
+		let server = SocketAddr::from(*capture.bound().as_sockaddr_in6().unwrap());
+
+		// a generator that never ends on its own
+		let (sender, receiver) = mpsc::channel();
+		let generator = thread::spawn(move || {
+			let delay = TimeSpec::new(0, 100_000);
+			while sender.send(PacketData { delay, size: MIN_SIZE, departure: None, payload: None }).is_ok() {}
+		});
+		run(server, MIN_SIZE, false, receiver, None, None, Options {
+			max_packets: Some(7), burst: 2, ..Default::default()
+		})?;
+		// stops once the client dropped the receiver
+		generator.join().unwrap();
+		let packets = capture.collect_all(100)?;
+		assert_eq!(packets.len(), 7);
+		assert_eq!(packets.last().map(|p| p.sequence), Some(6));
+		Ok(())
+	}
+
+	#[test]
+	fn stop() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddr>()?);
+		let capture = crate::server::Capture::start(
+			bind_addr, MIN_SIZE, Duration::from_millis(300),
+			crate::server::Options::default())?;
+		let server = SocketAddr::from(*capture.bound().as_sockaddr_in6().unwrap());
+
+		let (sender, receiver) = mpsc::channel();
+		let generator = thread::spawn(move || {
+			let delay = TimeSpec::new(0, 1_000_000);
+			while sender.send(PacketData { delay, size: MIN_SIZE, departure: None, payload: None }).is_ok() {}
+		});
+		let stop = Arc::new(AtomicBool::new(false));
+		let s = Arc::clone(&stop);
+		let setter = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(50));
+			s.store(true, Ordering::Relaxed);
+		});
+		run(server, MIN_SIZE, false, receiver, None, None, Options {
+			stop: Some(stop), ..Default::default()
+		})?;
+		setter.join().unwrap();
+		generator.join().unwrap();
+		let packets = capture.collect_all(1000)?;
+		assert!(!packets.is_empty());
+		assert!(packets.len() < 100, "sent {} packets", packets.len());
+		Ok(())
+	}
+
+	#[test]
+	fn duration() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddr>()?);
+		let delay = TimeSpec::new(0, 20_000_000);
+		for stall in [false, true] {
+			let capture = crate::server::Capture::start(
+				bind_addr, MIN_SIZE, Duration::from_millis(300),
+				crate::server::Options::default())?;
+			let server = SocketAddr::from(*capture.bound().as_sockaddr_in6().unwrap());
+			let (sender, receiver) = mpsc::sync_channel(20);
+			let count = if stall { 1 } else { 20 };
+			for _ in 0..count {
+				sender.send(PacketData { delay, size: MIN_SIZE, departure: None, payload: None })?;
+			}
+			// A stalled generator keeps the channel open, waiting
+			// for it has to end at the end, too.
+			let (done, generator_done) = mpsc::channel::<()>();
+			let generator = thread::spawn(move || {
+				let stopped = generator_done.recv_timeout(Duration::from_secs(5)).is_ok();
+				drop(sender);
+				stopped
+			});
+			run(server, MIN_SIZE, false, receiver, None, None, Options {
+				duration: Some(Duration::from_millis(110)), ..Default::default()
+			})?;
+			done.send(())?;
+			assert!(generator.join().unwrap(), "run did not end while the generator stalled");
+			let packets = capture.collect_all(100)?;
+			// the sixth packet would be due at 120ms
+			assert_eq!(packets.len(), if stall { 1 } else { 5 }, "stall: {stall}");
+			let window = packets.last().unwrap().timestamp - packets[0].timestamp;
+			assert!(window < TimeSpec::new(0, 110_000_000), "{window}");
+		}
+		Ok(())
+	}
+
+	/// Batches send the same packets as single sends, in fewer calls.
+	#[test]
+	fn batch() -> Result<(), Box<dyn std::error::Error>> {
+		let (count, burst) = (100, 4);
+		let mut runs = Vec::new();
+		for batch in [1, 16] {
+			let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddr>()?);
+			let (server_log, server_packets) = mpsc::channel();
+			let mut srv = crate::server::Server::new(
+				bind_addr, 64, Some(server_log), crate::server::Options::default());
+			let handle = srv.bind()?;
+			let server = SocketAddr::from(*srv.bound().unwrap().as_sockaddr_in6().unwrap());
+			let st = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+			// all due at once
+			let (sender, receiver) = mpsc::channel();
+			for _ in 0..count {
+				sender.send(PacketData {
+					delay: TimeSpec::new(0, 0), size: MIN_SIZE, departure: None, payload: None })?;
+			}
+			drop(sender);
+			let summary = run(server, MIN_SIZE, false, receiver, None, None, Options {
+				batch, burst, ..Default::default()
+			})?;
+			thread::sleep(Duration::from_millis(50));
+			handle.close()?;
+			st.join().unwrap()?;
+			let sequences: Vec<_> = server_packets.iter().map(|p| p.sequence).collect();
+			runs.push((sequences, summary.send_calls));
+		}
+		let all: Vec<_> = (0..(count + burst) as u32).collect();
+		assert_eq!(runs[0], (all.clone(), count + burst));
+		assert_eq!(runs[1], (all, (count + burst).div_ceil(16)));
+		Ok(())
+	}
+
+	#[test]
+	fn monotonic_clock() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddr>()?);
+		let (server_log, server_packets) = mpsc::channel();
+		let mut srv = crate::server::Server::new(
+			bind_addr, 64, Some(server_log), crate::server::Options::default());
+		let handle = srv.bind()?;
+		let server = SocketAddr::from(*srv.bound().unwrap().as_sockaddr_in6().unwrap());
+		let st = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		let options = || Options { clock: SendClock::Monotonic, header_length: true, ..Default::default() };
+		// records on both sides say which clock was chosen
+		for clock in [SendClock::Monotonic, SendClock::Tai] {
+			let (sender, receiver) = mpsc::channel();
+			for _ in 0..3 {
+				sender.send(PacketData {
+					delay: TimeSpec::new(0, 1_000_000), size: MIN_SIZE, departure: None, payload: None })?;
+			}
+			drop(sender);
+			let (log, echoes) = mpsc::channel();
+			run(
+				server, 64, true, receiver, Some(EchoWait::Fixed(Duration::from_millis(50))), Some(log),
+				Options { clock, ..options() })?;
+			let now = clock_gettime(clock.id())?;
+			let echoes: Vec<_> = echoes.iter().collect();
+			assert_eq!(echoes.len(), 3);
+			for e in echoes {
+				assert_eq!((e.clock, e.receive_time_source), (clock, TimestampSource::SendClock));
+				assert!(e.receive_time <= now);
+				let rtt = e.delay().unwrap();
+				assert!(rtt > TimeSpec::new(0, 0) && rtt < TimeSpec::new(1, 0), "{rtt}");
+				assert!(e.to_json().contains(&format!(r#""clock": "{clock}""#)));
+			}
+		}
+
+		handle.close()?;
+		st.join().unwrap()?;
+		// padded to make room for the clock byte, the server cannot
+		// compare its receive time with the timestamp
+		let clocks: Vec<_> = server_packets.iter()
+			.map(|p| {
+				assert_eq!((p.size, p.delay()), (CLOCK_SIZE, None));
+				p.clock
+			})
+			.collect();
+		assert_eq!(clocks, [[SendClock::Monotonic; 3], [SendClock::Tai; 3]].concat());
+
+		// the clock byte is in format version 3 only
+		let (_, receiver) = mpsc::channel();
+		let e = run(server, 64, true, receiver, None, None, Options { header_length: false, ..options() })
+			.unwrap_err();
+		assert!(e.to_string().starts_with("the monotonic clock needs"), "{e}");
+		Ok(())
+	}
+
+	#[test]
+	fn keepalive() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddr>()?);
+		let mut srv = crate::server::Server::new(
+			bind_addr, MIN_SIZE, None, crate::server::Options::default());
+		let handle = srv.bind()?;
+		let server = SocketAddr::from(*srv.bound().unwrap().as_sockaddr_in6().unwrap());
+		let (packets, st) = srv.spawn()?;
+
+		// idle for 150ms between two packets
+		let (sender, receiver) = mpsc::channel();
+		let generator = thread::spawn(move || {
+			let delay = TimeSpec::new(0, 0);
+			sender.send(PacketData { delay, size: MIN_SIZE, departure: None, payload: None }).unwrap();
+			thread::sleep(Duration::from_millis(150));
+			sender.send(PacketData { delay, size: MIN_SIZE, departure: None, payload: None }).unwrap();
+		});
+		let (log, echoes) = mpsc::channel();
+		run(
+			server, MIN_SIZE, true, receiver, Some(EchoWait::Fixed(Duration::from_millis(50))),
+			Some(log), Options { keepalive: Some(Duration::from_millis(20)), ..Default::default() })?;
+		generator.join().unwrap();
+		// keepalives are not echoed
+		assert_eq!(echoes.iter().map(|e| e.sequence).collect::<Vec<_>>(), [0, 1]);
+
+		handle.close()?;
+		let summary = st.join().unwrap()?;
+		// neither logged nor counted as test packets
+		assert_eq!(packets.map(|p| p.sequence).collect::<Vec<_>>(), [0, 1]);
+		assert_eq!(summary.received, 2);
+		assert!(summary.keepalives >= 3, "{} keepalives", summary.keepalives);
+		assert_eq!(summary.unsupported, 0);
+		Ok(())
+	}
+
+	#[test]
+	fn min_interval() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddr>()?);
+		let capture = crate::server::Capture::start(
+			bind_addr, MIN_SIZE, Duration::from_millis(300),
+			crate::server::Options::default())?;
+		let server = SocketAddr::from(*capture.bound().as_sockaddr_in6().unwrap());
+
+		let (sender, receiver) = mpsc::channel();
+		let delay = TimeSpec::new(0, 0);
+		for _ in 0..20 {
+			sender.send(PacketData { delay, size: MIN_SIZE, departure: None, payload: None })?;
+		}
+		drop(sender);
+		let floor = Duration::from_millis(2);
+		run(server, MIN_SIZE, false, receiver, None, None, Options {
+			min_interval: Some(floor), ..Default::default()
+		})?;
+		let packets = capture.collect_all(100)?;
+		assert_eq!(packets.len(), 20);
+		for w in packets.windows(2) {
+			let gap = w[1].timestamp - w[0].timestamp;
+			assert!(gap >= TimeSpec::from_duration(floor), "{gap}");
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn flow_id() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddr>()?);
+		let capture = crate::server::Capture::start(
+			bind_addr, 64, Duration::from_millis(300),
+			crate::server::Options::default())?;
+		let server = SocketAddr::from(*capture.bound().as_sockaddr_in6().unwrap());
+
+		let (sender, receiver) = mpsc::channel();
+		let delay = TimeSpec::new(0, 0);
+		for size in [64, FLOW_SIZE, MIN_SIZE] {
+			sender.send(PacketData { delay, size, departure: None, payload: None })?;
+		}
+		drop(sender);
+		let (log, echoes) = mpsc::channel();
+		run(server, 64, true, receiver, Some(EchoWait::Fixed(Duration::from_millis(100))), Some(log), Options {
+			flow: Some(7), unique_payload: true, ..Default::default()
+		})?;
+		// too small packets cannot carry the flow ID
+		assert_eq!(echoes.iter().map(|p| p.flow).collect::<Vec<_>>(), [7, 7, 0]);
+		let packets = capture.collect_all(10)?;
+		assert_eq!(packets.iter().map(|p| p.flow).collect::<Vec<_>>(), [7, 7, 0]);
+		Ok(())
+	}
+
+	#[test]
+	fn payload_sequence() -> Result<(), Box<dyn std::error::Error>> {
+		let sock = std::net::UdpSocket::bind("[::1]:0")?;
+		sock.set_read_timeout(Some(Duration::from_secs(1)))?;
+		let server = sock.local_addr()?;
+		// the 27 byte packet is too short for the flow ID, so its
+		// payload starts in front of it
+		let sizes = [64, 27, 64, MIN_SIZE + 3];
+		for header_length in [false, true] {
+			let (sender, receiver) = mpsc::channel();
+			for size in sizes {
+				sender.send(PacketData { delay: TimeSpec::new(0, 0), size, departure: None, payload: None })?;
+			}
+			drop(sender);
+			run(server, 64, false, receiver, None, None, Options {
+				payload_sequence: true, flow: Some(3), unique_payload: true, header_length,
+				..Default::default()
+			})?;
+			let mut buf = [0u8; 64];
+			for (seq, size) in sizes.into_iter().enumerate() {
+				let len = sock.recv(&mut buf)?;
+				assert_eq!(len, size);
+				let packet = &buf[..len];
+				let seq = seq as u32;
+				assert_eq!(packet[..4], seq.to_be_bytes());
+				assert_eq!(
+					crate::payload_sequence(packet)?, (size > MIN_SIZE + 3).then_some(seq),
+					"header length {header_length}, packet {seq}");
+				if size == 64 {
+					// header fields intact after the short packet
+					let fields = Flags::from(packet[20]).fields_offset();
+					assert_eq!(packet[fields..fields + 4], [0; 4]);
+					assert_eq!(packet[fields + 4..fields + 8], 3u32.to_be_bytes());
+				}
+			}
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn sample_echoes() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddr>()?);
+		let capture = crate::server::Capture::start(
+			bind_addr, MIN_SIZE, Duration::from_millis(300),
+			crate::server::Options::default())?;
+		let server = SocketAddr::from(*capture.bound().as_sockaddr_in6().unwrap());
+
+		let (sender, receiver) = mpsc::channel();
+		let delay = TimeSpec::new(0, 100_000);
+		for _ in 0..10 {
+			sender.send(PacketData { delay, size: MIN_SIZE, departure: None, payload: None })?;
+		}
+		drop(sender);
+		let (log, echoes) = mpsc::channel();
+		run(server, MIN_SIZE, true, receiver, Some(EchoWait::Fixed(Duration::from_millis(100))), Some(log), Options {
+			sample: NonZeroUsize::new(3), ..Default::default()
+		})?;
+		assert_eq!(echoes.iter().map(|p| p.sequence).collect::<Vec<_>>(), [0, 3, 6, 9]);
+		// the server got all of them
+		assert_eq!(capture.collect_all(100)?.len(), 10);
+		Ok(())
+	}
+
+	#[test]
+	fn send_cost() {
+		let mut c = SendCost::default();
+		let t = TimeSpec::new(5, 0);
+		assert_eq!(c.mean(), None);
+		assert_eq!(c.wake_time(t), t);
+		for i in 0..SEND_CALIBRATION as i64 {
+			c.record(TimeSpec::new(1, 999_999_000), TimeSpec::new(2, 1000 + i % 2 * 2));
+		}
+		// later measurements are ignored
+		c.record(TimeSpec::new(1, 0), TimeSpec::new(2, 0));
+		assert_eq!(c.mean(), Some(2001));
+		assert_eq!(c.wake_time(t), TimeSpec::new(4, 999_997_999));
+	}
+
+	#[test]
+	fn normalized() {
+		assert_eq!(
+			normalize(TimeSpec::new(0, 1_500_000_000)),
+			TimeSpec::new(1, 500_000_000));
+		assert_eq!(
+			normalize(TimeSpec::new(5, 3_000_000_001)),
+			TimeSpec::new(8, 1));
+		assert_eq!(
+			normalize(TimeSpec::new(1, -1)),
+			TimeSpec::new(0, 999_999_999));
+		assert_eq!(normalize(TimeSpec::new(2, 0)), TimeSpec::new(2, 0));
+
+		// repeated addition carries into seconds
+		let mut t = TimeSpec::new(100, 900_000_000);
+		for _ in 0..5 {
+			t = add_normalized(t, TimeSpec::new(0, 700_000_000));
+			assert!((0..1_000_000_000).contains(&t.tv_nsec()));
+		}
+		assert_eq!(t, TimeSpec::new(104, 400_000_000));
+		// unnormalized delay
+		assert_eq!(
+			add_normalized(TimeSpec::new(1, 999_999_999), TimeSpec::new(0, 2_000_000_001)),
+			TimeSpec::new(4, 0));
+		// intervals too large for the nanosecond based Add
+		let huge = TimeSpec::new(i64::MAX / 4, 999_999_999);
+		assert_eq!(
+			add_normalized(huge, huge),
+			TimeSpec::new(i64::MAX / 4 * 2 + 1, 999_999_998));
+	}
+
+	#[test]
+	fn departure_absolute() -> Result<(), Error> {
+		let abs = TimeSpec::new(20, 5);
+		let next = PacketData {
+			delay: TimeSpec::new(1, 0),
+			size: 32,
+			departure: Some(abs),
+			payload: None,
+		};
+		// delay is ignored in favor of the absolute time
+		assert_eq!(departure_time(ClockId::CLOCK_REALTIME, None, &next)?, abs);
+		assert_eq!(departure_time(ClockId::CLOCK_REALTIME, Some(TimeSpec::new(19, 0)), &next)?, abs);
+		// same time as the previous packet is fine
+		assert_eq!(departure_time(ClockId::CLOCK_REALTIME, Some(abs), &next)?, abs);
+		// going back in time is not
+		let err = departure_time(ClockId::CLOCK_REALTIME, Some(TimeSpec::new(20, 6)), &next)
+			.unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::InvalidInput);
+		Ok(())
+	}
 }
@@ -3,9 +3,10 @@ use std::{
 	fmt::{self, Debug, Display},
 	num::ParseIntError,
 	ops::Deref,
+	str::FromStr,
 	sync::mpsc,
 	thread,
-	time::Duration
+	time::{Duration, SystemTime}
 };
 #[cfg(feature = "python")]
 use std::ffi::{CStr, CString};
@@ -13,18 +14,25 @@ use std::ffi::{CStr, CString};
 use clap::ValueEnum;
 use nix::sys::time::TimeSpec;
 
-use crate::{PacketData, MIN_SIZE};
+use crate::{PacketData, SentPacket, Timestamp, MIN_SIZE};
 
 
 #[derive(Debug)]
 pub struct InvalidOption {
 	pub option: String,
+	/// the value given for the option, `None` if the option is
+	/// missing
+	pub value: Option<String>,
 	source: Box<dyn std::error::Error>,
 }
 
 impl Display for InvalidOption {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(f, "Option \"{}\" has an invalid value", self.option)
+		write!(f, "Option \"{}\" has an invalid value", self.option)?;
+		if let Some(v) = &self.value {
+			write!(f, " {v:?}")?;
+		}
+		Ok(())
 	}
 }
 
@@ -35,6 +43,39 @@ impl std::error::Error for InvalidOption {
 }
 
 
+/// default packet count for Generator::Default
+const DEFAULT_COUNT: usize = 10;
+/// default packet count for Generator::Vary
+const VARY_COUNT: usize = 20;
+/// default packet count for Generator::Poisson
+const POISSON_COUNT: usize = 100;
+/// default packet count for Generator::Burst
+const BURST_COUNT: usize = 100;
+/// default packet count for Generator::Target
+const TARGET_COUNT: usize = 1000;
+/// how long Generator::Target waits for send feedback beyond the
+/// requested interval before generating the next packet anyway,
+/// e.g. if the client skipped a packet
+const FEEDBACK_WAIT: Duration = Duration::from_millis(100);
+/// packets a generator may queue ahead of the client, it blocks
+/// when the queue is full
+const QUEUE_LENGTH: usize = 1024;
+
+
+macro_rules! parse_or_default {
+	($hash:expr, $key:literal, $default:expr) => {{
+		$hash.get($key)
+			.map(|s| s.parse().map_err(|e| InvalidOption {
+				option: $key.to_string(),
+				value: Some(s.clone()),
+				source: Box::new(e)
+			}))
+			.transpose()?
+			.unwrap_or($default)
+	}};
+}
+
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum Generator {
 	/// send fixed size packet with a fixed interval, defaults to
@@ -42,11 +83,54 @@ pub enum Generator {
 	Default,
 	/// change size between minimum and 1500, send every 1ms
 	Vary,
-	/// load the given string as a Python module and run its
-	/// "generate()" function to produce packet data
+	/// vary the packet rate following the load profile read from
+	/// the file set with the "file" option
+	Schedule,
+	/// send at the packet rate set with the "rate" option,
+	/// adjusting the interval to the achieved send times
+	///
+	/// See [`Generator::run_with_feedback`].
+	Target,
+	/// send with exponentially distributed intervals (a Poisson
+	/// process) at the average rate set with the "rate" option
+	Poisson,
+	/// send bursts of "burst-count" packets separated by
+	/// "off-interval" of silence, like periodic video frames
+	Burst,
+	/// replay the send pattern recorded in the packet or send log
+	/// set with the "file" option
+	Replay,
+	/// load the given string as a Python module and run the named
+	/// function (usually "generate") to produce packet data
 	#[cfg(feature = "python")]
 	#[value(skip)]
-	Py{code: CString, file: CString},
+	Py{code: CString, file: CString, function: String},
+	/// run the given generators one after the other, see
+	/// [`stage_options`] for how options are passed to each stage
+	#[value(skip)]
+	Sequence(Vec<Generator>),
+}
+
+
+/// Options for stage `index` (0-based) of a [`Generator::Sequence`]:
+/// an option prefixed with the stage number counting from 1 (e.g.
+/// "2.count") applies only to that stage, and overrides an option of
+/// the same name without prefix, which applies to all stages.
+pub fn stage_options(options: &HashMap<String, String>, index: usize)
+	-> HashMap<String, String>
+{
+	let prefix = format!("{}.", index + 1);
+	let mut stage: HashMap<String, String> = options.iter()
+		.filter(|(k, _)| k.split_once('.').is_none_or(
+			|(n, _)| n.parse::<usize>().is_err()))
+		.map(|(k, v)| (k.clone(), v.clone()))
+		.collect();
+	for (k, v) in options {
+		if let Some(name) = k.strip_prefix(&prefix) {
+			stage.insert(name.to_string(), v.clone());
+		}
+	}
+	stage
 }
 
 impl Generator {
@@ -54,18 +138,100 @@ impl Generator {
 		self, options: HashMap<String, String>)
 		-> Result<mpsc::Receiver<PacketData>, Box<dyn std::error::Error>>
 	{
-		let (sender, receiver) = mpsc::channel::<PacketData>();
+		self.run_with_feedback(options, None)
+	}
+
+	/// Like [`Generator::run`], with the packets actually sent by
+	/// the client (see [`crate::client::Options::send_log`]) as
+	/// `feedback` for generators that adjust to them (see
+	/// [`Generator::uses_feedback`]), others ignore it. Without
+	/// feedback [`Generator::Target`] sends at a fixed interval.
+	pub fn run_with_feedback(
+		self, options: HashMap<String, String>,
+		feedback: Option<mpsc::Receiver<SentPacket>>)
+		-> Result<mpsc::Receiver<PacketData>, Box<dyn std::error::Error>>
+	{
+		let (sender, receiver) = mpsc::sync_channel::<PacketData>(QUEUE_LENGTH);
 		match self {
 			Generator::Default => generator(sender, options)?,
 			Generator::Vary => generator_vary_size(sender, options)?,
+			Generator::Schedule => generator_schedule(sender, options)?,
+			Generator::Target => generator_target(sender, options, feedback)?,
+			Generator::Poisson => generator_poisson(sender, options)?,
+			Generator::Burst => generator_burst(sender, options)?,
+			Generator::Replay => generator_replay(sender, options)?,
 			#[cfg(feature = "python")]
-			Generator::Py{code, file} =>
+			Generator::Py{code, file, function} =>
 				thread::Builder::new()
 					.name(format!("python generator ({:?})", file))
-					.spawn(move || generator_py(&code, &file, sender, options).unwrap())?,
+					.spawn(move || {
+						// errors have already been reported
+						let _ = generator_py(&code, &file, &function, sender, options);
+					})?,
+			Generator::Sequence(stages) => {
+				// Start all stages right away so invalid options are
+				// reported before sending, and forward their packets
+				// in order. Stages get no feedback, it could not be
+				// matched to the stage that generated the packet.
+				let receivers = stages.into_iter().enumerate()
+					.map(|(i, g)| g.run(stage_options(&options, i)))
+					.collect::<Result<Vec<_>, _>>()?;
+				thread::Builder::new()
+					.name("sequence generator".to_string())
+					.spawn(move || {
+						for p in receivers.iter().flat_map(|r| r.iter()) {
+							if sender.send(p).is_err() {
+								break;
+							}
+						}
+					})?
+			},
 		};
 		Ok(receiver)
 	}
+
+	/// Whether the generator adjusts to feedback about the packets
+	/// actually sent, see [`Generator::run_with_feedback`].
+	pub fn uses_feedback(&self) -> bool {
+		matches!(self, Generator::Target)
+	}
+
+	/// Number of packets the generator will produce with the given
+	/// options, if known in advance.
+	pub fn planned_count(
+		&self, options: &HashMap<String, String>)
+		-> Result<Option<usize>, Box<dyn std::error::Error>>
+	{
+		Ok(match self {
+			Generator::Default => Some(parse_or_default!(options, "count", DEFAULT_COUNT)),
+			Generator::Vary => Some(parse_or_default!(options, "count", VARY_COUNT)),
+			Generator::Schedule => Some(read_schedule(options)?.send_times().count()),
+			Generator::Target => Some(parse_or_default!(options, "count", TARGET_COUNT)),
+			Generator::Poisson => Some(parse_or_default!(options, "count", POISSON_COUNT)),
+			Generator::Burst => Some(burst_delays(options)?.count()),
+			Generator::Replay => Some(read_trace(options)?.packets.len()),
+			#[cfg(feature = "python")]
+			Generator::Py{..} => None,
+			Generator::Sequence(stages) => {
+				let mut total = Some(0);
+				for (i, g) in stages.iter().enumerate() {
+					let count = g.planned_count(&stage_options(options, i))?;
+					total = total.zip(count).map(|(t, c)| t + c);
+				}
+				total
+			},
+		})
+	}
+}
+
+
+/// Built-in generators by name, as on the command line.
+impl FromStr for Generator {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		<Self as ValueEnum>::from_str(s, false)
+	}
 }
 
 
@@ -74,26 +240,29 @@ impl fmt::Display for Generator {
 		match self {
 			Generator::Default => write!(f, "Generator::Default"),
 			Generator::Vary => write!(f, "Generator::Vary"),
+			Generator::Schedule => write!(f, "Generator::Schedule"),
+			Generator::Target => write!(f, "Generator::Target"),
+			Generator::Poisson => write!(f, "Generator::Poisson"),
+			Generator::Burst => write!(f, "Generator::Burst"),
+			Generator::Replay => write!(f, "Generator::Replay"),
 			#[cfg(feature = "python")]
-			Generator::Py{code:_, file} => write!(f, "Generator::Py({:?})", file),
+			Generator::Py{code:_, file, function} =>
+				write!(f, "Generator::Py({:?}:{})", file, function),
+			Generator::Sequence(stages) => {
+				write!(f, "Generator::Sequence(")?;
+				for (i, g) in stages.iter().enumerate() {
+					if i > 0 {
+						write!(f, ", ")?;
+					}
+					write!(f, "{g}")?;
+				}
+				write!(f, ")")
+			},
 		}
 	}
 }
 
 
-macro_rules! parse_or_default {
-	($hash:expr, $key:literal, $default:expr) => {{
-		$hash.get($key).map(|s| s.parse())
-			.transpose()
-			.map_err(|e| InvalidOption {
-				option: $key.to_string(),
-				source: Box::new(e)
-			})?
-			.unwrap_or($default)
-	}};
-}
-
-
 fn parse_timespec(value: &str) -> Result<TimeSpec, ParseIntError> {
 	let t = value.split_once('.')
 		.or(Some((value, "")))
@@ -122,6 +291,7 @@ fn parse_interval(
 	if m.len() > 1 {
 		return Err(InvalidOption{
 			option: m[1].to_string(),
+			value: options.get(m[1]).cloned(),
 			source: format!("only one of {:?} may be specified", params).into()
 		});
 	}
@@ -129,39 +299,73 @@ fn parse_interval(
 		"msec" => Duration::from_millis(parse_or_default!(options, "msec", 0)).into(),
 		"usec" => Duration::from_micros(parse_or_default!(options, "usec", 0)).into(),
 		"nsec" => Duration::from_nanos(parse_or_default!(options, "nsec", 0)).into(),
-		_ => parse_timespec(options.get("interval").unwrap())
-			.map_err(|e| InvalidOption {
-				option: "interval".to_string(),
-				source: Box::new(e)
-			})?,
+		_ => {
+			let value = options.get("interval").unwrap();
+			parse_timespec(value)
+				.map_err(|e| InvalidOption {
+					option: "interval".to_string(),
+					value: Some(value.clone()),
+					source: Box::new(e)
+				})?
+		},
 	};
 	Ok(Some(t))
 }
 
 
+/// Parse the "sizes" option of the default generator, a comma
+/// separated list of packet sizes to cycle through. Each size must be
+/// at least [`MIN_SIZE`]. Returns `None` if the option is not set.
+pub fn parse_sizes(options: &HashMap<String, String>) -> Result<Option<Vec<usize>>, InvalidOption> {
+	let Some(value) = options.get("sizes") else {
+		return Ok(None);
+	};
+	let invalid = |source: Box<dyn std::error::Error>| InvalidOption {
+		option: "sizes".to_string(),
+		value: Some(value.clone()),
+		source,
+	};
+	if options.contains_key("size") {
+		return Err(invalid("only one of \"size\" and \"sizes\" may be specified".into()));
+	}
+	let sizes = value.split(',')
+		.map(|s| s.trim().parse::<usize>())
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|e| invalid(Box::new(e)))?;
+	if let Some(s) = sizes.iter().find(|s| **s < MIN_SIZE) {
+		return Err(invalid(format!("size {s} is less than the minimum of {MIN_SIZE}").into()));
+	}
+	Ok(Some(sizes))
+}
+
+
 fn generator(
-	target: mpsc::Sender<PacketData>, options: HashMap<String, String>)
+	target: mpsc::SyncSender<PacketData>, options: HashMap<String, String>)
 	-> Result<thread::JoinHandle<()>, Box<dyn std::error::Error>>
 {
-	let count = parse_or_default!(options, "count", 10);
+	let count = parse_or_default!(options, "count", DEFAULT_COUNT);
 	let size = parse_or_default!(options, "size", MIN_SIZE);
+	let sizes = parse_sizes(&options)?.unwrap_or(vec![size]);
 	let delay = parse_interval(&options)?
 		.unwrap_or(TimeSpec::new(0, 500_000_000));
 	Ok(thread::Builder::new()
 		.name("default generator".to_string())
 		.spawn(move || {
-			for _ in 0..count {
-				target.send(PacketData { delay, size }).unwrap();
+			for size in sizes.into_iter().cycle().take(count) {
+				if target.send(PacketData { delay, size, departure: None, payload: None }).is_err() {
+					// the client has stopped, e.g. after an error
+					break;
+				}
 			}
 		})?)
 }
 
 
 fn generator_vary_size(
-	target: mpsc::Sender<PacketData>, options: HashMap<String, String>)
+	target: mpsc::SyncSender<PacketData>, options: HashMap<String, String>)
 	-> Result<thread::JoinHandle<()>, Box<dyn std::error::Error>>
 {
-	let count = parse_or_default!(options, "count", 20);
+	let count = parse_or_default!(options, "count", VARY_COUNT);
 	let delay = parse_interval(&options)?
 		.unwrap_or(TimeSpec::new(0, 1_000_000));
 	let max_size = parse_or_default!(options, "max-size", 1452);
@@ -171,9 +375,11 @@ fn generator_vary_size(
 			let mut s = MIN_SIZE;
 			let mut grow = true;
 			for _ in 0..count {
-				target.send(
-					PacketData { delay, size: max_size.min(s) }
-				).unwrap();
+				if target.send(
+					PacketData { delay, size: max_size.min(s), departure: None, payload: None }
+				).is_err() {
+					break;
+				}
 				if grow {
 					s *= 2;
 					grow = s < max_size;
@@ -186,18 +392,541 @@ fn generator_vary_size(
 }
 
 
+/// A load profile, packet rate and size over time.
+#[derive(Clone, Debug, PartialEq)]
+struct Schedule {
+	/// (time offset in seconds, packets per second, packet size),
+	/// ordered by time offset
+	points: Vec<(f64, f64, usize)>,
+}
+
+impl Schedule {
+	/// Parse a schedule with one "time_offset rate size" point per
+	/// line, fields separated by whitespace. Empty lines and
+	/// comments starting with "#" are ignored. The first point must
+	/// be at offset 0, the schedule ends with the last point.
+	fn parse(s: &str) -> Result<Self, String> {
+		let mut points: Vec<(f64, f64, usize)> = Vec::new();
+		for (n, line) in s.lines().enumerate() {
+			let line = line.split('#').next().unwrap_or_default().trim();
+			if line.is_empty() {
+				continue;
+			}
+			let err = |e: &dyn Display| format!("line {}: {e}", n + 1);
+			let [time, rate, size] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+				return Err(err(&"expected \"time_offset rate size\""));
+			};
+			let time: f64 = time.parse().map_err(|e| err(&e))?;
+			let rate: f64 = rate.parse().map_err(|e| err(&e))?;
+			let size: usize = size.parse().map_err(|e| err(&e))?;
+			if !(rate.is_finite() && rate >= 0.0) {
+				return Err(err(&"rate must be a non-negative number"));
+			}
+			match points.last() {
+				None if time != 0.0 =>
+					return Err(err(&"schedule must start at offset 0")),
+				Some((prev, _, _)) if !(time.is_finite() && time > *prev) =>
+					return Err(err(&"time offsets must be increasing")),
+				_ => (),
+			}
+			points.push((time, rate, size));
+		}
+		if points.len() < 2 {
+			return Err("schedule needs at least two points".to_string());
+		}
+		Ok(Schedule { points })
+	}
+
+	/// Send times (seconds from the start) and sizes of all packets.
+	/// The rate is interpolated linearly between points, packet n is
+	/// sent when the integral of the rate over time reaches n. The
+	/// size changes in steps, each packet has the size of the last
+	/// point before it.
+	fn send_times(self) -> impl Iterator<Item = (f64, usize)> {
+		let mut seg = 0;
+		let mut t = 0.0;
+		std::iter::from_fn(move || {
+			let mut need = 1.0;
+			while seg + 1 < self.points.len() {
+				let (t0, r0, size) = self.points[seg];
+				let (t1, r1, _) = self.points[seg + 1];
+				let slope = (r1 - r0) / (t1 - t0);
+				let rate = r0 + slope * (t - t0);
+				let rest = t1 - t;
+				let available = rate * rest + slope * rest * rest / 2.0;
+				// tolerance for rounding errors, so a packet due
+				// exactly at the end of a segment is not lost
+				if available + 1e-9 >= need {
+					// solve rate * x + slope / 2 * x^2 = need, in a
+					// form that is stable for slope close to 0
+					let x = 2.0 * need
+						/ (rate + (rate * rate + 2.0 * slope * need).max(0.0).sqrt());
+					t += x.min(rest);
+					return Some((t, size));
+				}
+				need -= available;
+				t = t1;
+				seg += 1;
+			}
+			None
+		})
+	}
+}
+
+
+fn read_schedule(
+	options: &HashMap<String, String>)
+	-> Result<Schedule, InvalidOption>
+{
+	let file = options.get("file");
+	let invalid = |e: Box<dyn std::error::Error>| InvalidOption {
+		option: "file".to_string(),
+		value: file.cloned(),
+		source: e,
+	};
+	let file = file
+		.ok_or_else(|| invalid("a schedule file is required".into()))?;
+	let content = std::fs::read_to_string(file)
+		.map_err(|e| invalid(Box::new(e)))?;
+	Schedule::parse(&content).map_err(|e| invalid(e.into()))
+}
+
+
+/// Playback speed of a schedule from the "speed" option: 2 plays it
+/// twice as fast (half the delays), 0.5 at half speed.
+fn schedule_speed(
+	options: &HashMap<String, String>)
+	-> Result<f64, InvalidOption>
+{
+	let speed: f64 = parse_or_default!(options, "speed", 1.0);
+	if !(speed.is_finite() && speed > 0.0) {
+		return Err(InvalidOption {
+			option: "speed".to_string(),
+			value: options.get("speed").cloned(),
+			source: "speed must be a positive number".into(),
+		});
+	}
+	Ok(speed)
+}
+
+
+fn generator_schedule(
+	target: mpsc::SyncSender<PacketData>, options: HashMap<String, String>)
+	-> Result<thread::JoinHandle<()>, Box<dyn std::error::Error>>
+{
+	let schedule = read_schedule(&options)?;
+	let speed = schedule_speed(&options)?;
+	Ok(thread::Builder::new()
+		.name("schedule generator".to_string())
+		.spawn(move || {
+			// delays are calculated from absolute offsets, so
+			// rounding errors do not accumulate
+			let mut prev = 0;
+			for (t, size) in schedule.send_times() {
+				let offset = (t / speed * 1e9).round() as u64;
+				let delay = Duration::from_nanos(offset - prev).into();
+				if target.send(PacketData { delay, size, departure: None, payload: None }).is_err() {
+					break;
+				}
+				prev = offset;
+			}
+		})?)
+}
+
+
+/// A recorded send pattern for [`Generator::Replay`].
+#[derive(Clone, Debug, PartialEq)]
+struct Trace {
+	/// (delay since the previous packet, packet size)
+	packets: Vec<(Duration, usize)>,
+}
+
+impl Trace {
+	/// Parse a trace with one packet per line, fields separated by
+	/// tabs, commas, or whitespace. If the first line names the
+	/// columns (like the header of a packet or send log), the
+	/// "size" column and either "delay" (seconds since the previous
+	/// packet, as in send logs) or "timestamp" (send time, as in
+	/// packet logs) are used, other columns are ignored. Packets
+	/// are replayed in timestamp order, a log in receive order may
+	/// have reordered ones. Without header line each line is "delay
+	/// size". Empty lines and comments starting with "#" are ignored.
+	fn parse(s: &str) -> Result<Self, String> {
+		enum Time { Delay(usize), Timestamp(usize) }
+		let mut columns = None;
+		let mut timed: Vec<(TimeSpec, usize)> = Vec::new();
+		for (n, line) in s.lines().enumerate() {
+			let line = line.split('#').next().unwrap_or_default().trim();
+			if line.is_empty() {
+				continue;
+			}
+			let err = |e: &dyn Display| format!("line {}: {e}", n + 1);
+			let fields: Vec<&str> = if line.contains('\t') {
+				line.split('\t').map(str::trim).collect()
+			} else if line.contains(',') {
+				line.split(',').map(str::trim).collect()
+			} else {
+				line.split_whitespace().collect()
+			};
+			let (time, size) = match &columns {
+				Some(c) => c,
+				None if fields[0].parse::<f64>().is_ok() =>
+					columns.insert((Time::Delay(0), 1)),
+				None => {
+					let column = |name| fields.iter().position(|f| *f == name);
+					let time = column("delay").map(Time::Delay)
+						.or_else(|| column("timestamp").map(Time::Timestamp))
+						.ok_or_else(|| err(&"no \"delay\" or \"timestamp\" column"))?;
+					let size = column("size").ok_or_else(|| err(&"no \"size\" column"))?;
+					columns = Some((time, size));
+					continue;
+				},
+			};
+			let field = |i: usize| fields.get(i).copied()
+				.ok_or_else(|| err(&format!("expected at least {} fields", i + 1)));
+			let t = match time {
+				Time::Delay(i) if field(*i)?.starts_with('-') =>
+					return Err(err(&"delay must not be negative")),
+				Time::Delay(i) => parse_timespec(field(*i)?).map_err(|e| err(&e))?,
+				Time::Timestamp(i) => field(*i)?.parse::<Timestamp>()
+					.map_err(|_| err(&"invalid timestamp"))?.0,
+			};
+			let size: usize = field(*size)?.parse().map_err(|e| err(&e))?;
+			if size < MIN_SIZE {
+				return Err(err(&format!("size {size} is less than the minimum of {MIN_SIZE}")));
+			}
+			timed.push((t, size));
+		}
+		if timed.is_empty() {
+			return Err("trace contains no packets".to_string());
+		}
+		let packets = match columns {
+			Some((Time::Timestamp(_), _)) => {
+				timed.sort_by_key(|(t, _)| *t);
+				let start = timed[0].0;
+				timed.iter().scan(start, |prev, (t, size)| {
+					let delay = *t - *prev;
+					*prev = *t;
+					Some((delay.into(), *size))
+				}).collect()
+			},
+			_ => timed.into_iter().map(|(d, size)| (d.into(), size)).collect(),
+		};
+		Ok(Trace { packets })
+	}
+}
+
+
+fn read_trace(
+	options: &HashMap<String, String>)
+	-> Result<Trace, InvalidOption>
+{
+	let file = options.get("file");
+	let invalid = |e: Box<dyn std::error::Error>| InvalidOption {
+		option: "file".to_string(),
+		value: file.cloned(),
+		source: e,
+	};
+	let file = file
+		.ok_or_else(|| invalid("a trace file is required".into()))?;
+	let content = std::fs::read_to_string(file)
+		.map_err(|e| invalid(Box::new(e)))?;
+	Trace::parse(&content).map_err(|e| invalid(e.into()))
+}
+
+
+fn generator_replay(
+	target: mpsc::SyncSender<PacketData>, options: HashMap<String, String>)
+	-> Result<thread::JoinHandle<()>, Box<dyn std::error::Error>>
+{
+	let trace = read_trace(&options)?;
+	let speed = schedule_speed(&options)?;
+	Ok(thread::Builder::new()
+		.name("replay generator".to_string())
+		.spawn(move || {
+			for (delay, size) in trace.packets {
+				let delay = delay.div_f64(speed).into();
+				if target.send(PacketData { delay, size, departure: None, payload: None }).is_err() {
+					break;
+				}
+			}
+		})?)
+}
+
+
+/// Parse a positive number option of the target or Poisson generator.
+fn positive_option(
+	options: &HashMap<String, String>, name: &'static str, default: f64)
+	-> Result<f64, InvalidOption>
+{
+	let value = options.get(name)
+		.map(|s| s.parse::<f64>().map_err(|e| InvalidOption {
+			option: name.to_string(),
+			value: Some(s.clone()),
+			source: Box::new(e),
+		}))
+		.transpose()?
+		.unwrap_or(default);
+	if !(value.is_finite() && value > 0.0) {
+		return Err(InvalidOption {
+			option: name.to_string(),
+			value: options.get(name).cloned(),
+			source: format!("{name} must be a positive number").into(),
+		});
+	}
+	Ok(value)
+}
+
+
+/// Fail if the longest interval (in seconds) a generator may derive
+/// from its "rate" option does not fit a packet delay.
+fn check_longest_interval(options: &HashMap<String, String>, longest: f64)
+	-> Result<(), InvalidOption>
+{
+	if Duration::try_from_secs_f64(longest).is_err() || longest >= i64::MAX as f64 {
+		return Err(InvalidOption {
+			option: "rate".to_string(),
+			value: options.get("rate").cloned(),
+			source: "rate too small, intervals would overflow".into(),
+		});
+	}
+	Ok(())
+}
+
+
+/// Interval control of [`Generator::Target`]: each achieved interval
+/// that differs from the target moves the requested interval by
+/// `gain` times the difference in the opposite direction, so the
+/// achieved rate settles at the target if something (e.g. a slow
+/// send path) adds to every interval. The requested interval stays
+/// between zero and twice the target.
+#[derive(Clone, Debug, PartialEq)]
+struct RateControl {
+	target: f64,
+	gain: f64,
+	interval: f64,
+}
+
+impl RateControl {
+	fn new(rate: f64, gain: f64) -> Self {
+		RateControl { target: 1.0 / rate, gain, interval: 1.0 / rate }
+	}
+
+	/// Adjust to one achieved interval (seconds), return the new
+	/// requested interval.
+	fn update(&mut self, achieved: f64) -> f64 {
+		self.interval = (self.interval + self.gain * (self.target - achieved))
+			.clamp(0.0, 2.0 * self.target);
+		self.interval
+	}
+}
+
+
+fn generator_target(
+	target: mpsc::SyncSender<PacketData>, options: HashMap<String, String>,
+	mut feedback: Option<mpsc::Receiver<SentPacket>>)
+	-> Result<thread::JoinHandle<()>, Box<dyn std::error::Error>>
+{
+	let count = parse_or_default!(options, "count", TARGET_COUNT);
+	let size = parse_or_default!(options, "size", MIN_SIZE);
+	let rate = positive_option(&options, "rate", 100.0)?;
+	let gain = positive_option(&options, "gain", 0.5)?;
+	if gain > 1.0 {
+		return Err(InvalidOption {
+			option: "gain".to_string(),
+			value: options.get("gain").cloned(),
+			source: "gain must not be greater than 1".into(),
+		}.into());
+	}
+	// the requested interval stays below twice the target
+	check_longest_interval(&options, 2.0 / rate)?;
+	let mut control = RateControl::new(rate, gain);
+	Ok(thread::Builder::new()
+		.name("target generator".to_string())
+		.spawn(move || {
+			let mut interval = control.interval;
+			for _ in 0..count {
+				let delay = Duration::from_secs_f64(interval).into();
+				if target.send(PacketData { delay, size, departure: None, payload: None }).is_err() {
+					break;
+				}
+				// Wait until the packet has been sent, so the next
+				// interval reflects it. The first packet has no
+				// interval to measure.
+				let Some(f) = &feedback else {
+					continue;
+				};
+				match f.recv_timeout(Duration::from_secs_f64(interval) + FEEDBACK_WAIT) {
+					Ok(sent) if sent.delay != TimeSpec::new(0, 0) =>
+						interval = control.update(Duration::from(sent.delay).as_secs_f64()),
+					Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => (),
+					Err(mpsc::RecvTimeoutError::Disconnected) => feedback = None,
+				}
+			}
+		})?)
+}
+
+
+/// SplitMix64 pseudo-random numbers, good enough for send intervals
+/// and reproducible from the seed.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+	fn next_u64(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		z ^ (z >> 31)
+	}
+
+	/// Uniformly distributed in (0, 1], from the upper 53 bits.
+	fn next_f64(&mut self) -> f64 {
+		((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+	}
+}
+
+
+fn generator_poisson(
+	target: mpsc::SyncSender<PacketData>, options: HashMap<String, String>)
+	-> Result<thread::JoinHandle<()>, Box<dyn std::error::Error>>
+{
+	let count = parse_or_default!(options, "count", POISSON_COUNT);
+	let size = parse_or_default!(options, "size", MIN_SIZE);
+	let rate = positive_option(&options, "rate", 100.0)?;
+	// the longest interval SplitMix64::next_f64() can lead to
+	check_longest_interval(&options, -(1.0 / (1u64 << 53) as f64).ln() / rate)?;
+	let seed = options.get("seed")
+		.map(|s| s.parse::<u64>().map_err(|e| InvalidOption {
+			option: "seed".to_string(),
+			value: Some(s.clone()),
+			source: Box::new(e),
+		}))
+		.transpose()?
+		.unwrap_or_else(|| SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+			.map_or(0, |d| d.as_nanos() as u64));
+	let mut random = SplitMix64(seed);
+	Ok(thread::Builder::new()
+		.name("poisson generator".to_string())
+		.spawn(move || {
+			for _ in 0..count {
+				// inverse transform sampling of the exponential
+				// distribution, next_f64() is never 0
+				let interval = -random.next_f64().ln() / rate;
+				let delay = Duration::from_secs_f64(interval).into();
+				if target.send(PacketData { delay, size, departure: None, payload: None }).is_err() {
+					break;
+				}
+			}
+		})?)
+}
+
+
+/// Parse an option given in seconds, with up to 9 decimal places
+/// like the "interval" option.
+fn timespec_option(
+	options: &HashMap<String, String>, name: &'static str)
+	-> Result<Option<TimeSpec>, InvalidOption>
+{
+	options.get(name)
+		.map(|s| parse_timespec(s).map_err(|e| InvalidOption {
+			option: name.to_string(),
+			value: Some(s.clone()),
+			source: Box::new(e),
+		}))
+		.transpose()
+}
+
+
+/// Delays of the packets sent by [`Generator::Burst`]: the first
+/// packet of the run is sent right away, the first packet of each
+/// following burst after "off-interval", the other packets of a burst
+/// after the interval (default 0, back-to-back). The run ends after
+/// "count" packets or with the last packet sent within "duration"
+/// seconds from the first, only one of them may be given.
+fn burst_delays(options: &HashMap<String, String>)
+	-> Result<Box<dyn Iterator<Item = Duration> + Send>, InvalidOption>
+{
+	let burst: usize = parse_or_default!(options, "burst-count", 10);
+	if burst == 0 {
+		return Err(InvalidOption {
+			option: "burst-count".to_string(),
+			value: options.get("burst-count").cloned(),
+			source: "burst-count must be at least 1".into(),
+		});
+	}
+	let interval: Duration = parse_interval(options)?
+		.unwrap_or(TimeSpec::new(0, 0)).into();
+	let off: Duration = timespec_option(options, "off-interval")?
+		.unwrap_or(TimeSpec::new(0, 100_000_000)).into();
+	if off.is_zero() {
+		return Err(InvalidOption {
+			option: "off-interval".to_string(),
+			value: options.get("off-interval").cloned(),
+			source: "off-interval must be positive".into(),
+		});
+	}
+	let delays = (0..).map(move |i: usize| match i {
+		0 => Duration::ZERO,
+		i if i % burst == 0 => off,
+		_ => interval,
+	});
+	match timespec_option(options, "duration")? {
+		Some(_) if options.contains_key("count") => Err(InvalidOption {
+			option: "duration".to_string(),
+			value: options.get("duration").cloned(),
+			source: "only one of \"count\" and \"duration\" may be specified".into(),
+		}),
+		Some(duration) => {
+			let duration: Duration = duration.into();
+			Ok(Box::new(delays
+				.scan(Duration::ZERO, |elapsed, d| {
+					*elapsed += d;
+					Some((*elapsed, d))
+				})
+				.take_while(move |(elapsed, _)| *elapsed <= duration)
+				.map(|(_, d)| d)))
+		},
+		None => Ok(Box::new(delays.take(
+			parse_or_default!(options, "count", BURST_COUNT)))),
+	}
+}
+
+
+fn generator_burst(
+	target: mpsc::SyncSender<PacketData>, options: HashMap<String, String>)
+	-> Result<thread::JoinHandle<()>, Box<dyn std::error::Error>>
+{
+	let size = parse_or_default!(options, "size", MIN_SIZE);
+	let delays = burst_delays(&options)?;
+	Ok(thread::Builder::new()
+		.name("burst generator".to_string())
+		.spawn(move || {
+			for d in delays {
+				let delay = d.into();
+				if target.send(PacketData { delay, size, departure: None, payload: None }).is_err() {
+					break;
+				}
+			}
+		})?)
+}
+
+
 #[cfg(feature = "python")]
 fn generator_py(
-	generator_code: &CStr, generator_file: &CStr,
-	target: mpsc::Sender<PacketData>, options: HashMap<String, String>)
+	generator_code: &CStr, generator_file: &CStr, function: &str,
+	target: mpsc::SyncSender<PacketData>, options: HashMap<String, String>)
 	-> Result<(), pyo3::PyErr>
 {
-    use pyo3::exceptions::PyConnectionAbortedError;
+    use pyo3::exceptions::{PyAttributeError, PyConnectionAbortedError, PyTypeError};
     use pyo3::prelude::*;
 	use pyo3::ffi::c_str;
 
+	// set if the client stopped receiving, which is not an error
+	// of the generator
+	let mut hung_up = false;
 	Python::initialize();
-	Python::attach(|py| {
+	let result = Python::attach(|py| {
 		let generator = PyModule::from_code(
 			py,
 			generator_code,
@@ -205,20 +934,46 @@ fn generator_py(
 			c_str!("generator"),
 		)?;
 		generator.setattr("MIN_SIZE", MIN_SIZE)?;
-		let method = generator.getattr("generate")?;
+		let method = generator.getattr(function)
+			.map_err(|_| PyAttributeError::new_err(format!(
+				"generator module {generator_file:?} has no function {function:?}")))?;
+		if !method.is_callable() {
+			return Err(PyTypeError::new_err(format!(
+				"{function:?} in generator module {generator_file:?} is not callable")));
+		}
 		let i = method.call1((options,))?;
 		i.try_iter()?
-			.map(|t|
-				 t.and_then(|x| x.extract::<((i64, i64), usize)>()))
+			.map(|t| t.and_then(|x| {
+				// optional third element: if true, the time is an
+				// absolute departure time instead of a delay
+				x.extract::<((i64, i64), usize, bool)>()
+					.or_else(|_| x.extract::<((i64, i64), usize)>()
+							 .map(|(time, size)| (time, size, false)))
+			}))
 			.try_for_each(|t| {
-				let ((sec, nsec), size) = t?;
-				let step = TimeSpec::new(sec, nsec);
-				target.send(PacketData { delay: step, size })
-					.map_err(|_| PyConnectionAbortedError::new_err(
-						"client thread closed connection"))
+				let ((sec, nsec), size, absolute) = t?;
+				let time = TimeSpec::new(sec, nsec);
+				let data = if absolute {
+					PacketData {
+						delay: TimeSpec::new(0, 0),
+						size,
+						departure: Some(time),
+						payload: None,
+					}
+				} else {
+					PacketData { delay: time, size, departure: None, payload: None }
+				};
+				target.send(data).map_err(|_| {
+					hung_up = true;
+					PyConnectionAbortedError::new_err("client thread closed connection")
+				})
 			})?;
 		PyResult::Ok(())
-	}).inspect_err(
+	});
+	if hung_up {
+		return Ok(());
+	}
+	result.inspect_err(
 		|e| Python::attach(|py| {
 			eprintln!(
 				"Generator module failed: {}{}",
@@ -236,7 +991,7 @@ fn generator_py(
 mod tests {
 	use super::*;
 
-	use std::{error::Error, num::IntErrorKind};
+	use std::{error::Error, num::IntErrorKind, time::Instant};
 
 	#[test]
 	fn default() -> Result<(), Box<dyn std::error::Error>> {
@@ -258,6 +1013,26 @@ mod tests {
 		Ok(())
 	}
 
+	#[test]
+	fn sizes() -> Result<(), Box<dyn std::error::Error>> {
+		let mut options = HashMap::new();
+		options.insert("count".to_string(), "7".to_string());
+		options.insert("sizes".to_string(), "64, 512,1500".to_string());
+		let receiver = Generator::Default.run(options.clone())?;
+		let sizes: Vec<usize> = receiver.iter().map(|p| p.size).collect();
+		assert_eq!(sizes, vec![64, 512, 1500, 64, 512, 1500, 64]);
+
+		options.insert("sizes".to_string(), "64,20".to_string());
+		assert!(Generator::Default.run(options.clone()).unwrap_err().to_string()
+				.starts_with("Option \"sizes\" has an invalid value"));
+		options.insert("sizes".to_string(), "64,".to_string());
+		assert!(Generator::Default.run(options.clone()).is_err());
+		options.insert("sizes".to_string(), "64".to_string());
+		options.insert("size".to_string(), "64".to_string());
+		assert!(Generator::Default.run(options).is_err());
+		Ok(())
+	}
+
 	#[test]
 	fn vary() -> Result<(), Box<dyn std::error::Error>> {
 		let mut options = HashMap::new();
@@ -277,6 +1052,297 @@ mod tests {
 		Ok(())
 	}
 
+	#[test]
+	fn schedule() -> Result<(), Box<dyn std::error::Error>> {
+		let path = std::env::temp_dir()
+			.join(format!("luna-schedule-{}.txt", std::process::id()));
+		std::fs::write(&path, concat!(
+			"# constant, ramp up, constant with larger packets\n",
+			"0 100 21\n",
+			"1 100 21\n",
+			"\n",
+			"2 300 64  # ramp ends here\n",
+			"3 300 64\n"))?;
+		let mut options = HashMap::new();
+		options.insert("file".to_string(), path.to_str().unwrap().to_string());
+		assert_eq!(
+			Generator::Schedule.planned_count(&options)?,
+			Some(100 + 200 + 300));
+		let receiver = Generator::Schedule.run(options)?;
+		std::fs::remove_file(&path)?;
+
+		// packets per second and sizes seen in each second
+		let mut t = Duration::ZERO;
+		let mut per_second = [0; 3];
+		let mut sizes = [vec![], vec![], vec![]];
+		for pkt in receiver.iter() {
+			t += Duration::from(pkt.delay);
+			let s = (t.as_secs_f64() - 1e-6) as usize;
+			per_second[s] += 1;
+			sizes[s].push(pkt.size);
+		}
+		assert_eq!(per_second, [100, 200, 300]);
+		assert!(sizes[1].iter().all(|s| *s == 21));
+		assert!(sizes[2].iter().all(|s| *s == 64));
+
+		// the rate tracks the ramp: slower at the start
+		let mut times = Schedule::parse("0 0 21\n1 200 21\n")?.send_times()
+			.map(|(t, _)| t);
+		// integral of 200 t over [0, x] is 100 x^2
+		assert!((times.next().unwrap() - 0.1).abs() < 1e-9);
+		assert!((times.nth(2).unwrap() - 0.2).abs() < 1e-9);
+		assert_eq!(times.count(), 100 - 4);
+		Ok(())
+	}
+
+	#[test]
+	fn schedule_speed() -> Result<(), Box<dyn std::error::Error>> {
+		let path = std::env::temp_dir()
+			.join(format!("luna-schedule-speed-{}.txt", std::process::id()));
+		std::fs::write(&path, "0 10 21\n1 50 21\n")?;
+		let delays = |speed: Option<&str>| -> Result<Vec<Duration>, Box<dyn std::error::Error>> {
+			let mut options = HashMap::new();
+			options.insert("file".to_string(), path.to_str().unwrap().to_string());
+			if let Some(s) = speed {
+				options.insert("speed".to_string(), s.to_string());
+			}
+			Ok(Generator::Schedule.run(options)?.iter().map(|p| p.delay.into()).collect())
+		};
+		let recorded = delays(None)?;
+		let fast = delays(Some("2"))?;
+		let slow = delays(Some("0.5"))?;
+		std::fs::remove_file(&path)?;
+
+		assert_eq!(recorded.len(), 30);
+		assert_eq!(fast.len(), recorded.len());
+		assert_eq!(slow.len(), recorded.len());
+		// offsets are rounded to nanoseconds, so each delay may be off
+		// by one, doubled for the recorded delays at half speed
+		let close = |a: Duration, b: Duration| a.abs_diff(b) <= Duration::from_nanos(3);
+		for (i, d) in recorded.iter().enumerate() {
+			assert!(close(fast[i], *d / 2), "{i}: {:?} {d:?}", fast[i]);
+			assert!(close(slow[i], *d * 2), "{i}: {:?} {d:?}", slow[i]);
+		}
+
+		for invalid in ["0", "-1", "inf", "NaN", "fast"] {
+			let mut options = HashMap::new();
+			options.insert("speed".to_string(), invalid.to_string());
+			let e = super::schedule_speed(&options).unwrap_err();
+			assert_eq!(e.option, "speed");
+			assert_eq!(e.value.as_deref(), Some(invalid));
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn target() -> Result<(), Box<dyn std::error::Error>> {
+		let mut options = HashMap::new();
+		options.insert("count".to_string(), "600".to_string());
+		options.insert("rate".to_string(), "1000".to_string());
+		assert!(Generator::Target.uses_feedback());
+		assert_eq!(Generator::Target.planned_count(&options)?, Some(600));
+
+		// A sender that counts each delay from the previous send and
+		// takes extra time for every send, so the requested rate
+		// is not achieved without adjustment.
+		let extra = Duration::from_micros(300);
+		let (fb_sender, fb_receiver) = mpsc::channel();
+		let receiver = Generator::Target.run_with_feedback(options.clone(), Some(fb_receiver))?;
+		let mut sent = vec![];
+		let mut prev: Option<Instant> = None;
+		for pkt in receiver.iter() {
+			if let Some(p) = prev {
+				thread::sleep((p + Duration::from(pkt.delay)).saturating_duration_since(Instant::now()));
+			}
+			thread::sleep(extra);
+			let now = Instant::now();
+			let delay = prev.map(|p| now - p).unwrap_or_default();
+			fb_sender.send(SentPacket { delay: delay.into(), size: pkt.size, flags: Default::default() })?;
+			sent.push(now);
+			prev = Some(now);
+		}
+		assert_eq!(sent.len(), 600);
+		// average rate after settling
+		let rate = 299.0 / (sent[599] - sent[300]).as_secs_f64();
+		assert!((rate - 1000.0).abs() < 50.0, "achieved {rate:.1} packets/s");
+
+		// without feedback the interval stays fixed
+		let receiver = Generator::Target.run(options.clone())?;
+		assert!(receiver.iter().all(|p| p.delay == TimeSpec::new(0, 1_000_000)));
+
+		for (name, invalid) in [("rate", "0"), ("rate", "-5"), ("rate", "1e-300"), ("gain", "1.5"), ("gain", "NaN")] {
+			let mut options = options.clone();
+			options.insert(name.to_string(), invalid.to_string());
+			let e = Generator::Target.run(options).unwrap_err()
+				.downcast::<InvalidOption>().unwrap();
+			assert_eq!(e.option, name);
+			assert_eq!(e.value.as_deref(), Some(invalid));
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn poisson() -> Result<(), Box<dyn std::error::Error>> {
+		let count = 100_000;
+		let mut options = HashMap::new();
+		options.insert("count".to_string(), count.to_string());
+		options.insert("rate".to_string(), "1000".to_string());
+		options.insert("seed".to_string(), "7".to_string());
+		assert_eq!(Generator::Poisson.planned_count(&options)?, Some(count));
+		let delays: Vec<Duration> = Generator::Poisson.run(options.clone())?
+			.iter().map(|p| p.delay.into()).collect();
+		assert_eq!(delays.len(), count);
+		// the standard error of the mean is 1ms / sqrt(count)
+		let mean = delays.iter().sum::<Duration>() / count as u32;
+		assert!(mean.abs_diff(Duration::from_millis(1)) < Duration::from_micros(20), "{mean:?}");
+		// exponential: about 1/e of the intervals exceed the mean
+		let long = delays.iter().filter(|d| **d > Duration::from_millis(1)).count();
+		assert!((long as f64 / count as f64 - (-1f64).exp()).abs() < 0.01, "{long}");
+
+		// the same seed gives the same intervals
+		options.insert("count".to_string(), "10".to_string());
+		let again: Vec<Duration> = Generator::Poisson.run(options.clone())?
+			.iter().map(|p| p.delay.into()).collect();
+		assert_eq!(again, delays[..10]);
+		options.insert("seed".to_string(), "8".to_string());
+		let other: Vec<Duration> = Generator::Poisson.run(options.clone())?
+			.iter().map(|p| p.delay.into()).collect();
+		assert_ne!(other, again);
+
+		options.insert("seed".to_string(), "-1".to_string());
+		assert!(Generator::Poisson.run(options.clone()).is_err());
+		options.remove("seed");
+		options.insert("rate".to_string(), "0".to_string());
+		assert!(Generator::Poisson.run(options.clone()).is_err());
+		options.insert("rate".to_string(), "1e-300".to_string());
+		let e = Generator::Poisson.run(options).unwrap_err().downcast::<InvalidOption>()?;
+		assert_eq!(e.option, "rate");
+		assert_eq!(e.source.to_string(), "rate too small, intervals would overflow");
+		Ok(())
+	}
+
+	#[test]
+	fn burst() -> Result<(), Box<dyn std::error::Error>> {
+		let mut options = HashMap::new();
+		options.insert("burst-count".to_string(), "3".to_string());
+		options.insert("usec".to_string(), "100".to_string());
+		options.insert("off-interval".to_string(), "0.02".to_string());
+		options.insert("count".to_string(), "8".to_string());
+		assert_eq!(Generator::Burst.planned_count(&options)?, Some(8));
+		let delays: Vec<Duration> = Generator::Burst.run(options.clone())?
+			.iter().map(|p| p.delay.into()).collect();
+		let (on, off) = (Duration::from_micros(100), Duration::from_millis(20));
+		assert_eq!(delays, [Duration::ZERO, on, on, off, on, on, off, on]);
+
+		// bursts start at 0, 20.2ms, 40.4ms, 60.6ms, the last one
+		// is cut off after its first packet
+		options.remove("count");
+		options.insert("duration".to_string(), "0.0606".to_string());
+		assert_eq!(Generator::Burst.planned_count(&options)?, Some(10));
+		let delays: Vec<Duration> = Generator::Burst.run(options.clone())?
+			.iter().map(|p| p.delay.into()).collect();
+		assert_eq!(delays, [Duration::ZERO, on, on, off, on, on, off, on, on, off]);
+
+		options.insert("count".to_string(), "8".to_string());
+		assert_eq!(Generator::Burst.run(options.clone()).unwrap_err()
+			.downcast::<InvalidOption>()?.option, "duration");
+		options.remove("duration");
+		for (name, invalid) in [("burst-count", "0"), ("off-interval", "0"), ("off-interval", "x")] {
+			let mut o = options.clone();
+			o.insert(name.to_string(), invalid.to_string());
+			let e = Generator::Burst.run(o).unwrap_err().downcast::<InvalidOption>()?;
+			assert_eq!(e.option, name);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn rate_control() {
+		// an interval 0.5ms too long is compensated step by step
+		let mut control = RateControl::new(1000.0, 0.5);
+		let mut interval = control.interval;
+		for _ in 0..20 {
+			interval = control.update(interval + 0.0005);
+		}
+		assert!((interval - 0.0005).abs() < 1e-6, "{interval}");
+		// but never below zero or above twice the target
+		assert_eq!(control.update(1.0), 0.0);
+		assert_eq!(control.update(-1.0), 0.002);
+	}
+
+	#[test]
+	fn schedule_invalid() {
+		assert_eq!(
+			Schedule::parse("0 10 21\n2 10 21\n1 10 21\n"),
+			Err("line 3: time offsets must be increasing".to_string()));
+		assert_eq!(
+			Schedule::parse("0 10 21\n1 -1 21\n"),
+			Err("line 2: rate must be a non-negative number".to_string()));
+		assert_eq!(
+			Schedule::parse("1 10 21\n2 10 21\n"),
+			Err("line 1: schedule must start at offset 0".to_string()));
+		assert_eq!(
+			Schedule::parse("0 10\n"),
+			Err("line 1: expected \"time_offset rate size\"".to_string()));
+		assert_eq!(
+			Schedule::parse("0 10 21\n"),
+			Err("schedule needs at least two points".to_string()));
+		let e = Generator::Schedule.planned_count(&HashMap::new()).unwrap_err();
+		let e = e.downcast::<InvalidOption>().unwrap();
+		assert_eq!(e.option, "file");
+		assert_eq!(e.value, None);
+		assert_eq!(e.to_string(), "Option \"file\" has an invalid value");
+	}
+
+	#[test]
+	fn replay() -> Result<(), Box<dyn std::error::Error>> {
+		let ms = Duration::from_millis;
+		// send log
+		let send_log = "delay\tsize\tflags\n0.000000000\t64\t17\n0.002000000\t21\t17\n";
+		assert_eq!(Trace::parse(send_log)?.packets, [(ms(0), 64), (ms(2), 21)]);
+		// packet log in receive order, with RFC 3339 timestamps
+		let packet_log = concat!(
+			"receive_time\tsource\tport\tsequence\ttimestamp\tsize\tflow\n",
+			"1.0\t::1\t7800\t0\t1970-01-01T00:00:10.000000000Z\t100\t0\n",
+			"1.0\t::1\t7800\t2\t1970-01-01T00:00:10.005000000Z\t300\t0\n",
+			"1.0\t::1\t7800\t1\t1970-01-01T00:00:10.001000000Z\t200\t0\n");
+		assert_eq!(
+			Trace::parse(packet_log)?.packets,
+			[(ms(0), 100), (ms(1), 200), (ms(4), 300)]);
+		// without header, comma or whitespace separated
+		assert_eq!(
+			Trace::parse("# delay, size\n0.001, 40\n\n0.5 21\n")?.packets,
+			[(ms(1), 40), (ms(500), 21)]);
+
+		let path = std::env::temp_dir()
+			.join(format!("luna-replay-{}.tsv", std::process::id()));
+		std::fs::write(&path, send_log)?;
+		let mut options = HashMap::new();
+		options.insert("file".to_string(), path.to_str().unwrap().to_string());
+		options.insert("speed".to_string(), "2".to_string());
+		assert_eq!(Generator::Replay.planned_count(&options)?, Some(2));
+		let packets: Vec<(Duration, usize)> = Generator::Replay.run(options.clone())?
+			.iter().map(|p| (p.delay.into(), p.size)).collect();
+		assert_eq!(packets, [(ms(0), 64), (ms(1), 21)]);
+
+		std::fs::write(&path, "delay\tsize\n0.1\t64\nx\t64\n")?;
+		let e = Generator::Replay.run(options).unwrap_err().downcast::<InvalidOption>()?;
+		std::fs::remove_file(&path)?;
+		assert_eq!(e.option, "file");
+		assert!(e.source.to_string().starts_with("line 3: "), "{}", e.source);
+
+		for (trace, error) in [
+			("sequence\tsize\n", "line 1: no \"delay\" or \"timestamp\" column"),
+			("delay size\n0.1 20\n", "line 2: size 20 is less than the minimum of 21"),
+			("0.1\n", "line 1: expected at least 2 fields"),
+			("-0.5 21\n", "line 1: delay must not be negative"),
+			("delay\tsize\n", "trace contains no packets"),
+		] {
+			assert_eq!(Trace::parse(trace), Err(error.to_string()));
+		}
+		Ok(())
+	}
+
 	#[cfg(feature = "python")]
 	#[test]
 	fn py_gen() -> Result<(), Box<dyn std::error::Error>> {
@@ -291,7 +1357,8 @@ mod tests {
 		let mut options = HashMap::new();
 		let count = 256;
 		options.insert(String::from("count"), format!("{count}"));
-		let receiver = Generator::Py{code, file}.run(options)?;
+		let function = String::from("generate");
+		let receiver = Generator::Py{code, file, function}.run(options)?;
 		let step = TimeSpec::new(0, 1_000_000);
 		for i in 0..count {
 			let pkt = receiver.recv()?;
@@ -304,6 +1371,155 @@ mod tests {
 		Ok(())
 	}
 
+	#[cfg(feature = "python")]
+	#[test]
+	fn py_gen_absolute() -> Result<(), Box<dyn std::error::Error>> {
+		let file = CString::new("absolute.py")?;
+		let code = CString::new(concat!(
+			"def generate(options):\n",
+			"    yield ((0, 1000), MIN_SIZE)\n",
+			"    yield ((100, 5), MIN_SIZE, True)\n",
+			"    yield ((0, 1000), 64, False)\n",
+		))?;
+		let function = String::from("generate");
+		let receiver = Generator::Py{code, file, function}.run(HashMap::new())?;
+		assert_eq!(receiver.recv()?, PacketData {
+			delay: TimeSpec::new(0, 1000), size: MIN_SIZE, departure: None, payload: None,
+		});
+		assert_eq!(receiver.recv()?, PacketData {
+			delay: TimeSpec::new(0, 0),
+			size: MIN_SIZE,
+			departure: Some(TimeSpec::new(100, 5)),
+			payload: None,
+		});
+		assert_eq!(receiver.recv()?, PacketData {
+			delay: TimeSpec::new(0, 1000), size: 64, departure: None, payload: None,
+		});
+		assert_eq!(receiver.recv(), Err(mpsc::RecvError));
+		Ok(())
+	}
+
+	#[cfg(feature = "python")]
+	#[test]
+	fn py_gen_function() -> Result<(), Box<dyn std::error::Error>> {
+		let file = CString::new("library.py")?;
+		let code = CString::new(concat!(
+			"def generate(options):\n",
+			"    yield ((0, 1000), MIN_SIZE)\n",
+			"def large(options):\n",
+			"    yield ((0, 2000), 1000)\n",
+			"not_a_function = 5\n",
+		))?;
+		let function = String::from("large");
+		let receiver = Generator::Py{
+			code: code.clone(), file: file.clone(), function
+		}.run(HashMap::new())?;
+		assert_eq!(receiver.recv()?, PacketData {
+			delay: TimeSpec::new(0, 2000), size: 1000, departure: None, payload: None,
+		});
+		assert_eq!(receiver.recv(), Err(mpsc::RecvError));
+
+		for (function, msg) in [
+			("missing", "has no function \"missing\""),
+			("not_a_function", "\"not_a_function\" in generator module \"library.py\" is not callable"),
+		] {
+			let (sender, _receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+			let err = generator_py(&code, &file, function, sender, HashMap::new())
+				.unwrap_err();
+			assert!(err.to_string().contains(msg), "{err}");
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn receiver_dropped() -> Result<(), Box<dyn std::error::Error>> {
+		let mut options = HashMap::new();
+		options.insert("count".to_string(), "1000".to_string());
+		let path = std::env::temp_dir()
+			.join(format!("luna-schedule-dropped-{}.txt", std::process::id()));
+		std::fs::write(&path, "0 1000 21\n1 1000 21\n")?;
+		let mut schedule_options = HashMap::new();
+		schedule_options.insert("file".to_string(), path.to_str().unwrap().to_string());
+		type Start = fn(mpsc::SyncSender<PacketData>, HashMap<String, String>)
+			-> Result<thread::JoinHandle<()>, Box<dyn std::error::Error>>;
+		for (start, options) in [
+			(generator as Start, options.clone()),
+			(generator_vary_size, options.clone()),
+			(generator_schedule, schedule_options),
+		] {
+			let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+			drop(receiver);
+			let h = start(sender, options)?;
+			// the generator stops without panic
+			assert!(h.join().is_ok());
+		}
+		std::fs::remove_file(&path)?;
+
+		#[cfg(feature = "python")]
+		{
+			let code = CString::new(concat!(
+				"def generate(options):\n",
+				"    while True:\n",
+				"        yield ((0, 1000), MIN_SIZE)\n",
+			))?;
+			let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+			drop(receiver);
+			generator_py(
+				&code, &CString::new("endless.py")?, "generate", sender, options)?;
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn sequence() -> Result<(), Box<dyn std::error::Error>> {
+		let mut options = HashMap::new();
+		options.insert("usec".to_string(), "100".to_string());
+		options.insert("1.count".to_string(), "3".to_string());
+		options.insert("2.count".to_string(), "4".to_string());
+		options.insert("2.max-size".to_string(), "64".to_string());
+		assert_eq!(
+			stage_options(&options, 1),
+			HashMap::from([
+				("usec".to_string(), "100".to_string()),
+				("count".to_string(), "4".to_string()),
+				("max-size".to_string(), "64".to_string()),
+			]));
+		let g = Generator::Sequence(vec![Generator::Default, Generator::Vary]);
+		assert_eq!(format!("{g}"), "Generator::Sequence(Generator::Default, Generator::Vary)");
+		assert_eq!(g.planned_count(&options)?, Some(7));
+		let sizes: Vec<usize> = g.run(options.clone())?.iter()
+			.inspect(|p| assert_eq!(p.delay, TimeSpec::new(0, 100_000)))
+			.map(|p| p.size)
+			.collect();
+		assert_eq!(sizes, [MIN_SIZE, MIN_SIZE, MIN_SIZE, MIN_SIZE, 42, 64, 42]);
+
+		// invalid options of any stage are reported on start
+		options.insert("2.count".to_string(), "many".to_string());
+		let g = Generator::Sequence(vec![Generator::Default, Generator::Vary]);
+		assert!(g.run(options).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn planned_count() -> Result<(), Box<dyn std::error::Error>> {
+		let mut options = HashMap::new();
+		assert_eq!(Generator::Default.planned_count(&options)?, Some(10));
+		assert_eq!(Generator::Vary.planned_count(&options)?, Some(20));
+		options.insert("count".to_string(), "42".to_string());
+		assert_eq!(Generator::Default.planned_count(&options)?, Some(42));
+		assert_eq!(Generator::Vary.planned_count(&options)?, Some(42));
+		#[cfg(feature = "python")]
+		assert_eq!(
+			Generator::Py{
+				code: CString::new("")?, file: CString::new("")?,
+				function: String::from("generate"),
+			}.planned_count(&options)?,
+			None);
+		options.insert("count".to_string(), "many".to_string());
+		assert!(Generator::Default.planned_count(&options).is_err());
+		Ok(())
+	}
+
 	#[test]
 	fn invalid_count() {
 		let mut go = HashMap::new();
@@ -314,7 +1530,8 @@ mod tests {
 		};
 		let err = func(&go).unwrap_err().downcast::<InvalidOption>().unwrap();
 		eprintln!("{err:?}");
-		assert_eq!(format!("{err}"), "Option \"msec\" has an invalid value");
+		assert_eq!(format!("{err}"), "Option \"msec\" has an invalid value \"abc\"");
+		assert_eq!(err.value.as_deref(), Some("abc"));
 		let source = Box::new(err.deref().source().unwrap());
 		assert_eq!(
 			*source.downcast_ref::<ParseIntError>().unwrap().kind(),
@@ -361,7 +1578,9 @@ mod tests {
 		go.clear();
 		// invalid interval value
 		go.insert("interval".to_string(), "a.001".to_string());
-		assert!(parse_interval(&go).is_err());
+		assert_eq!(
+			parse_interval(&go).unwrap_err().to_string(),
+			"Option \"interval\" has an invalid value \"a.001\"");
 		Ok(())
 	}
 }
@@ -1,14 +1,77 @@
-use nix::{libc::timespec, sys::{socket, time::TimeSpec}};
+use nix::{cmsg_space, errno::Errno, libc::timespec, sys::{mman, resource, socket, time::{TimeSpec, TimeVal, TimeValLike}}, time::{ClockId, clock_gettime}};
 use core::fmt;
-use std::{fmt::{Display, Formatter}, io::{Error, ErrorKind}};
+use std::{
+	fmt::{Display, Formatter},
+	io::{self, Error, ErrorKind, Read, Write},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	os::fd::{AsFd, AsRawFd},
+	str::FromStr,
+	time::Duration,
+};
 
+// Flag bits of the packet format, see [`Flags`].
+
+/// Request that the server echoes the packet back to the sender.
 pub const ECHO_FLAG: u8 = 1;
+/// Request that the server writes its echo sequence number (counting
+/// echoes per client) into the echo, right after the flags. Only
+/// honored for packets of at least [`ECHO_SEQ_SIZE`] bytes.
+pub const ECHO_SEQ_FLAG: u8 = 2;
+/// Set by the server in echoes that carry an echo sequence number.
+pub const ECHO_SEQ_STAMPED: u8 = 4;
+/// The packet carries a flow ID identifying the stream it belongs
+/// to, after the echo sequence number (which is reserved even if not
+/// used). Only present in packets of at least [`FLOW_SIZE`] bytes,
+/// packets without flow ID belong to flow 0.
+pub const FLOW_FLAG: u8 = 8;
 pub const MIN_SIZE: usize = size_of::<u32>() + size_of::<timespec>() + size_of::<u8>();
+/// Newest version of the packet format, stored in the upper four
+/// bits of the flags byte. Version 0 is the original LUNA format,
+/// version 1 the layout with the extensions selected by flags, see
+/// [`HEADER_LENGTH_VERSION`] for version 2 and [`CLOCK_VERSION`] for
+/// version 3.
+pub const PROTOCOL_VERSION: u8 = 3;
+/// Format version that adds a header length byte after the flags
+/// byte, in packets larger than [`MIN_SIZE`]. The optional fields
+/// (echo sequence number, flow ID) follow it, the payload starts at
+/// the header length, so a receiver can find it without knowing all
+/// fields. Otherwise the same as version 1.
+pub const HEADER_LENGTH_VERSION: u8 = 2;
+/// Format version that adds a clock byte after the header length
+/// byte: the clock the send timestamp was read from, see
+/// [`SendClock`]. Packets must have room for it ([`CLOCK_SIZE`]),
+/// otherwise the same as version 2. Packets of earlier versions
+/// always carry [`SendClock::Realtime`] timestamps, so clients use
+/// this version only for other clocks.
+pub const CLOCK_VERSION: u8 = 3;
+/// Minimum packet size in format version 3, with header length and
+/// clock byte.
+pub const CLOCK_SIZE: usize = MIN_SIZE + 2;
+/// Format version reserved for keepalive packets: [`MIN_SIZE`] bytes
+/// with the flags byte `0xf0`, sent by a client that has been idle
+/// for a while to keep NAT and firewall state for the path alive.
+/// They carry no measurement data, servers neither echo nor log
+/// them. Servers that do not know keepalives ignore them as an
+/// unsupported version.
+pub const KEEPALIVE_VERSION: u8 = 0xf;
+const VERSION_SHIFT: u8 = 4;
+/// Flag bits defined in version 1 of the packet format. All four bits
+/// below the version are in use, further flags need a new version.
+const FLAGS_V1: u8 = ECHO_FLAG | ECHO_SEQ_FLAG | ECHO_SEQ_STAMPED | FLOW_FLAG;
+/// Minimum packet size to carry an echo sequence number (in format
+/// version 1).
+pub const ECHO_SEQ_SIZE: usize = MIN_SIZE + size_of::<u32>();
+/// Minimum packet size to carry a flow ID (in format version 1).
+pub const FLOW_SIZE: usize = ECHO_SEQ_SIZE + size_of::<u32>();
 
 
+pub mod addr;
+pub mod calibrate;
 pub mod generator;
 pub mod client;
 pub mod server;
+pub mod sink;
+pub mod stats;
 
 
 /// Read an int of the given format from a byte slice. Will panic if
@@ -22,13 +85,17 @@ macro_rules! parse_int {
 }
 
 
+/// Return errors of `$call` except permission denied, which is
+/// reported as a warning unless `$quiet` is true.
 macro_rules! accept_noperm {
-	($call:expr, $warn:literal) => {{
+	($call:expr, $warn:literal, $quiet:expr) => {{
 		if let Err(e) = $call {
 			match e.downcast::<Error>() {
 				Ok(e) => {
 					if e.kind() == ErrorKind::PermissionDenied {
-						eprintln!("{}: {}", $warn, e);
+						if !$quiet {
+							eprintln!("{}: {}", $warn, e);
+						}
 					} else {
 						return Err(e);
 					}
@@ -41,10 +108,512 @@ macro_rules! accept_noperm {
 pub(crate) use accept_noperm;
 
 
+/// Flags byte of a packet: flag bits (e.g. [`ECHO_FLAG`]) in the
+/// lower four bits, the packet format version in the upper four.
+/// Displays as the numeric value of the byte.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags(u8);
+
+
+impl Flags {
+	/// Flags byte of the given format version, without flags set.
+	pub fn new(version: u8) -> Self {
+		Flags(version << VERSION_SHIFT)
+	}
+
+	/// Packet format version.
+	pub fn version(self) -> u8 {
+		self.0 >> VERSION_SHIFT
+	}
+
+	/// Flags byte of a keepalive packet, see [`KEEPALIVE_VERSION`].
+	pub fn keepalive() -> Self {
+		Flags::new(KEEPALIVE_VERSION)
+	}
+
+	/// Check if this is the flags byte of a keepalive packet.
+	pub fn is_keepalive(self) -> bool {
+		self.version() == KEEPALIVE_VERSION
+	}
+
+	/// Check if all bits of `flag` are set.
+	pub fn contains(self, flag: u8) -> bool {
+		self.0 & flag == flag
+	}
+
+	/// Set the bits of `flag`.
+	pub fn set(&mut self, flag: u8) {
+		self.0 |= flag;
+	}
+
+	/// Copy with the bits of `flag` set.
+	pub fn with(mut self, flag: u8) -> Self {
+		self.set(flag);
+		self
+	}
+
+	/// Flag bits that are set, but not defined in the format version
+	/// of the packet. Only meaningful for supported versions, a newer
+	/// version may define anything.
+	pub fn unknown(self) -> u8 {
+		let known = if self.version() == 0 { ECHO_FLAG } else { FLAGS_V1 };
+		self.0 & !(u8::MAX << VERSION_SHIFT) & !known
+	}
+
+	/// The flags byte as sent.
+	pub fn bits(self) -> u8 {
+		self.0
+	}
+
+	/// Offset of the optional header fields (echo sequence number,
+	/// then flow ID) in packets of this format version.
+	pub fn fields_offset(self) -> usize {
+		match self.version() {
+			v if v >= CLOCK_VERSION => CLOCK_SIZE,
+			HEADER_LENGTH_VERSION => MIN_SIZE + 1,
+			_ => MIN_SIZE,
+		}
+	}
+
+	/// Header length of a packet of `size` bytes with these flags:
+	/// the base header, the header length field (version 2), and the
+	/// echo sequence number and flow ID fields the flags call for, as
+	/// far as they fit. The echo sequence number is reserved if the
+	/// packet carries a flow ID.
+	pub fn header_length(self, size: usize) -> usize {
+		if self.version() == 0 || size <= MIN_SIZE {
+			return MIN_SIZE;
+		}
+		let echo_seq = self.fields_offset() + size_of::<u32>();
+		let flow = echo_seq + size_of::<u32>();
+		if self.contains(FLOW_FLAG) && size >= flow {
+			flow
+		} else if size >= echo_seq && (self.contains(ECHO_SEQ_FLAG) || self.contains(ECHO_SEQ_STAMPED)) {
+			echo_seq
+		} else {
+			self.fields_offset()
+		}
+	}
+}
+
+
+/// Header length of the received packet `data`, where the payload
+/// starts: read from the header length field in packets of format
+/// version 2, otherwise derived from flags and size, see
+/// [`Flags::header_length`]. Fails if the packet is smaller than
+/// [`MIN_SIZE`] (or [`CLOCK_SIZE`] in version 3) or its header length
+/// field is invalid (shorter than the fields of its version, or
+/// longer than the packet).
+pub fn header_length(data: &[u8]) -> Result<usize, Error> {
+	if data.len() < MIN_SIZE {
+		return Err(Error::new(ErrorKind::InvalidData, "packet too small"));
+	}
+	let flags = Flags::from(data[MIN_SIZE - 1]);
+	if flags.version() >= CLOCK_VERSION && data.len() < CLOCK_SIZE {
+		return Err(Error::new(ErrorKind::InvalidData, "packet too small for clock byte"));
+	}
+	if flags.version() < HEADER_LENGTH_VERSION || data.len() == MIN_SIZE {
+		return Ok(flags.header_length(data.len()));
+	}
+	let length = usize::from(data[MIN_SIZE]);
+	if length < flags.fields_offset() || length > data.len() {
+		return Err(Error::new(
+			ErrorKind::InvalidData,
+			format!("invalid header length {length} in packet of {} bytes", data.len())));
+	}
+	Ok(length)
+}
+
+
+/// Sequence number repeated in the first four bytes of the payload
+/// (at the [`header_length`]) of `data`, if the client was asked to
+/// write it, so a capture elsewhere can restore order and detect
+/// rewritten headers without relying on the header. The packet does
+/// not say whether the marker is present, the reader must know.
+/// `None` if the payload is too short to hold it.
+pub fn payload_sequence(data: &[u8]) -> Result<Option<u32>, Error> {
+	let start = header_length(data)?;
+	Ok(data.get(start..start + size_of::<u32>())
+		.map(|b| u32::from_be_bytes(b.try_into().unwrap())))
+}
+
+
+impl From<u8> for Flags {
+	fn from(bits: u8) -> Self {
+		Flags(bits)
+	}
+}
+
+
+impl Display for Flags {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+
+/// Quote and escape a string for use in JSON output.
+pub fn json_string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+
+/// Format up to `limit` bytes of `data` as a hex dump with offsets
+/// and ASCII column, 16 bytes per line, one trailing newline per
+/// line.
+pub fn hexdump(data: &[u8], limit: usize) -> String {
+	let mut out = String::new();
+	for (i, line) in data[..data.len().min(limit)].chunks(16).enumerate() {
+		out.push_str(&format!("{:08x} ", i * 16));
+		for j in 0..16 {
+			if j == 8 {
+				out.push(' ');
+			}
+			match line.get(j) {
+				Some(b) => out.push_str(&format!(" {b:02x}")),
+				None => out.push_str("   "),
+			}
+		}
+		out.push_str("  |");
+		out.extend(line.iter().map(
+			|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }));
+		out.push_str("|\n");
+	}
+	if data.len() > limit {
+		out.push_str(&format!("({} more bytes)\n", data.len() - limit));
+	}
+	out
+}
+
+
+/// ECN codepoint, the lower two bits of the IPv4 TOS or IPv6 traffic
+/// class byte (RFC 3168).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Ecn {
+	/// not ECN capable transport
+	NotEct = 0,
+	/// ECN capable transport, ECT(1)
+	Ect1 = 1,
+	/// ECN capable transport, ECT(0)
+	Ect0 = 2,
+	/// congestion experienced
+	Ce = 3,
+}
+
+impl From<u8> for Ecn {
+	fn from(tos: u8) -> Self {
+		match tos & 0b11 {
+			0 => Ecn::NotEct,
+			1 => Ecn::Ect1,
+			2 => Ecn::Ect0,
+			_ => Ecn::Ce,
+		}
+	}
+}
+
+
+/// DSCP codepoint, the upper six bits of the IPv4 TOS or IPv6
+/// traffic class byte (RFC 2474). Parses from a number from 0 to 63
+/// or a name: "CS0" to "CS7", "AF11" to "AF43", "EF", "VA" (RFC
+/// 5865), "LE" (RFC 8622), or "DF" (same as "CS0"), ignoring case.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Dscp(u8);
+
+impl Dscp {
+	/// Named codepoints other than the class selectors and assured
+	/// forwarding classes, which follow a pattern.
+	const NAMES: [(&str, u8); 4] = [("EF", 46), ("VA", 44), ("LE", 1), ("DF", 0)];
+
+	/// The codepoint with the given value, if it fits in six bits.
+	pub fn new(value: u8) -> Option<Self> {
+		(value < 64).then_some(Dscp(value))
+	}
+
+	pub fn value(self) -> u8 {
+		self.0
+	}
+
+	/// The TOS / traffic class byte with this codepoint and the
+	/// given ECN codepoint.
+	pub fn tos(self, ecn: Ecn) -> u8 {
+		self.0 << 2 | ecn as u8
+	}
+
+	fn name(self) -> Option<String> {
+		let (class, drop) = (self.0 >> 3, self.0 & 0b111);
+		match drop {
+			0 => Some(format!("CS{class}")),
+			2 | 4 | 6 if (1..=4).contains(&class) => Some(format!("AF{class}{}", drop / 2)),
+			_ => Self::NAMES.iter().find(|(_, v)| *v == self.0).map(|(n, _)| n.to_string()),
+		}
+	}
+}
+
+impl FromStr for Dscp {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || format!("invalid DSCP {s:?}, expected 0 to 63 or a name like EF or AF41");
+		if let Ok(v) = s.parse::<u8>() {
+			return Dscp::new(v).ok_or_else(invalid);
+		}
+		let name = s.to_ascii_uppercase();
+		let digit = |c: Option<char>, max| c.and_then(|c| c.to_digit(10))
+			.filter(|d| (1..=max).contains(d)).map(|d| d as u8);
+		if let Some(n) = Self::NAMES.iter().find(|(n, _)| *n == name) {
+			Ok(Dscp(n.1))
+		} else if let Some(c) = name.strip_prefix("CS")
+			&& c.len() == 1
+			&& let Some(class) = c.chars().next().and_then(|c| c.to_digit(8))
+		{
+			Ok(Dscp((class as u8) << 3))
+		} else if let Some(af) = name.strip_prefix("AF")
+			&& af.len() == 2
+			&& let (Some(class), Some(drop)) = (digit(af.chars().next(), 4), digit(af.chars().nth(1), 3))
+		{
+			Ok(Dscp(class << 3 | drop << 1))
+		} else {
+			Err(invalid())
+		}
+	}
+}
+
+/// Displays the name of the codepoint if it has one (class selectors
+/// as "CSn"), otherwise the number.
+impl Display for Dscp {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match self.name() {
+			Some(n) => write!(f, "{n}"),
+			None => write!(f, "{}", self.0),
+		}
+	}
+}
+
+
+/// Set the TOS (IPv4) or traffic class (IPv6) byte of packets sent
+/// on an IP socket, does nothing for other socket families.
+pub(crate) fn set_tos<F: AsFd>(
+	sock: &F, family: Option<socket::AddressFamily>, tos: u8)
+	-> Result<(), Errno>
+{
+	match family {
+		Some(socket::AddressFamily::Inet6) =>
+			socket::setsockopt(sock, socket::sockopt::Ipv6TClass, &libc::c_int::from(tos)),
+		Some(socket::AddressFamily::Inet) =>
+			socket::setsockopt(sock, socket::sockopt::Ipv4Tos, &libc::c_int::from(tos)),
+		_ => Ok(()),
+	}
+}
+
+
+/// Enable reporting the TOS / traffic class byte of received packets
+/// on an IP socket, does nothing for other socket families. IPv6
+/// sockets also get IPv4 reporting for IPv4-mapped peers.
+pub(crate) fn recv_tos<F: AsFd>(
+	sock: &F, family: Option<socket::AddressFamily>)
+	-> Result<(), Errno>
+{
+	match family {
+		Some(socket::AddressFamily::Inet6) => {
+			socket::setsockopt(sock, socket::sockopt::Ipv6RecvTClass, &true)?;
+			socket::setsockopt(sock, socket::sockopt::IpRecvTos, &true)
+		},
+		Some(socket::AddressFamily::Inet) =>
+			socket::setsockopt(sock, socket::sockopt::IpRecvTos, &true),
+		_ => Ok(()),
+	}
+}
+
+
+/// Enable receive timestamps on the socket: kernel software
+/// timestamps (`SO_TIMESTAMPNS`), or if `hardware` is set
+/// `SO_TIMESTAMPING` with raw hardware timestamps from the network
+/// interface, and software timestamps for packets the interface did
+/// not stamp. Hardware timestamps need NIC and driver support, and
+/// receive timestamping enabled on the interface.
+pub(crate) fn recv_timestamps<F: AsFd>(sock: &F, hardware: bool) -> Result<(), Errno> {
+	use socket::TimestampingFlag as T;
+	if !hardware {
+		return socket::setsockopt(sock, socket::sockopt::ReceiveTimestampns, &true);
+	}
+	socket::setsockopt(
+		sock, socket::sockopt::Timestamping,
+		&(T::SOF_TIMESTAMPING_RX_HARDWARE | T::SOF_TIMESTAMPING_RAW_HARDWARE
+		  | T::SOF_TIMESTAMPING_RX_SOFTWARE | T::SOF_TIMESTAMPING_SOFTWARE))
+}
+
+
+/// Control messages enabled on a socket, to size the `recvmsg`
+/// control message buffer for all of them: if the buffer is too
+/// small the kernel truncates the messages (`MSG_CTRUNC`), and
+/// [`ReceivedPacket`] rejects the packet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ControlMessages {
+	/// `SO_TIMESTAMPING` instead of `SO_TIMESTAMPNS`, see
+	/// [`recv_timestamps`]
+	pub hardware_timestamps: bool,
+	/// TOS or traffic class, see [`recv_tos`] (a packet carries only
+	/// one of them)
+	pub tos: bool,
+	/// largest fragment size, see [`recv_frag_size`]
+	pub frag_size: bool,
+}
+
+
+impl ControlMessages {
+	/// Buffer with room for all enabled control messages.
+	pub fn buffer(&self) -> Vec<u8> {
+		vec![0u8; self.space()]
+	}
+
+	/// Size of the control messages in bytes, including alignment.
+	pub fn space(&self) -> usize {
+		let timestamp = if self.hardware_timestamps {
+			cmsg_space!([TimeSpec; 3])
+		} else {
+			cmsg_space!(TimeSpec)
+		};
+		let int = cmsg_space!(libc::c_int).len();
+		timestamp.len()
+			+ if self.tos { int } else { 0 }
+			+ if self.frag_size { int } else { 0 }
+	}
+}
+
+
+/// Enable reporting the largest fragment size of packets that
+/// arrived fragmented on an IP socket (`IP_RECVFRAGSIZE` /
+/// `IPV6_RECVFRAGSIZE`), does nothing for other socket families.
+pub(crate) fn recv_frag_size<F: AsFd>(
+	sock: &F, family: Option<socket::AddressFamily>)
+	-> Result<(), Errno>
+{
+	let (level, name) = match family {
+		Some(socket::AddressFamily::Inet6) => (libc::IPPROTO_IPV6, libc::IPV6_RECVFRAGSIZE),
+		Some(socket::AddressFamily::Inet) => (libc::IPPROTO_IP, libc::IP_RECVFRAGSIZE),
+		_ => return Ok(()),
+	};
+	let value: libc::c_int = 1;
+	let ret = unsafe {
+		libc::setsockopt(
+			sock.as_fd().as_raw_fd(), level, name,
+			&value as *const libc::c_int as *const libc::c_void,
+			size_of::<libc::c_int>() as libc::socklen_t)
+	};
+	Errno::result(ret).map(drop)
+}
+
+
+/// Convert a duration to a socket timeout (`SO_RCVTIMEO`,
+/// `SO_SNDTIMEO`). Zero means no timeout to the kernel, so shorter
+/// durations are rounded up to one microsecond.
+pub(crate) fn socket_timeout(d: Duration) -> TimeVal {
+	TimeVal::microseconds((d.as_micros() as i64).max(1))
+}
+
+
+// SIOCOUTQ, libc only defines it under its terminal name
+nix::ioctl_read_bad!(siocoutq, libc::TIOCOUTQ, libc::c_int);
+
+
+/// Memory used by packets queued on a socket, in bytes including the
+/// kernel overhead per packet. A growing send queue means packets are
+/// sent faster than the interface takes them, a receive queue that
+/// approaches the receive buffer size means the receiver can't keep
+/// up and the kernel will soon drop packets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketQueues {
+	/// send queue (`SIOCOUTQ`)
+	pub send: usize,
+	/// receive queue
+	pub receive: usize,
+	/// size of the receive buffer, the limit for `receive`
+	pub receive_buffer: usize,
+}
+
+
+impl SocketQueues {
+	/// Read the current queue sizes of the socket. For UDP `SIOCINQ`
+	/// reports only the size of the next datagram, so the receive
+	/// side comes from `SO_MEMINFO` instead.
+	pub fn read<F: AsFd>(sock: &F) -> Result<Self, Errno> {
+		let fd = sock.as_fd().as_raw_fd();
+		let mut send: libc::c_int = 0;
+		unsafe { siocoutq(fd, &mut send) }?;
+		let mut meminfo = [0u32; 2];
+		let mut len = size_of_val(&meminfo) as libc::socklen_t;
+		let ret = unsafe {
+			libc::getsockopt(
+				fd, libc::SOL_SOCKET, libc::SO_MEMINFO,
+				meminfo.as_mut_ptr() as *mut libc::c_void, &mut len)
+		};
+		Errno::result(ret)?;
+		Ok(SocketQueues {
+			send: send as usize,
+			receive: meminfo[libc::SK_MEMINFO_RMEM_ALLOC as usize] as usize,
+			receive_buffer: meminfo[libc::SK_MEMINFO_RCVBUF as usize] as usize,
+		})
+	}
+}
+
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct PacketData {
+	/// send time relative to the previous packet
 	pub delay: TimeSpec,
+	/// size of the packet
 	pub size: usize,
+	/// if `Some`, the absolute earliest departure time of the packet
+	/// on the client send clock, `delay` is ignored
+	pub departure: Option<TimeSpec>,
+	/// if `Some`, the exact payload after the header, `size` is
+	/// ignored and the packet is the header plus this, instead of
+	/// zeros (or unique payload) up to `size`
+	pub payload: Option<Vec<u8>>,
+}
+
+
+/// A packet as actually sent by the client, for recording the
+/// achieved send schedule (as opposed to the one the generator
+/// requested), see [`client::Options::send_log`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SentPacket {
+	/// time since the previous packet was sent, zero for the first
+	pub delay: TimeSpec,
+	/// size of the packet
+	pub size: usize,
+	/// flags byte of the packet
+	pub flags: Flags,
+}
+
+
+impl SentPacket {
+	pub fn header() -> String {
+		String::from("delay\tsize\tflags")
+	}
+}
+
+
+impl Display for SentPacket {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(
+			f, "{}.{:09}\t{}\t{}",
+			self.delay.tv_sec(), self.delay.tv_nsec(), self.size, self.flags)
+	}
 }
 
 
@@ -111,21 +680,326 @@ pub fn set_rt_prio(offset: i32) -> Result<(), Error> {
 }
 
 
-#[derive(Debug, PartialEq, Eq)]
+/// Scheduling policy and priority of a thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scheduling {
+	pub policy: libc::c_int,
+	pub priority: libc::c_int,
+}
+
+
+impl Scheduling {
+	/// Get the scheduling settings of the current thread.
+	pub fn current() -> Result<Self, Error> {
+		let mut policy = 0;
+		let mut sparam: libc::sched_param = unsafe { std::mem::zeroed() };
+		let ret = unsafe {
+			libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut sparam)
+		};
+		if ret != 0 {
+			return Err(Error::from_raw_os_error(ret));
+		}
+		Ok(Scheduling { policy, priority: sparam.sched_priority })
+	}
+
+	/// Name of the scheduling policy, e.g. "SCHED_RR".
+	pub fn policy_name(&self) -> &'static str {
+		match self.policy {
+			libc::SCHED_OTHER => "SCHED_OTHER",
+			libc::SCHED_FIFO => "SCHED_FIFO",
+			libc::SCHED_RR => "SCHED_RR",
+			libc::SCHED_BATCH => "SCHED_BATCH",
+			libc::SCHED_IDLE => "SCHED_IDLE",
+			_ => "unknown",
+		}
+	}
+
+	/// Whether this is a realtime policy.
+	pub fn realtime(&self) -> bool {
+		matches!(self.policy, libc::SCHED_FIFO | libc::SCHED_RR)
+	}
+}
+
+
+/// Effective limit for locking memory of the process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemlockLimit {
+	/// soft `RLIMIT_MEMLOCK` in bytes
+	Bytes(u64),
+	/// `RLIMIT_MEMLOCK` is infinite, or CAP_IPC_LOCK is permitted,
+	/// which exempts the process from the limit
+	Unlimited,
+}
+
+impl MemlockLimit {
+	/// Get the limit that applies to the current process.
+	pub fn current() -> Result<Self, Box<dyn std::error::Error>> {
+		if caps::has_cap(None, caps::CapSet::Permitted, caps::Capability::CAP_IPC_LOCK)? {
+			return Ok(MemlockLimit::Unlimited);
+		}
+		let (soft, _) = resource::getrlimit(resource::Resource::RLIMIT_MEMLOCK)?;
+		Ok(if soft == resource::RLIM_INFINITY {
+			MemlockLimit::Unlimited
+		} else {
+			MemlockLimit::Bytes(soft)
+		})
+	}
+
+	/// Whether `bytes` may be locked within the limit.
+	pub fn allows(&self, bytes: u64) -> bool {
+		match self {
+			MemlockLimit::Bytes(b) => bytes <= *b,
+			MemlockLimit::Unlimited => true,
+		}
+	}
+}
+
+impl Display for MemlockLimit {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match self {
+			MemlockLimit::Bytes(b) => write!(f, "{b} bytes"),
+			MemlockLimit::Unlimited => write!(f, "unlimited"),
+		}
+	}
+}
+
+
+/// Read a size in bytes from a "kB" line of /proc/self/status, e.g.
+/// "VmSize". `None` if not available.
+pub(crate) fn proc_status_bytes(key: &str) -> Option<u64> {
+	std::fs::read_to_string("/proc/self/status")
+		.ok()?
+		.lines()
+		.find_map(|l| l.strip_prefix(key)?.strip_prefix(':'))
+		.and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+		.map(|kb| kb * 1024)
+}
+
+
+/// Lock all memory currently mapped by the process to prevent
+/// swapping (`mlockall` with `MCL_CURRENT`), if possible, and return
+/// the limit that applied. The kernel refuses to lock more than
+/// [`MemlockLimit`] without CAP_IPC_LOCK, so compare the mapped size
+/// with it first and warn (unless `quiet`) with both numbers instead
+/// of failing on a cryptic error. Within the limit no capability is
+/// needed, if locking fails anyway (the mapped size is only an
+/// estimate) the run goes on unlocked with the same warning.
+pub(crate) fn lock_memory(quiet: bool) -> Result<MemlockLimit, Box<dyn std::error::Error>> {
+	let limit = MemlockLimit::current()?;
+	let mapped = proc_status_bytes("VmSize").unwrap_or(0);
+	if caps::has_cap(None, caps::CapSet::Permitted, caps::Capability::CAP_IPC_LOCK)? {
+		with_capability(
+			|| mman::mlockall(mman::MlockAllFlags::MCL_CURRENT),
+			caps::Capability::CAP_IPC_LOCK)?;
+	} else if limit.allows(mapped) {
+		if let Err(e) = mman::mlockall(mman::MlockAllFlags::MCL_CURRENT)
+			&& !quiet
+		{
+			eprintln!(
+				"cannot lock memory: {e}, {mapped} bytes mapped, locked memory limit is {limit} \
+				 (raise it with \"ulimit -l\" or grant CAP_IPC_LOCK)");
+		}
+	} else if !quiet {
+		eprintln!(
+			"cannot lock memory: {mapped} bytes mapped, locked memory limit is {limit} \
+			 (raise it with \"ulimit -l\" or grant CAP_IPC_LOCK)");
+	}
+	Ok(limit)
+}
+
+
+/// Which clock provided the receive time of a packet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampSource {
+	/// kernel software timestamp, from `SO_TIMESTAMPNS` or the
+	/// software field of `SO_TIMESTAMPING`
+	#[default]
+	Software,
+	/// raw hardware timestamp from the network interface, via
+	/// `SO_TIMESTAMPING`
+	Hardware,
+	/// read from the send clock of the packet right after receiving
+	/// it, for echoes with send timestamps on a clock other than
+	/// `CLOCK_REALTIME`, see [`SendClock`]
+	SendClock,
+	/// time the packet was processed, the kernel did not stamp it
+	/// (possible with `SO_TIMESTAMPING`)
+	Processing,
+}
+
+
+impl Display for TimestampSource {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			TimestampSource::Software => "software",
+			TimestampSource::Hardware => "hardware",
+			TimestampSource::SendClock => "send_clock",
+			TimestampSource::Processing => "processing",
+		})
+	}
+}
+
+
+/// Clock the client paces packets on and reads send timestamps from.
+/// Kernel receive timestamps are always on `CLOCK_REALTIME`, so the
+/// delay from send to receive is only meaningful with realtime send
+/// timestamps, and across hosts only with synchronized clocks.
+/// `CLOCK_MONOTONIC` is not stepped by time synchronization (e.g.
+/// NTP) during a run, but has no meaning outside the sending host.
+/// `CLOCK_TAI` is realtime without leap seconds, offset by the TAI
+/// offset the kernel got from time synchronization (e.g. chrony or
+/// ptp4l), and equal to realtime if none was set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SendClock {
+	/// `CLOCK_REALTIME`, wall clock time
+	#[default]
+	Realtime,
+	/// `CLOCK_MONOTONIC`, time since an unspecified point (usually
+	/// boot) on the sending host
+	Monotonic,
+	/// `CLOCK_TAI`, international atomic time
+	Tai,
+}
+
+
+impl SendClock {
+	/// The clock to read or sleep on.
+	pub fn id(self) -> ClockId {
+		match self {
+			SendClock::Realtime => ClockId::CLOCK_REALTIME,
+			SendClock::Monotonic => ClockId::CLOCK_MONOTONIC,
+			SendClock::Tai => ClockId::CLOCK_TAI,
+		}
+	}
+
+	/// Value of the clock byte in packets, see [`CLOCK_VERSION`].
+	pub fn byte(self) -> u8 {
+		self as u8
+	}
+}
+
+
+/// Parse the clock byte of a packet, fails with the byte if the
+/// clock is unknown.
+impl TryFrom<u8> for SendClock {
+	type Error = u8;
+
+	fn try_from(b: u8) -> Result<Self, Self::Error> {
+		match b {
+			0 => Ok(SendClock::Realtime),
+			1 => Ok(SendClock::Monotonic),
+			2 => Ok(SendClock::Tai),
+			b => Err(b),
+		}
+	}
+}
+
+
+impl Display for SendClock {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		use clap::ValueEnum;
+		f.write_str(self.to_possible_value().unwrap().get_name())
+	}
+}
+
+
+/// Clock names as displayed, e.g. "monotonic".
+impl FromStr for SendClock {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		<Self as clap::ValueEnum>::from_str(s, false)
+	}
+}
+
+
+/// Pick the receive time from the control messages of a received
+/// packet. A non-zero hardware timestamp from `SCM_TIMESTAMPING` is
+/// preferred, otherwise its software timestamp, otherwise the one
+/// from `SCM_TIMESTAMPNS`.
+fn receive_timestamp(
+	cmsgs: impl Iterator<Item = socket::ControlMessageOwned>)
+	-> (Option<(TimeSpec, TimestampSource)>, Option<u8>)
+{
+	let zero = TimeSpec::new(0, 0);
+	let mut legacy = None;
+	let mut software = None;
+	let mut hardware = None;
+	let mut tos = None;
+	for c in cmsgs {
+		match c {
+			socket::ControlMessageOwned::ScmTimestampns(t) => legacy = Some(t),
+			socket::ControlMessageOwned::ScmTimestampsns(t) => {
+				if t.hw_raw != zero {
+					hardware = Some(t.hw_raw);
+				}
+				if t.system != zero {
+					software = Some(t.system);
+				}
+			}
+			socket::ControlMessageOwned::Ipv4Tos(t) => tos = Some(t),
+			socket::ControlMessageOwned::Ipv6TClass(t) => tos = Some(t as u8),
+			_ => (),
+		}
+	}
+	let rtime = hardware.map(|t| (t, TimestampSource::Hardware))
+		.or(software.or(legacy).map(|t| (t, TimestampSource::Software)));
+	(rtime, tos)
+}
+
+
+/// Largest fragment size from the control messages of a received
+/// packet. The kernel reports it only for packets it reassembled
+/// from fragments, if enabled with [`recv_frag_size`]. Nix has no
+/// type for this message, it arrives as an unknown one.
+fn fragment_size(
+	cmsgs: impl Iterator<Item = socket::ControlMessageOwned>)
+	-> Option<usize>
+{
+	cmsgs.filter_map(|c| match c {
+		socket::ControlMessageOwned::Unknown(u) => Some(u),
+		_ => None,
+	}).find(|u| matches!(
+		(u.cmsg_header.cmsg_level, u.cmsg_header.cmsg_type),
+		(libc::IPPROTO_IP, libc::IP_RECVFRAGSIZE)
+			| (libc::IPPROTO_IPV6, libc::IPV6_RECVFRAGSIZE)))
+		.and_then(|u| u.data_bytes.get(..size_of::<libc::c_int>())
+			.map(|b| libc::c_int::from_ne_bytes(b.try_into().unwrap())))
+		.and_then(|v| usize::try_from(v).ok())
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ReceivedPacket {
 	/// where the packet was received from (client on the server side,
 	/// server for echo packets received by the client)
 	pub source: socket::SockaddrStorage,
 	/// time the packet was received
 	pub receive_time: TimeSpec,
+	/// which timestamp `receive_time` was taken from
+	pub receive_time_source: TimestampSource,
 	/// size of the packet
 	pub size: usize,
+	/// length of the header, the payload follows, see
+	/// [`header_length`]
+	pub header_length: usize,
 	/// sequence number recorded in the packet
 	pub sequence: u32,
 	/// send timestamp as recorded in the packet
 	pub timestamp: TimeSpec,
 	/// flags recorded in the packet
-	pub flags: u8,
+	pub flags: Flags,
+	/// echo sequence number stamped by the server, if any
+	pub echo_sequence: Option<u32>,
+	/// IPv4 TOS or IPv6 traffic class byte, if reported
+	pub tos: Option<u8>,
+	/// flow ID recorded in the packet, 0 if none, see [`FLOW_FLAG`]
+	pub flow: u32,
+	/// clock of `timestamp`, see [`CLOCK_VERSION`]
+	pub clock: SendClock,
+	/// size of the largest fragment if the packet arrived fragmented
+	/// and the size was reported, see [`recv_frag_size`]
+	pub fragment_size: Option<usize>,
 }
 
 
@@ -134,58 +1008,597 @@ impl TryFrom<socket::RecvMsg<'_, '_, socket::SockaddrStorage>> for ReceivedPacke
 
 	fn try_from(r: socket::RecvMsg<'_, '_, socket::SockaddrStorage>) -> Result<Self, Self::Error> {
 		let data = r.iovs().next().unwrap();
-		if r.bytes < MIN_SIZE {
-			return Err(Error::new(ErrorKind::InvalidData, "packet too small"));
-		}
+		let header = header_length(&data[..r.bytes])?;
 		let source = r.address
 			.ok_or_else(|| Error::new(ErrorKind::InvalidData, "no source address"))?;
-		let rtime = r.cmsgs()?
-			.filter_map(|c| match c {
-				socket::ControlMessageOwned::ScmTimestampns(t) => Some(t),
-				_ => None
-			})
-			.next()
-			.ok_or_else(|| Error::new(ErrorKind::InvalidData, "no receive time data"))?;
+		let (rtime, tos) = receive_timestamp(r.cmsgs()?);
+		let fragment_size = fragment_size(r.cmsgs()?);
+		// Packets the kernel did not stamp (possible with
+		// SO_TIMESTAMPING) get the time they are processed.
+		let (rtime, receive_time_source) = match rtime {
+			Some(r) => r,
+			None => (
+				clock_gettime(ClockId::CLOCK_REALTIME)?,
+				TimestampSource::Processing),
+		};
 
 		let (seq, rest) = parse_int!(data, u32);
 		let (sec, rest) = parse_int!(rest, i64);
 		let (nsec, rest) = parse_int!(rest, i64);
 		let stamp = TimeSpec::new(sec, nsec);
+		let flags = Flags::from(rest[0]);
+		// optional fields only within the header, anything after
+		// them that this version does not know is skipped
+		let echo_seq = flags.fields_offset();
+		let echo_sequence = if header >= echo_seq + size_of::<u32>()
+			&& flags.contains(ECHO_SEQ_STAMPED)
+		{
+			Some(parse_int!(&data[echo_seq..], u32).0)
+		} else {
+			None
+		};
+		let flow = if header >= echo_seq + 2 * size_of::<u32>() && flags.contains(FLOW_FLAG) {
+			parse_int!(&data[echo_seq + size_of::<u32>()..], u32).0
+		} else {
+			0
+		};
+		let clock = if flags.version() >= CLOCK_VERSION {
+			SendClock::try_from(data[MIN_SIZE + 1]).map_err(|c| Error::new(
+				ErrorKind::InvalidData, format!("unknown clock {c}")))?
+		} else {
+			SendClock::Realtime
+		};
 
 		Ok(ReceivedPacket {
 			source,
 			receive_time: rtime,
+			receive_time_source,
 			size: r.bytes,
+			header_length: header,
 			sequence: seq,
 			timestamp: stamp,
-			flags: rest[0],
+			flags,
+			echo_sequence,
+			tos,
+			flow,
+			clock,
+			fragment_size,
 		})
 	}
 }
 
 
-impl Display for ReceivedPacket {
+/// How to write timestamps in packet logs. Timestamps are on
+/// `CLOCK_REALTIME` unless the packet names another clock (see
+/// [`SendClock`]), then the send timestamp and, in the client, the
+/// echo receive time are on that clock. RFC 3339 only gives wall
+/// clock time for realtime timestamps, monotonic ones come out as
+/// dates shortly after the epoch and TAI ones off by the leap
+/// seconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TimeFormat {
+	/// seconds since the epoch with nanosecond precision
+	#[default]
+	Epoch,
+	/// RFC 3339 date and time in UTC with nanosecond precision
+	Rfc3339,
+}
+
+
+/// Format of packet logs, on standard output as well as in `--output`
+/// files, FIFOs, and sockets (see [`crate::sink`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+	/// tab separated columns after a header line, see [`LogField`]
+	#[default]
+	Tsv,
+	/// one JSON object per packet, see [`ReceivedPacket::to_json`]
+	Json,
+	/// fixed size binary records after [`BINARY_MAGIC`], see
+	/// [`ReceivedPacket::to_binary`], for high packet rates
+	Binary,
+}
+
+
+impl LogFormat {
+	/// Fields of packet logs in this format if not selected
+	/// otherwise: [`JSON_FIELDS`] for [`LogFormat::Json`],
+	/// [`DEFAULT_FIELDS`] for the others (binary records have fixed
+	/// fields anyway).
+	pub fn default_fields(self) -> &'static [LogField] {
+		match self {
+			LogFormat::Json => JSON_FIELDS,
+			LogFormat::Tsv | LogFormat::Binary => DEFAULT_FIELDS,
+		}
+	}
+
+	/// Write the start of a packet log to `out`: the header line of
+	/// [`LogFormat::Tsv`], the magic of [`LogFormat::Binary`].
+	pub fn write_header(self, out: &mut impl Write, fields: &[LogField]) -> io::Result<()> {
+		match self {
+			LogFormat::Tsv => writeln!(out, "{}", ReceivedPacket::header_fields(fields)),
+			LogFormat::Json => Ok(()),
+			LogFormat::Binary => out.write_all(BINARY_MAGIC),
+		}
+	}
+
+	/// Write one packet to `out`. The time format applies only to
+	/// [`LogFormat::Tsv`], fields to it and [`LogFormat::Json`],
+	/// which also includes the label if set.
+	pub fn write_packet(
+		self, out: &mut impl Write, p: &ReceivedPacket, time_format: TimeFormat,
+		fields: &[LogField], label: Option<&str>)
+		-> io::Result<()>
+	{
+		match self {
+			LogFormat::Tsv => writeln!(out, "{}", p.formatted_fields(time_format, fields)),
+			LogFormat::Json => writeln!(out, "{}", p.to_json_fields(fields, label)),
+			LogFormat::Binary => out.write_all(&p.to_binary()?),
+		}
+	}
+
+	/// Flush `out` after a packet if this format is read line by
+	/// line, so readers see each packet as it arrives. Binary
+	/// records stay buffered until the buffer is full or the log
+	/// ends.
+	pub fn flush_packet(self, out: &mut impl Write) -> io::Result<()> {
+		match self {
+			LogFormat::Tsv | LogFormat::Json => out.flush(),
+			LogFormat::Binary => Ok(()),
+		}
+	}
+}
+
+
+/// Start of a binary packet log, the last byte is the version of
+/// the record format.
+pub const BINARY_MAGIC: &[u8; 8] = b"LUNALOG\x01";
+/// Size of a record in a binary packet log.
+pub const BINARY_RECORD_SIZE: usize = 65;
+
+
+/// Reads the records of a binary packet log, see
+/// [`LogFormat::Binary`]. The iterator ends at the end of the input,
+/// a partial record at the end is an error.
+pub struct BinaryLog<R> {
+	input: R,
+}
+
+
+impl<R: Read> BinaryLog<R> {
+	/// Check the magic at the start of `input`, and return a reader
+	/// for the records that follow.
+	pub fn new(mut input: R) -> io::Result<Self> {
+		let mut magic = [0u8; BINARY_MAGIC.len()];
+		input.read_exact(&mut magic)?;
+		if &magic != BINARY_MAGIC {
+			return Err(Error::new(ErrorKind::InvalidData, "not a binary packet log"));
+		}
+		Ok(BinaryLog { input })
+	}
+}
+
+
+impl<R: Read> Iterator for BinaryLog<R> {
+	type Item = io::Result<ReceivedPacket>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let mut record = [0u8; BINARY_RECORD_SIZE];
+		let mut filled = 0;
+		while filled < record.len() {
+			match self.input.read(&mut record[filled..]) {
+				Ok(0) if filled == 0 => return None,
+				Ok(0) => return Some(Err(Error::new(
+					ErrorKind::UnexpectedEof, "partial record at the end of the log"))),
+				Ok(n) => filled += n,
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => return Some(Err(e)),
+			}
+		}
+		Some(ReceivedPacket::from_binary(&record))
+	}
+}
+
+
+/// A timestamp that displays in the given format.
+pub struct Timestamp(pub TimeSpec, pub TimeFormat);
+
+
+impl Display for Timestamp {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let Timestamp(t, format) = self;
+		match format {
+			TimeFormat::Epoch => write!(f, "{}.{:09}", t.tv_sec(), t.tv_nsec()),
+			TimeFormat::Rfc3339 => {
+				let days = t.tv_sec().div_euclid(86400);
+				let secs = t.tv_sec().rem_euclid(86400);
+				// civil date from days since the epoch, see
+				// https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+				let z = days + 719468;
+				let era = z.div_euclid(146097);
+				let doe = z.rem_euclid(146097);
+				let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+				let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+				let mp = (5 * doy + 2) / 153;
+				let day = doy - (153 * mp + 2) / 5 + 1;
+				let month = if mp < 10 { mp + 3 } else { mp - 9 };
+				let year = yoe + era * 400 + i64::from(month <= 2);
+				write!(
+					f, "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:09}Z",
+					secs / 3600, secs / 60 % 60, secs % 60, t.tv_nsec())
+			},
+		}
+	}
+}
+
+
+/// Parse a timestamp in either format, with nanosecond precision as
+/// written to logs.
+impl FromStr for Timestamp {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (secs, nsec) = s.strip_suffix('Z').unwrap_or(s).split_once('.').ok_or(())?;
+		if nsec.len() != 9 {
+			return Err(());
+		}
+		let nsec: i64 = nsec.parse().map_err(|_| ())?;
+		let Some((date, time)) = secs.split_once('T') else {
+			return Ok(Timestamp(
+				TimeSpec::new(secs.parse().map_err(|_| ())?, nsec), TimeFormat::Epoch));
+		};
+		let num = |s: Option<&str>| s.and_then(|s| s.parse::<i64>().ok()).ok_or(());
+		let mut d = date.splitn(3, '-');
+		let (year, month, day) = (num(d.next())?, num(d.next())?, num(d.next())?);
+		let mut t = time.splitn(3, ':');
+		let (hour, min, sec) = (num(t.next())?, num(t.next())?, num(t.next())?);
+		// days since the epoch from civil date, see
+		// https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+		let y = year - i64::from(month <= 2);
+		let era = y.div_euclid(400);
+		let yoe = y.rem_euclid(400);
+		let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+		let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+		let days = era * 146097 + doe - 719468;
+		Ok(Timestamp(
+			TimeSpec::new(days * 86400 + hour * 3600 + min * 60 + sec, nsec),
+			TimeFormat::Rfc3339))
+	}
+}
+
+
+/// Column of the packet log, named as in the header line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogField {
+	/// time the packet was received
+	#[value(name = "receive_time")]
+	ReceiveTime,
+	/// source IP address
+	#[value(name = "source")]
+	Source,
+	/// source port
+	#[value(name = "port")]
+	Port,
+	/// sequence number
+	#[value(name = "sequence")]
+	Sequence,
+	/// send timestamp recorded in the packet
+	#[value(name = "timestamp")]
+	Timestamp,
+	/// packet size in bytes
+	#[value(name = "size")]
+	Size,
+	/// flow ID, 0 if none
+	#[value(name = "flow")]
+	Flow,
+	/// receive time minus send timestamp in seconds: the round trip
+	/// time of echoes, the one way delay (including clock offset) on
+	/// the server, empty if the clocks differ, see
+	/// [`ReceivedPacket::delay`]
+	#[value(name = "delay")]
+	Delay,
+	/// flags byte
+	#[value(name = "flags")]
+	Flags,
+	/// echo sequence number, empty if none
+	#[value(name = "echo_sequence")]
+	EchoSequence,
+	/// TOS or traffic class byte, empty if not reported
+	#[value(name = "tos")]
+	Tos,
+	/// clock of the send timestamp, see [`SendClock`]
+	#[value(name = "clock")]
+	Clock,
+	/// source of the receive time: `software`, `hardware`,
+	/// `send_clock`, or `processing`, see [`TimestampSource`]
+	#[value(name = "receive_time_source")]
+	ReceiveTimeSource,
+}
+
+
+/// Columns of the packet log if not selected otherwise, the log
+/// can be read back with [`ReceivedPacket::from_str`].
+pub const DEFAULT_FIELDS: &[LogField] = &[
+	LogField::ReceiveTime, LogField::Source, LogField::Port, LogField::Sequence,
+	LogField::Timestamp, LogField::Size, LogField::Flow,
+];
+/// Columns of the packet log of a client sending with a clock other
+/// than [`SendClock::Realtime`]: [`DEFAULT_FIELDS`] and the clock, so
+/// logs merged with those of other runs say which clock their
+/// timestamps are from. Can be read back like the default columns.
+pub const CLOCK_FIELDS: &[LogField] = &[
+	LogField::ReceiveTime, LogField::Source, LogField::Port, LogField::Sequence,
+	LogField::Timestamp, LogField::Size, LogField::Flow, LogField::Clock,
+];
+/// Keys of [`LogFormat::Json`] objects if not selected otherwise, see
+/// [`ReceivedPacket::to_json`].
+pub const JSON_FIELDS: &[LogField] = &[
+	LogField::Source, LogField::Port, LogField::ReceiveTime, LogField::Sequence,
+	LogField::Timestamp, LogField::Size, LogField::Flow, LogField::Flags, LogField::Clock,
+	LogField::ReceiveTimeSource,
+];
+
+
+impl Display for LogField {
 	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-		let (ip, port) = if let Some(a) = self.source.as_sockaddr_in6() {
+		use clap::ValueEnum;
+		f.write_str(self.to_possible_value().unwrap().get_name())
+	}
+}
+
+
+/// A packet log line with timestamps in the given format, see
+/// [`ReceivedPacket::formatted`].
+pub struct FormattedPacket<'a>(&'a ReceivedPacket, TimeFormat, &'a [LogField]);
+
+
+impl Display for FormattedPacket<'_> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let FormattedPacket(p, format, fields) = self;
+		let (ip, port) = if let Some(a) = p.source.as_sockaddr_in6() {
 			(format!("{}", a.ip()), a.port())
-		} else { if let Some(a) = self.source.as_sockaddr_in() {
+		} else { if let Some(a) = p.source.as_sockaddr_in() {
 			(format!("{}", a.ip()), a.port())
 		} else {
 			return fmt::Result::Err(fmt::Error::default());
 		}};
-		write!(
-			f, "{}.{:09}\t{}\t{}\t{}\t{}.{:09}\t{}",
-			self.receive_time.tv_sec(), self.receive_time.tv_nsec(),
-			ip, port, self.sequence,
-			self.timestamp.tv_sec(), self.timestamp.tv_nsec(),
-			self.size)
+		for (i, field) in fields.iter().enumerate() {
+			if i > 0 {
+				f.write_str("\t")?;
+			}
+			match field {
+				LogField::ReceiveTime => write!(f, "{}", Timestamp(p.receive_time, *format))?,
+				LogField::Source => write!(f, "{ip}")?,
+				LogField::Port => write!(f, "{port}")?,
+				LogField::Sequence => write!(f, "{}", p.sequence)?,
+				LogField::Timestamp => write!(f, "{}", Timestamp(p.timestamp, *format))?,
+				LogField::Size => write!(f, "{}", p.size)?,
+				LogField::Flow => write!(f, "{}", p.flow)?,
+				LogField::Delay => if let Some(d) = p.delay() {
+					stats::write_secs(f, stats::timespec_nanos(&d))?
+				},
+				LogField::Flags => write!(f, "{}", p.flags)?,
+				LogField::EchoSequence => if let Some(s) = p.echo_sequence {
+					write!(f, "{s}")?
+				},
+				LogField::Tos => if let Some(t) = p.tos {
+					write!(f, "{t}")?
+				},
+				LogField::Clock => write!(f, "{}", p.clock)?,
+				LogField::ReceiveTimeSource => write!(f, "{}", p.receive_time_source)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+
+impl Display for ReceivedPacket {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		self.formatted(TimeFormat::Epoch).fmt(f)
+	}
+}
+
+
+/// Parse a log line as written by [`ReceivedPacket::formatted`], with
+/// timestamps in either [`TimeFormat`], e.g. to analyze captured
+/// files. Fields not included in the log (flags, echo sequence, TOS)
+/// are left empty, the clock is [`SendClock::Realtime`], the header
+/// length is [`MIN_SIZE`], the receive time is assumed to be a
+/// software timestamp. Logs from before the
+/// flow column was added are read as flow 0.
+impl FromStr for ReceivedPacket {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = |what: &str| Error::new(
+			ErrorKind::InvalidData, format!("invalid {what} in log line {s:?}"));
+		let time = |t: &str| t.parse::<Timestamp>().ok().map(|t| t.0);
+		let fields: Vec<&str> = s.split('\t').collect();
+		let (receive_time, ip, port, sequence, timestamp, size, flow, clock) = match fields[..] {
+			[r, i, p, seq, t, size] => (r, i, p, seq, t, size, "0", None),
+			[r, i, p, seq, t, size, flow] => (r, i, p, seq, t, size, flow, None),
+			[r, i, p, seq, t, size, flow, clock] => (r, i, p, seq, t, size, flow, Some(clock)),
+			_ => return Err(invalid("number of fields")),
+		};
+		let clock = clock.map_or(Ok(SendClock::Realtime), str::parse)
+			.map_err(|_| invalid("clock"))?;
+		let ip: IpAddr = ip.parse().map_err(|_| invalid("source address"))?;
+		let port: u16 = port.parse().map_err(|_| invalid("source port"))?;
+		Ok(ReceivedPacket {
+			source: socket::SockaddrStorage::from(SocketAddr::new(ip, port)),
+			receive_time: time(receive_time).ok_or_else(|| invalid("receive time"))?,
+			receive_time_source: TimestampSource::Software,
+			size: size.parse().map_err(|_| invalid("size"))?,
+			header_length: MIN_SIZE,
+			sequence: sequence.parse().map_err(|_| invalid("sequence"))?,
+			timestamp: time(timestamp).ok_or_else(|| invalid("timestamp"))?,
+			flags: Flags::default(),
+			echo_sequence: None,
+			tos: None,
+			flow: flow.parse().map_err(|_| invalid("flow"))?,
+			clock,
+			fragment_size: None,
+		})
 	}
 }
 
 
 impl ReceivedPacket {
 	pub fn header() -> String {
-		String::from("receive_time\tsource\tport\tsequence\ttimestamp\tsize")
+		Self::header_fields(DEFAULT_FIELDS)
+	}
+
+	/// Header line of a log with the given columns.
+	pub fn header_fields(fields: &[LogField]) -> String {
+		fields.iter().map(LogField::to_string).collect::<Vec<_>>().join("\t")
+	}
+
+	/// Log line with timestamps in the given format, the
+	/// [`Display`] implementation uses [`TimeFormat::Epoch`].
+	pub fn formatted(&self, format: TimeFormat) -> FormattedPacket<'_> {
+		FormattedPacket(self, format, DEFAULT_FIELDS)
+	}
+
+	/// Log line with the given columns, in that order, see
+	/// [`ReceivedPacket::formatted`].
+	pub fn formatted_fields<'a>(&'a self, format: TimeFormat, fields: &'a [LogField])
+		-> FormattedPacket<'a>
+	{
+		FormattedPacket(self, format, fields)
+	}
+
+	/// Receive time minus send timestamp, `None` if they are on
+	/// different clocks: a send timestamp on a clock other than
+	/// `CLOCK_REALTIME` can only be compared with a receive time
+	/// read from the same clock ([`TimestampSource::SendClock`]).
+	pub fn delay(&self) -> Option<TimeSpec> {
+		(self.clock == SendClock::Realtime || self.receive_time_source == TimestampSource::SendClock)
+			.then(|| self.receive_time - self.timestamp)
+	}
+
+	/// The packet as a single line JSON object with the
+	/// [`JSON_FIELDS`]: source address and port (`null` if the source
+	/// is neither IPv4 nor IPv6), sequence number, size, flow, flags,
+	/// send clock, and receive time source. Receive time and
+	/// timestamp are integer nanoseconds (since the epoch on
+	/// `CLOCK_REALTIME`), so there is no loss of precision when
+	/// parsed as 64 bit integers.
+	pub fn to_json(&self) -> String {
+		self.to_json_fields(JSON_FIELDS, None)
+	}
+
+	/// The packet as a single line JSON object with the given fields
+	/// as keys, in this order, after the run label if there is one.
+	/// Times and the delay are integer nanoseconds, fields the packet
+	/// does not have (delay, echo sequence number, TOS) are `null`.
+	pub fn to_json_fields(&self, fields: &[LogField], label: Option<&str>) -> String {
+		let source = self.source.as_sockaddr_in6().map(|a| (IpAddr::from(a.ip()), a.port()))
+			.or_else(|| self.source.as_sockaddr_in().map(|a| (IpAddr::from(a.ip()), a.port())));
+		let null = || "null".to_string();
+		let mut members = Vec::with_capacity(fields.len() + 1);
+		if let Some(l) = label {
+			members.push(format!("\"label\": {}", json_string(l)));
+		}
+		for field in fields {
+			let value = match field {
+				LogField::ReceiveTime => stats::timespec_nanos(&self.receive_time).to_string(),
+				LogField::Source => source.map_or_else(null, |(ip, _)| json_string(&ip.to_string())),
+				LogField::Port => source.map_or_else(null, |(_, port)| port.to_string()),
+				LogField::Sequence => self.sequence.to_string(),
+				LogField::Timestamp => stats::timespec_nanos(&self.timestamp).to_string(),
+				LogField::Size => self.size.to_string(),
+				LogField::Flow => self.flow.to_string(),
+				LogField::Delay => self.delay()
+					.map_or_else(null, |d| stats::timespec_nanos(&d).to_string()),
+				LogField::Flags => self.flags.to_string(),
+				LogField::EchoSequence => self.echo_sequence.map_or_else(null, |s| s.to_string()),
+				LogField::Tos => self.tos.map_or_else(null, |t| t.to_string()),
+				LogField::Clock => json_string(&self.clock.to_string()),
+				LogField::ReceiveTimeSource => json_string(&self.receive_time_source.to_string()),
+			};
+			members.push(format!("\"{field}\": {value}"));
+		}
+		format!("{{{}}}", members.join(", "))
+	}
+
+	/// The packet as a record of a binary log, all numbers big
+	/// endian: sequence number (u32), receive time and send
+	/// timestamp (seconds and nanoseconds, i64 each), size and flow
+	/// (u32), flags and clock byte, address family (4 or 6), port
+	/// (u16), and the address (16 bytes, IPv4 in the first 4). Other
+	/// fields are not included, see [`ReceivedPacket::from_binary`].
+	/// The source must be IPv4 or IPv6.
+	pub fn to_binary(&self) -> io::Result<[u8; BINARY_RECORD_SIZE]> {
+		let (family, ip, port) = if let Some(a) = self.source.as_sockaddr_in6() {
+			(6, a.ip().octets(), a.port())
+		} else if let Some(a) = self.source.as_sockaddr_in() {
+			let mut ip = [0u8; 16];
+			ip[..4].copy_from_slice(&a.ip().octets());
+			(4, ip, a.port())
+		} else {
+			return Err(Error::new(ErrorKind::InvalidInput, "source is neither IPv4 nor IPv6"));
+		};
+		let mut b = [0u8; BINARY_RECORD_SIZE];
+		b[0..4].copy_from_slice(&self.sequence.to_be_bytes());
+		b[4..12].copy_from_slice(&self.receive_time.tv_sec().to_be_bytes());
+		b[12..20].copy_from_slice(&self.receive_time.tv_nsec().to_be_bytes());
+		b[20..28].copy_from_slice(&self.timestamp.tv_sec().to_be_bytes());
+		b[28..36].copy_from_slice(&self.timestamp.tv_nsec().to_be_bytes());
+		b[36..40].copy_from_slice(&(self.size as u32).to_be_bytes());
+		b[40..44].copy_from_slice(&self.flow.to_be_bytes());
+		b[44] = self.flags.bits();
+		b[45] = self.clock.byte();
+		b[46] = family;
+		b[47..49].copy_from_slice(&port.to_be_bytes());
+		b[49..65].copy_from_slice(&ip);
+		Ok(b)
+	}
+
+	/// Parse a record written by [`ReceivedPacket::to_binary`]. The
+	/// header length is derived from flags and size, echo sequence
+	/// number and TOS are left empty, the receive time is assumed
+	/// to be a software timestamp.
+	pub fn from_binary(b: &[u8; BINARY_RECORD_SIZE]) -> io::Result<Self> {
+		let invalid = |what: &str| Error::new(
+			ErrorKind::InvalidData, format!("invalid {what} in binary log record"));
+		let u32_at = |i: usize| u32::from_be_bytes(b[i..i + 4].try_into().unwrap());
+		let i64_at = |i: usize| i64::from_be_bytes(b[i..i + 8].try_into().unwrap());
+		let port = u16::from_be_bytes([b[47], b[48]]);
+		let ip: [u8; 16] = b[49..65].try_into().unwrap();
+		let ip = match b[46] {
+			4 => IpAddr::from(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+			6 => IpAddr::from(Ipv6Addr::from(ip)),
+			_ => return Err(invalid("address family")),
+		};
+		let size = u32_at(36) as usize;
+		let flags = Flags::from(b[44]);
+		Ok(ReceivedPacket {
+			source: socket::SockaddrStorage::from(SocketAddr::new(ip, port)),
+			receive_time: TimeSpec::new(i64_at(4), i64_at(12)),
+			receive_time_source: TimestampSource::Software,
+			size,
+			header_length: flags.header_length(size),
+			sequence: u32_at(0),
+			timestamp: TimeSpec::new(i64_at(20), i64_at(28)),
+			flags,
+			echo_sequence: None,
+			tos: None,
+			flow: u32_at(40),
+			clock: SendClock::try_from(b[45]).map_err(|_| invalid("clock"))?,
+			fragment_size: None,
+		})
+	}
+
+	/// ECN codepoint of the packet, if the TOS byte was reported.
+	pub fn ecn(&self) -> Option<Ecn> {
+		self.tos.map(Ecn::from)
+	}
+
+	/// DSCP of the packet (upper six bits of the TOS byte), if the
+	/// TOS byte was reported.
+	pub fn dscp(&self) -> Option<u8> {
+		self.tos.map(|t| t >> 2)
 	}
 }
 
@@ -210,12 +1623,29 @@ mod tests {
 	/// packets.
 	#[test]
 	fn full() -> Result<(), Box<dyn std::error::Error>> {
+		full_cycle(client::Options::default())
+	}
+
+	/// Same as [`full`], but receive echoes in the send loop.
+	#[test]
+	fn full_sync_echo() -> Result<(), Box<dyn std::error::Error>> {
+		full_cycle(client::Options { sync_echo: true, ..Default::default() })
+	}
+
+	/// Same as [`full`], but with packets in format version 2.
+	#[test]
+	fn full_header_length() -> Result<(), Box<dyn std::error::Error>> {
+		full_cycle(client::Options { header_length: true, ..Default::default() })
+	}
+
+	fn full_cycle(options: client::Options) -> Result<(), Box<dyn std::error::Error>> {
 		let buf_size = 32;
 		// address with 0 port to make the server pick a free one
 		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
 		let (server_log_sender, server_logger) = mpsc::channel();
 		let mut srv = server::Server::new(
-			bind_addr, buf_size, Some(server_log_sender));
+			bind_addr, buf_size, Some(server_log_sender),
+			server::Options::default());
 		let server_handle = srv.bind()?;
 		// address the server is *actually* bound to
 		let bind_addr = srv.bound().unwrap().clone();
@@ -235,7 +1665,8 @@ mod tests {
 			client::run(
 				server_addr, buf_size,
 				true, receiver,
-				Some(Duration::from_millis(50)), Some(client_log_sender)
+				Some(client::EchoWait::Fixed(Duration::from_millis(50))), Some(client_log_sender),
+				options
 			).map_err(|e| e.to_string())
 		});
 
@@ -273,6 +1704,523 @@ mod tests {
 		Ok(())
 	}
 
+	#[test]
+	fn json_escape() {
+		assert_eq!(json_string("run 1"), r#""run 1""#);
+		assert_eq!(
+			json_string("a \"quoted\"\tlabel\\\n"),
+			r#""a \"quoted\"\tlabel\\\n""#);
+		assert_eq!(json_string("\u{1}ü"), r#""\u0001ü""#);
+	}
+
+	#[test]
+	fn ecn() {
+		assert_eq!(Ecn::from(0xb8), Ecn::NotEct);
+		assert_eq!(Ecn::from(0xb9), Ecn::Ect1);
+		assert_eq!(Ecn::from(0x02), Ecn::Ect0);
+		assert_eq!(Ecn::from(0xbb), Ecn::Ce);
+		assert_eq!(Ecn::Ect0 as u8, 2);
+	}
+
+	#[test]
+	fn dscp() -> Result<(), Box<dyn std::error::Error>> {
+		for (s, value, name) in [
+			("46", 46, "EF"), ("ef", 46, "EF"), ("AF41", 34, "AF41"), ("af13", 14, "AF13"),
+			("CS1", 8, "CS1"), ("cs7", 56, "CS7"), ("0", 0, "CS0"), ("DF", 0, "CS0"),
+			("VA", 44, "VA"), ("le", 1, "LE"), ("63", 63, "63"),
+		] {
+			let d: Dscp = s.parse()?;
+			assert_eq!((d.value(), d.to_string().as_str()), (value, name), "{s}");
+		}
+		for invalid in ["64", "-1", "AF51", "AF14", "AF1", "CS8", "CS", "BE", ""] {
+			assert!(invalid.parse::<Dscp>().is_err(), "{invalid}");
+		}
+		assert_eq!(Dscp::new(64), None);
+		assert_eq!("EF".parse::<Dscp>()?.tos(Ecn::Ect0), 0xba);
+
+		// the socket reports what was set
+		let ef = "EF".parse::<Dscp>()?.tos(Ecn::NotEct);
+		let v6 = std::net::UdpSocket::bind("[::1]:0")?;
+		set_tos(&v6, Some(socket::AddressFamily::Inet6), ef)?;
+		assert_eq!(socket::getsockopt(&v6, socket::sockopt::Ipv6TClass)?, 0xb8);
+		let v4 = std::net::UdpSocket::bind("127.0.0.1:0")?;
+		set_tos(&v4, Some(socket::AddressFamily::Inet), ef)?;
+		assert_eq!(socket::getsockopt(&v4, socket::sockopt::Ipv4Tos)?, 0xb8);
+		Ok(())
+	}
+
+	#[test]
+	fn receive_timestamp_cmsgs() {
+		use socket::{ControlMessageOwned, Timestamps};
+		let zero = TimeSpec::new(0, 0);
+		let legacy = ControlMessageOwned::ScmTimestampns(TimeSpec::new(5, 1));
+		let timestamping = |system, hw_raw| ControlMessageOwned::ScmTimestampsns(
+			Timestamps { system, hw_trans: zero, hw_raw });
+
+		assert_eq!(
+			receive_timestamp([legacy.clone(), ControlMessageOwned::Ipv4Tos(0xb9)].into_iter()),
+			(Some((TimeSpec::new(5, 1), TimestampSource::Software)), Some(0xb9)));
+		assert_eq!(
+			receive_timestamp([timestamping(TimeSpec::new(6, 2), zero)].into_iter()).0,
+			Some((TimeSpec::new(6, 2), TimestampSource::Software)));
+		assert_eq!(
+			receive_timestamp([legacy.clone(), timestamping(TimeSpec::new(6, 2), TimeSpec::new(6, 1))].into_iter()).0,
+			Some((TimeSpec::new(6, 1), TimestampSource::Hardware)));
+		// an empty SCM_TIMESTAMPING falls back to SCM_TIMESTAMPNS
+		assert_eq!(
+			receive_timestamp([timestamping(zero, zero), legacy].into_iter()).0,
+			Some((TimeSpec::new(5, 1), TimestampSource::Software)));
+		assert_eq!(receive_timestamp(std::iter::empty()), (None, None));
+	}
+
+	#[test]
+	fn fragment_size_cmsgs() {
+		use socket::{ControlMessageOwned, UnknownCmsg};
+		let unknown = |level, kind, value: libc::c_int| {
+			let mut cmsg_header: libc::cmsghdr = unsafe { std::mem::zeroed() };
+			cmsg_header.cmsg_level = level;
+			cmsg_header.cmsg_type = kind;
+			ControlMessageOwned::Unknown(UnknownCmsg {
+				cmsg_header, data_bytes: value.to_ne_bytes().to_vec() })
+		};
+		let tos = ControlMessageOwned::Ipv6TClass(0);
+
+		assert_eq!(
+			fragment_size([
+				tos.clone(),
+				unknown(libc::IPPROTO_IPV6, libc::IPV6_RECVFRAGSIZE, 1232),
+			].into_iter()),
+			Some(1232));
+		assert_eq!(
+			fragment_size([unknown(libc::IPPROTO_IP, libc::IP_RECVFRAGSIZE, 1480)].into_iter()),
+			Some(1480));
+		// same type number on another level is something else
+		assert_eq!(
+			fragment_size([unknown(libc::IPPROTO_IP, libc::IPV6_RECVFRAGSIZE, 1)].into_iter()),
+			None);
+		assert_eq!(fragment_size([tos].into_iter()), None);
+	}
+
+	#[test]
+	fn recv_frag_size_option() -> Result<(), Errno> {
+		for family in [socket::AddressFamily::Inet6, socket::AddressFamily::Inet] {
+			let sock = socket::socket(
+				family, socket::SockType::Datagram, socket::SockFlag::empty(), None)?;
+			recv_frag_size(&sock, Some(family))?;
+		}
+		Ok(())
+	}
+
+	/// A buffer sized for all enabled control messages receives all
+	/// of them, one with room for the timestamp only gets truncated,
+	/// and the packet cannot be parsed. Loopback does not fragment,
+	/// so only the room for the fragment size is checked, see
+	/// `fragment_size_cmsgs` for parsing.
+	#[test]
+	fn control_messages() -> Result<(), Box<dyn std::error::Error>> {
+		let family = Some(socket::AddressFamily::Inet6);
+		let receiver = std::net::UdpSocket::bind("[::1]:0")?;
+		recv_timestamps(&receiver, true)?;
+		recv_tos(&receiver, family)?;
+		recv_frag_size(&receiver, family)?;
+		let sender = std::net::UdpSocket::bind("[::1]:0")?;
+		socket::setsockopt(&sender, socket::sockopt::Ipv6TClass, &0x22)?;
+		// ignore strays from other tests
+		receiver.connect(sender.local_addr()?)?;
+
+		let cmsgs = ControlMessages { hardware_timestamps: true, tos: true, frag_size: true };
+		let int = cmsg_space!(libc::c_int).len();
+		assert_eq!(cmsgs.space(), cmsg_space!([TimeSpec; 3]).len() + 2 * int);
+		assert_eq!(ControlMessages::default().space(), cmsg_space!(TimeSpec).len());
+
+		let mut packet = [0u8; MIN_SIZE];
+		packet[20] = Flags::new(1).bits();
+		let mut buf = [0u8; MIN_SIZE];
+		let mut receive = |space: &mut Vec<u8>| -> Result<_, Box<dyn std::error::Error>> {
+			sender.send_to(&packet, receiver.local_addr()?)?;
+			let mut iov = [std::io::IoSliceMut::new(&mut buf)];
+			let r = socket::recvmsg::<SockaddrStorage>(
+				receiver.as_raw_fd(), &mut iov, Some(space), socket::MsgFlags::empty())?;
+			let stamped = r.cmsgs().is_ok_and(|c| receive_timestamp(c).0.is_some());
+			Ok((r.flags, stamped, ReceivedPacket::try_from(r)))
+		};
+		// The kernel switches on timestamping asynchronously if no
+		// other socket has it enabled, wait for the first stamp.
+		// Packets before that get the time they are parsed.
+		for _ in 0..100 {
+			let (_, stamped, p) = receive(&mut cmsgs.buffer())?;
+			assert_eq!(
+				p?.receive_time_source,
+				if stamped { TimestampSource::Software } else { TimestampSource::Processing });
+			if stamped {
+				break;
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		for (mut space, complete) in [(cmsgs.buffer(), true), (cmsg_space!([TimeSpec; 3]), false)] {
+			let (flags, _, p) = receive(&mut space)?;
+			assert_eq!(flags.contains(socket::MsgFlags::MSG_CTRUNC), !complete);
+			let p = match p {
+				Ok(p) => p,
+				Err(e) => {
+					assert!(!complete, "{e}");
+					continue;
+				},
+			};
+			assert_eq!(p.receive_time_source, TimestampSource::Software);
+			assert!(p.receive_time.tv_sec() > 0);
+			assert_eq!(p.tos, Some(0x22), "{p:?}");
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn socket_queues() -> Result<(), Box<dyn std::error::Error>> {
+		let receiver = std::net::UdpSocket::bind("[::1]:0")?;
+		let sender = std::net::UdpSocket::bind("[::1]:0")?;
+		let empty = SocketQueues::read(&receiver)?;
+		assert_eq!((empty.send, empty.receive), (0, 0));
+		assert!(empty.receive_buffer > 0);
+		for _ in 0..3 {
+			sender.send_to(&[0u8; 100], receiver.local_addr()?)?;
+		}
+		// packets stay queued until read, with overhead
+		let queued = SocketQueues::read(&receiver)?;
+		assert!(queued.receive > 300, "{queued:?}");
+		let mut buf = [0u8; 100];
+		for _ in 0..3 {
+			receiver.recv(&mut buf)?;
+		}
+		assert_eq!(SocketQueues::read(&receiver)?.receive, 0);
+		Ok(())
+	}
+
+	#[test]
+	fn flags() {
+		let base = Flags::new(PROTOCOL_VERSION);
+		assert_eq!(base.version(), PROTOCOL_VERSION);
+		assert_eq!(base.bits(), 0x30);
+		let defined = [ECHO_FLAG, ECHO_SEQ_FLAG, ECHO_SEQ_STAMPED, FLOW_FLAG];
+		for flag in defined {
+			assert!(!base.contains(flag));
+			let mut f = base;
+			f.set(flag);
+			assert_eq!(f, base.with(flag));
+			// round trip through the packet byte
+			let parsed = Flags::from(f.bits());
+			assert_eq!(parsed.version(), PROTOCOL_VERSION);
+			assert_eq!(parsed.unknown(), 0);
+			// flags do not overlap
+			for other in defined {
+				assert_eq!(parsed.contains(other), other == flag);
+			}
+		}
+		let all = base.with(ECHO_FLAG | ECHO_SEQ_FLAG | ECHO_SEQ_STAMPED | FLOW_FLAG);
+		assert_eq!(all.version(), PROTOCOL_VERSION);
+		assert_eq!(all.unknown(), 0);
+		assert_eq!(format!("{all}"), "63");
+		assert_eq!(Flags::keepalive().bits(), 0xf0);
+		assert!(Flags::from(0xf0).is_keepalive());
+		assert!(!base.is_keepalive() && !all.is_keepalive());
+		// the original format only knows the echo flag
+		assert_eq!(Flags::new(0).with(ECHO_FLAG).unknown(), 0);
+		assert_eq!(Flags::new(0).with(ECHO_FLAG | FLOW_FLAG).unknown(), FLOW_FLAG);
+	}
+
+	#[test]
+	fn header_lengths() {
+		let v1 = Flags::new(1).with(ECHO_FLAG | ECHO_SEQ_FLAG | FLOW_FLAG);
+		let v2 = Flags::new(HEADER_LENGTH_VERSION).with(ECHO_FLAG | ECHO_SEQ_FLAG | FLOW_FLAG);
+		assert_eq!((v1.fields_offset(), v2.fields_offset()), (MIN_SIZE, MIN_SIZE + 1));
+		// fields are included as far as they fit
+		for (size, v1_len, v2_len) in [
+			(MIN_SIZE, MIN_SIZE, MIN_SIZE),
+			(ECHO_SEQ_SIZE, ECHO_SEQ_SIZE, MIN_SIZE + 1),
+			(FLOW_SIZE, FLOW_SIZE, ECHO_SEQ_SIZE + 1),
+			(64, FLOW_SIZE, FLOW_SIZE + 1)]
+		{
+			assert_eq!((v1.header_length(size), v2.header_length(size)), (v1_len, v2_len));
+		}
+		assert_eq!(Flags::new(HEADER_LENGTH_VERSION).header_length(64), MIN_SIZE + 1);
+		assert_eq!(Flags::new(0).with(ECHO_FLAG).header_length(64), MIN_SIZE);
+
+		let mut packet = [0u8; 64];
+		packet[20] = v1.bits();
+		assert_eq!(header_length(&packet).unwrap(), FLOW_SIZE);
+		assert_eq!(header_length(&packet[..MIN_SIZE - 1]).unwrap_err().kind(), ErrorKind::InvalidData);
+		// version 2 takes the length from the packet, which may
+		// include fields unknown to this version
+		packet[20] = v2.bits();
+		for length in [MIN_SIZE + 1, FLOW_SIZE + 1, 40, 64] {
+			packet[MIN_SIZE] = length as u8;
+			assert_eq!(header_length(&packet).unwrap(), length);
+		}
+		// no room for the length field, the base header only
+		assert_eq!(header_length(&packet[..MIN_SIZE]).unwrap(), MIN_SIZE);
+		for length in [0, MIN_SIZE, 65] {
+			packet[MIN_SIZE] = length as u8;
+			assert_eq!(header_length(&packet).unwrap_err().kind(), ErrorKind::InvalidData);
+		}
+		// version 3 adds the clock byte, and needs room for it
+		let v3 = Flags::new(CLOCK_VERSION).with(ECHO_SEQ_FLAG);
+		assert_eq!(v3.fields_offset(), CLOCK_SIZE);
+		assert_eq!(v3.header_length(64), CLOCK_SIZE + 4);
+		packet[20] = v3.bits();
+		packet[MIN_SIZE] = CLOCK_SIZE as u8;
+		assert_eq!(header_length(&packet).unwrap(), CLOCK_SIZE);
+		assert_eq!(header_length(&packet[..CLOCK_SIZE]).unwrap(), CLOCK_SIZE);
+		packet[MIN_SIZE] = (MIN_SIZE + 1) as u8;
+		assert_eq!(header_length(&packet).unwrap_err().kind(), ErrorKind::InvalidData);
+		assert_eq!(header_length(&packet[..MIN_SIZE]).unwrap_err().kind(), ErrorKind::InvalidData);
+	}
+
+	/// Packets with headers of different length are parsed with
+	/// the known fields, payload after the header is not mistaken for
+	/// them.
+	#[test]
+	fn parse_header_length() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let (sender, receiver) = mpsc::channel();
+		let mut srv = server::Server::new(bind_addr, 64, Some(sender), server::Options::default());
+		let handle = srv.bind()?;
+		let target = srv.bound().unwrap().as_sockaddr_in6().unwrap().to_string();
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		let sock = std::net::UdpSocket::bind("[::1]:0")?;
+		let flags = Flags::new(HEADER_LENGTH_VERSION).with(FLOW_FLAG);
+		let mut packet = [0xaau8; 48];
+		packet[..4].fill(0);
+		packet[20] = flags.bits();
+		packet[MIN_SIZE + 5..MIN_SIZE + 9].copy_from_slice(&7u32.to_be_bytes());
+		// header with flow ID, then with an unknown extra field, then
+		// without room for the flow ID
+		for (seq, header, flow) in [(0u8, FLOW_SIZE + 1, 7), (1, 40, 7), (2, ECHO_SEQ_SIZE + 1, 0)] {
+			packet[3] = seq;
+			packet[MIN_SIZE] = header as u8;
+			sock.send_to(&packet, &target)?;
+			let r = receiver.recv()?;
+			assert_eq!((r.sequence, r.header_length, r.flow), (seq.into(), header, flow));
+			assert_eq!(r.echo_sequence, None);
+		}
+		// invalid header length, not logged
+		packet[3] = 3;
+		packet[MIN_SIZE] = 49;
+		sock.send_to(&packet, &target)?;
+		packet[3] = 4;
+		packet[MIN_SIZE] = MIN_SIZE as u8 + 1;
+		sock.send_to(&packet, &target)?;
+		assert_eq!(receiver.recv()?.sequence, 4);
+
+		handle.close()?;
+		sh.join().unwrap()?;
+		Ok(())
+	}
+
+	#[test]
+	fn parse_log_line() -> Result<(), Error> {
+		let line = "10.000000500\t::1\t7800\t42\t10.000000001\t64\t3";
+		let p: ReceivedPacket = line.parse()?;
+		assert_eq!(p.receive_time, TimeSpec::new(10, 500));
+		assert_eq!(
+			p.source,
+			socket::SockaddrStorage::from("[::1]:7800".parse::<SocketAddrV6>().unwrap()));
+		assert_eq!(p.sequence, 42);
+		assert_eq!(p.timestamp, TimeSpec::new(10, 1));
+		assert_eq!(p.size, 64);
+		assert_eq!(p.flow, 3);
+		assert_eq!(format!("{p}"), line);
+
+		// without flow column
+		let p: ReceivedPacket = "1.000000000\t192.0.2.1\t7800\t0\t0.999999999\t21".parse()?;
+		assert!(p.source.as_sockaddr_in().is_some());
+		assert_eq!(p.flow, 0);
+		assert_eq!(p.clock, SendClock::Realtime);
+
+		// with clock column
+		let clock_line = format!("{line}\tmonotonic");
+		let p: ReceivedPacket = clock_line.parse()?;
+		assert_eq!(p.clock, SendClock::Monotonic);
+		assert_eq!(p.formatted_fields(TimeFormat::Epoch, CLOCK_FIELDS).to_string(), clock_line);
+		for invalid in [
+			"", "1.000000000\t::1\t7800\t0\t1.5\t21", "1.0\t::1\t7800\t0\t1.0",
+			"1\t::1\t7800\t0\t1.0\t21", "1.0\t::1\t7800\t0\t1.0\t21\tx",
+			"1.0\t::1\t7800\t0\t1.0\t21\t0\tsundial",
+		] {
+			assert_eq!(
+				invalid.parse::<ReceivedPacket>().unwrap_err().kind(),
+				ErrorKind::InvalidData);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn binary_log() -> Result<(), Error> {
+		let mut v6: ReceivedPacket = "10.000000500\t2001:db8::1\t7800\t42\t-1.999999999\t64\t3".parse()?;
+		v6.flags = Flags::new(CLOCK_VERSION).with(ECHO_FLAG | FLOW_FLAG);
+		v6.clock = SendClock::Monotonic;
+		v6.header_length = v6.flags.header_length(v6.size);
+		let v4: ReceivedPacket = "1.000000000\t192.0.2.1\t9\t4294967295\t0.999999999\t21".parse()?;
+		let mut log = BINARY_MAGIC.to_vec();
+		for p in [&v6, &v4] {
+			log.extend_from_slice(&p.to_binary()?);
+		}
+		assert_eq!(log.len(), BINARY_MAGIC.len() + 2 * BINARY_RECORD_SIZE);
+		let read = BinaryLog::new(&log[..])?.collect::<Result<Vec<_>, _>>()?;
+		assert_eq!(read.len(), 2);
+		for (r, p) in read.iter().zip([&v6, &v4]) {
+			assert_eq!(r.to_json(), p.to_json());
+			assert_eq!(r.header_length, p.header_length);
+			assert_eq!(r.to_string(), p.to_string());
+		}
+
+		// a partial record at the end is an error, after the
+		// complete ones
+		let mut records = BinaryLog::new(&log[..log.len() - 1])?;
+		assert!(records.next().unwrap().is_ok());
+		assert_eq!(records.next().unwrap().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+		assert_eq!(BinaryLog::new(&b"receive_time\t"[..]).err().unwrap().kind(), ErrorKind::InvalidData);
+		log[BINARY_MAGIC.len() + 46] = 5;
+		assert_eq!(
+			BinaryLog::new(&log[..])?.next().unwrap().unwrap_err().kind(),
+			ErrorKind::InvalidData);
+		Ok(())
+	}
+
+	#[test]
+	fn parse_payload_sequence() -> Result<(), Error> {
+		let mut packet = [0u8; FLOW_SIZE + 4];
+		packet[..4].copy_from_slice(&9u32.to_be_bytes());
+		packet[MIN_SIZE..MIN_SIZE + 4].copy_from_slice(&9u32.to_be_bytes());
+		packet[20] = Flags::new(1).bits();
+		assert_eq!(payload_sequence(&packet)?, Some(9));
+		// after the flow ID
+		packet[20] = Flags::new(1).with(FLOW_FLAG).bits();
+		packet[FLOW_SIZE..].copy_from_slice(&10u32.to_be_bytes());
+		assert_eq!(payload_sequence(&packet)?, Some(10));
+		assert_eq!(payload_sequence(&packet[..FLOW_SIZE + 3])?, None);
+		assert_eq!(payload_sequence(&packet[..MIN_SIZE])?, None);
+		assert!(payload_sequence(&packet[..MIN_SIZE - 1]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn log_fields() -> Result<(), Error> {
+		use clap::ValueEnum;
+		let mut p: ReceivedPacket = "10.000000500\t::1\t7800\t42\t10.000000001\t64\t3".parse()?;
+		let fields = [
+			LogField::Sequence, LogField::ReceiveTime, LogField::Delay, LogField::Size,
+			LogField::EchoSequence, LogField::Tos,
+		];
+		assert_eq!(
+			ReceivedPacket::header_fields(&fields),
+			"sequence\treceive_time\tdelay\tsize\techo_sequence\ttos");
+		assert_eq!(
+			p.formatted_fields(TimeFormat::Epoch, &fields).to_string(),
+			"42\t10.000000500\t0.000000499\t64\t\t");
+		p.echo_sequence = Some(7);
+		p.tos = Some(2);
+		assert_eq!(
+			p.formatted_fields(TimeFormat::Rfc3339, &fields[1..]).to_string(),
+			"1970-01-01T00:00:10.000000500Z\t0.000000499\t64\t7\t2");
+		// the defaults are the columns the log parser reads
+		assert_eq!(ReceivedPacket::header_fields(DEFAULT_FIELDS), ReceivedPacket::header());
+		assert_eq!(
+			LogField::value_variants().iter().map(LogField::to_string).collect::<Vec<_>>(),
+			["receive_time", "source", "port", "sequence", "timestamp", "size", "flow",
+			 "delay", "flags", "echo_sequence", "tos", "clock", "receive_time_source"]);
+		Ok(())
+	}
+
+	#[test]
+	fn packet_json() -> Result<(), Error> {
+		let mut p: ReceivedPacket = "10.000000500\t192.0.2.1\t7800\t42\t10.000000001\t64\t3".parse()?;
+		p.flags = Flags::from(0x11);
+		assert_eq!(
+			p.to_json(),
+			concat!(
+				r#"{"source": "192.0.2.1", "port": 7800, "receive_time": 10000000500, "#,
+				r#""sequence": 42, "timestamp": 10000000001, "size": 64, "flow": 3, "flags": 17, "#,
+				r#""clock": "realtime", "receive_time_source": "software"}"#));
+
+		// selected fields in the given order, after the label
+		p.tos = Some(2);
+		let fields = [LogField::Sequence, LogField::Delay, LogField::EchoSequence, LogField::Tos];
+		assert_eq!(
+			p.to_json_fields(&fields, Some("run \"1\"")),
+			r#"{"label": "run \"1\"", "sequence": 42, "delay": 499, "echo_sequence": null, "tos": 2}"#);
+		p.clock = SendClock::Monotonic;
+		assert_eq!(p.to_json_fields(&fields[..2], None), r#"{"sequence": 42, "delay": null}"#);
+		assert_eq!(LogFormat::Json.default_fields(), JSON_FIELDS);
+		assert_eq!(LogFormat::Tsv.default_fields(), DEFAULT_FIELDS);
+		Ok(())
+	}
+
+	#[test]
+	fn time_format() {
+		let t = |sec, nsec| format!("{}", Timestamp(TimeSpec::new(sec, nsec), TimeFormat::Rfc3339));
+		assert_eq!(t(0, 0), "1970-01-01T00:00:00.000000000Z");
+		assert_eq!(t(951_782_400, 5), "2000-02-29T00:00:00.000000005Z");
+		assert_eq!(t(1_792_039_309, 423_759_766), "2026-10-15T04:41:49.423759766Z");
+		assert_eq!(t(-1, 0), "1969-12-31T23:59:59.000000000Z");
+		for (sec, nsec) in [(0, 0), (951_782_400, 5), (1_792_039_309, 423_759_766), (-1, 0)] {
+			let parsed: Timestamp = t(sec, nsec).parse().unwrap();
+			assert_eq!(parsed.0, TimeSpec::new(sec, nsec));
+		}
+		assert!("2026-10-15T04:41:49.4Z".parse::<Timestamp>().is_err());
+		assert_eq!(
+			format!("{}", Timestamp(TimeSpec::new(12, 34), TimeFormat::Epoch)),
+			"12.000000034");
+	}
+
+	#[test]
+	fn hex() {
+		let data: Vec<u8> = (0x3eu8..0x54).collect();
+		assert_eq!(
+			hexdump(&data, 64),
+			concat!(
+				"00000000  3e 3f 40 41 42 43 44 45  46 47 48 49 4a 4b 4c 4d  |>?@ABCDEFGHIJKLM|\n",
+				"00000010  4e 4f 50 51 52 53                                 |NOPQRS|\n"));
+		assert_eq!(
+			hexdump(&[0, 0x7f, b'a', 0xff], 3),
+			concat!(
+				"00000000  00 7f 61                                          |..a|\n",
+				"(1 more bytes)\n"));
+		assert_eq!(hexdump(&[], 16), "");
+	}
+
+	#[test]
+	fn scheduling() -> Result<(), Error> {
+		// a new thread has the default policy unless the test runner
+		// was started with realtime priority
+		let s = thread::spawn(Scheduling::current).join().unwrap()?;
+		assert_eq!(s.policy_name(), "SCHED_OTHER");
+		assert!(!s.realtime());
+		assert_eq!(s.priority, 0);
+		Ok(())
+	}
+
+	#[test]
+	fn memlock_limit() -> Result<(), Box<dyn std::error::Error>> {
+		let (soft, _) = resource::getrlimit(resource::Resource::RLIMIT_MEMLOCK)?;
+		let limit = MemlockLimit::current()?;
+		if caps::has_cap(None, caps::CapSet::Permitted, caps::Capability::CAP_IPC_LOCK)?
+			|| soft == resource::RLIM_INFINITY
+		{
+			assert_eq!(limit, MemlockLimit::Unlimited);
+		} else {
+			assert_eq!(limit, MemlockLimit::Bytes(soft));
+		}
+		assert!(MemlockLimit::Bytes(4096).allows(4096));
+		assert!(!MemlockLimit::Bytes(4096).allows(4097));
+		assert!(MemlockLimit::Unlimited.allows(u64::MAX));
+		assert_eq!(MemlockLimit::Bytes(65536).to_string(), "65536 bytes");
+		assert_eq!(MemlockLimit::Unlimited.to_string(), "unlimited");
+
+		assert!(proc_status_bytes("VmSize").is_some_and(|b| b > 0 && b % 1024 == 0));
+		assert_eq!(proc_status_bytes("VmNone"), None);
+		Ok(())
+	}
+
 	#[test]
 	fn rt_priority() {
 		let offset = 5;
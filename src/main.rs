@@ -1,14 +1,82 @@
-use luna_rs::{client, generator::Generator, server};
+use luna_rs::{
+	addr, calibrate, client, generator::{self, Generator}, server, sink, stats, LogField, LogFormat,
+	BinaryLog, ReceivedPacket, SendClock, SentPacket, TimeFormat, BINARY_MAGIC, CLOCK_FIELDS, DEFAULT_FIELDS
+};
 use clap::{Parser, Subcommand};
-use nix::sys::{signal, socket::SockaddrStorage};
+use nix::sys::signal;
 use std::{
 	collections::HashMap,
-	net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
-	sync::OnceLock,
+	fs::{self, File},
+	io::{self, BufReader, BufWriter, Read, Write},
+	net::SocketAddr,
+	num::NonZeroUsize,
+	path::{Path, PathBuf},
+	process::ExitCode,
+	sync::{atomic::AtomicBool, mpsc, Arc},
+	thread,
 	time::Duration,
 };
 #[cfg(feature = "python")]
-use std::{ffi::CString, fs, path::PathBuf};
+use std::ffi::CString;
+
+
+/// Parse a (fractional) number of seconds into a Duration.
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+	s.parse::<f64>()
+		.map_err(|e| e.to_string())
+		.and_then(|s| Duration::try_from_secs_f64(s).map_err(|e| e.to_string()))
+}
+
+
+/// Parse a (fractional) number of milliseconds into a Duration.
+fn parse_millis(s: &str) -> Result<Duration, String> {
+	s.parse::<f64>()
+		.map_err(|e| e.to_string())
+		.and_then(|ms| Duration::try_from_secs_f64(ms / 1000.0).map_err(|e| e.to_string()))
+}
+
+
+/// Exit status if the client run completed, but a limit set with
+/// `--fail-if-loss` or `--fail-if-rtt-p99` was exceeded. Other errors
+/// exit with 1, invalid arguments with 2.
+const EXIT_THRESHOLD: u8 = 3;
+
+
+/// Parse a size in bytes, with optional binary K, M, or G suffix.
+fn parse_size(s: &str) -> Result<usize, String> {
+	let (num, shift) = match s.char_indices().last() {
+		Some((i, 'K' | 'k')) => (&s[..i], 10),
+		Some((i, 'M' | 'm')) => (&s[..i], 20),
+		Some((i, 'G' | 'g')) => (&s[..i], 30),
+		_ => (s, 0),
+	};
+	num.parse::<usize>()
+		.map_err(|e| e.to_string())?
+		.checked_mul(1 << shift)
+		.ok_or(String::from("size too large"))
+}
+
+
+/// Parse a sampling rate, given as "N" or "1/N".
+fn parse_sample(s: &str) -> Result<NonZeroUsize, String> {
+	s.strip_prefix("1/").unwrap_or(s)
+		.parse()
+		.map_err(|e| format!("invalid sampling rate: {e}"))
+}
+
+
+/// Split a Python generator argument into file and function name,
+/// the function defaults to "generate".
+#[cfg(feature = "python")]
+fn split_py_generator(s: &str) -> (PathBuf, String) {
+	match s.rsplit_once(':') {
+		Some((file, function))
+			if !function.is_empty()
+			&& function.chars().all(|c| c.is_alphanumeric() || c == '_') =>
+			(PathBuf::from(file), String::from(function)),
+		_ => (PathBuf::from(s), String::from("generate")),
+	}
+}
 
 
 #[derive(Parser, Debug)]
@@ -18,140 +86,879 @@ pub struct Args {
 	/// sent, larger incoming packets will be truncated
 	#[arg(short, long, default_value_t = 1500)]
 	buffer_size: usize,
+	/// label to tag the run with in statistics output, summary, and
+	/// JSON packet logs
+	#[arg(long)]
+	label: Option<String>,
+	/// write received packets to this file instead of standard
+	/// output. If it is a FIFO or Unix socket, packets are streamed
+	/// to the reader at the other end as they arrive. The server
+	/// reopens the file on SIGHUP, for log rotation.
+	#[arg(short, long, value_name = "FILE")]
+	output: Option<PathBuf>,
+	/// what to do if the reader of an --output FIFO or socket goes
+	/// away: stop the run, or buffer packets until a new reader
+	/// connects
+	#[arg(long, value_enum, requires = "output", default_value = "stop")]
+	on_disconnect: sink::Disconnect,
+	/// flush the output file at this interval (seconds), so less
+	/// data is lost if the process crashes during a long run (by
+	/// default data is written when the buffer is full)
+	#[arg(long, value_name = "SECONDS", requires = "output", value_parser = parse_seconds)]
+	flush_interval: Option<Duration>,
+	/// fsync the output file after each flush, for durability
+	/// against system crashes at the cost of more disk I/O
+	#[arg(long, requires = "output", default_value_t = false)]
+	fsync: bool,
+	/// write a hex dump of each sent and received packet to
+	/// standard error, limited to the given number of bytes per
+	/// packet (for debugging, slows down sending)
+	#[arg(
+		long, value_name = "BYTES", require_equals = true,
+		num_args = 0..=1, default_missing_value = "64")]
+	hexdump: Option<usize>,
+	/// format of timestamps in packet logs, RFC 3339 is easier to
+	/// read and to merge with other logs
+	#[arg(long, value_enum, default_value = "epoch")]
+	time_format: TimeFormat,
+	/// comma separated columns of packet logs (standard output and
+	/// --output), in this order, or keys of JSON records. Logs read
+	/// by the correlate command need the default columns.
+	#[arg(long, value_enum, value_delimiter = ',', value_name = "FIELD,...")]
+	fields: Option<Vec<LogField>>,
+	/// format of packet logs (standard output and --output): tab
+	/// separated with a header line, one JSON object per line (for
+	/// jq and log processors), or compact binary records for high
+	/// packet rates (see the convert command). --time-format applies
+	/// only to tsv, --fields to tsv and json.
+	#[arg(long, value_enum, default_value = "tsv")]
+	log_format: LogFormat,
+	/// log only every N-th received packet (or echo), given as N or
+	/// 1/N, to keep up with high packet rates. Totals still count
+	/// all packets, round trip times only the sample.
+	#[arg(long, value_name = "1/N", value_parser = parse_sample)]
+	sample: Option<NonZeroUsize>,
+	/// stop waiting for packets (server) or echoes (client) when
+	/// none arrived for this long (seconds), instead of blocking
+	/// forever
+	#[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+	recv_timeout: Option<Duration>,
+	/// skip packets (client) or echoes (server) whose send blocked
+	/// for this long (seconds)
+	#[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+	send_timeout: Option<Duration>,
+	/// request hardware receive timestamps from the network interface
+	/// (SO_TIMESTAMPING), falling back to software timestamps for
+	/// packets it did not stamp. Needs NIC and driver support, and
+	/// receive timestamping enabled on the interface.
+	#[arg(long, default_value_t = false)]
+	hardware_timestamps: bool,
+	/// do not warn if realtime priority or memory locking are not
+	/// permitted, e.g. when running without CAP_SYS_NICE and
+	/// CAP_IPC_LOCK on purpose
+	#[arg(short, long)]
+	quiet: bool,
 	#[command(subcommand)]
 	command: Commands,
 }
 
+#[derive(clap::Args, Debug)]
+struct ClientArgs {
+	/// server to send to
+	#[arg(short, long, default_value = "localhost:7800")]
+	server: String,
+	/// address family to use for the server address
+	#[arg(long, value_enum, default_value = "auto")]
+	family: addr::Family,
+	/// request packet echo from server
+	#[arg(short, long, default_value_t = false)]
+	echo: bool,
+	/// select a built-in generator, if given multiple times the
+	/// generators run one after the other
+	#[arg(short, long, value_enum, default_value = "default", group = "generator_choice")]
+	generator: Vec<Generator>,
+	/// use the 'generate' function from this Python file as the
+	/// generator, or the function named after a colon
+	#[cfg(feature = "python")]
+	#[arg(
+		long, value_name = "MODULE_PY[:FUNCTION]", group = "generator_choice")]
+	py_generator: Option<String>,
+	/// option to pass to the generator in name=value format, may
+	/// be specificed multiple times
+	#[arg(
+		short = 'O',
+		long,
+		value_name = "NAME=VALUE",
+		value_parser = |s: &str| s.split_once('=')
+			.ok_or("invaild option, no '=' to split at")
+			.map(|s| (String::from(s.0), String::from(s.1)))
+	)]
+	generator_option: Vec<(String, String)>,
+	/// number of packets to send, same as "-O count=COUNT" (giving
+	/// both is an error)
+	#[arg(short, long)]
+	count: Option<usize>,
+	/// interval between packets in seconds, same as "-O
+	/// interval=SECONDS" (giving both is an error)
+	#[arg(short, long, value_name = "SECONDS")]
+	interval: Option<String>,
+	/// packet size in bytes, same as "-O size=SIZE" (giving both
+	/// is an error)
+	#[arg(long)]
+	size: Option<usize>,
+	/// number of packets at the start of the run to exclude from
+	/// the steady state throughput calculation
+	#[arg(long, default_value_t = 0)]
+	warmup: usize,
+	/// report echo round trip times by packet size, the values
+	/// are the inclusive upper size bounds of the buckets
+	#[arg(long, value_name = "SIZE,...", value_delimiter = ',')]
+	size_buckets: Option<Vec<usize>>,
+	/// write a JSON statistics snapshot to stderr at this interval
+	/// (seconds) during the run
+	#[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+	stats_interval: Option<Duration>,
+	/// verify that echoed packets match what was sent, within a
+	/// window of the given number of most recent packets
+	#[arg(
+		long, value_name = "WINDOW",
+		num_args = 0..=1, default_missing_value = "1024")]
+	verify_echo: Option<usize>,
+	/// fill the packet payload with pseudo-random bytes unique to
+	/// each packet instead of zeros
+	#[arg(long, default_value_t = false)]
+	unique_payload: bool,
+	/// repeat the sequence number in the first four bytes of the
+	/// payload, so captures can restore order even if a middlebox
+	/// rewrites headers
+	#[arg(long, default_value_t = false)]
+	payload_sequence: bool,
+	/// measure the cost of the send syscall on the first packets
+	/// and start sending that much earlier afterwards
+	#[arg(long, default_value_t = false)]
+	compensate_send: bool,
+	/// request echo sequence numbers from the server, to tell
+	/// forward and reverse path loss apart (needs packets of at
+	/// least 25 bytes)
+	#[arg(long, requires = "echo", default_value_t = false)]
+	echo_sequence: bool,
+	/// fail the run if buffers and statistics would need more
+	/// memory than this, in bytes with optional K, M, or G suffix
+	#[arg(
+		long, value_name = "BYTES", value_parser = parse_size,
+		default_value_t = client::DEFAULT_MEM_BUDGET)]
+	mem_budget: usize,
+	/// send packets with this ECN codepoint, and count CE marks
+	/// on echoes
+	#[arg(long, value_enum)]
+	ecn: Option<luna_rs::Ecn>,
+	/// send packets with this DSCP codepoint, a number from 0 to 63
+	/// or a name like EF, AF41, or CS1
+	#[arg(long, value_name = "DSCP")]
+	dscp: Option<luna_rs::Dscp>,
+	/// receive echoes in the send loop instead of a separate
+	/// thread, for low rate probes only (receiving delays sending)
+	#[arg(long, requires = "echo", default_value_t = false)]
+	no_echo_thread: bool,
+	/// report the distribution of gaps actually achieved between
+	/// consecutive sends
+	#[arg(long, default_value_t = false)]
+	send_gaps: bool,
+	/// send this many packets of the full buffer size back to
+	/// back before the generator packets and report their round
+	/// trip times separately, to see how buffers on the path fill
+	#[arg(long, value_name = "COUNT", default_value_t = 0)]
+	burst: usize,
+	/// send up to this many packets that are due at the same time
+	/// (e.g. the burst, or when the generator asks for more than
+	/// the host can send) with one sendmmsg call
+	#[arg(long, value_name = "COUNT", default_value_t = 1)]
+	batch: usize,
+	/// how to handle packets larger than the path MTU: let the
+	/// kernel fragment them, set DF and skip them, or set DF and
+	/// ignore the path MTU (default: system setting)
+	#[arg(long, value_enum)]
+	fragment: Option<client::Fragment>,
+	/// write the delay since the previous packet, size, and flags
+	/// of each packet as actually sent to this file, to replay the
+	/// achieved schedule later
+	#[arg(long, value_name = "FILE")]
+	send_log: Option<PathBuf>,
+	/// stop after sending this many packets in total, even if the
+	/// generators would send more
+	#[arg(long, value_name = "COUNT")]
+	max_packets: Option<usize>,
+	/// stop sending after this many seconds, even if the generators
+	/// would send more
+	#[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+	duration: Option<Duration>,
+	/// count echoes whose kernel receive timestamp is earlier than
+	/// that of an echo with lower sequence number, a sign of clock
+	/// steps or mixed timestamp sources
+	#[arg(long, default_value_t = false)]
+	check_timestamps: bool,
+	/// mark packets with this flow ID, to tell several streams to
+	/// the same server apart in the logs (only packets of at least
+	/// 29 bytes can carry it)
+	#[arg(long, value_name = "ID")]
+	flow_id: Option<u32>,
+	/// send packets in format version 2, which carry their header
+	/// length (servers that only support version 1 ignore them)
+	#[arg(long, default_value_t = false)]
+	header_length: bool,
+	/// exit with status 3 if more than this percentage of packets
+	/// got no echo
+	#[arg(long, value_name = "PERCENT", requires = "echo")]
+	fail_if_loss: Option<f64>,
+	/// exit with status 3 if the 99th percentile of the round trip
+	/// times is above this many milliseconds
+	#[arg(long, value_name = "MS", value_parser = parse_millis, requires = "echo")]
+	fail_if_rtt_p99: Option<Duration>,
+	/// accept echoes from any source address, e.g. behind NAT or a
+	/// load balancer (spoofed echoes are accepted, too)
+	#[arg(long, requires = "echo", default_value_t = false)]
+	no_echo_source_check: bool,
+	/// send packets at least this long after the previous one,
+	/// whatever the generator requests, this caps the packet rate
+	#[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+	min_interval: Option<Duration>,
+	/// send a keepalive packet when no packet was sent for this long
+	/// while waiting for the generator, to keep NAT or firewall state
+	/// alive. The server neither echoes nor logs keepalives.
+	#[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+	keepalive: Option<Duration>,
+	/// clock to pace packets on and read send timestamps from,
+	/// monotonic is not stepped by NTP, but its timestamps mean
+	/// nothing on other hosts (packets say which clock they use),
+	/// tai is realtime without leap seconds (both need
+	/// --header-length)
+	#[arg(
+		long, value_enum, default_value = "realtime",
+		requires_ifs = [("monotonic", "header_length"), ("tai", "header_length")])]
+	clock: SendClock,
+	/// how long to wait for echoes after the last packet: a fixed
+	/// time, twice the p99 round trip time so far ("adaptive"), or
+	/// until no echo arrived for the given time, but at most
+	/// MAX_SECONDS (default 10)
+	#[arg(
+		long, value_name = "fixed:SECONDS|adaptive|until-idle:SECONDS[:MAX_SECONDS]",
+		default_value = "fixed:0.2")]
+	echo_wait_strategy: client::EchoWait,
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
-	Client {
-		/// server to send to
-		#[arg(short, long, default_value = "localhost:7800")]
-		server: String,
-		/// request packet echo from server
-		#[arg(short, long, default_value_t = false)]
-		echo: bool,
-		/// select a built-in generator
-		#[arg(short, long, value_enum, default_value = "default", group = "generator_choice")]
-		generator: Generator,
-		/// use the 'generate' function from this Python file as the
-		/// generator
-		#[cfg(feature = "python")]
-		#[arg(long, value_name = "MODULE_PY", group = "generator_choice")]
-		py_generator: Option<PathBuf>,
-		/// option to pass to the generator in name=value format, may
-		/// be specificed multiple times
-		#[arg(
-			short = 'O',
-			long,
-			value_name = "NAME=VALUE",
-			value_parser = |s: &str| s.split_once('=')
-				.ok_or("invaild option, no '=' to split at")
-				.map(|s| (String::from(s.0), String::from(s.1)))
-		)]
-		generator_option: Vec<(String, String)>,
-	},
+	// boxed, the client has far more options than the other commands
+	Client(Box<ClientArgs>),
 	Server {
 		/// port to listen on
-		#[arg(short, long, default_value_t = 7800)]
+		#[arg(short, long, default_value_t = addr::DEFAULT_PORT)]
 		port: u16,
-		/// local address to bind to for listening
+		/// local address or host name to bind to for listening, if
+		/// given multiple times one thread receives on all addresses
+		/// using epoll
 		#[arg(short, long, default_value = "::")]
-		bind: IpAddr,
+		bind: Vec<String>,
+		/// do not set SO_REUSEADDR on the server socket (by default
+		/// it is set to allow immediate restarts on the same port)
+		#[arg(long, default_value_t = false)]
+		no_reuse_addr: bool,
+		/// format of the summary at the end of the run (also on
+		/// SIGINT or SIGTERM), JSON is written to standard output
+		#[arg(long, value_enum, default_value_t)]
+		summary_format: server::SummaryFormat,
+		/// end the session of a source that sent no packets for
+		/// this long, print its summary and free its state
+		#[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+		session_timeout: Option<Duration>,
+		/// echo only packets whose sequence number is within this
+		/// distance of the highest one of the session of the source
+		/// (requires --session-timeout)
+		#[arg(long, value_name = "COUNT", requires = "session_timeout")]
+		seq_window: Option<u32>,
+		/// write a JSON snapshot of the socket receive queues to
+		/// stderr at this interval (seconds)
+		#[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+		stats_interval: Option<Duration>,
+		/// compare the sequence number clients repeat in the payload
+		/// (client --payload-sequence) with the header, and count
+		/// packets where they differ
+		#[arg(long, default_value_t = false)]
+		check_payload_sequence: bool,
+		/// receive up to this many packets with one recvmmsg call
+		/// (single bind address only, several addresses are always
+		/// received in batches)
+		#[arg(long, value_name = "COUNT", default_value_t = 1)]
+		batch: usize,
+	},
+	/// estimate the timestamping and scheduling noise floor of this
+	/// host from round trip times over the IPv6 loopback interface
+	Calibrate {
+		/// number of echo packets to send
+		#[arg(short, long, default_value_t = 1000)]
+		count: usize,
+		/// interval between packets (seconds)
+		#[arg(short, long, default_value = "0.001", value_parser = parse_seconds)]
+		interval: Duration,
+	},
+	/// merge a server log and a client echo log into one record per
+	/// echoed packet with send, server receive, and echo receive
+	/// times
+	Correlate {
+		/// log file written by the server
+		server_log: PathBuf,
+		/// echo log file written by the client
+		client_log: PathBuf,
+		/// estimate the offset between server and client clock from
+		/// the echoes (assuming symmetric delay), report it, and
+		/// correct the server receive time and one-way delays for it
+		#[arg(long, default_value_t = false)]
+		clock_offset: bool,
+	},
+	/// convert a binary packet log (--log-format binary) to the
+	/// format selected with --log-format, on standard output or
+	/// --output
+	Convert {
+		/// binary log file
+		log: PathBuf,
 	},
 }
 
 
-static SERVER_CLOSE: OnceLock<server::CloseHandle> = OnceLock::new();
+/// Signals that stop the server.
+fn shutdown_signals() -> signal::SigSet {
+	let mut s = signal::SigSet::empty();
+	s.add(signal::Signal::SIGINT);
+	s.add(signal::Signal::SIGTERM);
+	s
+}
 
 
-extern "C" fn handle_shutdown_sig(signal: libc::c_int) {
-	let signal = signal::Signal::try_from(signal).unwrap();
-	match signal {
-		signal::Signal::SIGINT | signal::Signal::SIGTERM => SERVER_CLOSE.get().map(|h| h.close()),
-		_ => panic!("signal handler was installed for unsupported signal"),
-	};
+/// Signals that make the server reopen its `--output` file.
+fn reopen_signals() -> signal::SigSet {
+	let mut s = signal::SigSet::empty();
+	s.add(signal::Signal::SIGHUP);
+	s
 }
 
 
+/// Run the client, `echo` is the echo wait strategy if echo is
+/// requested.
 fn run_client(
-	server: String,
+	server: SocketAddr,
 	buffer_size: usize,
-	echo: bool,
+	echo: Option<client::EchoWait>,
 	generator: Generator,
-	generator_option: Vec<(String, String)>)
+	go: HashMap<String, String>,
+	logger: Option<mpsc::Sender<ReceivedPacket>>,
+	mut options: client::Options)
 	-> Result<(), Box<dyn std::error::Error>>
 {
-	let go = {
-		let mut go = HashMap::with_capacity(generator_option.len());
-		for (name, value) in generator_option {
-			go.insert(name, value);
-		}
-		go
+	options.planned_count = generator.planned_count(&go)
+		.inspect_err(|e| eprintln!("{}", e))?;
+	// the generator cannot check this, it does not know the buffer
+	if let Some(s) = generator::parse_sizes(&go)?.and_then(
+		|sizes| sizes.into_iter().find(|s| *s > buffer_size))
+	{
+		return Err(format!("size {s} in \"sizes\" exceeds the buffer size of {buffer_size}").into());
+	}
+	// a generator that adjusts to the achieved send times gets the
+	// send log, which is copied to the send log file if requested
+	let feedback = if generator.uses_feedback() {
+		let (sender, feedback) = mpsc::channel::<SentPacket>();
+		options.send_log = Some(match options.send_log.take() {
+			Some(log) => {
+				let (tee, r) = mpsc::channel::<SentPacket>();
+				thread::Builder::new()
+					.name("send log copy".to_string())
+					.spawn(move || {
+						for p in r.iter() {
+							// the generator may be done before the client
+							let _ = sender.send(p.clone());
+							if log.send(p).is_err() {
+								break;
+							}
+						}
+					})?;
+				tee
+			},
+			None => sender,
+		});
+		Some(feedback)
+	} else {
+		None
 	};
-	let receiver = generator.run(go)
+	let receiver = generator.run_with_feedback(go, feedback)
 		.inspect_err(|e| eprintln!("{}", e))?;
-	let server_addr: SocketAddr = server
-		.to_socket_addrs()
-		.expect("cannot parse server address")
-		.next().expect("no address");
 	client::run(
-		server_addr, buffer_size, echo, receiver,
-		Some(Duration::from_millis(200)), None)?;
+		server, buffer_size, echo.is_some(), receiver,
+		echo, logger, options)?;
 	Ok(())
 }
 
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// Exit status of a finished client run: [`EXIT_THRESHOLD`] if it
+/// completed but exceeded a limit, 0 if it succeeded. Other errors
+/// are passed on.
+fn client_exit_status(result: Result<(), Box<dyn std::error::Error>>)
+	-> Result<u8, Box<dyn std::error::Error>>
+{
+	match result {
+		Err(e) if e.is::<client::ThresholdExceeded>() => {
+			eprintln!("{e}");
+			Ok(EXIT_THRESHOLD)
+		},
+		r => r.map(|()| 0),
+	}
+}
+
+
+/// Build the generator options from `-O` options and the dedicated
+/// flags for common options (name and value, if given). Giving an
+/// option both ways is an error, repeated `-O` options use the last
+/// value.
+fn generator_options(
+	generator_option: Vec<(String, String)>,
+	flags: &[(&str, Option<String>)])
+	-> Result<HashMap<String, String>, String>
+{
+	let mut go: HashMap<String, String> = generator_option.into_iter().collect();
+	for (name, value) in flags {
+		if let Some(v) = value {
+			if let Some(o) = go.get(*name) {
+				return Err(format!(
+					"--{name} {v} conflicts with -O {name}={o}, use only one"));
+			}
+			go.insert(name.to_string(), v.clone());
+		}
+	}
+	Ok(go)
+}
+
+
+/// Columns of packet logs: as selected, otherwise the default ones
+/// (`None`), except that tab separated client logs of timestamps
+/// from another clock than realtime include the clock, see
+/// [`CLOCK_FIELDS`] (JSON objects always have it).
+fn log_fields(args: &Args) -> Option<Vec<LogField>> {
+	args.fields.clone().or_else(|| match &args.command {
+		Commands::Client(c) if c.clock != SendClock::Realtime && args.log_format == LogFormat::Tsv =>
+			Some(CLOCK_FIELDS.to_vec()),
+		_ => None,
+	})
+}
+
+
+/// Fail if `--time-format rfc3339` would show client timestamps from
+/// a clock other than realtime as if they were wall clock time.
+fn check_time_format(args: &Args) -> Result<(), String> {
+	match &args.command {
+		Commands::Client(c) if c.clock != SendClock::Realtime
+			&& args.time_format == TimeFormat::Rfc3339 =>
+			Err(format!(
+				"--time-format rfc3339 needs --clock realtime, {} timestamps are not wall clock time",
+				c.clock)),
+		_ => Ok(()),
+	}
+}
+
+
+/// Read a packet log as written with `--output`, tab separated with
+/// the default fields (and the clock, see [`CLOCK_FIELDS`]) or
+/// binary.
+fn read_log(path: &PathBuf) -> Result<Vec<ReceivedPacket>, Box<dyn std::error::Error>> {
+	let mut magic = [0u8; BINARY_MAGIC.len()];
+	let mut file = File::open(path)?;
+	if file.read_exact(&mut magic).is_ok() && &magic == BINARY_MAGIC {
+		return Ok(BinaryLog::new(BufReader::new(File::open(path)?))?
+			.collect::<Result<_, _>>()
+			.map_err(|e| format!("{}: {e}", path.display()))?);
+	}
+	let headers = [DEFAULT_FIELDS, CLOCK_FIELDS].map(ReceivedPacket::header_fields);
+	fs::read_to_string(path)?
+		.lines()
+		.enumerate()
+		.filter(|(_, l)| !l.is_empty() && !headers.iter().any(|h| h == l))
+		.map(|(i, l)| l.parse()
+			.map_err(|e| format!("{}:{}: {e}", path.display(), i + 1).into()))
+		.collect()
+}
+
+
+/// Convert the binary packet log `log` to the selected log format,
+/// written to `out`, or send the records to `logger` (the sink of
+/// `--output`) if there is one.
+fn convert(
+	args: &Args, log: &Path, logger: Option<&mpsc::Sender<ReceivedPacket>>, out: &mut impl Write)
+	-> Result<(), Box<dyn std::error::Error>>
+{
+	let fields = args.fields.as_deref().unwrap_or(args.log_format.default_fields());
+	if logger.is_none() {
+		args.log_format.write_header(out, fields)?;
+	}
+	let records = BinaryLog::new(BufReader::new(File::open(log)?))
+		.map_err(|e| format!("{}: {e}", log.display()))?;
+	for p in records {
+		let p = p.map_err(|e| format!("{}: {e}", log.display()))?;
+		match logger {
+			Some(l) => l.send(p)?,
+			None => args.log_format.write_packet(
+				out, &p, args.time_format, fields, args.label.as_deref())?,
+		}
+	}
+	Ok(out.flush()?)
+}
+
+
+fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
 	let args = Args::parse();
+	let mut exit = 0;
 	#[cfg(debug_assertions)]
 	eprintln!("{args:?}");
+	check_time_format(&args)?;
+	// the server reopens its output on SIGHUP, for log rotation
+	let reopen = match args.command {
+		Commands::Server { .. } if args.output.is_some() => Some(Arc::new(AtomicBool::new(false))),
+		_ => None,
+	};
+	if let Commands::Server { .. } = args.command {
+		// before starting any threads, so all inherit the mask and
+		// only the signal wait threads receive the signals
+		shutdown_signals().thread_block()?;
+		if reopen.is_some() {
+			reopen_signals().thread_block()?;
+		}
+	}
+	let fields = log_fields(&args);
+	let (logger, sink) = match &args.output {
+		Some(path) => {
+			if let Some(r) = &reopen {
+				sink::reopen_on_signal(Arc::clone(r), reopen_signals())?;
+			}
+			let (s, h) = sink::to_file(path, sink::Options {
+				flush_interval: args.flush_interval,
+				fsync: args.fsync,
+				time_format: args.time_format,
+				fields: fields.clone(),
+				log_format: args.log_format,
+				disconnect: args.on_disconnect,
+				pending_limit: None,
+				label: args.label.clone(),
+				reopen,
+			})?;
+			(Some(s), Some(h))
+		},
+		None => (None, None),
+	};
 	match args.command {
-		Commands::Client {
-			server,
-			echo,
-			generator,
-			#[cfg(feature = "python")]
-			py_generator,
-			generator_option,
-		} => {
+		Commands::Client(c) => {
+			let ClientArgs {
+				server,
+				family,
+				echo,
+				generator,
+				#[cfg(feature = "python")]
+				py_generator,
+				generator_option,
+				count,
+				interval,
+				size,
+				warmup,
+				size_buckets,
+				stats_interval,
+				verify_echo,
+				unique_payload,
+				payload_sequence,
+				compensate_send,
+				echo_sequence,
+				mem_budget,
+				ecn,
+				dscp,
+				no_echo_thread,
+				send_gaps,
+				burst,
+				batch,
+				fragment,
+				send_log,
+				max_packets,
+				duration,
+				check_timestamps,
+				flow_id,
+				header_length,
+				fail_if_loss,
+				fail_if_rtt_p99,
+				no_echo_source_check,
+				min_interval,
+				keepalive,
+				clock,
+				echo_wait_strategy,
+			} = *c;
+			let mut generator = generator;
+			let generator = if generator.len() == 1 {
+				generator.pop().unwrap()
+			} else {
+				Generator::Sequence(generator)
+			};
 			#[cfg(feature = "python")]
 			let generator = py_generator
-				.as_ref()
-				.map(|p| (fs::read_to_string(p).unwrap(), p.to_str().unwrap()))
-				.map(|s| (CString::new(s.0).unwrap(), CString::new(s.1).unwrap()))
-				.map(|s| Generator::Py{code: s.0, file: s.1})
+				.as_deref()
+				.map(split_py_generator)
+				.map(|(p, f)| (fs::read_to_string(&p).unwrap(), p.into_os_string(), f))
+				.map(|s| (CString::new(s.0).unwrap(), CString::new(s.1.into_encoded_bytes()).unwrap(), s.2))
+				.map(|s| Generator::Py{code: s.0, file: s.1, function: s.2})
 				.or(Some(generator));
 			#[cfg(not(feature = "python"))]
 			let generator = Some(generator);
-			run_client(
+			let go = generator_options(generator_option, &[
+				("count", count.map(|c| c.to_string())),
+				("interval", interval),
+				("size", size.map(|s| s.to_string())),
+			])?;
+			let server = addr::resolve(&server, family)
+				.inspect_err(|e| eprintln!("cannot resolve server address: {e}"))?;
+			let (send_log, send_sink) = match send_log {
+				Some(path) => {
+					let (s, h) = sink::to_file(&path, sink::Options {
+						flush_interval: args.flush_interval,
+						fsync: args.fsync,
+						time_format: args.time_format,
+						fields: None,
+						log_format: LogFormat::Tsv,
+						disconnect: args.on_disconnect,
+						pending_limit: None,
+						label: None,
+						reopen: None,
+					})?;
+					(Some(s), Some(h))
+				},
+				None => (None, None),
+			};
+			let result = run_client(
 				server,
 				args.buffer_size,
-				echo,
+				echo.then_some(echo_wait_strategy),
 				generator.unwrap(),
-				generator_option
-			)?;
+				go,
+				logger,
+				client::Options {
+					warmup, size_buckets, stats_interval, label: args.label,
+					verify_echo, unique_payload, compensate_send, echo_sequence,
+					mem_budget: Some(mem_budget), hexdump: args.hexdump, ecn, dscp,
+					sync_echo: no_echo_thread, send_gaps, burst, batch, fragment,
+					time_format: args.time_format, fields,
+					log_format: args.log_format, send_log, max_packets, duration,
+					check_timestamps, flow: flow_id, sample: args.sample,
+					min_interval, keepalive, clock, recv_timeout: args.recv_timeout,
+					send_timeout: args.send_timeout, quiet: args.quiet, header_length,
+					hardware_timestamps: args.hardware_timestamps,
+					fail_if_loss, fail_if_rtt_p99, no_echo_source_check, payload_sequence,
+					..Default::default()
+				},
+			);
+			exit = client_exit_status(result)?;
+			if let Some(h) = send_sink {
+				h.join().map_err(|_| "send log sink thread panicked")??;
+			}
 		},
-		Commands::Server { port, bind } => {
-			let bind_addr: SockaddrStorage = if bind.is_ipv6() {
-				let s = format!("[{}]:{}", bind, port);
-				SockaddrStorage::from(s.parse::<SocketAddrV6>()?)
+		Commands::Server {
+			port, bind, no_reuse_addr, summary_format, session_timeout, seq_window,
+			stats_interval, check_payload_sequence, batch,
+		} => {
+			let bind_addrs = bind.iter()
+				.map(|b| addr::bind_addr(b, port, addr::Family::Auto))
+				.collect::<Result<Vec<_>, _>>()
+				.inspect_err(|e| eprintln!("invalid bind address: {e}"))?;
+			let options = server::Options {
+				reuse_addr: !no_reuse_addr,
+				label: args.label,
+				hexdump: args.hexdump,
+				time_format: args.time_format,
+				fields: args.fields,
+				log_format: args.log_format,
+				summary_format,
+				sample: args.sample,
+				session_timeout,
+				seq_window,
+				stats_interval,
+				recv_timeout: args.recv_timeout,
+				send_timeout: args.send_timeout,
+				quiet: args.quiet,
+				check_payload_sequence,
+				hardware_timestamps: args.hardware_timestamps,
+				recv_batch: batch,
+			};
+			type Run = Box<dyn FnOnce() -> Result<server::Summary, Box<dyn std::error::Error>>>;
+			let (handle, run): (_, Run) = if bind_addrs.len() == 1 {
+				let mut srv = server::Server::new(
+					bind_addrs[0], args.buffer_size, logger, options);
+				(srv.bind()?, Box::new(move || srv.run()))
 			} else {
-				let s = format!("{}:{}", bind, port);
-				SockaddrStorage::from(s.parse::<SocketAddrV4>()?)
+				let mut srv = server::MultiServer::new(
+					bind_addrs, args.buffer_size, logger, options);
+				(srv.bind()?, Box::new(move || srv.run()))
 			};
-			let mut srv = server::Server::new(bind_addr, args.buffer_size, None);
-			let handle = srv.bind()?;
-			if let Err(_) = SERVER_CLOSE.set(handle) {
-				panic!("programming error: server close handle already set")
+			server::close_on_signal(handle, shutdown_signals())?;
+			run()?;
+		},
+		Commands::Calibrate { count, interval } => {
+			let c = calibrate::run(count, interval)?;
+			eprintln!("{c}");
+		},
+		Commands::Correlate { server_log, client_log, clock_offset } => {
+			let mut merged = stats::correlate(&read_log(&server_log)?, &read_log(&client_log)?);
+			if clock_offset {
+				let offset = stats::ClockOffset::estimate(&merged)
+					.ok_or("no echoes to estimate the clock offset from")?;
+				eprintln!("{offset}");
+				merged = merged.iter().map(|c| c.corrected(offset.offset)).collect();
 			}
-			let handler = signal::SigHandler::Handler(handle_shutdown_sig);
-			unsafe {
-				signal::signal(signal::Signal::SIGINT, handler)?;
-				signal::signal(signal::Signal::SIGTERM, handler)?;
+			println!("{}", stats::Correlated::header());
+			for c in merged {
+				println!("{c}");
 			}
-			srv.run()?;
 		},
+		Commands::Convert { ref log } => {
+			convert(&args, log, logger.as_ref(), &mut BufWriter::new(io::stdout().lock()))?;
+			drop(logger);
+		},
+	}
+	// all senders are gone now, wait for the sink to write the rest
+	if let Some(h) = sink {
+		h.join().map_err(|_| "file sink thread panicked")??;
+	}
+	Result::Ok(ExitCode::from(exit))
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sample_rate() {
+		assert_eq!(parse_sample("10"), Ok(NonZeroUsize::new(10).unwrap()));
+		assert_eq!(parse_sample("1/100"), Ok(NonZeroUsize::new(100).unwrap()));
+		assert!(parse_sample("0").is_err());
+		assert!(parse_sample("2/3").is_err());
+	}
+
+	#[test]
+	fn generator_option_flags() {
+		let o = |n: &str, v: &str| (n.to_string(), v.to_string());
+		let go = generator_options(
+			vec![o("max-size", "64"), o("count", "1"), o("count", "3")],
+			&[("count", None), ("interval", Some("0.5".into())), ("size", None)])
+			.unwrap();
+		assert_eq!(go, HashMap::from([
+			o("max-size", "64"), o("count", "3"), o("interval", "0.5")]));
+
+		let err = generator_options(
+			vec![o("count", "3")], &[("count", Some("5".into()))])
+			.unwrap_err();
+		assert_eq!(err, "--count 5 conflicts with -O count=3, use only one");
+	}
+
+	#[test]
+	fn clock_column() {
+		let fields = |a: &[&str]| log_fields(&Args::try_parse_from(
+			["luna-rs"].iter().chain(a)).unwrap());
+		assert_eq!(
+			fields(&["client", "--header-length", "--clock", "monotonic"]),
+			Some(CLOCK_FIELDS.to_vec()));
+		assert_eq!(fields(&["client"]), None);
+		// JSON records always include the clock
+		assert_eq!(
+			fields(&["--log-format", "json", "client", "--header-length", "--clock", "tai"]),
+			None);
+		assert_eq!(fields(&["server"]), None);
+		assert_eq!(
+			fields(&["--fields", "sequence", "client", "--header-length", "--clock", "tai"]),
+			Some(vec![LogField::Sequence]));
+	}
+
+	#[test]
+	fn threshold_exit_status() -> Result<(), Box<dyn std::error::Error>> {
+		// a server that never echoes, so all packets are lost
+		let silent = std::net::UdpSocket::bind("[::1]:0")?;
+		let run = |fail_if_loss| client_exit_status(run_client(
+			silent.local_addr().unwrap(), 64, Some(client::EchoWait::Fixed(Duration::ZERO)),
+			Generator::Default,
+			HashMap::from([("count".to_string(), "2".to_string()), ("interval".to_string(), "0".to_string())]),
+			None, client::Options { fail_if_loss, quiet: true, ..Default::default() }));
+		assert_eq!(run(Some(50.0))?, EXIT_THRESHOLD);
+		assert_eq!(run(Some(100.0))?, 0);
+		assert_eq!(run(None)?, 0);
+		// other errors are not a threshold
+		assert!(client_exit_status(Err("failed".into())).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn convert_log() -> Result<(), Box<dyn std::error::Error>> {
+		let path = std::env::temp_dir()
+			.join(format!("luna-convert-{}.bin", std::process::id()));
+		let mut log = BINARY_MAGIC.to_vec();
+		for line in [
+			"10.000000500\t192.0.2.1\t7800\t42\t10.000000001\t64\t3",
+			"11.000000000\t2001:db8::1\t7801\t43\t10.900000000\t21\t0",
+		] {
+			log.extend(line.parse::<ReceivedPacket>()?.to_binary()?);
+		}
+		fs::write(&path, log)?;
+		let converted = |a: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+			let args = Args::try_parse_from(
+				["luna-rs"].iter().chain(a).chain(&["convert", path.to_str().unwrap()]))?;
+			let mut out = Vec::new();
+			convert(&args, &path, None, &mut out)?;
+			Ok(String::from_utf8(out)?)
+		};
+		assert_eq!(
+			converted(&[])?,
+			concat!(
+				"receive_time\tsource\tport\tsequence\ttimestamp\tsize\tflow\n",
+				"10.000000500\t192.0.2.1\t7800\t42\t10.000000001\t64\t3\n",
+				"11.000000000\t2001:db8::1\t7801\t43\t10.900000000\t21\t0\n"));
+		assert_eq!(
+			converted(&["--log-format", "json", "--fields", "sequence,delay", "--label", "a"])?,
+			concat!(
+				"{\"label\": \"a\", \"sequence\": 42, \"delay\": 499}\n",
+				"{\"label\": \"a\", \"sequence\": 43, \"delay\": 100000000}\n"));
+
+		// records go to the sink of --output instead
+		let (sender, receiver) = mpsc::channel();
+		let args = Args::try_parse_from(["luna-rs", "convert", path.to_str().unwrap()])?;
+		let mut out = Vec::new();
+		convert(&args, &path, Some(&sender), &mut out)?;
+		assert!(out.is_empty());
+		drop(sender);
+		assert_eq!(receiver.iter().map(|p| p.sequence).collect::<Vec<_>>(), [42, 43]);
+
+		fs::write(&path, b"not a log")?;
+		assert!(converted(&[]).is_err());
+		fs::remove_file(&path)?;
+		Ok(())
+	}
+
+	#[test]
+	fn clock_options() {
+		let parse = |a: &[&str]| Args::try_parse_from(["luna-rs"].iter().chain(a));
+		// clocks other than realtime need the header length field
+		for clock in ["monotonic", "tai"] {
+			assert!(parse(&["client", "--clock", clock]).is_err());
+			let args = parse(&["client", "--header-length", "--clock", clock]).unwrap();
+			assert_eq!(check_time_format(&args), Ok(()));
+		}
+		let args = parse(&["client", "--clock", "realtime"]).unwrap();
+		assert_eq!(check_time_format(&args), Ok(()));
+
+		let args = parse(&[
+			"--time-format", "rfc3339", "client", "--header-length", "--clock", "monotonic"])
+			.unwrap();
+		assert_eq!(
+			check_time_format(&args).unwrap_err(),
+			"--time-format rfc3339 needs --clock realtime, monotonic timestamps are not wall clock time");
+		for a in [&["--time-format", "rfc3339", "client"][..], &["--time-format", "rfc3339", "server"]] {
+			assert_eq!(check_time_format(&parse(a).unwrap()), Ok(()));
+		}
 	}
-	Result::Ok(())
 }
@@ -1,18 +1,29 @@
-use crate::{set_rt_prio, ReceivedPacket, ECHO_FLAG, MIN_SIZE};
+use crate::{
+	header_length, hexdump, json_string, payload_sequence, recv_timestamps, recv_tos, set_rt_prio, socket_timeout,
+	stats::{ResourceUsage, RunUsage}, ControlMessages, Ecn, Flags, LogField, MemlockLimit, ReceivedPacket, Scheduling, SocketQueues,
+	LogFormat, TimeFormat, PROTOCOL_VERSION,
+	ECHO_FLAG, ECHO_SEQ_FLAG, ECHO_SEQ_STAMPED, MIN_SIZE
+};
 use nix::{
-	cmsg_space,
 	errno::Errno,
 	sys::{
-		mman,
 		resource,
-		socket::{self, SockaddrLike, SockaddrStorage},
-		time::TimeSpec
+		epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout},
+		eventfd::{EfdFlags, EventFd},
+		signal::{SigSet, Signal},
+		socket::{self, MultiHeaders, SockaddrLike, SockaddrStorage},
 	}
 };
 use std::{
-	io::{Error, ErrorKind, IoSlice, IoSliceMut},
-	os::fd::{AsRawFd, OwnedFd},
-	sync::{mpsc, Mutex}
+	collections::HashMap,
+	fmt::{self, Display, Formatter},
+	num::NonZeroUsize,
+	io::{self, BufWriter, Error, ErrorKind, IoSlice, IoSliceMut, Write},
+	net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+	os::fd::{AsFd, AsRawFd, OwnedFd, RawFd},
+	sync::{mpsc, Arc, Mutex, Weak},
+	thread,
+	time::{Duration, Instant}
 };
 
 
@@ -20,25 +31,162 @@ pub struct Server {
 	bind: SockaddrStorage,
 	buf_size: usize,
 	logger: Option<mpsc::Sender<ReceivedPacket>>,
-	sock: Option<OwnedFd>,
+	options: Options,
+	sock: Option<Arc<OwnedFd>>,
+}
+
+
+/// Optional settings for the server.
+#[derive(Clone, Debug)]
+pub struct Options {
+	/// Set SO_REUSEADDR on the server socket before binding, so a
+	/// restarted server can bind immediately. Note that on Linux this
+	/// also allows another socket with SO_REUSEADDR to bind the same
+	/// address and port while the server is running, which will then
+	/// receive (some of) the packets. Enabled by default.
+	pub reuse_addr: bool,
+	/// label to tag the run with in the summary
+	pub label: Option<String>,
+	/// if `Some`, write a hex dump of up to this many bytes of each
+	/// received packet to standard error, for debugging
+	pub hexdump: Option<usize>,
+	/// timestamp format for packets written to standard output
+	pub time_format: TimeFormat,
+	/// if `Some`, the columns of packet logs written to standard
+	/// output in this order, otherwise
+	/// [`LogFormat::default_fields`]
+	pub fields: Option<Vec<LogField>>,
+	/// format of packets written to standard output, time format
+	/// applies only to [`LogFormat::Tsv`], fields also to
+	/// [`LogFormat::Json`], which includes the label
+	pub log_format: LogFormat,
+	/// format of the summary at the end of the run
+	pub summary_format: SummaryFormat,
+	/// if `Some`, log only every n-th valid packet, to reduce the
+	/// cost per packet at high rates. All packets are still echoed
+	/// and counted.
+	pub sample: Option<NonZeroUsize>,
+	/// if `Some`, a source that sent no packets for this long is
+	/// considered done: its session summary is printed and its
+	/// state (including the echo sequence counter) is freed
+	pub session_timeout: Option<Duration>,
+	/// if `Some`, echo only packets whose sequence number differs
+	/// by at most this much from the highest one of the session of
+	/// the source, to limit what a reflector open to the public
+	/// sends for replayed or forged packets. Packets outside the
+	/// window are still logged, but do not count for the session.
+	/// The first packet of a session sets the reference. Only
+	/// applies together with `session_timeout`, which bounds the
+	/// state kept per source.
+	pub seq_window: Option<u32>,
+	/// if `Some`, write a JSON snapshot of the receive queue of each
+	/// socket to standard error at this interval, see
+	/// [`SocketQueues`]
+	pub stats_interval: Option<Duration>,
+	/// if `Some`, stop the server when no packet arrived for this
+	/// long, instead of waiting forever. Implemented with
+	/// `SO_RCVTIMEO` for a single socket, and the epoll timeout for
+	/// several.
+	pub recv_timeout: Option<Duration>,
+	/// if `Some`, give up on sending an echo that blocked for this
+	/// long (`SO_SNDTIMEO`), it is counted as failed
+	pub send_timeout: Option<Duration>,
+	/// do not warn if realtime priority or memory locking are not
+	/// permitted
+	pub quiet: bool,
+	/// compare the sequence number repeated in the payload (see
+	/// [`crate::payload_sequence`]) with the one in the header, and
+	/// count packets where they differ, e.g. because a middlebox
+	/// rewrote the header. Only for clients that write the marker.
+	pub check_payload_sequence: bool,
+	/// request hardware receive timestamps from the network
+	/// interface, see [`crate::TimestampSource::Hardware`]. Packets
+	/// the interface did not stamp get software timestamps.
+	pub hardware_timestamps: bool,
+	/// receive up to this many packets with one `recvmmsg` call in
+	/// [`Server::run`], 1 (the default) receives one packet per
+	/// `recvmsg` call. [`MultiServer`] always receives in batches.
+	pub recv_batch: usize,
+}
+
+impl Options {
+	fn fields(&self) -> &[LogField] {
+		self.fields.as_deref().unwrap_or(self.log_format.default_fields())
+	}
+
+	/// Control messages on server sockets: the receive timestamp
+	/// and TOS, see [`bind_socket`].
+	fn control_messages(&self) -> ControlMessages {
+		ControlMessages { hardware_timestamps: self.hardware_timestamps, tos: true, frag_size: false }
+	}
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Options {
+			reuse_addr: true,
+			label: None,
+			hexdump: None,
+			time_format: TimeFormat::Epoch,
+			fields: None,
+			log_format: LogFormat::Tsv,
+			summary_format: SummaryFormat::Text,
+			sample: None,
+			session_timeout: None,
+			seq_window: None,
+			stats_interval: None,
+			recv_timeout: None,
+			send_timeout: None,
+			quiet: false,
+			check_payload_sequence: false,
+			hardware_timestamps: false,
+			recv_batch: 1,
+		}
+	}
 }
 
 
+/// How the server reports the summary at the end of the run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SummaryFormat {
+	/// human readable lines on standard error
+	#[default]
+	Text,
+	/// a single line JSON object on standard output, after the
+	/// packet log (if written there)
+	Json,
+}
+
+
+/// Handle to stop a running server from another thread. The handle
+/// does not keep the socket alive: Once the server is dropped (and
+/// with it the socket), closing is a no-op.
 pub struct CloseHandle {
-	fd: Mutex<Option<i32>>
+	target: Mutex<Option<CloseTarget>>
+}
+
+
+/// What a [`CloseHandle`] acts on to stop the server.
+enum CloseTarget {
+	/// socket of a [`Server`], shut down to stop it
+	Socket(Weak<OwnedFd>),
+	/// eventfd the epoll loop of a [`MultiServer`] waits on
+	Wake(Weak<EventFd>),
 }
 
 
 impl Server {
 	pub fn new(
 		bind_addr: SockaddrStorage, buf_size: usize,
-		logger: Option<mpsc::Sender<ReceivedPacket>>)
+		logger: Option<mpsc::Sender<ReceivedPacket>>,
+		options: Options)
 		-> Self
 	{
 		Server {
 			bind: bind_addr,
 			buf_size,
 			logger,
+			options,
 			sock: None,
 		}
 	}
@@ -47,20 +195,47 @@ impl Server {
 	/// the bind address passed to Server::new(), this is where the
 	/// actual port is picked.
 	pub fn bind(&mut self) -> Result<CloseHandle, Errno> {
-		let sock = socket::socket(
-			self.bind.family().unwrap(),
-			socket::SockType::Datagram,
-			socket::SockFlag::empty(),
-			None
-		)?;
-		socket::setsockopt(&sock, socket::sockopt::ReceiveTimestampns, &true)?;
-		socket::bind(sock.as_raw_fd(), &self.bind)?;
-		self.bind = socket::getsockname::<SockaddrStorage>(sock.as_raw_fd())?;
-		let handle = CloseHandle::new(sock.as_raw_fd());
+		let (sock, bound) = bind_socket(&self.bind, &self.options)?;
+		self.bind = bound;
+		let sock = Arc::new(sock);
+		let handle = CloseHandle::new(&sock);
 		self.sock = Some(sock);
 		Ok(handle)
 	}
 
+	/// Create a server using an already bound socket, e.g. one
+	/// received through systemd socket activation or bound by a
+	/// privileged process. The socket must be a datagram socket,
+	/// otherwise `EPROTOTYPE` is returned. Because no bind step is
+	/// needed the close handle is returned directly.
+	pub fn from_fd(
+		sock: OwnedFd, buf_size: usize,
+		logger: Option<mpsc::Sender<ReceivedPacket>>,
+		options: Options)
+		-> Result<(Self, CloseHandle), Errno>
+	{
+		if socket::getsockopt(&sock, socket::sockopt::SockType)?
+			!= socket::SockType::Datagram
+		{
+			return Err(Errno::EPROTOTYPE);
+		}
+		recv_timestamps(&sock, options.hardware_timestamps)?;
+		let bind = socket::getsockname::<SockaddrStorage>(sock.as_raw_fd())?;
+		recv_tos(&sock, bind.family())?;
+		let sock = Arc::new(sock);
+		let handle = CloseHandle::new(&sock);
+		Ok((
+			Server {
+				bind,
+				buf_size,
+				logger,
+				options,
+				sock: Some(sock),
+			},
+			handle
+		))
+	}
+
 	/// If the server is bound to a port (after successful
 	/// Server::bind()), return the socket address of the server
 	/// socket.
@@ -72,95 +247,2192 @@ impl Server {
 		}
 	}
 
-	pub fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
-		let fd = if let Some(sock) = self.sock.as_ref() {
-			sock.as_raw_fd()
-		} else {
+	/// Receive packets until the socket is closed, and return the
+	/// summary of the run.
+	pub fn run(&self) -> Result<Summary, Box<dyn std::error::Error>> {
+		self.receive(None)
+	}
+
+	/// Like [`Server::run`], but call `f` in the receive loop for
+	/// every valid packet (including those not logged because of
+	/// [`Options::sample`]), so embedders can aggregate inline
+	/// without a channel. Packets are still sent to the logger
+	/// passed to [`Server::new`], if any, but not printed to standard
+	/// output. `f` delays receiving the next packet, keep it short.
+	///
+	/// ```
+	/// use luna_rs::{server::{Options, Server}, MIN_SIZE};
+	/// use std::{net::{SocketAddr, UdpSocket}, thread};
+	///
+	/// let bind: SocketAddr = "[::1]:0".parse()?;
+	/// let mut srv = Server::new(bind.into(), MIN_SIZE, None, Options::default());
+	/// let handle = srv.bind()?;
+	/// let target = srv.bound().unwrap().as_sockaddr_in6().unwrap().to_string();
+	/// let server = thread::spawn(move || {
+	///     let mut bytes = 0;
+	///     srv.run_with(|p| bytes += p.size).map(|_| bytes).map_err(|e| e.to_string())
+	/// });
+	///
+	/// let sock = UdpSocket::bind("[::1]:0")?;
+	/// sock.send_to(&[0u8; MIN_SIZE], &target)?;
+	/// # std::thread::sleep(std::time::Duration::from_millis(100));
+	/// handle.close()?;
+	/// assert_eq!(server.join().unwrap()?, MIN_SIZE);
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn run_with<F: FnMut(&ReceivedPacket)>(&self, mut f: F)
+		-> Result<Summary, Box<dyn std::error::Error>>
+	{
+		self.receive(Some(&mut f))
+	}
+
+	fn receive<'a>(&'a self, inspect: Option<&'a mut dyn FnMut(&ReceivedPacket)>)
+		-> Result<Summary, Box<dyn std::error::Error>>
+	{
+		let Some(sock) = self.sock.as_ref() else {
 			return Err(Box::new(Error::new(ErrorKind::NotConnected, "socket not bound")));
 		};
+		let fd = sock.as_raw_fd();
 
 		let flags = socket::MsgFlags::empty();
-		let mut buffer = vec![0u8; self.buf_size];
-		let mut cmsgspace = cmsg_space!(TimeSpec);
-		let mut iov = [IoSliceMut::new(&mut buffer)];
+		let batch = self.options.recv_batch.max(1);
+		let mut buffers = vec![vec![0u8; self.buf_size]; batch];
+		let mut cmsgspace = self.options.control_messages().buffer();
+		let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(
+			batch, Some(cmsgspace.clone()));
+		if let Some(t) = wake_interval(&self.options) {
+			socket::setsockopt(sock, socket::sockopt::ReceiveTimeout, &socket_timeout(t))?;
+		}
+		if let Some(t) = self.options.send_timeout {
+			socket::setsockopt(sock, socket::sockopt::SendTimeout, &socket_timeout(t))?;
+		}
+
+		serve(self.logger.as_ref(), inspect, &self.options, &[sock], |handler| {
+			if batch > 1 {
+				// MSG_WAITFORONE blocks (up to SO_RCVTIMEO) for the
+				// first packet only, then takes what is queued.
+				loop {
+					let mut iovs: Vec<[IoSliceMut; 1]> = buffers.iter_mut()
+						.map(|b| [IoSliceMut::new(b)])
+						.collect();
+					let received = match socket::recvmmsg(
+						fd, &mut headers, iovs.iter_mut(),
+						socket::MsgFlags::MSG_WAITFORONE, None)
+					{
+						Ok(r) => r,
+						Err(Errno::EAGAIN) => {
+							if handler.idle(Instant::now()) {
+								return Ok(());
+							}
+							continue;
+						},
+						Err(e) => return Err(e.into()),
+					};
+					*handler.recv_calls.get_or_insert(0) += 1;
+					let mut any = false;
+					for r in received {
+						// server socket has been closed
+						if r.bytes == 0 || !handler.packet(fd, r)? {
+							return Ok(());
+						}
+						any = true;
+					}
+					if !any {
+						return Ok(());
+					}
+				}
+			}
+			let mut iov = [IoSliceMut::new(&mut buffers[0])];
+			// One wakeup per packet. SO_RCVLOWAT cannot batch wakeups
+			// here: Linux ignores it for UDP sockets, both in recv and
+			// in poll, a datagram is always readable immediately.
+			loop {
+				let r = match socket::recvmsg::<socket::SockaddrStorage>(
+					fd, &mut iov, Some(&mut cmsgspace), flags)
+				{
+					Ok(r) => r,
+					Err(Errno::EAGAIN) => {
+						if handler.idle(Instant::now()) {
+							return Ok(());
+						}
+						continue;
+					},
+					Err(e) => return Err(e.into()),
+				};
+				if r.bytes == 0 {
+					// server socket has been closed
+					return Ok(());
+				}
+				if !handler.packet(fd, r)? {
+					return Ok(());
+				}
+			}
+		})
+	}
+
+	/// Run the bound server in a new thread, and return the stream
+	/// of packets it receives along with the thread, which returns
+	/// the summary. Packets go to the stream instead of any logger
+	/// passed to [`Server::new`]. Stop the server with the close
+	/// handle from [`Server::bind`], the stream ends after the
+	/// remaining packets have been read.
+	///
+	/// ```
+	/// use luna_rs::{server::{Options, Server}, MIN_SIZE};
+	/// use nix::sys::socket::SockaddrStorage;
+	/// use std::{net::{SocketAddr, UdpSocket}, time::Duration};
+	///
+	/// let bind: SocketAddr = "[::1]:0".parse()?;
+	/// let mut srv = Server::new(bind.into(), MIN_SIZE, None, Options::default());
+	/// let handle = srv.bind()?;
+	/// let target = srv.bound().unwrap().as_sockaddr_in6().unwrap().to_string();
+	/// let (mut packets, server) = srv.spawn()?;
+	///
+	/// let sock = UdpSocket::bind("[::1]:0")?;
+	/// for seq in 0u32..3 {
+	///     let mut packet = [0u8; MIN_SIZE];
+	///     packet[..4].copy_from_slice(&seq.to_be_bytes());
+	///     sock.send_to(&packet, &target)?;
+	/// }
+	/// let first = packets.recv_timeout(Duration::from_secs(5))?;
+	/// assert_eq!(first.sequence, 0);
+	/// let rest: Vec<u32> = packets.by_ref().take(2).map(|p| p.sequence).collect();
+	/// assert_eq!(rest, [1, 2]);
+	///
+	/// handle.close()?;
+	/// assert!(packets.next().is_none());
+	/// let summary = server.join().unwrap()?;
+	/// assert_eq!(summary.received, 3);
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn spawn(mut self)
+		-> Result<(PacketStream, thread::JoinHandle<Result<Summary, String>>), Error>
+	{
+		if self.sock.is_none() {
+			return Err(Error::new(ErrorKind::NotConnected, "socket not bound"));
+		}
+		let (sender, receiver) = mpsc::channel();
+		self.logger = Some(sender);
+		let server = thread::Builder::new()
+			.name("luna server".to_string())
+			.spawn(move || self.run().map_err(|e| e.to_string()))?;
+		Ok((PacketStream { receiver }, server))
+	}
+}
+
+
+/// Packets received by a server running in another thread, see
+/// [`Server::spawn`]. Iterating waits for the next packet, and ends
+/// once the server has stopped and all packets it received have been
+/// read.
+pub struct PacketStream {
+	receiver: mpsc::Receiver<ReceivedPacket>,
+}
+
+
+impl PacketStream {
+	/// Wait at most `timeout` for the next packet.
+	/// [`mpsc::RecvTimeoutError::Disconnected`] means the stream has
+	/// ended, like `None` from [`Iterator::next`].
+	pub fn recv_timeout(&self, timeout: Duration)
+		-> Result<ReceivedPacket, mpsc::RecvTimeoutError>
+	{
+		self.receiver.recv_timeout(timeout)
+	}
+}
+
+
+impl Iterator for PacketStream {
+	type Item = ReceivedPacket;
+
+	/// Wait for the next packet, returns `None` after the server has
+	/// stopped and all packets have been read.
+	fn next(&mut self) -> Option<Self::Item> {
+		self.receiver.recv().ok()
+	}
+}
+
 
-		if self.logger.is_none() {
-			println!("{}", ReceivedPacket::header());
+/// Number of packets to receive with one `recvmmsg` call in
+/// [`MultiServer::run`].
+const BATCH: usize = 16;
+
+/// Maximum number of distinct sources the server counts packets for,
+/// see [`Summary::sources`].
+const MAX_SOURCES: usize = 1 << 14;
+
+/// Number of sources listed in [`Summary::top_sources`].
+const TOP_SOURCES: usize = 5;
+
+
+/// Server listening on several addresses. Instead of a thread per
+/// socket, one thread waits for all sockets with epoll and reads the
+/// ready ones with `recvmmsg`. Packets are handled as by [`Server`],
+/// echoes are sent from the socket the packet arrived on, and all
+/// packets go to the same logger.
+pub struct MultiServer {
+	binds: Vec<SockaddrStorage>,
+	buf_size: usize,
+	logger: Option<mpsc::Sender<ReceivedPacket>>,
+	options: Options,
+	socks: Vec<OwnedFd>,
+	/// eventfd to wake up the epoll loop for shutdown, `Some` once
+	/// bound
+	wake: Option<Arc<EventFd>>,
+}
+
+
+impl MultiServer {
+	pub fn new(
+		bind_addrs: Vec<SockaddrStorage>, buf_size: usize,
+		logger: Option<mpsc::Sender<ReceivedPacket>>,
+		options: Options)
+		-> Self
+	{
+		MultiServer {
+			binds: bind_addrs,
+			buf_size,
+			logger,
+			options,
+			socks: Vec::new(),
+			wake: None,
 		}
+	}
+
+	/// Bind a socket to each of the configured addresses, see
+	/// [`Server::bind`].
+	pub fn bind(&mut self) -> Result<CloseHandle, Errno> {
+		let mut socks = Vec::with_capacity(self.binds.len());
+		let mut bound = Vec::with_capacity(self.binds.len());
+		for addr in &self.binds {
+			let (sock, b) = bind_socket(addr, &self.options)?;
+			socks.push(sock);
+			bound.push(b);
+		}
+		let wake = Arc::new(EventFd::from_flags(EfdFlags::EFD_CLOEXEC)?);
+		let handle = CloseHandle::wake(&wake);
+		self.binds = bound;
+		self.socks = socks;
+		self.wake = Some(wake);
+		Ok(handle)
+	}
 
-		crate::accept_noperm!(
-			crate::with_capability(
-				|| set_rt_prio(20),
-				caps::Capability::CAP_SYS_NICE),
-			"no permission to set realtime priority");
+	/// If the server is bound (after successful MultiServer::bind()),
+	/// return the socket addresses in the order they were given to
+	/// MultiServer::new().
+	pub fn bound(&self) -> Option<&[SockaddrStorage]> {
+		self.wake.as_ref().map(|_| self.binds.as_slice())
+	}
 
-		// Prevent swapping, if possible. Needs to be done as late as
-		// possible so all allocations needed for the loop are covered
-		// with MCL_CURRENT.
-		crate::accept_noperm!(
-			crate::with_capability(
-				|| mman::mlockall(mman::MlockAllFlags::MCL_CURRENT),
-				caps::Capability::CAP_IPC_LOCK),
-			"no permission to lock memory");
+	/// Receive packets on all sockets until the close handle is
+	/// used, and return the summary of the run.
+	pub fn run(&self) -> Result<Summary, Box<dyn std::error::Error>> {
+		self.receive(None)
+	}
 
-		caps::clear(None, caps::CapSet::Effective)?;
-		caps::clear(None, caps::CapSet::Permitted)?;
+	/// Like [`MultiServer::run`], but call `f` for every valid
+	/// packet, see [`Server::run_with`].
+	pub fn run_with<F: FnMut(&ReceivedPacket)>(&self, mut f: F)
+		-> Result<Summary, Box<dyn std::error::Error>>
+	{
+		self.receive(Some(&mut f))
+	}
+
+	fn receive<'a>(&'a self, inspect: Option<&'a mut dyn FnMut(&ReceivedPacket)>)
+		-> Result<Summary, Box<dyn std::error::Error>>
+	{
+		let Some(wake) = self.wake.as_ref() else {
+			return Err(Box::new(Error::new(ErrorKind::NotConnected, "sockets not bound")));
+		};
+		let ep = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+		for (i, sock) in self.socks.iter().enumerate() {
+			ep.add(sock, EpollEvent::new(EpollFlags::EPOLLIN, i as u64))?;
+		}
+		// any data value past the sockets means the eventfd
+		ep.add(wake.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, u64::MAX))?;
 
-		let rusage_pre = resource::getrusage(resource::UsageWho::RUSAGE_THREAD)?;
+		let mut events = vec![EpollEvent::empty(); self.socks.len() + 1];
+		let mut buffers = vec![vec![0u8; self.buf_size]; BATCH];
+		let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(
+			BATCH, Some(self.options.control_messages().buffer()));
 
-		loop {
-			let r = socket::recvmsg::<socket::SockaddrStorage>(fd, &mut iov, Some(&mut cmsgspace), flags)?;
-			if r.bytes == 0 {
-				// server socket has been closed
-				break;
+		let timeout = wake_interval(&self.options)
+			.map_or(EpollTimeout::NONE, |t| EpollTimeout::try_from(t)
+				.unwrap_or(EpollTimeout::MAX));
+		if let Some(t) = self.options.send_timeout {
+			for sock in &self.socks {
+				socket::setsockopt(sock, socket::sockopt::SendTimeout, &socket_timeout(t))?;
 			}
-			let data = r.iovs().next().unwrap();
+		}
 
-			// send echo if requested
-			if r.bytes >= MIN_SIZE && 0 != (data[20] & ECHO_FLAG) {
-				let iov = [IoSlice::new(data)];
-				socket::sendmsg(fd, &iov, &[], flags, r.address.as_ref())?;
+		let socks: Vec<&OwnedFd> = self.socks.iter().collect();
+		serve(self.logger.as_ref(), inspect, &self.options, &socks, |handler| {
+			loop {
+				let n = match ep.wait(&mut events, timeout) {
+					Ok(n) => n,
+					// a signal handler may have used the close handle
+					Err(Errno::EINTR) => continue,
+					Err(e) => return Err(e.into()),
+				};
+				if n == 0 && handler.idle(Instant::now()) {
+					return Ok(());
+				}
+				for event in &events[..n] {
+					let Some(sock) = self.socks.get(event.data() as usize) else {
+						// woken up by the close handle
+						return Ok(());
+					};
+					let fd = sock.as_raw_fd();
+					let mut iovs: Vec<[IoSliceMut; 1]> = buffers.iter_mut()
+						.map(|b| [IoSliceMut::new(b)])
+						.collect();
+					let received = match socket::recvmmsg(
+						fd, &mut headers, iovs.iter_mut(),
+						socket::MsgFlags::MSG_DONTWAIT, None)
+					{
+						Ok(r) => r,
+						Err(Errno::EAGAIN | Errno::EINTR) => continue,
+						Err(e) => return Err(e.into()),
+					};
+					*handler.recv_calls.get_or_insert(0) += 1;
+					// Unlike for a shut down socket in the single
+					// socket loop, an empty datagram carries no
+					// meaning here, skip it.
+					for r in received.filter(|r| r.bytes > 0) {
+						if !handler.packet(fd, r)? {
+							return Ok(());
+						}
+					}
+				}
 			}
+		})
+	}
+}
+
+
+/// How long the receive loops may block before they have to end idle
+/// sessions or check the receive timeout, `None` if neither is
+/// enabled.
+fn wake_interval(options: &Options) -> Option<Duration> {
+	let sessions = options.session_timeout.map(Sessions::check_interval);
+	match (sessions, options.recv_timeout) {
+		(Some(s), Some(r)) => Some(s.min(r)),
+		(s, r) => s.or(r),
+	}
+}
+
+
+/// Create a datagram socket with receive timestamps and TOS
+/// reporting enabled, and bind it to the address. Returns the socket
+/// and the address it is actually bound to.
+fn bind_socket(addr: &SockaddrStorage, options: &Options)
+	-> Result<(OwnedFd, SockaddrStorage), Errno>
+{
+	let sock = socket::socket(
+		addr.family().unwrap(),
+		socket::SockType::Datagram,
+		socket::SockFlag::empty(),
+		None
+	)?;
+	recv_timestamps(&sock, options.hardware_timestamps)?;
+	recv_tos(&sock, addr.family())?;
+	if options.reuse_addr {
+		socket::setsockopt(&sock, socket::sockopt::ReuseAddr, &true)?;
+	}
+	socket::bind(sock.as_raw_fd(), addr)?;
+	let bound = socket::getsockname::<SockaddrStorage>(sock.as_raw_fd())?;
+	Ok((sock, bound))
+}
+
+
+/// Start a thread that writes a JSON snapshot of the receive queues
+/// of the sockets to standard error at the interval, until the
+/// returned sender is dropped. The thread uses duplicates of the
+/// sockets, so they stay valid until it is done.
+fn queue_reporter(socks: &[&OwnedFd], interval: Duration, label: Option<String>)
+	-> Result<(mpsc::Sender<()>, thread::JoinHandle<()>), Error>
+{
+	let socks = socks.iter()
+		.map(|s| s.try_clone())
+		.collect::<Result<Vec<_>, _>>()?;
+	let (stop, stopped) = mpsc::channel::<()>();
+	let start = Instant::now();
+	let h = thread::Builder::new().name("stats reporter".to_string()).spawn(move || {
+		let label = label.as_deref().map(json_string)
+			.unwrap_or(String::from("null"));
+		while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+			let queues: Vec<Option<SocketQueues>> = socks.iter()
+				.map(|s| SocketQueues::read(s).ok())
+				.collect();
+			let list = |f: fn(&SocketQueues) -> usize| queues.iter()
+				.map(|q| q.as_ref().map_or(String::from("null"), |q| f(q).to_string()))
+				.collect::<Vec<_>>()
+				.join(", ");
+			eprintln!(
+				"{{\"label\": {label}, \"elapsed\": {:.9}, \"receive_queue\": [{}], \"receive_buffer\": [{}]}}",
+				start.elapsed().as_secs_f64(),
+				list(|q| q.receive), list(|q| q.receive_buffer));
+		}
+	})?;
+	Ok((stop, h))
+}
+
 
-			if let Ok(recv) = ReceivedPacket::try_from(r) {
-				if let Some(sender) = &self.logger {
-					if let Err(_) = sender.send(recv) {
-						// receiver hung up, no point in listening
-						break;
+/// Common frame of the receive loops: Print the log header if there
+/// is neither logger nor `inspect` function, start the queue
+/// reporter if requested, get realtime priority and lock memory if
+/// permitted, drop capabilities, run `receive` until it returns, and
+/// print the summary in the selected format.
+fn serve<'a>(
+	logger: Option<&'a mpsc::Sender<ReceivedPacket>>,
+	inspect: Option<&'a mut dyn FnMut(&ReceivedPacket)>,
+	options: &'a Options, socks: &[&OwnedFd],
+	receive: impl FnOnce(&mut Handler) -> Result<(), Box<dyn std::error::Error>>)
+	-> Result<Summary, Box<dyn std::error::Error>>
+{
+	if logger.is_none() && inspect.is_none() {
+		options.log_format.write_header(&mut io::stdout(), options.fields())?;
+	}
+	// stops when the sender is dropped, also on error
+	let reporter = options.stats_interval
+		.map(|i| queue_reporter(socks, i, options.label.clone()))
+		.transpose()?;
+
+	crate::accept_noperm!(
+		crate::with_capability(
+			|| set_rt_prio(20),
+			caps::Capability::CAP_SYS_NICE),
+		"no permission to set realtime priority", options.quiet);
+
+	// Prevent swapping, if possible. Needs to be done as late as
+	// possible so all allocations needed for the loop are covered
+	// with MCL_CURRENT.
+	let memlock = crate::lock_memory(options.quiet)?;
+
+	caps::clear(None, caps::CapSet::Effective)?;
+	caps::clear(None, caps::CapSet::Permitted)?;
+
+	let rusage_pre = resource::getrusage(resource::UsageWho::RUSAGE_THREAD)?;
+	let mut handler = Handler::new(logger, options);
+	handler.inspect = inspect;
+	receive(&mut handler)?;
+	handler.stdout.flush()?;
+	let rusage_post = resource::getrusage(resource::UsageWho::RUSAGE_THREAD)?;
+	let scheduling = Scheduling::current()?;
+	eprintln!("server shutting down");
+	if let Some((stop, h)) = reporter {
+		drop(stop);
+		if h.join().is_err() {
+			eprintln!("panic in stats reporter thread");
+		}
+	}
+	if let Some(s) = &handler.sessions {
+		for (address, session) in &s.active {
+			eprintln!("session {} active at shutdown: {session}", peer_name(address));
+		}
+	}
+	let summary = Summary {
+		label: options.label.clone(),
+		received: handler.received,
+		sample: options.sample.map_or(1, NonZeroUsize::get),
+		no_address: handler.no_address,
+		unsupported: handler.unsupported,
+		unknown_flags: handler.unknown_flags,
+		keepalives: handler.keepalives,
+		ect: handler.ect,
+		ce: handler.ce,
+		echo_errors: handler.echo_errors,
+		echoes_unstamped: handler.echoes_unstamped,
+		echoes_out_of_window: options.seq_window
+			.and(handler.sessions.as_ref())
+			.map(|_| handler.echoes_out_of_window),
+		sessions_timed_out: handler.sessions.as_ref().map(|s| s.timed_out),
+		sources: handler.sources.len(),
+		sources_limited: handler.sources_limited,
+		top_sources: handler.top_sources(),
+		recv_timed_out: handler.recv_timed_out,
+		payload_sequence: options.check_payload_sequence
+			.then_some((handler.payload_checked, handler.payload_mismatches)),
+		recv_calls: handler.recv_calls,
+		usage: RunUsage {
+			resources: ResourceUsage::between(&rusage_pre, &rusage_post),
+			scheduling,
+			memlock,
+		},
+	};
+	match options.summary_format {
+		SummaryFormat::Text => eprintln!("{summary}"),
+		SummaryFormat::Json => println!("{}", summary.json()),
+	}
+	Ok(summary)
+}
+
+
+/// Counters and resource usage of a finished server run.
+#[derive(Clone, Debug)]
+pub struct Summary {
+	/// label of the run, if set
+	pub label: Option<String>,
+	/// valid packets received, see `sample` for how many of them
+	/// were logged
+	pub received: usize,
+	/// only every n-th received packet was logged
+	pub sample: usize,
+	/// packets ignored because they had no source address
+	pub no_address: usize,
+	/// packets ignored because of an unsupported format version
+	pub unsupported: usize,
+	/// packets ignored because of flags their format version does
+	/// not define
+	pub unknown_flags: usize,
+	/// keepalive packets received, see [`crate::KEEPALIVE_VERSION`]
+	pub keepalives: usize,
+	/// ECN capable packets without CE mark
+	pub ect: usize,
+	/// CE marked packets
+	pub ce: usize,
+	/// echoes that could not be sent
+	pub echo_errors: usize,
+	/// echoes sent without echo sequence number because the server
+	/// already kept counters for [`MAX_SOURCES`] clients
+	pub echoes_unstamped: usize,
+	/// echoes not sent because the sequence number was outside
+	/// [`Options::seq_window`], `None` if the window did not apply
+	pub echoes_out_of_window: Option<usize>,
+	/// sessions ended by the idle timeout, `None` if
+	/// [`Options::session_timeout`] was not set
+	pub sessions_timed_out: Option<usize>,
+	/// distinct IP sources (address and port) valid packets were
+	/// received from
+	pub sources: usize,
+	/// more sources than the server keeps track of sent packets,
+	/// `sources` is a lower bound
+	pub sources_limited: bool,
+	/// address and packet count of the sources that sent the most
+	/// packets, most first
+	pub top_sources: Vec<(SocketAddr, usize)>,
+	/// the server stopped because no packet arrived within
+	/// [`Options::recv_timeout`]
+	pub recv_timed_out: bool,
+	/// packets large enough to carry a payload sequence number, and
+	/// how many of them had one different from the header, `None` if
+	/// [`Options::check_payload_sequence`] was not set
+	pub payload_sequence: Option<(usize, usize)>,
+	/// number of `recvmmsg` calls, if packets were received in
+	/// batches (see [`Options::recv_batch`])
+	pub recv_calls: Option<usize>,
+	/// resource usage and scheduling of the receive thread
+	pub usage: RunUsage,
+}
+
+
+impl Summary {
+	/// The summary as a single line JSON object, for tools that
+	/// collect the results of a run.
+	pub fn json(&self) -> String {
+		let r = &self.usage.resources;
+		format!(
+			concat!(
+				"{{\"label\": {}, \"received\": {}, \"sample\": {}, \"ignored_no_address\": {}, ",
+				"\"ignored_unsupported\": {}, \"ignored_unknown_flags\": {}, \"keepalives\": {}, ",
+				"\"ecn_capable\": {}, \"ce_marked\": {}, ",
+				"\"echo_errors\": {}, \"echoes_unstamped\": {}, \"echoes_out_of_window\": {}, \"sessions_timed_out\": {}, ",
+				"\"sources\": {}, \"sources_limited\": {}, \"top_sources\": [{}], ",
+				"\"receive_timed_out\": {}, ",
+				"\"payload_sequence_checked\": {}, \"payload_sequence_mismatches\": {}, ",
+				"\"receive_calls\": {}, ",
+				"\"user_time\": {:.6}, \"system_time\": {:.6}, ",
+				"\"major_page_faults\": {}, \"minor_page_faults\": {}, ",
+				"\"voluntary_context_switches\": {}, ",
+				"\"involuntary_context_switches\": {}, ",
+				"\"sched_policy\": {}, \"sched_priority\": {}, \"memlock_limit\": {}}}"),
+			self.label.as_deref().map(json_string)
+				.unwrap_or(String::from("null")),
+			self.received, self.sample, self.no_address, self.unsupported, self.unknown_flags,
+			self.keepalives,
+			self.ect + self.ce, self.ce, self.echo_errors, self.echoes_unstamped,
+			self.echoes_out_of_window.map_or(String::from("null"), |n| n.to_string()),
+			self.sessions_timed_out.map_or(String::from("null"), |n| n.to_string()),
+			self.sources, self.sources_limited,
+			self.top_sources.iter()
+				.map(|(a, n)| format!("{{\"source\": {}, \"packets\": {n}}}", json_string(&a.to_string())))
+				.collect::<Vec<_>>().join(", "),
+			self.recv_timed_out,
+			self.payload_sequence.map_or(String::from("null"), |(c, _)| c.to_string()),
+			self.payload_sequence.map_or(String::from("null"), |(_, m)| m.to_string()),
+			self.recv_calls.map_or(String::from("null"), |n| n.to_string()),
+			r.user_time.as_secs_f64(), r.system_time.as_secs_f64(),
+			r.major_page_faults, r.minor_page_faults,
+			r.voluntary_context_switches, r.involuntary_context_switches,
+			json_string(self.usage.scheduling.policy_name()),
+			self.usage.scheduling.priority,
+			match self.usage.memlock {
+				MemlockLimit::Bytes(b) => b.to_string(),
+				MemlockLimit::Unlimited => String::from("null"),
+			})
+	}
+}
+
+
+/// Counters of unusual events are only shown if non-zero.
+impl Display for Summary {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		if let Some(label) = &self.label {
+			writeln!(f, "run label: {label}")?;
+		}
+		if self.sample > 1 {
+			writeln!(
+				f, "logged 1 of every {} packets ({} of {})",
+				self.sample, self.received.div_ceil(self.sample), self.received)?;
+		}
+		if self.no_address > 0 {
+			writeln!(f, "ignored {} packets without source address", self.no_address)?;
+		}
+		if self.unsupported > 0 {
+			writeln!(f, "ignored {} packets with unsupported format version", self.unsupported)?;
+		}
+		if self.unknown_flags > 0 {
+			writeln!(f, "ignored {} packets with unknown flags", self.unknown_flags)?;
+		}
+		if self.keepalives > 0 {
+			writeln!(f, "received {} keepalive packets", self.keepalives)?;
+		}
+		if self.ect + self.ce > 0 {
+			writeln!(
+				f, "ECN: {} of {} ECN capable packets CE marked",
+				self.ce, self.ect + self.ce)?;
+		}
+		if self.echo_errors > 0 {
+			writeln!(f, "failed to send {} echoes", self.echo_errors)?;
+		}
+		if self.echoes_unstamped > 0 {
+			writeln!(
+				f, "sent {} echoes without echo sequence number (tracking limit reached)",
+				self.echoes_unstamped)?;
+		}
+		if let Some(n) = self.echoes_out_of_window.filter(|n| *n > 0) {
+			writeln!(f, "suppressed {n} echoes with sequence number outside the window")?;
+		}
+		if let Some(n) = self.sessions_timed_out {
+			writeln!(f, "{n} sessions ended by idle timeout")?;
+		}
+		if self.sources > 0 {
+			writeln!(
+				f, "distinct sources: {}{}", self.sources,
+				if self.sources_limited { " (tracking limit reached)" } else { "" })?;
+			let top: Vec<_> = self.top_sources.iter()
+				.map(|(a, n)| format!("{a} ({n} packets)"))
+				.collect();
+			writeln!(f, "busiest sources: {}", top.join(", "))?;
+		}
+		if self.recv_timed_out {
+			writeln!(f, "stopped by receive timeout")?;
+		}
+		if let Some((checked, mismatches)) = self.payload_sequence {
+			writeln!(
+				f, "payload sequence differs from header in {mismatches} of {checked} packets")?;
+		}
+		if let Some(n) = self.recv_calls {
+			writeln!(f, "received {} packets with {n} recvmmsg calls", self.received)?;
+		}
+		writeln!(f, "{}", self.usage.resources)?;
+		write!(f, "locked memory limit: {}", self.usage.memlock)
+	}
+}
+
+
+/// Processing of received packets shared by the receive loops: echo,
+/// logging, and the counters for the summary.
+struct Handler<'a> {
+	logger: Option<&'a mpsc::Sender<ReceivedPacket>>,
+	/// called for every valid packet, see [`Server::run_with`]
+	inspect: Option<&'a mut dyn FnMut(&ReceivedPacket)>,
+	options: &'a Options,
+	/// valid packets received, logged or not
+	received: usize,
+	/// packets received without source address
+	no_address: usize,
+	/// packets with unsupported format version
+	unsupported: usize,
+	/// packets with flags not defined in their format version
+	unknown_flags: usize,
+	/// keepalive packets
+	keepalives: usize,
+	/// next echo sequence number for each client
+	echo_seq: HashMap<SockaddrStorage, u32>,
+	/// echoes sent without echo sequence number because `echo_seq`
+	/// was full
+	echoes_unstamped: usize,
+	/// ECN capable packets
+	ect: usize,
+	/// CE marked packets
+	ce: usize,
+	/// echoes that could not be sent
+	echo_errors: usize,
+	/// echoes suppressed by [`Options::seq_window`]
+	echoes_out_of_window: usize,
+	/// per-source sessions, if the idle timeout is enabled
+	sessions: Option<Sessions>,
+	/// valid packets per IP source, for at most [`MAX_SOURCES`]
+	/// sources
+	sources: HashMap<SocketAddr, usize>,
+	/// packets from sources beyond [`MAX_SOURCES`] were not counted
+	sources_limited: bool,
+	/// time the most recent packet arrived, or the handler was
+	/// created
+	last_packet: Instant,
+	/// the server stopped because of [`Options::recv_timeout`]
+	recv_timed_out: bool,
+	/// packets with payload sequence number checked, and mismatches,
+	/// see [`Options::check_payload_sequence`]
+	payload_checked: usize,
+	payload_mismatches: usize,
+	/// `recvmmsg` calls, if the receive loop uses them
+	recv_calls: Option<usize>,
+	/// reply buffer for echoes of [`MIN_SIZE`] packets, see
+	/// [`echo_min_size`]
+	reply: [u8; MIN_SIZE],
+	/// packet log on standard output, if there is neither logger nor
+	/// inspect function
+	stdout: BufWriter<io::Stdout>,
+}
+
+
+impl<'a> Handler<'a> {
+	fn new(logger: Option<&'a mpsc::Sender<ReceivedPacket>>, options: &'a Options) -> Self {
+		Handler {
+			logger,
+			inspect: None,
+			options,
+			received: 0,
+			no_address: 0,
+			unsupported: 0,
+			unknown_flags: 0,
+			keepalives: 0,
+			echo_seq: HashMap::new(),
+			echoes_unstamped: 0,
+			reply: [0; MIN_SIZE],
+			ect: 0,
+			ce: 0,
+			echo_errors: 0,
+			echoes_out_of_window: 0,
+			sessions: options.session_timeout.map(Sessions::new),
+			sources: HashMap::new(),
+			sources_limited: false,
+			last_packet: Instant::now(),
+			recv_timed_out: false,
+			payload_checked: 0,
+			payload_mismatches: 0,
+			recv_calls: None,
+			stdout: BufWriter::new(io::stdout()),
+		}
+	}
+
+	/// Called when the receive loop woke up without packets: ends
+	/// idle sessions, and returns `true` if no packet arrived within
+	/// the receive timeout, so the server should stop.
+	fn idle(&mut self, now: Instant) -> bool {
+		self.end_idle_sessions(now);
+		let Some(t) = self.options.recv_timeout else {
+			return false;
+		};
+		if now.duration_since(self.last_packet) < t {
+			return false;
+		}
+		eprintln!("no packets received for {:.3}s, stopping", t.as_secs_f64());
+		self.recv_timed_out = true;
+		true
+	}
+
+	/// Count a valid packet from `source`. New sources are not
+	/// tracked any more once [`MAX_SOURCES`] are known, so a flood
+	/// of spoofed addresses cannot exhaust memory.
+	fn count_source(&mut self, source: &SockaddrStorage) {
+		let source = if let Some(a) = source.as_sockaddr_in6() {
+			SocketAddr::from(SocketAddrV6::from(*a))
+		} else if let Some(a) = source.as_sockaddr_in() {
+			SocketAddr::from(SocketAddrV4::from(*a))
+		} else {
+			return;
+		};
+		if let Some(n) = self.sources.get_mut(&source) {
+			*n += 1;
+		} else if self.sources.len() < MAX_SOURCES {
+			self.sources.insert(source, 1);
+		} else {
+			self.sources_limited = true;
+		}
+	}
+
+	/// Next echo sequence number for `address`. Like
+	/// [`Handler::count_source`] no new counters are created once
+	/// [`MAX_SOURCES`] exist, `None` means the echo goes out
+	/// unstamped.
+	fn next_echo_seq(&mut self, address: &SockaddrStorage) -> Option<u32> {
+		if let Some(counter) = self.echo_seq.get_mut(address) {
+			let seq = *counter;
+			*counter = counter.wrapping_add(1);
+			Some(seq)
+		} else if self.echo_seq.len() < MAX_SOURCES {
+			self.echo_seq.insert(*address, 1);
+			Some(0)
+		} else {
+			self.echoes_unstamped += 1;
+			None
+		}
+	}
+
+	/// The [`TOP_SOURCES`] sources that sent the most packets, most
+	/// packets first.
+	fn top_sources(&self) -> Vec<(SocketAddr, usize)> {
+		let mut top: Vec<_> = self.sources.iter().map(|(a, n)| (*a, *n)).collect();
+		top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+		top.truncate(TOP_SOURCES);
+		top
+	}
+
+	/// Whether `sequence` is within [`Options::seq_window`] of the
+	/// highest sequence number of the session of `source`. Always
+	/// true without window or session.
+	fn in_seq_window(&self, source: &SockaddrStorage, sequence: u32) -> bool {
+		let (Some(window), Some(s)) = (self.options.seq_window, self.sessions.as_ref()) else {
+			return true;
+		};
+		s.active.get(source)
+			.is_none_or(|session| session.max_seq.abs_diff(sequence) <= window)
+	}
+
+	/// End sessions that have been idle for longer than the
+	/// timeout: print their summary and free their state. Checks
+	/// only if the check interval has passed since the last check,
+	/// so calling this for every packet is cheap.
+	fn end_idle_sessions(&mut self, now: Instant) {
+		let Some(s) = self.sessions.as_mut() else {
+			return;
+		};
+		if now < s.next_check {
+			return;
+		}
+		s.next_check = now + Sessions::check_interval(s.timeout);
+		let timeout = s.timeout;
+		let mut ended = 0;
+		s.active.retain(|address, session| {
+			if now.duration_since(session.last) < timeout {
+				return true;
+			}
+			eprintln!("session {} ended: {session}", peer_name(address));
+			self.echo_seq.remove(address);
+			ended += 1;
+			false
+		});
+		s.timed_out += ended;
+	}
+
+	/// Handle a non-empty packet received on socket `fd`, echoes are
+	/// sent from the same socket. Returns `false` if the receiver of
+	/// the log hung up, then there is no point in listening.
+	fn packet(&mut self, fd: RawFd, r: socket::RecvMsg<'_, '_, SockaddrStorage>)
+		-> Result<bool, Box<dyn std::error::Error>>
+	{
+		let flags = socket::MsgFlags::empty();
+		self.last_packet = Instant::now();
+		// The kernel always reports the source of datagrams on an
+		// IPv4/IPv6 socket, but not e.g. for Unix datagram sockets
+		// with an unbound peer. Neither echo nor logging is possible
+		// without a source, so skip such packets.
+		let Some(address) = r.address else {
+			if self.no_address == 0 {
+				eprintln!("warning: ignoring packet(s) without source address");
+			}
+			self.no_address += 1;
+			return Ok(true);
+		};
+		let data = r.iovs().next().unwrap();
+		if let Some(n) = self.options.hexdump {
+			eprint!(
+				"received from {address} ({} bytes):\n{}",
+				r.bytes, hexdump(&data[..r.bytes], n));
+		}
+
+		let packet_flags = (r.bytes >= MIN_SIZE).then(|| Flags::from(data[20]));
+		// Keepalives only refresh the session of the client, if any,
+		// they are not test traffic.
+		if packet_flags.is_some_and(Flags::is_keepalive) {
+			self.keepalives += 1;
+			if let Some(s) = self.sessions.as_mut() {
+				s.touch(&address, Instant::now());
+			}
+			return Ok(true);
+		}
+		// Newer format versions may use header fields differently,
+		// neither echo nor log what might be misinterpreted.
+		if let Some(f) = packet_flags.filter(|f| f.version() > PROTOCOL_VERSION) {
+			if self.unsupported == 0 {
+				eprintln!(
+					"warning: ignoring packet(s) with unsupported format version {}",
+					f.version());
+			}
+			self.unsupported += 1;
+			return Ok(true);
+		}
+		// Same for flags the version of the packet does not define.
+		if let Some(f) = packet_flags.filter(|f| f.unknown() != 0) {
+			if self.unknown_flags == 0 {
+				eprintln!(
+					"warning: ignoring packet(s) with unknown flags {:#04x} for format version {}",
+					f.unknown(), f.version());
+			}
+			self.unknown_flags += 1;
+			return Ok(true);
+		}
+
+		// Packets too short for a sequence number are not valid
+		// either, the window does not matter for them.
+		let in_window = r.bytes < MIN_SIZE || self.in_seq_window(
+			&address, u32::from_be_bytes(data[..4].try_into().unwrap()));
+		// send echo if requested, unless the header is invalid or
+		// the sequence number outside the window
+		let echo = packet_flags
+			.filter(|f| f.contains(ECHO_FLAG))
+			.and_then(|f| Some((f, header_length(&data[..r.bytes]).ok()?)))
+			.filter(|_| {
+				if !in_window {
+					self.echoes_out_of_window += 1;
+				}
+				in_window
+			});
+		if let Some((packet_flags, header)) = echo {
+			// Reflect ECN capability so CE marks on the way back are
+			// visible to the client. The original codepoint of CE
+			// marked packets is unknown, use ECT(0) for those.
+			let tos = received_tos(&r)
+				.map(Ecn::from)
+				.filter(|e| *e != Ecn::NotEct)
+				.map(|e| if e == Ecn::Ce { Ecn::Ect0 } else { e } as u8);
+			let tclass = tos.map(libc::c_int::from).unwrap_or_default();
+			let tos = tos.unwrap_or_default();
+			let cmsg_v4 = [socket::ControlMessage::Ipv4Tos(&tos)];
+			let cmsg_v6 = [socket::ControlMessage::Ipv6TClass(&tclass)];
+			let cmsgs: &[socket::ControlMessage] = if tos == 0 {
+				&[]
+			} else if is_ipv4(&address) {
+				&cmsg_v4
+			} else {
+				&cmsg_v6
+			};
+			let echo_seq = packet_flags.fields_offset();
+			let sent = if header >= echo_seq + size_of::<u32>()
+				&& packet_flags.contains(ECHO_SEQ_FLAG)
+				&& let Some(seq) = self.next_echo_seq(&address)
+			{
+				// replace flags and echo sequence number, reflect the
+				// rest
+				let f = [packet_flags.with(ECHO_SEQ_STAMPED).bits()];
+				let s = seq.to_be_bytes();
+				let iov = [
+					IoSlice::new(&data[..20]), IoSlice::new(&f),
+					IoSlice::new(&data[MIN_SIZE..echo_seq]), IoSlice::new(&s),
+					IoSlice::new(&data[echo_seq + size_of::<u32>()..])];
+				socket::sendmsg(fd, &iov, cmsgs, flags, Some(&address))
+			} else if r.bytes == MIN_SIZE {
+				echo_min_size(fd, &mut self.reply, data, cmsgs, flags, &address)
+			} else {
+				echo_reflect(fd, data, cmsgs, flags, &address)
+			};
+			match sent {
+				// errors concerning the socket itself
+				Err(e @ (Errno::EBADF | Errno::ENOTSOCK | Errno::EFAULT)) =>
+					return Err(Box::new(e)),
+				// A client that went away or an unreachable
+				// destination must not stop the server for the other
+				// clients.
+				Err(e) => {
+					if self.echo_errors == 0 {
+						eprintln!(
+							"warning: could not send echo to {}: {e}",
+							peer_name(&address));
 					}
+					self.echo_errors += 1;
+				},
+				Ok(_) => (),
+			}
+		}
+
+		let payload_seq = self.options.check_payload_sequence
+			.then(|| payload_sequence(&data[..r.bytes]).ok().flatten())
+			.flatten();
+		if let Ok(recv) = ReceivedPacket::try_from(r) {
+			if let Some(s) = payload_seq {
+				self.payload_checked += 1;
+				if s != recv.sequence {
+					if self.payload_mismatches == 0 {
+						eprintln!(
+							"warning: payload sequence {s} differs from header sequence {} \
+							 in packet from {}", recv.sequence, peer_name(&address));
+					}
+					self.payload_mismatches += 1;
+				}
+			}
+			match recv.ecn() {
+				Some(Ecn::Ect0 | Ecn::Ect1) => self.ect += 1,
+				Some(Ecn::Ce) => self.ce += 1,
+				_ => (),
+			}
+			self.count_source(&recv.source);
+			if let Some(s) = self.sessions.as_mut() {
+				let now = Instant::now();
+				if in_window {
+					s.record(recv.source, recv.sequence, now);
 				} else {
-					println!("{recv}");
+					s.touch(&recv.source, now);
+				}
+				self.end_idle_sessions(now);
+			}
+			if let Some(f) = self.inspect.as_mut() {
+				f(&recv);
+			}
+			let sampled = self.received.is_multiple_of(self.options.sample.map_or(1, NonZeroUsize::get));
+			self.received += 1;
+			if !sampled {
+				return Ok(true);
+			}
+			if let Some(sender) = self.logger {
+				if let Err(_) = sender.send(recv) {
+					return Ok(false);
 				}
+			} else if self.inspect.is_none() {
+				let format = self.options.log_format;
+				format.write_packet(
+					&mut self.stdout, &recv, self.options.time_format, self.options.fields(),
+					self.options.label.as_deref())?;
+				format.flush_packet(&mut self.stdout)?;
 			}
 		}
-		let rusage_post = resource::getrusage(resource::UsageWho::RUSAGE_THREAD)?;
-		eprintln!("server shutting down");
-		eprintln!(
-			"major page faults: {}, minor page faults: {}",
-			rusage_post.major_page_faults() - rusage_pre.major_page_faults(),
-			rusage_post.minor_page_faults() - rusage_pre.minor_page_faults()
-		);
-		Ok(())
+		Ok(true)
+	}
+}
+
+
+/// Packets received from one source since its first packet, or since
+/// its previous session ended.
+#[derive(Clone, Debug)]
+struct Session {
+	/// time of the first packet
+	start: Instant,
+	/// time of the most recent packet
+	last: Instant,
+	/// valid packets received
+	packets: usize,
+	/// sequence number of the first packet
+	first_seq: u32,
+	/// highest sequence number received
+	max_seq: u32,
+}
+
+
+impl Session {
+	fn new(sequence: u32, now: Instant) -> Self {
+		Session { start: now, last: now, packets: 1, first_seq: sequence, max_seq: sequence }
+	}
+
+	fn record(&mut self, sequence: u32, now: Instant) {
+		self.last = now;
+		self.packets += 1;
+		self.max_seq = self.max_seq.max(sequence);
+	}
+
+	/// Packets missing between the first and the highest sequence
+	/// number. Loss before the first packet of the session is
+	/// unknown, duplicates hide loss.
+	fn lost(&self) -> usize {
+		((self.max_seq - self.first_seq) as usize + 1).saturating_sub(self.packets)
+	}
+}
+
+
+impl Display for Session {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(
+			f, "{} packets, {} lost, {:.3}s",
+			self.packets, self.lost(), (self.last - self.start).as_secs_f64())
+	}
+}
+
+
+/// Sessions of all active sources, see [`Options::session_timeout`].
+#[derive(Debug)]
+struct Sessions {
+	timeout: Duration,
+	active: HashMap<SockaddrStorage, Session>,
+	/// earliest time to check for idle sessions again
+	next_check: Instant,
+	/// sessions ended by the timeout so far
+	timed_out: usize,
+}
+
+
+impl Sessions {
+	fn new(timeout: Duration) -> Self {
+		Sessions {
+			timeout,
+			active: HashMap::new(),
+			next_check: Instant::now() + Self::check_interval(timeout),
+			timed_out: 0,
+		}
+	}
+
+	/// How often to check for idle sessions: A session ends at most
+	/// half the timeout late.
+	fn check_interval(timeout: Duration) -> Duration {
+		(timeout / 2).max(Duration::from_millis(1))
+	}
+
+	fn record(&mut self, source: SockaddrStorage, sequence: u32, now: Instant) {
+		self.active.entry(source)
+			.and_modify(|s| s.record(sequence, now))
+			.or_insert_with(|| Session::new(sequence, now));
+	}
+
+	/// Keep an active session from timing out without counting a
+	/// packet, e.g. for a keepalive.
+	fn touch(&mut self, source: &SockaddrStorage, now: Instant) {
+		if let Some(s) = self.active.get_mut(source) {
+			s.last = now;
+		}
+	}
+}
+
+
+/// Echo a packet of exactly [`MIN_SIZE`] bytes, the common header
+/// only case, from the fixed size `reply` buffer.
+fn echo_min_size(
+	fd: RawFd, reply: &mut [u8; MIN_SIZE], data: &[u8],
+	cmsgs: &[socket::ControlMessage], flags: socket::MsgFlags,
+	address: &SockaddrStorage)
+	-> Result<usize, Errno>
+{
+	reply.copy_from_slice(data);
+	socket::sendmsg(fd, &[IoSlice::new(reply)], cmsgs, flags, Some(address))
+}
+
+
+/// Echo a packet of any size by reflecting the receive buffer as
+/// is, without copying.
+fn echo_reflect(
+	fd: RawFd, data: &[u8], cmsgs: &[socket::ControlMessage],
+	flags: socket::MsgFlags, address: &SockaddrStorage)
+	-> Result<usize, Errno>
+{
+	socket::sendmsg(fd, &[IoSlice::new(data)], cmsgs, flags, Some(address))
+}
+
+
+/// TOS / traffic class byte of a received packet, if reported.
+fn received_tos(r: &socket::RecvMsg<'_, '_, SockaddrStorage>) -> Option<u8> {
+	r.cmsgs().ok()?.find_map(|c| match c {
+		socket::ControlMessageOwned::Ipv4Tos(t) => Some(t),
+		socket::ControlMessageOwned::Ipv6TClass(t) => Some(t as u8),
+		_ => None,
+	})
+}
+
+
+/// Check if the address is IPv4, including IPv4-mapped IPv6.
+fn is_ipv4(address: &SockaddrStorage) -> bool {
+	address.as_sockaddr_in().is_some()
+		|| address.as_sockaddr_in6()
+			.is_some_and(|a| a.ip().to_ipv4_mapped().is_some())
+}
+
+
+/// Describe a peer address for messages. Only IP addresses are
+/// formatted, formatting other addresses received from the kernel
+/// (e.g. Unix socket paths) panics in nix.
+fn peer_name(address: &SockaddrStorage) -> String {
+	if let Some(a) = address.as_sockaddr_in6() {
+		a.to_string()
+	} else if let Some(a) = address.as_sockaddr_in() {
+		a.to_string()
+	} else {
+		match address.family() {
+			Some(f) => format!("{f:?} peer"),
+			None => String::from("unknown peer"),
+		}
 	}
 }
 
 
 impl CloseHandle {
-	pub fn new(fd: i32) -> Self {
+	pub fn new(sock: &Arc<OwnedFd>) -> Self {
 		CloseHandle {
-			fd: Mutex::new(Some(fd))
+			target: Mutex::new(Some(CloseTarget::Socket(Arc::downgrade(sock))))
 		}
 	}
 
+	fn wake(event: &Arc<EventFd>) -> Self {
+		CloseHandle {
+			target: Mutex::new(Some(CloseTarget::Wake(Arc::downgrade(event))))
+		}
+	}
+
+	/// Shut down the server socket (or wake up the epoll loop of a
+	/// [`MultiServer`]), which makes a running server return. Does
+	/// nothing if the handle has already been used or the server has
+	/// been dropped.
 	pub fn close(&self) -> Result<(), Errno> {
-		let mut f = self.fd.lock().unwrap();
-		match &f.take() {
+		let mut f = self.target.lock().unwrap();
+		// Holding the upgraded reference keeps the descriptor open
+		// (and thus not reusable) until shutdown has returned.
+		match f.take() {
 			None => Ok(()),
-			Some(fd) => match socket::shutdown(*fd, socket::Shutdown::Both).err() {
+			Some(CloseTarget::Socket(w)) => match w.upgrade()
+				.map(|sock| socket::shutdown(sock.as_raw_fd(), socket::Shutdown::Both))
+			{
+				None | Some(Ok(())) => Ok(()),
+				Some(Err(Errno::ENOTCONN)) => Ok(()),
+				Some(Err(e)) => Err(e),
+			},
+			Some(CloseTarget::Wake(w)) => match w.upgrade() {
 				None => Ok(()),
-				Some(Errno::ENOTCONN) => Ok(()),
-				Some(e) => return Err(e),
+				Some(event) => event.write(1).map(|_| ()),
+			},
+		}
+	}
+}
+
+
+/// Start a thread that waits for one of `signals` and then closes
+/// the server using `handle`, so the run ends with the usual summary.
+/// The thread returns the signal received. The signals must be blocked
+/// in all threads (do it at the start of `main`, before creating
+/// other threads, they inherit the signal mask), the thread accepts
+/// them with sigwait. No code runs in signal handler context, which
+/// avoids the restrictions on async-signal-safe functions.
+pub fn close_on_signal(handle: CloseHandle, signals: SigSet)
+	-> Result<thread::JoinHandle<Option<Signal>>, Error>
+{
+	thread::Builder::new().name("signal wait".to_string()).spawn(move || {
+		let signal = signals.wait()
+			.inspect_err(|e| eprintln!("could not wait for signals: {e}"))
+			.ok()?;
+		eprintln!("received {signal}, stopping server");
+		if let Err(e) = handle.close() {
+			eprintln!("could not close server: {e}");
+		}
+		Some(signal)
+	})
+}
+
+
+/// A server running in the background for a limited time, collecting
+/// received packets. Packets can be read as they arrive by iterating
+/// over the Capture, or all at once with [`Capture::collect_all`].
+/// The server stops when the duration expires, the Capture is
+/// stopped, or it is dropped.
+pub struct Capture {
+	bound: SockaddrStorage,
+	packets: PacketStream,
+	stop: Option<mpsc::Sender<()>>,
+	server: Option<thread::JoinHandle<Result<Summary, String>>>,
+}
+
+
+impl Capture {
+	/// Bind a server to the given address and start capturing for
+	/// the given duration.
+	pub fn start(
+		bind_addr: SockaddrStorage, buf_size: usize, duration: Duration,
+		options: Options)
+		-> Result<Self, Box<dyn std::error::Error>>
+	{
+		let mut srv = Server::new(bind_addr, buf_size, None, options);
+		let handle = srv.bind()?;
+		let bound = *srv.bound().unwrap();
+		let (packets, server) = srv.spawn()?;
+		// close the server after the duration, or when the stop
+		// sender is dropped
+		let (stop, stopped) = mpsc::channel::<()>();
+		thread::Builder::new()
+			.name("capture timer".to_string())
+			.spawn(move || {
+				let _ = stopped.recv_timeout(duration);
+				if let Err(e) = handle.close() {
+					eprintln!("could not close capture server: {e}");
+				}
+			})?;
+		Ok(Capture {
+			bound,
+			packets,
+			stop: Some(stop),
+			server: Some(server),
+		})
+	}
+
+	/// The socket address the server is bound to.
+	pub fn bound(&self) -> &SockaddrStorage {
+		&self.bound
+	}
+
+	/// Stop the server (if still running) and wait for it to
+	/// finish. Packets received before remain available.
+	pub fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		self.stop.take();
+		match self.server.take().map(|t| t.join()) {
+			None => Ok(()),
+			Some(Err(_)) => Err("panic in capture server thread".into()),
+			Some(Ok(r)) => r.map(|_| ()).map_err(Into::into),
+		}
+	}
+
+	/// Wait until the capture ends and return the received packets,
+	/// at most `limit`. If the limit is reached the capture stops
+	/// early.
+	pub fn collect_all(mut self, limit: usize)
+		-> Result<Vec<ReceivedPacket>, Box<dyn std::error::Error>>
+	{
+		let packets = self.by_ref().take(limit).collect();
+		self.stop()?;
+		Ok(packets)
+	}
+}
+
+
+impl Iterator for Capture {
+	type Item = ReceivedPacket;
+
+	/// Wait for the next packet, returns `None` after the capture
+	/// has ended and all packets have been read.
+	fn next(&mut self) -> Option<Self::Item> {
+		self.packets.next()
+	}
+}
+
+
+impl Drop for Capture {
+	fn drop(&mut self) {
+		if let Err(e) = self.stop() {
+			eprintln!("capture server failed: {e}");
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{CLOCK_SIZE, CLOCK_VERSION, ECHO_SEQ_SIZE, HEADER_LENGTH_VERSION, SendClock};
+	use nix::{cmsg_space, sys::time::TimeSpec};
+	use std::net::{SocketAddrV6, UdpSocket};
+
+	/// Send `count` minimum size packets to the given address.
+	fn send_packets(target: &SockaddrStorage, count: u32) -> std::io::Result<()> {
+		let sock = UdpSocket::bind("[::1]:0")?;
+		let target = target.as_sockaddr_in6().unwrap().to_string();
+		let mut packet = [0u8; MIN_SIZE];
+		for seq in 0..count {
+			packet[..4].copy_from_slice(&seq.to_be_bytes());
+			sock.send_to(&packet, &target)?;
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn capture_collect() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let capture = Capture::start(
+			bind_addr, MIN_SIZE, Duration::from_millis(200), Options::default())?;
+		send_packets(capture.bound(), 5)?;
+		let packets = capture.collect_all(100)?;
+		assert_eq!(packets.len(), 5);
+		for (i, p) in packets.iter().enumerate() {
+			assert_eq!(p.sequence, i as u32);
+		}
+
+		// the limit stops the capture early
+		let capture = Capture::start(
+			bind_addr, MIN_SIZE, Duration::from_secs(60), Options::default())?;
+		send_packets(capture.bound(), 5)?;
+		assert_eq!(capture.collect_all(3)?.len(), 3);
+		Ok(())
+	}
+
+	#[test]
+	fn capture_stream() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let mut capture = Capture::start(
+			bind_addr, MIN_SIZE, Duration::from_millis(100), Options::default())?;
+		send_packets(capture.bound(), 3)?;
+		assert_eq!(capture.next().map(|p| p.sequence), Some(0));
+		assert_eq!(capture.next().map(|p| p.sequence), Some(1));
+		assert_eq!(capture.next().map(|p| p.sequence), Some(2));
+		// iteration ends when the duration expires
+		assert_eq!(capture.next(), None);
+		Ok(())
+	}
+
+	#[test]
+	fn spawn_stream() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let srv = Server::new(bind_addr, MIN_SIZE, None, Options::default());
+		assert_eq!(srv.spawn().err().map(|e| e.kind()), Some(ErrorKind::NotConnected));
+
+		let mut srv = Server::new(bind_addr, MIN_SIZE, None, Options::default());
+		let handle = srv.bind()?;
+		let target = *srv.bound().unwrap();
+		let (packets, server) = srv.spawn()?;
+		assert_eq!(
+			packets.recv_timeout(Duration::from_millis(10)).unwrap_err(),
+			mpsc::RecvTimeoutError::Timeout);
+		send_packets(&target, 4)?;
+		let mut packets = packets.take(4);
+		assert_eq!(packets.next().map(|p| p.sequence), Some(0));
+		assert_eq!(packets.map(|p| p.sequence).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+		let mut srv = Server::new(bind_addr, MIN_SIZE, None, Options::default());
+		let handle2 = srv.bind()?;
+		let (packets, server2) = srv.spawn()?;
+		handle2.close()?;
+		// the stream ends when the server stops
+		assert_eq!(
+			packets.recv_timeout(Duration::from_secs(5)).unwrap_err(),
+			mpsc::RecvTimeoutError::Disconnected);
+		assert_eq!(server2.join().unwrap()?.received, 0);
+
+		handle.close()?;
+		assert_eq!(server.join().unwrap()?.received, 4);
+		Ok(())
+	}
+
+	#[test]
+	fn from_fd() -> Result<(), Box<dyn std::error::Error>> {
+		let sock = UdpSocket::bind("[::1]:0")?;
+		let addr = SockaddrStorage::from(sock.local_addr()?);
+		let (sender, receiver) = mpsc::channel();
+		let (srv, handle) = Server::from_fd(
+			OwnedFd::from(sock), MIN_SIZE, Some(sender), Options::default())?;
+		assert_eq!(srv.bound(), Some(&addr));
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+		send_packets(&addr, 2)?;
+		assert_eq!(receiver.recv()?.sequence, 0);
+		assert_eq!(receiver.recv()?.sequence, 1);
+		handle.close()?;
+		sh.join().unwrap()?;
+
+		// stream sockets are rejected
+		let listener = std::net::TcpListener::bind("[::1]:0")?;
+		assert_eq!(
+			Server::from_fd(
+				OwnedFd::from(listener), MIN_SIZE, None, Options::default()).err(),
+			Some(Errno::EPROTOTYPE));
+		Ok(())
+	}
+
+	#[test]
+	fn echo_send_error() -> Result<(), Box<dyn std::error::Error>> {
+		use std::os::unix::net::UnixDatagram;
+		let dir = std::env::temp_dir()
+			.join(format!("luna-echo-error-{}", std::process::id()));
+		std::fs::create_dir(&dir)?;
+		let sock = UnixDatagram::bind(dir.join("server"))?;
+		let (sender, receiver) = mpsc::channel();
+		let (srv, handle) = Server::from_fd(
+			OwnedFd::from(sock), MIN_SIZE, Some(sender), Options::default())?;
+
+		let mut packet = [0u8; MIN_SIZE];
+		packet[20] = ECHO_FLAG;
+		// the client is gone before the server tries to echo
+		let gone = UnixDatagram::bind(dir.join("gone"))?;
+		gone.send_to(&packet, dir.join("server"))?;
+		drop(gone);
+		std::fs::remove_file(dir.join("gone"))?;
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		// the server keeps running and receives from the next client
+		let client = UnixDatagram::bind(dir.join("client"))?;
+		packet[0] = 1;
+		client.send_to(&packet, dir.join("server"))?;
+		assert_eq!(receiver.recv()?.sequence, 0);
+		assert_eq!(receiver.recv()?.sequence, 1 << 24);
+
+		handle.close()?;
+		sh.join().unwrap()?;
+		std::fs::remove_dir_all(&dir)?;
+		Ok(())
+	}
+
+	#[test]
+	fn multi_epoll() -> Result<(), Box<dyn std::error::Error>> {
+		let binds = ["127.0.0.1:0", "127.0.0.2:0", "[::1]:0"]
+			.iter()
+			.map(|a| a.parse::<std::net::SocketAddr>().map(SockaddrStorage::from))
+			.collect::<Result<Vec<_>, _>>()?;
+		let (sender, receiver) = mpsc::channel();
+		let mut srv = MultiServer::new(binds, 64, Some(sender), Options::default());
+		assert!(srv.bound().is_none());
+		let handle = srv.bind()?;
+		let targets: Vec<String> = srv.bound().unwrap().iter()
+			.map(|a| a.as_sockaddr_in().map(|a| a.to_string())
+				.or(a.as_sockaddr_in6().map(|a| a.to_string()))
+				.unwrap())
+			.collect();
+		assert_eq!(targets.len(), 3);
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		let v4 = UdpSocket::bind("127.0.0.1:0")?;
+		let v6 = UdpSocket::bind("[::1]:0")?;
+		v6.set_read_timeout(Some(Duration::from_secs(1)))?;
+		let mut packet = [0u8; MIN_SIZE];
+		for (seq, target) in targets.iter().enumerate() {
+			packet[..4].copy_from_slice(&(seq as u32).to_be_bytes());
+			let sock = if target.starts_with('[') { &v6 } else { &v4 };
+			// several packets per socket to fill a recvmmsg batch
+			for _ in 0..4 {
+				sock.send_to(&packet, target)?;
+			}
+		}
+		// echoes are sent from the socket the packet arrived on
+		packet[20] = ECHO_FLAG;
+		v6.send_to(&packet, &targets[2])?;
+		let mut echo = [0u8; 64];
+		let (len, from) = v6.recv_from(&mut echo)?;
+		assert_eq!(echo[..len], packet);
+		assert_eq!(from.to_string(), targets[2]);
+
+		let mut seqs: Vec<u32> = receiver.iter().take(13).map(|p| p.sequence).collect();
+		seqs.sort();
+		assert_eq!(seqs, [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 2]);
+
+		// the eventfd wakes up the epoll loop
+		handle.close()?;
+		sh.join().unwrap()?;
+		assert!(receiver.recv().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn sample() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let (sender, receiver) = mpsc::channel();
+		let mut srv = Server::new(bind_addr, MIN_SIZE, Some(sender), Options {
+			sample: NonZeroUsize::new(4),
+			..Default::default()
+		});
+		let handle = srv.bind()?;
+		let target = *srv.bound().unwrap();
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+		send_packets(&target, 22)?;
+		let logged: Vec<u32> = receiver.iter().take(6).map(|p| p.sequence).collect();
+		assert_eq!(logged, [0, 4, 8, 12, 16, 20]);
+		handle.close()?;
+		let summary = sh.join().unwrap()?;
+		assert_eq!((summary.received, summary.sample), (22, 4));
+		assert!(receiver.recv().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn session_timeout() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let options = Options {
+			session_timeout: Some(Duration::from_millis(100)),
+			..Default::default()
+		};
+		for multi in [false, true] {
+			type Run = Box<dyn FnOnce() -> Result<Summary, String> + Send>;
+			let (handle, target, run): (_, _, Run) = if multi {
+				let mut srv = MultiServer::new(vec![bind_addr], MIN_SIZE, None, options.clone());
+				let handle = srv.bind()?;
+				let target = srv.bound().unwrap()[0];
+				(handle, target, Box::new(move || srv.run().map_err(|e| e.to_string())))
+			} else {
+				let mut srv = Server::new(bind_addr, MIN_SIZE, None, options.clone());
+				let handle = srv.bind()?;
+				let target = *srv.bound().unwrap();
+				(handle, target, Box::new(move || srv.run().map_err(|e| e.to_string())))
+			};
+			let sh = thread::spawn(run);
+
+			let sock = UdpSocket::bind("[::1]:0")?;
+			let target = target.as_sockaddr_in6().unwrap().to_string();
+			let mut packet = [0u8; MIN_SIZE];
+			for seq in [0u32, 1, 3] {
+				packet[..4].copy_from_slice(&seq.to_be_bytes());
+				sock.send_to(&packet, &target)?;
+			}
+			// no packets arrive, the session must end anyway
+			thread::sleep(Duration::from_millis(300));
+			handle.close()?;
+			let summary = sh.join().unwrap()?;
+			assert_eq!(summary.received, 3);
+			assert_eq!(summary.sessions_timed_out, Some(1), "multi: {multi}");
+		}
+
+		let now = Instant::now();
+		let mut session = Session::new(5, now);
+		for seq in [6, 9, 7] {
+			session.record(seq, now + Duration::from_millis(seq as u64));
+		}
+		assert_eq!((session.packets, session.lost()), (4, 1));
+		assert_eq!(session.to_string(), "4 packets, 1 lost, 0.007s");
+		Ok(())
+	}
+
+	#[test]
+	fn seq_window() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let options = Options {
+			session_timeout: Some(Duration::from_secs(10)),
+			seq_window: Some(10),
+			..Default::default()
+		};
+		let mut srv = Server::new(bind_addr, MIN_SIZE, None, options);
+		let handle = srv.bind()?;
+		let target = srv.bound().unwrap().as_sockaddr_in6().unwrap().to_string();
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		let sock = UdpSocket::bind("[::1]:0")?;
+		sock.set_read_timeout(Some(Duration::from_millis(200)))?;
+		let mut packet = [0u8; MIN_SIZE];
+		packet[20] = ECHO_FLAG;
+		let mut echo = [0u8; MIN_SIZE];
+		// the out of window packet does not move the window, so the
+		// following ones are still echoed
+		for (seq, echoed) in [(0u32, true), (1, true), (1000, false), (11, true), (3, true)] {
+			packet[..4].copy_from_slice(&seq.to_be_bytes());
+			sock.send_to(&packet, &target)?;
+			match sock.recv(&mut echo) {
+				Ok(_) => assert_eq!(echo[..4], seq.to_be_bytes()),
+				Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+			}
+			assert_eq!(echo[..4] == seq.to_be_bytes(), echoed, "sequence {seq}");
+		}
+		handle.close()?;
+		let summary = sh.join().unwrap()?;
+		assert_eq!(summary.received, 5);
+		assert_eq!(summary.echoes_out_of_window, Some(1));
+		assert!(summary.to_string().contains(
+			"suppressed 1 echoes with sequence number outside the window\n"));
+		assert!(summary.json().contains(r#""echoes_out_of_window": 1, "#));
+		Ok(())
+	}
+
+	#[test]
+	fn recv_timeout() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let options = Options {
+			recv_timeout: Some(Duration::from_millis(100)),
+			send_timeout: Some(Duration::from_millis(100)),
+			..Default::default()
+		};
+		for multi in [false, true] {
+			let (done, finished) = mpsc::channel();
+			// the close handle is kept, but never used
+			let _handle = if multi {
+				let mut srv = MultiServer::new(vec![bind_addr], MIN_SIZE, None, options.clone());
+				let handle = srv.bind()?;
+				thread::spawn(move || done.send(srv.run().map_err(|e| e.to_string())).unwrap());
+				handle
+			} else {
+				let mut srv = Server::new(bind_addr, MIN_SIZE, None, options.clone());
+				let handle = srv.bind()?;
+				thread::spawn(move || done.send(srv.run().map_err(|e| e.to_string())).unwrap());
+				handle
+			};
+			// no traffic, the server must stop on its own
+			let summary = finished.recv_timeout(Duration::from_secs(5))??;
+			assert!(summary.recv_timed_out, "multi: {multi}");
+			assert_eq!(summary.received, 0);
+			assert!(summary.to_string().contains("stopped by receive timeout\n"));
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn distinct_sources() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let mut srv = Server::new(bind_addr, MIN_SIZE, None, Options::default());
+		let handle = srv.bind()?;
+		let target = srv.bound().unwrap().as_sockaddr_in6().unwrap().to_string();
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		let socks = [UdpSocket::bind("[::1]:0")?, UdpSocket::bind("[::1]:0")?, UdpSocket::bind("[::1]:0")?];
+		let packet = [0u8; MIN_SIZE];
+		for (i, sock) in socks.iter().enumerate() {
+			for _ in 0..=i {
+				sock.send_to(&packet, &target)?;
 			}
 		}
+		thread::sleep(Duration::from_millis(100));
+		handle.close()?;
+		let summary = sh.join().unwrap()?;
+		assert_eq!(summary.received, 6);
+		assert_eq!(summary.sources, 3);
+		assert!(!summary.sources_limited);
+		let busiest = socks[2].local_addr()?;
+		assert_eq!(summary.top_sources[0], (busiest, 3));
+		assert_eq!(
+			summary.top_sources.iter().map(|(_, n)| *n).collect::<Vec<_>>(),
+			vec![3, 2, 1]);
+		assert!(summary.json().contains(
+			&format!("\"top_sources\": [{{\"source\": \"{busiest}\", \"packets\": 3}}, ")));
+		assert!(summary.to_string().contains("distinct sources: 3\n"));
+
+		// new sources beyond the limit are not tracked
+		let options = Options::default();
+		let mut handler = Handler::new(None, &options);
+		for port in 0..=MAX_SOURCES {
+			let a = SocketAddrV6::new(std::net::Ipv6Addr::LOCALHOST, port as u16, 0, 0);
+			handler.count_source(&SockaddrStorage::from(a));
+		}
+		assert_eq!(handler.sources.len(), MAX_SOURCES);
+		assert!(handler.sources_limited);
+		assert_eq!(handler.top_sources().len(), TOP_SOURCES);
+
+		// the same goes for echo sequence counters, echoes to new
+		// clients beyond the limit are not stamped
+		for port in 0..=MAX_SOURCES {
+			let a = SocketAddrV6::new(std::net::Ipv6Addr::LOCALHOST, port as u16, 0, 0);
+			assert_eq!(
+				handler.next_echo_seq(&SockaddrStorage::from(a)),
+				(port < MAX_SOURCES).then_some(0));
+		}
+		let known = SockaddrStorage::from(
+			SocketAddrV6::new(std::net::Ipv6Addr::LOCALHOST, 0, 0, 0));
+		assert_eq!(handler.next_echo_seq(&known), Some(1));
+		assert_eq!(handler.echo_seq.len(), MAX_SOURCES);
+		assert_eq!(handler.echoes_unstamped, 1);
+		Ok(())
+	}
+
+	#[test]
+	fn run_with() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let (sender, receiver) = mpsc::channel();
+		let mut srv = Server::new(bind_addr, MIN_SIZE, Some(sender), Options {
+			sample: NonZeroUsize::new(2),
+			..Default::default()
+		});
+		let handle = srv.bind()?;
+		let target = *srv.bound().unwrap();
+		let sh = thread::spawn(move || {
+			let mut sequences = Vec::new();
+			let summary = srv.run_with(|p| sequences.push(p.sequence))
+				.map_err(|e| e.to_string())?;
+			Ok::<_, String>((summary, sequences))
+		});
+		send_packets(&target, 5)?;
+		// the logger still gets the sampled packets
+		assert_eq!(receiver.iter().take(3).map(|p| p.sequence).collect::<Vec<_>>(), [0, 2, 4]);
+		handle.close()?;
+		let (summary, sequences) = sh.join().unwrap()?;
+		// the closure sees all of them
+		assert_eq!(sequences, [0, 1, 2, 3, 4]);
+		assert_eq!(summary.received, 5);
+		Ok(())
+	}
+
+	#[test]
+	fn recv_batch() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let mut srv = Server::new(bind_addr, MIN_SIZE, None, Options {
+			recv_batch: 8,
+			..Default::default()
+		});
+		let handle = srv.bind()?;
+		// queued before the server runs, so they arrive in full batches
+		send_packets(srv.bound().unwrap(), 20)?;
+		let sh = thread::spawn(move || {
+			let mut sequences = Vec::new();
+			let summary = srv.run_with(|p| sequences.push(p.sequence))
+				.map_err(|e| e.to_string())?;
+			Ok::<_, String>((summary, sequences))
+		});
+		thread::sleep(Duration::from_millis(100));
+		handle.close()?;
+		let (summary, sequences) = sh.join().unwrap()?;
+		assert_eq!(sequences, (0..20).collect::<Vec<u32>>());
+		assert_eq!(summary.received, 20);
+		// 8 + 8 + 4 packets, and the call ended by the shutdown
+		assert_eq!(summary.recv_calls, Some(4));
+		assert!(summary.to_string().contains("received 20 packets with 4 recvmmsg calls"));
+		Ok(())
+	}
+
+	/// Send bursts of packets with sendmmsg as fast as possible to
+	/// a server with a small receive buffer, and return how many it
+	/// received.
+	fn recv_load(recv_batch: usize, bursts: usize) -> Result<usize, Box<dyn std::error::Error>> {
+		const BURST: usize = 64;
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let mut srv = Server::new(bind_addr, MIN_SIZE, None, Options {
+			recv_batch,
+			quiet: true,
+			..Default::default()
+		});
+		let handle = srv.bind()?;
+		socket::setsockopt(srv.sock.as_ref().unwrap(), socket::sockopt::RcvBuf, &16384)?;
+		let target = *srv.bound().unwrap();
+		let sh = thread::spawn(move || srv.run_with(|_| ()).map_err(|e| e.to_string()));
+
+		let sock = UdpSocket::bind("[::1]:0")?;
+		sock.connect(target.as_sockaddr_in6().unwrap().to_string())?;
+		let packets = vec![[0u8; MIN_SIZE]; BURST];
+		let iovs: Vec<[IoSlice; 1]> = packets.iter().map(|p| [IoSlice::new(p)]).collect();
+		let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(BURST, None);
+		let addrs = vec![None; BURST];
+		for _ in 0..bursts {
+			socket::sendmmsg(
+				sock.as_raw_fd(), &mut headers, &iovs, &addrs, [], socket::MsgFlags::empty())?;
+		}
+		thread::sleep(Duration::from_millis(100));
+		handle.close()?;
+		Ok(sh.join().unwrap()?.received)
+	}
+
+	/// Benchmark, run with `--ignored`: the batched server drops
+	/// fewer packets of the same flood. On a single CPU the server
+	/// wakes for almost every packet either way and both keep up
+	/// about equally, so this is not part of the regular tests.
+	#[test]
+	#[ignore]
+	fn recv_batch_load() -> Result<(), Box<dyn std::error::Error>> {
+		let bursts = 200;
+		// best of three runs each, to reduce scheduling noise
+		let (mut single, mut batched) = (0, 0);
+		for _ in 0..3 {
+			single = single.max(recv_load(1, bursts)?);
+			batched = batched.max(recv_load(32, bursts)?);
+		}
+		let sent = bursts * 64;
+		assert!(
+			sent - batched <= sent - single,
+			"dropped {} batched, {} single", sent - batched, sent - single);
+		Ok(())
+	}
+
+	#[test]
+	fn check_payload_sequence() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let (sender, receiver) = mpsc::channel();
+		let mut srv = Server::new(bind_addr, 64, Some(sender), Options {
+			check_payload_sequence: true,
+			..Default::default()
+		});
+		let handle = srv.bind()?;
+		let target = srv.bound().unwrap().as_sockaddr_in6().unwrap().to_string();
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		let sock = UdpSocket::bind("[::1]:0")?;
+		let mut packet = [0u8; MIN_SIZE + 4];
+		packet[20] = Flags::new(1).bits();
+		// matching, rewritten header, too short for the marker
+		for (seq, marker, len) in [(5u32, 5u32, MIN_SIZE + 4), (6, 1, MIN_SIZE + 4), (7, 0, MIN_SIZE)] {
+			packet[..4].copy_from_slice(&seq.to_be_bytes());
+			packet[MIN_SIZE..].copy_from_slice(&marker.to_be_bytes());
+			sock.send_to(&packet[..len], &target)?;
+		}
+		assert_eq!(receiver.iter().take(3).count(), 3);
+		handle.close()?;
+		let summary = sh.join().unwrap()?;
+		assert_eq!(summary.payload_sequence, Some((2, 1)));
+		assert!(summary.to_string().contains("payload sequence differs from header in 1 of 2 packets\n"));
+		assert!(summary.json().contains(
+			r#""payload_sequence_checked": 2, "payload_sequence_mismatches": 1, "#));
+		Ok(())
+	}
+
+	/// Loopback does not stamp packets in hardware, they get the
+	/// software timestamp from SO_TIMESTAMPING.
+	#[test]
+	fn hardware_timestamp_fallback() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let (sender, receiver) = mpsc::channel();
+		let mut srv = Server::new(bind_addr, 64, Some(sender), Options {
+			hardware_timestamps: true,
+			..Default::default()
+		});
+		let handle = srv.bind()?;
+		let target = srv.bound().unwrap().as_sockaddr_in6().unwrap().to_string();
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		let sock = UdpSocket::bind("[::1]:0")?;
+		let before = nix::time::clock_gettime(nix::time::ClockId::CLOCK_REALTIME)?;
+		sock.send_to(&[0u8; MIN_SIZE], &target)?;
+		let p = receiver.recv()?;
+		handle.close()?;
+		sh.join().unwrap()?;
+		assert_eq!(p.receive_time_source, crate::TimestampSource::Software);
+		assert!(p.receive_time >= before && p.receive_time - before < TimeSpec::new(1, 0), "{p:?}");
+		Ok(())
+	}
+
+	#[test]
+	fn sigterm_summary() -> Result<(), Box<dyn std::error::Error>> {
+		use std::os::unix::thread::JoinHandleExt;
+
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let (sender, receiver) = mpsc::channel();
+		let mut srv = Server::new(bind_addr, MIN_SIZE, Some(sender), Options {
+			label: Some(String::from("orchestrated")),
+			summary_format: SummaryFormat::Json,
+			..Default::default()
+		});
+		let handle = srv.bind()?;
+		let target = *srv.bound().unwrap();
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+		send_packets(&target, 3)?;
+		assert_eq!(receiver.iter().take(3).count(), 3);
+
+		// Blocked in this thread only, which the signal wait thread
+		// inherits. The signal is sent to that thread, so the rest of
+		// the test process is not affected.
+		let mut signals = SigSet::empty();
+		signals.add(Signal::SIGTERM);
+		signals.thread_block()?;
+		let wait = close_on_signal(handle, signals)?;
+		assert_eq!(unsafe { libc::pthread_kill(wait.as_pthread_t(), libc::SIGTERM) }, 0);
+		assert_eq!(wait.join().unwrap(), Some(Signal::SIGTERM));
+
+		let summary = sh.join().unwrap()?;
+		assert_eq!(summary.received, 3);
+		let json = summary.json();
+		assert!(json.starts_with(
+			r#"{"label": "orchestrated", "received": 3, "sample": 1, "ignored_no_address": 0, "#));
+		assert!(json.ends_with('}'));
+		// the limit that applied is reported
+		let limit = MemlockLimit::current()?;
+		assert_eq!(summary.usage.memlock, limit);
+		assert!(summary.to_string().ends_with(&format!("locked memory limit: {limit}")));
+		Ok(())
+	}
+
+	/// Benchmark, run with `--ignored --nocapture`: time per echo of
+	/// a [`MIN_SIZE`] packet with [`echo_min_size`] and with
+	/// [`echo_reflect`], on loopback.
+	#[test]
+	#[ignore]
+	fn echo_min_size_benchmark() -> Result<(), Box<dyn std::error::Error>> {
+		const ROUNDS: u32 = 200_000;
+		let sink = UdpSocket::bind("[::1]:0")?;
+		let address = SockaddrStorage::from(sink.local_addr()?);
+		let sock = UdpSocket::bind("[::1]:0")?;
+		let fd = sock.as_raw_fd();
+		let data = [0u8; MIN_SIZE];
+		let mut reply = [0u8; MIN_SIZE];
+		let flags = socket::MsgFlags::empty();
+		let (mut fast, mut general) = (Duration::ZERO, Duration::ZERO);
+		// alternate in steps to spread scheduling noise over both
+		for _ in 0..20 {
+			let start = Instant::now();
+			for _ in 0..ROUNDS / 20 {
+				echo_min_size(fd, &mut reply, &data, &[], flags, &address)?;
+			}
+			fast += start.elapsed();
+			let start = Instant::now();
+			for _ in 0..ROUNDS / 20 {
+				echo_reflect(fd, &data, &[], flags, &address)?;
+			}
+			general += start.elapsed();
+		}
+		eprintln!(
+			"echo of {MIN_SIZE} bytes: {} ns with reply buffer, {} ns reflected",
+			(fast / ROUNDS).as_nanos(), (general / ROUNDS).as_nanos());
+		Ok(())
+	}
+
+	#[test]
+	fn echo_sequence() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let mut srv = Server::new(bind_addr, 64, None, Options::default());
+		let handle = srv.bind()?;
+		let target = srv.bound().unwrap().as_sockaddr_in6().unwrap().to_string();
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		let sock = UdpSocket::bind("[::1]:0")?;
+		sock.set_read_timeout(Some(Duration::from_secs(1)))?;
+		let mut packet = [0xaau8; ECHO_SEQ_SIZE + 2];
+		// echo sequence numbers are not defined in the original format
+		let flags = Flags::new(1).with(ECHO_FLAG | ECHO_SEQ_FLAG);
+		packet[20] = flags.bits();
+		let mut echo = [0u8; 64];
+		for i in 0..3u32 {
+			sock.send_to(&packet, &target)?;
+			let len = sock.recv(&mut echo)?;
+			assert_eq!(len, packet.len());
+			assert_eq!(echo[20], flags.with(ECHO_SEQ_STAMPED).bits());
+			assert_eq!(echo[MIN_SIZE..ECHO_SEQ_SIZE], i.to_be_bytes());
+			// everything else is reflected
+			assert_eq!(echo[..20], packet[..20]);
+			assert_eq!(echo[ECHO_SEQ_SIZE..len], packet[ECHO_SEQ_SIZE..]);
+		}
+
+		// too small for an echo sequence number, reflected as is
+		sock.send_to(&packet[..MIN_SIZE], &target)?;
+		let len = sock.recv(&mut echo)?;
+		assert_eq!(echo[..len], packet[..MIN_SIZE]);
+
+		// in version 2 the echo sequence number follows the header
+		// length field
+		let flags = Flags::new(HEADER_LENGTH_VERSION).with(ECHO_FLAG | ECHO_SEQ_FLAG);
+		let header = flags.header_length(packet.len());
+		assert_eq!(header, MIN_SIZE + 5);
+		packet[20] = flags.bits();
+		packet[MIN_SIZE] = header as u8;
+		sock.send_to(&packet, &target)?;
+		let len = sock.recv(&mut echo)?;
+		assert_eq!(echo[20], flags.with(ECHO_SEQ_STAMPED).bits());
+		assert_eq!(echo[MIN_SIZE], packet[MIN_SIZE]);
+		assert_eq!(echo[MIN_SIZE + 1..header], 3u32.to_be_bytes());
+		assert_eq!(echo[header..len], packet[header..]);
+
+		// a header longer than the packet is invalid, not echoed
+		sock.set_read_timeout(Some(Duration::from_millis(200)))?;
+		packet[MIN_SIZE] = 64;
+		sock.send_to(&packet, &target)?;
+		assert_eq!(
+			sock.recv(&mut echo).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+		handle.close()?;
+		sh.join().unwrap()?;
+		Ok(())
+	}
+
+	#[test]
+	fn version() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let (sender, receiver) = mpsc::channel();
+		let mut srv = Server::new(bind_addr, 64, Some(sender), Options::default());
+		let handle = srv.bind()?;
+		let target = srv.bound().unwrap().as_sockaddr_in6().unwrap().to_string();
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		let sock = UdpSocket::bind("[::1]:0")?;
+		sock.set_read_timeout(Some(Duration::from_millis(200)))?;
+		let mut packet = [0u8; MIN_SIZE];
+		let mut echo = [0u8; 64];
+		// a future version is neither echoed nor logged
+		packet[0] = 1;
+		packet[20] = Flags::new(PROTOCOL_VERSION + 1).with(ECHO_FLAG).bits();
+		sock.send_to(&packet, &target)?;
+		assert_eq!(
+			sock.recv(&mut echo).unwrap_err().kind(), ErrorKind::WouldBlock);
+		// the original LUNA format and later versions are accepted
+		for (seq, version) in [(2, 0), (3, HEADER_LENGTH_VERSION)] {
+			packet[0] = seq;
+			packet[20] = Flags::new(version).with(ECHO_FLAG).bits();
+			sock.send_to(&packet, &target)?;
+			assert_eq!(sock.recv(&mut echo)?, MIN_SIZE);
+			assert_eq!(echo[..MIN_SIZE], packet);
+			assert_eq!(receiver.recv()?.sequence, (seq as u32) << 24);
+		}
+		// version 3 packets carry header length and clock byte
+		let mut clock_packet = [0u8; CLOCK_SIZE];
+		clock_packet[0] = 5;
+		clock_packet[20] = Flags::new(CLOCK_VERSION).with(ECHO_FLAG).bits();
+		clock_packet[21] = CLOCK_SIZE as u8;
+		clock_packet[22] = SendClock::Monotonic.byte();
+		sock.send_to(&clock_packet, &target)?;
+		assert_eq!(sock.recv(&mut echo)?, CLOCK_SIZE);
+		assert_eq!(echo[..CLOCK_SIZE], clock_packet);
+		let p = receiver.recv()?;
+		assert_eq!((p.sequence, p.clock), (5 << 24, SendClock::Monotonic));
+		// the original format defines only the echo flag
+		packet[0] = 4;
+		packet[20] = Flags::new(0).with(ECHO_FLAG | ECHO_SEQ_FLAG).bits();
+		sock.send_to(&packet, &target)?;
+		assert_eq!(
+			sock.recv(&mut echo).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+		handle.close()?;
+		let summary = sh.join().unwrap()?;
+		assert_eq!((summary.unsupported, summary.unknown_flags), (1, 1));
+		assert_eq!(summary.received, 3);
+		Ok(())
+	}
+
+	#[test]
+	fn ecn() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let (sender, receiver) = mpsc::channel();
+		let mut srv = Server::new(bind_addr, 64, Some(sender), Options::default());
+		let handle = srv.bind()?;
+		let target = *srv.bound().unwrap();
+		let sh = thread::spawn(move || srv.run().map_err(|e| e.to_string()));
+
+		let sock = UdpSocket::bind("[::1]:0")?;
+		sock.set_read_timeout(Some(Duration::from_secs(1)))?;
+		socket::setsockopt(&sock, socket::sockopt::Ipv6RecvTClass, &true)?;
+		let mut packet = [0u8; MIN_SIZE];
+		packet[20] = ECHO_FLAG;
+		let mut echo = [0u8; 64];
+		let mut cmsgspace = cmsg_space!(libc::c_int);
+		for (sent, reflected) in [
+			(Ecn::Ect0, Ecn::Ect0), (Ecn::Ect1, Ecn::Ect1), (Ecn::Ce, Ecn::Ect0)]
+		{
+			// DSCP CS1 plus ECN codepoint
+			socket::setsockopt(
+				&sock, socket::sockopt::Ipv6TClass, &(0x20 | sent as libc::c_int))?;
+			sock.send_to(&packet, target.as_sockaddr_in6().unwrap().to_string())?;
+			let p = receiver.recv()?;
+			assert_eq!(p.ecn(), Some(sent));
+			assert_eq!(p.dscp(), Some(8));
+
+			let mut iov = [IoSliceMut::new(&mut echo)];
+			let r = socket::recvmsg::<SockaddrStorage>(
+				sock.as_raw_fd(), &mut iov, Some(&mut cmsgspace),
+				socket::MsgFlags::empty())?;
+			assert_eq!(received_tos(&r).map(Ecn::from), Some(reflected));
+		}
+
+		handle.close()?;
+		sh.join().unwrap()?;
+		Ok(())
+	}
+
+	/// Closing the handle after the server has been dropped must not
+	/// touch a socket that reused the descriptor number.
+	#[test]
+	fn close_after_drop() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let mut srv = Server::new(bind_addr, MIN_SIZE, None, Options::default());
+		let handle = srv.bind()?;
+		drop(srv);
+
+		let other = UdpSocket::bind("[::1]:0")?;
+		other.set_read_timeout(Some(Duration::from_secs(1)))?;
+		handle.close()?;
+		// a socket shut down for reading would return 0 immediately
+		other.send_to(b"ping", other.local_addr()?)?;
+		let mut buf = [0u8; 8];
+		assert_eq!(other.recv(&mut buf)?, 4);
+		// a second close is a no-op as well
+		handle.close()?;
+		Ok(())
+	}
+
+	#[test]
+	fn rebind() -> Result<(), Box<dyn std::error::Error>> {
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let mut srv = Server::new(bind_addr, MIN_SIZE, None, Options::default());
+		srv.bind()?;
+		assert!(socket::getsockopt(
+			srv.sock.as_ref().unwrap(), socket::sockopt::ReuseAddr)?);
+		let bind_addr = *srv.bound().unwrap();
+		drop(srv);
+
+		// bind the same port again right after closing
+		let mut srv = Server::new(bind_addr, MIN_SIZE, None, Options::default());
+		srv.bind()?;
+		assert_eq!(srv.bound(), Some(&bind_addr));
+
+		// without SO_REUSEADDR the option stays off
+		let bind_addr = SockaddrStorage::from("[::1]:0".parse::<SocketAddrV6>()?);
+		let mut srv = Server::new(
+			bind_addr, MIN_SIZE, None,
+			Options { reuse_addr: false, ..Default::default() });
+		srv.bind()?;
+		assert!(!socket::getsockopt(
+			srv.sock.as_ref().unwrap(), socket::sockopt::ReuseAddr)?);
+		Ok(())
 	}
 }
@@ -0,0 +1,703 @@
+use crate::{LogField, LogFormat, ReceivedPacket, SentPacket, TimeFormat};
+use nix::sys::signal::SigSet;
+use std::{
+	collections::VecDeque,
+	fs::{self, File, OpenOptions},
+	io::{self, BufWriter, Write},
+	os::unix::{fs::{FileTypeExt, OpenOptionsExt}, net::UnixStream},
+	path::{Path, PathBuf},
+	sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Mutex},
+	thread,
+	time::{Duration, Instant},
+};
+
+
+/// Optional settings for the file sink.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+	/// if `Some`, flush buffered output to the file at this
+	/// interval, otherwise only when the buffer is full and at the
+	/// end of the run
+	pub flush_interval: Option<Duration>,
+	/// call fsync after each flush, so flushed data is durably
+	/// stored and not only handed to the kernel (regular files only)
+	pub fsync: bool,
+	/// format of the packet timestamps
+	pub time_format: TimeFormat,
+	/// if `Some`, the columns of packet logs in this order, otherwise
+	/// [`LogFormat::default_fields`]
+	pub fields: Option<Vec<LogField>>,
+	/// format of packet logs, time format applies only to
+	/// [`LogFormat::Tsv`], fields also to [`LogFormat::Json`]. Send
+	/// logs are always tab separated.
+	pub log_format: LogFormat,
+	/// run label, included in [`LogFormat::Json`] records
+	pub label: Option<String>,
+	/// what to do if the reader of a FIFO or Unix socket goes away
+	pub disconnect: Disconnect,
+	/// with [`Disconnect::Buffer`] keep at most this many records
+	/// while there is no reader, dropping the oldest ones, default
+	/// [`PENDING_LIMIT`]
+	pub pending_limit: Option<usize>,
+	/// if `Some`, flush the output and open the path again whenever
+	/// this is set (the sink clears it), so an external tool can
+	/// rotate the log, see [`reopen_on_signal`]. A regular file is
+	/// appended to if it still exists, with a header if it is
+	/// new. FIFOs and sockets are only flushed.
+	pub reopen: Option<Arc<AtomicBool>>,
+}
+
+impl Options {
+	fn fields(&self) -> &[LogField] {
+		self.fields.as_deref().unwrap_or(self.log_format.default_fields())
+	}
+}
+
+
+/// What the file sink does when the reader of a FIFO or Unix socket
+/// it writes to goes away.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Disconnect {
+	/// stop writing and return the error, so the run ends with the
+	/// next record
+	#[default]
+	Stop,
+	/// keep records in memory until a new reader connects, then
+	/// write them after a new header. Records the old reader
+	/// had not read yet are lost, and so are the oldest ones beyond
+	/// [`Options::pending_limit`].
+	Buffer,
+}
+
+
+/// How often to check for a new reader after the old one went away.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Records [`Disconnect::Buffer`] keeps while there is no reader,
+/// unless [`Options::pending_limit`] says otherwise.
+pub const PENDING_LIMIT: usize = 1 << 20;
+
+/// How often the file sink checks for [`Options::reopen`] while no
+/// records arrive.
+const REOPEN_CHECK: Duration = Duration::from_millis(100);
+
+
+/// Destination of the file sink.
+#[derive(Debug)]
+enum Output {
+	File(File),
+	Fifo(File),
+	Socket(UnixStream),
+}
+
+
+impl Output {
+	/// Open `path` depending on what is there: open a FIFO for
+	/// writing, which waits for a reader, connect to a Unix stream
+	/// socket, or create (or truncate) a regular file.
+	fn open(path: &Path) -> io::Result<Self> {
+		match fs::metadata(path).map(|m| m.file_type()) {
+			Ok(t) if t.is_fifo() =>
+				Ok(Output::Fifo(OpenOptions::new().write(true).open(path)?)),
+			Ok(t) if t.is_socket() => Ok(Output::Socket(UnixStream::connect(path)?)),
+			_ => Ok(Output::File(File::create(path)?)),
+		}
+	}
+
+	/// Open `path` again like `self` was, if there is a reader.
+	/// Returns `None` if there is none yet.
+	fn reopen(&self, path: &Path) -> io::Result<Option<Self>> {
+		let r = match self {
+			// Opening a FIFO without a reader waits, unless it is
+			// non-blocking, which fails with ENXIO instead. Once
+			// there is a reader, the blocking open returns at once.
+			Output::Fifo(_) => OpenOptions::new()
+				.write(true).custom_flags(libc::O_NONBLOCK).open(path)
+				.and_then(|_| OpenOptions::new().write(true).open(path))
+				.map(Output::Fifo),
+			Output::Socket(_) => UnixStream::connect(path).map(Output::Socket),
+			Output::File(_) => return Err(io::Error::other("cannot reopen a regular file")),
+		};
+		match r {
+			Ok(o) => Ok(Some(o)),
+			Err(e) if matches!(
+				e.raw_os_error(),
+				Some(libc::ENXIO | libc::ENOENT | libc::ECONNREFUSED)) => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// FIFOs and sockets are written to as records arrive, for
+	/// readers that process them live.
+	fn is_stream(&self) -> bool {
+		!matches!(self, Output::File(_))
+	}
+}
+
+
+impl Write for Output {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match self {
+			Output::File(f) | Output::Fifo(f) => f.write(buf),
+			Output::Socket(s) => s.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self {
+			Output::File(f) | Output::Fifo(f) => f.flush(),
+			Output::Socket(s) => s.flush(),
+		}
+	}
+}
+
+
+/// A record the file sink can write, after a header at the start of
+/// the file (usually a header line, one line per record).
+pub trait Record: Send + 'static {
+	fn write_header(out: &mut impl Write, options: &Options) -> io::Result<()>;
+	fn write_record(&self, out: &mut impl Write, options: &Options) -> io::Result<()>;
+}
+
+
+impl Record for ReceivedPacket {
+	fn write_header(out: &mut impl Write, options: &Options) -> io::Result<()> {
+		options.log_format.write_header(out, options.fields())
+	}
+
+	fn write_record(&self, out: &mut impl Write, options: &Options) -> io::Result<()> {
+		options.log_format.write_packet(
+			out, self, options.time_format, options.fields(), options.label.as_deref())
+	}
+}
+
+
+impl Record for SentPacket {
+	fn write_header(out: &mut impl Write, _: &Options) -> io::Result<()> {
+		writeln!(out, "{}", SentPacket::header())
+	}
+
+	/// Delays are durations, time format and fields do not apply.
+	fn write_record(&self, out: &mut impl Write, _: &Options) -> io::Result<()> {
+		writeln!(out, "{self}")
+	}
+}
+
+
+/// Create (or truncate) the file at `path` and start a thread
+/// writing all records sent to the returned sender to it, in the
+/// same format as standard output. Flushing happens in the writer
+/// thread, so even a slow fsync does not block the sender. The thread
+/// exits after all senders have been dropped and the remaining data
+/// has been written.
+///
+/// If `path` is a FIFO or a Unix stream socket, records are written
+/// to the reader at the other end as they arrive, for live
+/// processing. Opening a FIFO waits until a reader opens it, too.
+/// [`Options::disconnect`] selects what happens if the reader goes
+/// away.
+pub fn to_file<R: Record>(path: &Path, options: Options)
+	-> io::Result<(mpsc::Sender<R>, thread::JoinHandle<io::Result<()>>)>
+{
+	let output = Output::open(path)?;
+	let path = path.to_path_buf();
+	let (sender, receiver) = mpsc::channel();
+	let h = thread::Builder::new().name("file sink".to_string()).spawn(
+		move || write_log(receiver, output, path, options))?;
+	Ok((sender, h))
+}
+
+
+/// Start a thread that waits for one of `signals` and then sets
+/// `reopen` (see [`Options::reopen`]), again for every signal, e.g.
+/// SIGHUP after logrotate renamed the output. Like with
+/// [`crate::server::close_on_signal`] the signals must be blocked in
+/// all threads, the thread accepts them with sigwait, so no code
+/// runs in signal handler context.
+pub fn reopen_on_signal(reopen: Arc<AtomicBool>, signals: SigSet)
+	-> io::Result<thread::JoinHandle<()>>
+{
+	thread::Builder::new().name("reopen signal wait".to_string()).spawn(move || {
+		loop {
+			match signals.wait() {
+				Ok(_) => reopen.store(true, Ordering::Relaxed),
+				Err(e) => {
+					eprintln!("could not wait for signals: {e}");
+					return;
+				},
+			}
+		}
+	})
+}
+
+
+/// A fixed size buffer of the most recent records: once it is full
+/// each new record overwrites the oldest one. For consumers that only
+/// need a recent window, e.g. a live view.
+#[derive(Debug)]
+pub struct Ring<R> {
+	capacity: usize,
+	records: Mutex<VecDeque<R>>,
+}
+
+
+impl<R: Clone> Ring<R> {
+	pub fn new(capacity: usize) -> Self {
+		Ring { capacity, records: Mutex::new(VecDeque::with_capacity(capacity)) }
+	}
+
+	/// Maximum number of records kept.
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Add a record, dropping the oldest one if the buffer is full.
+	pub fn push(&self, record: R) {
+		if self.capacity == 0 {
+			return;
+		}
+		let mut records = self.records.lock().unwrap();
+		if records.len() == self.capacity {
+			records.pop_front();
+		}
+		records.push_back(record);
+	}
+
+	/// Copy of the current contents, oldest first.
+	pub fn snapshot(&self) -> Vec<R> {
+		self.records.lock().unwrap().iter().cloned().collect()
+	}
+}
+
+
+/// Start a thread that stores all records sent to the returned
+/// sender in a [`Ring`] of the given capacity, and passes them on to
+/// `forward` (if any), so a consumer can read the full stream and
+/// still look at the recent window. A record is in the ring before
+/// it is forwarded. The thread exits after all senders have been
+/// dropped, or when the receiver of `forward` hangs up.
+pub fn to_ring<R: Clone + Send + 'static>(
+	capacity: usize, forward: Option<mpsc::Sender<R>>)
+	-> io::Result<(mpsc::Sender<R>, Arc<Ring<R>>)>
+{
+	let ring = Arc::new(Ring::new(capacity));
+	let (sender, receiver) = mpsc::channel::<R>();
+	let r = Arc::clone(&ring);
+	thread::Builder::new().name("ring sink".to_string()).spawn(move || {
+		for record in receiver {
+			if let Some(f) = &forward {
+				r.push(record.clone());
+				if f.send(record).is_err() {
+					break;
+				}
+			} else {
+				r.push(record);
+			}
+		}
+	})?;
+	Ok((sender, ring))
+}
+
+
+fn flush(out: &mut BufWriter<Output>, fsync: bool) -> io::Result<()> {
+	out.flush()?;
+	if fsync && let Output::File(f) = out.get_ref() {
+		f.sync_data()?;
+	}
+	Ok(())
+}
+
+
+fn write_header<R: Record>(out: &mut BufWriter<Output>, options: &Options) -> io::Result<()> {
+	R::write_header(out, options)?;
+	if out.get_ref().is_stream() {
+		out.flush()?;
+	}
+	Ok(())
+}
+
+
+/// Write the pending records, removing each once it is written. On
+/// a stream a record is written only after it reached the reader.
+fn write_pending<R: Record>(
+	out: &mut BufWriter<Output>, pending: &mut VecDeque<R>, options: &Options)
+	-> io::Result<()>
+{
+	while let Some(r) = pending.front() {
+		r.write_record(out, options)?;
+		if out.get_ref().is_stream() {
+			out.flush()?;
+		}
+		pending.pop_front();
+	}
+	Ok(())
+}
+
+
+/// Flush `out` and open `path` again if it is a regular file, see
+/// [`Options::reopen`].
+fn reopen<R: Record>(mut out: BufWriter<Output>, path: &Path, options: &Options)
+	-> io::Result<BufWriter<Output>>
+{
+	flush(&mut out, options.fsync)?;
+	if out.get_ref().is_stream() {
+		return Ok(out);
+	}
+	let file = OpenOptions::new().create(true).append(true).open(path)?;
+	let new = file.metadata()?.len() == 0;
+	let mut out = BufWriter::new(Output::File(file));
+	if new {
+		write_header::<R>(&mut out, options)?;
+	}
+	Ok(out)
+}
+
+
+/// Handle the write error `e`: if the reader of a stream went away
+/// and the policy is [`Disconnect::Buffer`], wait for a new reader,
+/// collecting records in `pending` meanwhile, up to
+/// [`Options::pending_limit`]. Returns the error if not, or if all
+/// senders are gone before a reader connects.
+fn reconnect<R: Record>(
+	e: io::Error, out: BufWriter<Output>, path: &Path,
+	receiver: &mpsc::Receiver<R>, pending: &mut VecDeque<R>, options: &Options)
+	-> io::Result<BufWriter<Output>>
+{
+	if options.disconnect == Disconnect::Stop
+		|| !out.get_ref().is_stream()
+		|| !matches!(e.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset)
+	{
+		return Err(e);
+	}
+	// the old reader cannot get buffered data anymore
+	let (output, _) = out.into_parts();
+	let limit = options.pending_limit.unwrap_or(PENDING_LIMIT);
+	let mut dropped = 0;
+	loop {
+		if let Some(o) = output.reopen(path)? {
+			let mut out = BufWriter::new(o);
+			match write_header::<R>(&mut out, options) {
+				Ok(()) => {
+					if dropped > 0 {
+						eprintln!(
+							"new reader connected, dropped the {dropped} oldest records \
+							 beyond the limit of {limit} meanwhile");
+					}
+					return Ok(out);
+				},
+				Err(e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+				Err(e) => return Err(e),
+			}
+		}
+		match receiver.recv_timeout(RECONNECT_INTERVAL) {
+			Ok(r) => {
+				if pending.len() >= limit {
+					pending.pop_front();
+					dropped += 1;
+				}
+				pending.push_back(r);
+			},
+			Err(mpsc::RecvTimeoutError::Timeout) => (),
+			Err(mpsc::RecvTimeoutError::Disconnected) => return Err(io::Error::new(
+				e.kind(),
+				format!(
+					"reader went away, {} records not written, {dropped} older ones dropped",
+					pending.len()))),
+		}
+	}
+}
+
+
+fn write_log<R: Record>(
+	receiver: mpsc::Receiver<R>, output: Output, path: PathBuf, options: Options)
+	-> io::Result<()>
+{
+	let mut pending = VecDeque::new();
+	let mut out = BufWriter::new(output);
+	if let Err(e) = write_header::<R>(&mut out, &options) {
+		out = reconnect(e, out, &path, &receiver, &mut pending, &options)?;
+	}
+	let mut next_flush = options.flush_interval.map(|i| Instant::now() + i);
+	loop {
+		let deadline = next_flush.into_iter()
+			.chain(options.reopen.as_ref().map(|_| Instant::now() + REOPEN_CHECK))
+			.min();
+		let r = match deadline {
+			Some(t) => receiver.recv_timeout(t.saturating_duration_since(Instant::now())),
+			None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+		};
+		match r {
+			Ok(p) => pending.push_back(p),
+			Err(mpsc::RecvTimeoutError::Timeout) => (),
+			Err(mpsc::RecvTimeoutError::Disconnected) => break,
+		}
+		while let Err(e) = write_pending(&mut out, &mut pending, &options) {
+			out = reconnect(e, out, &path, &receiver, &mut pending, &options)?;
+		}
+		if options.reopen.as_ref().is_some_and(|r| r.swap(false, Ordering::Relaxed)) {
+			out = reopen::<R>(out, &path, &options)?;
+		}
+		if let (Some(t), Some(i)) = (next_flush, options.flush_interval) {
+			let now = Instant::now();
+			if t <= now {
+				flush(&mut out, options.fsync)?;
+				next_flush = Some(now + i);
+			}
+		}
+	}
+	flush(&mut out, options.fsync)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use nix::sys::{socket::SockaddrStorage, time::TimeSpec};
+	use std::{
+		ffi::CString,
+		fs,
+		io::{BufRead, BufReader},
+		net::SocketAddrV6,
+		os::unix::{ffi::OsStrExt, net::UnixListener},
+	};
+
+	fn packet(sequence: u32) -> ReceivedPacket {
+		ReceivedPacket {
+			source: SockaddrStorage::from("[::1]:7800".parse::<SocketAddrV6>().unwrap()),
+			receive_time: TimeSpec::new(10, 5),
+			receive_time_source: crate::TimestampSource::Software,
+			size: 21,
+			header_length: 21,
+			sequence,
+			timestamp: TimeSpec::new(10, 1),
+			flags: crate::Flags::default(),
+			echo_sequence: None,
+			tos: None,
+			flow: 0,
+			clock: crate::SendClock::Realtime,
+			fragment_size: None,
+		}
+	}
+
+	#[test]
+	fn interval_flush() -> Result<(), Box<dyn std::error::Error>> {
+		let path = std::env::temp_dir()
+			.join(format!("luna-sink-{}.tsv", std::process::id()));
+		let (sender, h) = to_file::<ReceivedPacket>(&path, Options {
+			flush_interval: Some(Duration::from_millis(20)),
+			fsync: true,
+			..Default::default()
+		})?;
+		sender.send(packet(0))?;
+		// data is written while the sender is still open
+		thread::sleep(Duration::from_millis(200));
+		let content = fs::read_to_string(&path)?;
+		assert_eq!(
+			content,
+			format!("{}\n10.000000005\t::1\t7800\t0\t10.000000001\t21\t0\n",
+					ReceivedPacket::header()));
+
+		sender.send(packet(1))?;
+		drop(sender);
+		h.join().unwrap()?;
+		assert_eq!(fs::read_to_string(&path)?.lines().count(), 3);
+		fs::remove_file(&path)?;
+		Ok(())
+	}
+
+	#[test]
+	fn reopen_signal() -> Result<(), Box<dyn std::error::Error>> {
+		use nix::sys::signal::Signal;
+		use std::os::unix::thread::JoinHandleExt;
+
+		let path = std::env::temp_dir()
+			.join(format!("luna-sink-reopen-{}.tsv", std::process::id()));
+		let rotated = path.with_extension("tsv.1");
+		let flag = Arc::new(AtomicBool::new(false));
+		let (sender, h) = to_file::<ReceivedPacket>(&path, Options {
+			reopen: Some(Arc::clone(&flag)),
+			..Default::default()
+		})?;
+		sender.send(packet(0))?;
+
+		// Blocked in this thread only, which the signal wait thread
+		// inherits. The signal is sent to that thread, so the rest of
+		// the test process is not affected.
+		let mut signals = SigSet::empty();
+		signals.add(Signal::SIGHUP);
+		signals.thread_block()?;
+		let wait = reopen_on_signal(flag, signals)?;
+		fs::rename(&path, &rotated)?;
+		assert_eq!(unsafe { libc::pthread_kill(wait.as_pthread_t(), libc::SIGHUP) }, 0);
+		let start = Instant::now();
+		while !path.exists() {
+			assert!(start.elapsed() < Duration::from_secs(5), "output not reopened");
+			thread::sleep(Duration::from_millis(10));
+		}
+		// the old file got the buffered record before the new one
+		// was opened
+		let header = ReceivedPacket::header();
+		assert_eq!(
+			fs::read_to_string(&rotated)?,
+			format!("{header}\n10.000000005\t::1\t7800\t0\t10.000000001\t21\t0\n"));
+
+		sender.send(packet(1))?;
+		drop(sender);
+		h.join().unwrap()?;
+		assert_eq!(
+			fs::read_to_string(&path)?,
+			format!("{header}\n10.000000005\t::1\t7800\t1\t10.000000001\t21\t0\n"));
+		fs::remove_file(&path)?;
+		fs::remove_file(&rotated)?;
+		Ok(())
+	}
+
+	#[test]
+	fn selected_fields() -> Result<(), Box<dyn std::error::Error>> {
+		let path = std::env::temp_dir()
+			.join(format!("luna-sink-fields-{}.tsv", std::process::id()));
+		let (sender, h) = to_file::<ReceivedPacket>(&path, Options {
+			fields: Some(vec![LogField::Size, LogField::Sequence, LogField::Delay]),
+			..Default::default()
+		})?;
+		sender.send(packet(3))?;
+		drop(sender);
+		h.join().unwrap()?;
+		assert_eq!(
+			fs::read_to_string(&path)?,
+			"size\tsequence\tdelay\n21\t3\t0.000000004\n");
+		fs::remove_file(&path)?;
+		Ok(())
+	}
+
+	#[test]
+	fn ring() -> Result<(), Box<dyn std::error::Error>> {
+		let n = 5;
+		let (forward, forwarded) = mpsc::channel();
+		let (sender, ring) = to_ring::<ReceivedPacket>(n, Some(forward))?;
+		for i in 0..2 * n as u32 {
+			sender.send(packet(i))?;
+		}
+		drop(sender);
+		// the full stream is passed on
+		assert_eq!(forwarded.iter().count(), 2 * n);
+		// the last n, in order
+		assert_eq!(
+			ring.snapshot().iter().map(|p| p.sequence).collect::<Vec<_>>(),
+			vec![5, 6, 7, 8, 9]);
+
+		let ring = Ring::new(0);
+		ring.push(packet(0));
+		assert!(ring.snapshot().is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn sent_packets() -> Result<(), Box<dyn std::error::Error>> {
+		let path = std::env::temp_dir()
+			.join(format!("luna-sink-sent-{}.tsv", std::process::id()));
+		let (sender, h) = to_file(&path, Options::default())?;
+		sender.send(SentPacket { delay: TimeSpec::new(0, 0), size: 21, flags: 0x11.into() })?;
+		sender.send(SentPacket { delay: TimeSpec::new(1, 5), size: 64, flags: 0x10.into() })?;
+		drop(sender);
+		h.join().unwrap()?;
+		assert_eq!(
+			fs::read_to_string(&path)?,
+			"delay\tsize\tflags\n0.000000000\t21\t17\n1.000000005\t64\t16\n");
+		fs::remove_file(&path)?;
+		Ok(())
+	}
+
+	#[test]
+	fn fifo() -> Result<(), Box<dyn std::error::Error>> {
+		let path = std::env::temp_dir()
+			.join(format!("luna-sink-fifo-{}", std::process::id()));
+		let p = CString::new(path.as_os_str().as_bytes())?;
+		let ret = unsafe { libc::mkfifo(p.as_ptr(), 0o600) };
+		assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+		let (line_sender, lines) = mpsc::channel();
+		let reader_path = path.clone();
+		let reader = thread::spawn(move || -> io::Result<()> {
+			for line in BufReader::new(File::open(reader_path)?).lines() {
+				line_sender.send(line?).unwrap();
+			}
+			Ok(())
+		});
+
+		let (sender, h) = to_file::<ReceivedPacket>(&path, Options {
+			fields: Some(vec![LogField::Sequence]), ..Default::default()
+		})?;
+		// records arrive while the sender is still open
+		let timeout = Duration::from_secs(5);
+		assert_eq!(lines.recv_timeout(timeout)?, "sequence");
+		sender.send(packet(4))?;
+		assert_eq!(lines.recv_timeout(timeout)?, "4");
+		sender.send(packet(5))?;
+		assert_eq!(lines.recv_timeout(timeout)?, "5");
+		drop(sender);
+		h.join().unwrap()?;
+		reader.join().unwrap()?;
+		assert!(lines.recv().is_err());
+		fs::remove_file(&path)?;
+		Ok(())
+	}
+
+	#[test]
+	fn socket_disconnect() -> Result<(), Box<dyn std::error::Error>> {
+		let path = std::env::temp_dir()
+			.join(format!("luna-sink-socket-{}", std::process::id()));
+		let listener = UnixListener::bind(&path)?;
+		let read = |n| -> io::Result<Vec<String>> {
+			BufReader::new(listener.accept()?.0).lines().take(n).collect()
+		};
+		let options = |disconnect| Options {
+			fields: Some(vec![LogField::Sequence]), disconnect, ..Default::default()
+		};
+
+		let (sender, h) = to_file::<ReceivedPacket>(&path, options(Disconnect::Buffer))?;
+		sender.send(packet(0))?;
+		assert_eq!(read(2)?, ["sequence", "0"]);
+		// the reader is gone, these wait for the next one
+		sender.send(packet(1))?;
+		sender.send(packet(2))?;
+		assert_eq!(read(3)?, ["sequence", "1", "2"]);
+		drop(sender);
+		h.join().unwrap()?;
+
+		let (sender, h) = to_file::<ReceivedPacket>(&path, options(Disconnect::Stop))?;
+		assert_eq!(read(1)?, ["sequence"]);
+		sender.send(packet(0))?;
+		assert_eq!(h.join().unwrap().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+		// the sink is gone, so is the receiver
+		assert!(sender.send(packet(1)).is_err());
+		fs::remove_file(&path)?;
+		Ok(())
+	}
+
+	#[test]
+	fn pending_limit() -> Result<(), Box<dyn std::error::Error>> {
+		let path = std::env::temp_dir()
+			.join(format!("luna-sink-limit-{}", std::process::id()));
+		let listener = UnixListener::bind(&path)?;
+		let (sender, h) = to_file::<ReceivedPacket>(&path, Options {
+			fields: Some(vec![LogField::Sequence]), disconnect: Disconnect::Buffer,
+			pending_limit: Some(2), ..Default::default()
+		})?;
+		sender.send(packet(0))?;
+		let lines: Vec<String> = BufReader::new(listener.accept()?.0).lines()
+			.take(2).collect::<Result<_, _>>()?;
+		assert_eq!(lines, ["sequence", "0"]);
+		// no reader can connect any more, only the newest two of
+		// these are kept
+		drop(listener);
+		fs::remove_file(&path)?;
+		for seq in 1..=4 {
+			sender.send(packet(seq))?;
+		}
+		drop(sender);
+		assert_eq!(
+			h.join().unwrap().unwrap_err().to_string(),
+			"reader went away, 2 records not written, 2 older ones dropped");
+		Ok(())
+	}
+}
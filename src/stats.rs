@@ -0,0 +1,1472 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	fmt::{self, Display, Formatter},
+	io::{Error, ErrorKind},
+	sync::{atomic::{AtomicUsize, Ordering}, Mutex},
+	time::Duration
+};
+
+use nix::sys::{resource::Usage, time::{TimeSpec, TimeVal, TimeValLike}};
+
+use crate::{json_string, MemlockLimit, ReceivedPacket, Scheduling, SendClock, SocketQueues};
+
+
+/// Convert a TimeSpec to seconds as floating point value.
+pub(crate) fn timespec_secs(t: &TimeSpec) -> f64 {
+	t.tv_sec() as f64 + t.tv_nsec() as f64 / 1_000_000_000.0
+}
+
+
+/// Convert a TimeSpec to nanoseconds.
+pub(crate) fn timespec_nanos(t: &TimeSpec) -> i64 {
+	t.tv_sec() * 1_000_000_000 + t.tv_nsec()
+}
+
+
+/// Write nanoseconds as seconds with nanosecond precision, without
+/// unit.
+pub(crate) fn write_secs(f: &mut Formatter<'_>, ns: i64) -> fmt::Result {
+	let sign = if ns < 0 { "-" } else { "" };
+	let ns = ns.unsigned_abs();
+	write!(f, "{sign}{}.{:09}", ns / 1_000_000_000, ns % 1_000_000_000)
+}
+
+
+/// Format nanoseconds as seconds with nanosecond precision.
+fn fmt_nanos(f: &mut Formatter<'_>, ns: i64) -> fmt::Result {
+	write_secs(f, ns)?;
+	write!(f, "s")
+}
+
+
+/// Collection of latency samples (e.g. round trip times), in
+/// nanoseconds.
+#[derive(Clone, Debug, Default)]
+pub struct Latency {
+	samples: Vec<i64>,
+}
+
+
+impl Latency {
+	pub fn new() -> Self {
+		Latency::default()
+	}
+
+	pub fn record(&mut self, latency: TimeSpec) {
+		self.samples.push(timespec_nanos(&latency));
+	}
+
+	pub fn count(&self) -> usize {
+		self.samples.len()
+	}
+
+	/// Mean latency in nanoseconds, `None` if there are no samples.
+	pub fn mean(&self) -> Option<i64> {
+		if self.samples.is_empty() {
+			return None;
+		}
+		let sum: i128 = self.samples.iter().map(|s| *s as i128).sum();
+		Some((sum / self.samples.len() as i128) as i64)
+	}
+
+	/// Population standard deviation in nanoseconds, `None` if
+	/// there are no samples.
+	pub fn stddev(&self) -> Option<i64> {
+		let mean = self.mean()? as f64;
+		let var = self.samples.iter()
+			.map(|s| (*s as f64 - mean).powi(2))
+			.sum::<f64>() / self.samples.len() as f64;
+		Some(var.sqrt().round() as i64)
+	}
+
+	/// Latency percentile (nearest rank) in nanoseconds, `None` if
+	/// there are no samples. `p` must be in (0, 100].
+	pub fn percentile(&self, p: f64) -> Option<i64> {
+		if self.samples.is_empty() {
+			return None;
+		}
+		let mut sorted = self.samples.clone();
+		sorted.sort_unstable();
+		let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+		Some(sorted[rank.clamp(1, sorted.len()) - 1])
+	}
+}
+
+
+impl Display for Latency {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{} packets", self.count())?;
+		if let (Some(mean), Some(p99)) = (self.mean(), self.percentile(99.0)) {
+			write!(f, ", mean ")?;
+			fmt_nanos(f, mean)?;
+			write!(f, ", p99 ")?;
+			fmt_nanos(f, p99)?;
+		}
+		Ok(())
+	}
+}
+
+
+/// One echoed packet seen from both ends: T1 is the client send
+/// time, T2 the server receive time, T4 the client receive time of
+/// the echo. The server sends the echo right after receiving the
+/// packet without recording the time (T3), so server processing
+/// time counts towards the reverse delay. Forward and reverse delay
+/// are only meaningful if client and server clocks are synchronized
+/// or corrected for their offset (see [`ClockOffset`]), the round
+/// trip time is not affected by clock offset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Correlated {
+	pub sequence: u32,
+	pub size: usize,
+	pub t1: TimeSpec,
+	pub t2: TimeSpec,
+	pub t4: TimeSpec,
+}
+
+
+impl Correlated {
+	pub fn header() -> String {
+		String::from("sequence\tsize\tt1\tt2\tt4\tforward\treverse\trtt")
+	}
+
+	pub fn forward(&self) -> TimeSpec {
+		self.t2 - self.t1
+	}
+
+	pub fn reverse(&self) -> TimeSpec {
+		self.t4 - self.t2
+	}
+
+	pub fn rtt(&self) -> TimeSpec {
+		self.t4 - self.t1
+	}
+
+	/// Move the server receive time to the client clock, given the
+	/// offset of the server clock in nanoseconds (see
+	/// [`ClockOffset`]), so forward and reverse delay do not include
+	/// the clock offset.
+	pub fn corrected(&self, offset: i64) -> Self {
+		Correlated {
+			t2: self.t2 - nanos_timespec(offset),
+			..self.clone()
+		}
+	}
+}
+
+
+fn nanos_timespec(ns: i64) -> TimeSpec {
+	TimeSpec::new(ns.div_euclid(1_000_000_000), ns.rem_euclid(1_000_000_000))
+}
+
+
+/// Estimate of the offset of the server clock against the client
+/// clock from correlated echoes, using the NTP style four timestamp
+/// model: assuming equal forward and reverse delay, the offset is
+/// `((T2 - T1) + (T3 - T4)) / 2`. T3 is not recorded (see
+/// [`Correlated`]) and taken to be equal to T2. The echo with the
+/// lowest round trip time is used, it has the least queuing delay
+/// that might be asymmetric. Whatever the asymmetry, the true offset
+/// is within half that round trip time of the estimate, which is
+/// reported as the uncertainty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockOffset {
+	/// server clock minus client clock, in nanoseconds
+	pub offset: i64,
+	/// maximum error of the offset for any path asymmetry, in
+	/// nanoseconds
+	pub uncertainty: i64,
+	/// number of echoes the estimate was selected from
+	pub samples: usize,
+}
+
+
+impl ClockOffset {
+	/// Estimate the offset, `None` if there are no records.
+	pub fn estimate(records: &[Correlated]) -> Option<Self> {
+		let best = records.iter().min_by_key(|c| c.rtt())?;
+		let rtt = timespec_nanos(&best.rtt());
+		Some(ClockOffset {
+			offset: timespec_nanos(&best.forward()) - rtt / 2,
+			uncertainty: rtt / 2,
+			samples: records.len(),
+		})
+	}
+}
+
+
+impl Display for ClockOffset {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "clock offset (server - client): ")?;
+		fmt_nanos(f, self.offset)?;
+		write!(f, " +/- ")?;
+		fmt_nanos(f, self.uncertainty)?;
+		write!(f, ", best of {} echoes", self.samples)
+	}
+}
+
+
+impl Display for Correlated {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}\t{}", self.sequence, self.size)?;
+		for t in [self.t1, self.t2, self.t4, self.forward(), self.reverse(), self.rtt()] {
+			write!(f, "\t")?;
+			write_secs(f, timespec_nanos(&t))?;
+		}
+		Ok(())
+	}
+}
+
+
+/// Merge the server log and the client echo log of a run into one
+/// record per echoed packet, ordered by sequence number. Packets are
+/// matched by flow ID, sequence number, and send timestamp, so logs
+/// of a server with several clients or streams work, too. Packets
+/// missing from either log are skipped.
+pub fn correlate(server: &[ReceivedPacket], client: &[ReceivedPacket]) -> Vec<Correlated> {
+	let received: HashMap<(u32, u32, TimeSpec), TimeSpec> = server.iter()
+		.map(|p| ((p.flow, p.sequence, p.timestamp), p.receive_time))
+		.collect();
+	let mut merged: Vec<Correlated> = client.iter()
+		.filter_map(|echo| received.get(&(echo.flow, echo.sequence, echo.timestamp))
+			.map(|t2| Correlated {
+				sequence: echo.sequence,
+				size: echo.size,
+				t1: echo.timestamp,
+				t2: *t2,
+				t4: echo.receive_time,
+			}))
+		.collect();
+	merged.sort_by_key(|c| (c.sequence, c.t1));
+	merged
+}
+
+
+/// Round trip times of a burst of packets sent back to back at the
+/// start of a run (sequence numbers `0..count`). How the RTT grows
+/// from the first to the last packet of the burst shows how the
+/// buffers along the path fill.
+#[derive(Clone, Debug)]
+pub struct BurstRtt {
+	rtts: Vec<Option<TimeSpec>>,
+}
+
+
+impl BurstRtt {
+	pub fn new(count: usize) -> Self {
+		BurstRtt { rtts: vec![None; count] }
+	}
+
+	/// Record the RTT of an echo, returns `false` if the sequence
+	/// number is not part of the burst.
+	pub fn record(&mut self, sequence: u32, rtt: TimeSpec) -> bool {
+		match self.rtts.get_mut(sequence as usize) {
+			Some(r) => {
+				*r = Some(rtt);
+				true
+			},
+			None => false,
+		}
+	}
+
+	/// RTTs of the burst packets in send order, `None` for packets
+	/// without echo.
+	pub fn rtts(&self) -> &[Option<TimeSpec>] {
+		&self.rtts
+	}
+}
+
+
+impl Display for BurstRtt {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let mut latency = Latency::new();
+		self.rtts.iter().flatten().for_each(|r| latency.record(*r));
+		write!(f, "burst of {} packets: {latency}", self.rtts.len())?;
+		let mut echoed = self.rtts.iter().flatten();
+		if let Some(first) = echoed.next() {
+			let last = echoed.last().unwrap_or(first);
+			write!(f, ", first ")?;
+			fmt_nanos(f, timespec_nanos(first))?;
+			write!(f, ", last ")?;
+			fmt_nanos(f, timespec_nanos(last))?;
+		}
+		Ok(())
+	}
+}
+
+
+/// Gaps between consecutive sends, measured from the send side
+/// timestamps. Unlike the scheduling error against the intended
+/// send time this shows the pacing actually achieved, including
+/// syscall and scheduler effects.
+#[derive(Clone, Debug, Default)]
+pub struct SendGaps {
+	prev: Option<TimeSpec>,
+	gaps: Latency,
+}
+
+
+impl SendGaps {
+	pub fn new() -> Self {
+		SendGaps::default()
+	}
+
+	/// Record a send at time `t`, adding the gap to the previous
+	/// send if there was one.
+	pub fn record(&mut self, t: TimeSpec) {
+		if let Some(p) = self.prev.replace(t) {
+			self.gaps.record(t - p);
+		}
+	}
+
+	pub fn gaps(&self) -> &Latency {
+		&self.gaps
+	}
+}
+
+
+impl Display for SendGaps {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let g = &self.gaps;
+		write!(f, "inter-send gaps: {}", g.count())?;
+		if let (Some(mean), Some(stddev), Some(p99)) =
+			(g.mean(), g.stddev(), g.percentile(99.0))
+		{
+			write!(f, ", mean ")?;
+			fmt_nanos(f, mean)?;
+			write!(f, ", stddev ")?;
+			fmt_nanos(f, stddev)?;
+			write!(f, ", p99 ")?;
+			fmt_nanos(f, p99)?;
+		}
+		Ok(())
+	}
+}
+
+
+/// Requested versus achieved packet rate. The requested rate follows
+/// from the scheduled send times, the achieved one from the actual
+/// send times. If the per-packet cost of sending exceeds the
+/// interval, the actual send times fall further and further behind
+/// the schedule.
+#[derive(Clone, Debug, Default)]
+pub struct SendRate {
+	/// number of packets at the start to exclude, like for
+	/// [`Throughput`]
+	skip: usize,
+	packets: usize,
+	/// scheduled and actual send time of the first packet after skip
+	first: Option<(TimeSpec, TimeSpec)>,
+	/// scheduled and actual send time of the most recent packet
+	last: Option<(TimeSpec, TimeSpec)>,
+}
+
+
+impl SendRate {
+	/// Minimum number of packets before [`SendRate::unachievable`]
+	/// reports anything, so single late wakeups don't count.
+	pub const MIN_PACKETS: usize = 1000;
+	/// Fraction of the requested rate below which it is considered
+	/// not achievable.
+	pub const THRESHOLD: f64 = 0.9;
+
+	pub fn new(skip: usize) -> Self {
+		SendRate { skip, ..Default::default() }
+	}
+
+	/// Record a packet scheduled for `scheduled` and sent at
+	/// `actual`.
+	pub fn record(&mut self, scheduled: TimeSpec, actual: TimeSpec) {
+		if self.skip > 0 {
+			self.skip -= 1;
+			return;
+		}
+		self.packets += 1;
+		self.first.get_or_insert((scheduled, actual));
+		self.last = Some((scheduled, actual));
+	}
+
+	fn rate(&self, span: impl Fn((TimeSpec, TimeSpec)) -> TimeSpec) -> Option<f64> {
+		let (first, last) = (self.first?, self.last?);
+		let s = timespec_secs(&(span(last) - span(first)));
+		(self.packets > 1 && s > 0.0).then(|| (self.packets - 1) as f64 / s)
+	}
+
+	/// Requested rate in packets per second, `None` if there are
+	/// less than two packets or all were scheduled at the same time.
+	pub fn requested(&self) -> Option<f64> {
+		self.rate(|(scheduled, _)| scheduled)
+	}
+
+	/// Achieved rate in packets per second, `None` if there are less
+	/// than two packets.
+	pub fn achieved(&self) -> Option<f64> {
+		self.rate(|(_, actual)| actual)
+	}
+
+	/// If the achieved rate persistently stays below the requested
+	/// one, returns the requested rate and the achieved one, which
+	/// is then an estimate of the maximum rate on this host.
+	pub fn unachievable(&self) -> Option<(f64, f64)> {
+		if self.packets < Self::MIN_PACKETS {
+			return None;
+		}
+		let (requested, achieved) = (self.requested()?, self.achieved()?);
+		(achieved < requested * Self::THRESHOLD).then_some((requested, achieved))
+	}
+}
+
+
+impl Display for SendRate {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "packet rate: requested ")?;
+		match self.requested() {
+			Some(r) => write!(f, "{r:.1}/s")?,
+			None => write!(f, "unlimited")?,
+		}
+		match self.achieved() {
+			Some(a) => write!(f, ", achieved {a:.1}/s"),
+			None => write!(f, ", achieved n/a"),
+		}
+	}
+}
+
+
+/// Latency statistics by packet size. Each bucket is defined by its
+/// inclusive upper size bound, plus a final bucket for all sizes
+/// above the largest bound.
+#[derive(Clone, Debug)]
+pub struct SizeBuckets {
+	bounds: Vec<usize>,
+	buckets: Vec<Latency>,
+}
+
+
+impl SizeBuckets {
+	pub fn new(mut bounds: Vec<usize>) -> Self {
+		bounds.sort_unstable();
+		bounds.dedup();
+		let buckets = vec![Latency::new(); bounds.len() + 1];
+		SizeBuckets { bounds, buckets }
+	}
+
+	pub fn record(&mut self, size: usize, latency: TimeSpec) {
+		let i = self.bounds.partition_point(|b| *b < size);
+		self.buckets[i].record(latency);
+	}
+
+	/// Iterate over the buckets as tuples of lower and upper size
+	/// bound (both inclusive, `None` for no upper bound) and
+	/// latency.
+	pub fn iter(&self) -> impl Iterator<Item = (usize, Option<usize>, &Latency)> {
+		let lower = [0].into_iter().chain(self.bounds.iter().map(|b| b + 1));
+		let upper = self.bounds.iter().map(|b| Some(*b)).chain([None]);
+		lower.zip(upper).zip(self.buckets.iter())
+			.map(|((l, u), b)| (l, u, b))
+	}
+}
+
+
+impl Display for SizeBuckets {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "latency by packet size:")?;
+		for (lower, upper, latency) in self.iter() {
+			if latency.count() == 0 {
+				continue;
+			}
+			match upper {
+				Some(u) => write!(f, "\n  {lower}-{u} bytes: {latency}")?,
+				None => write!(f, "\n  >= {lower} bytes: {latency}")?,
+			}
+		}
+		Ok(())
+	}
+}
+
+
+/// Throughput calculation for sent packets. The steady state rate
+/// covers the time from the first send after the warmup packets to
+/// the last send, so neither setup before the first packet nor
+/// waiting for pending echoes after the last one skews the result.
+#[derive(Debug, Default)]
+pub struct Throughput {
+	/// number of packets at the start to exclude from the steady
+	/// state rate
+	warmup: usize,
+	/// total packets recorded
+	packets: usize,
+	/// total bytes recorded
+	bytes: usize,
+	/// bytes sent after the start of the steady state window
+	steady_bytes: usize,
+	/// send time of the first packet after warmup
+	first: Option<TimeSpec>,
+	/// send time of the most recent packet
+	last: Option<TimeSpec>,
+}
+
+
+impl Throughput {
+	pub fn new(warmup: usize) -> Self {
+		Throughput { warmup, ..Default::default() }
+	}
+
+	/// Record a packet of the given size sent at the given time.
+	pub fn record(&mut self, time: TimeSpec, size: usize) {
+		self.packets += 1;
+		self.bytes += size;
+		if self.packets <= self.warmup {
+			return;
+		}
+		// The window starts at the first send, so the size of that
+		// packet is not part of the window: n packets span n - 1
+		// intervals.
+		if self.first.is_none() {
+			self.first = Some(time);
+		} else {
+			self.steady_bytes += size;
+		}
+		self.last = Some(time);
+	}
+
+	/// Total number of packets recorded.
+	pub fn packets(&self) -> usize {
+		self.packets
+	}
+
+	/// Total number of bytes recorded.
+	pub fn bytes(&self) -> usize {
+		self.bytes
+	}
+
+	/// Duration of the steady state window, `None` if it does not
+	/// contain at least two packets.
+	pub fn window(&self) -> Option<TimeSpec> {
+		match (self.first, self.last) {
+			(Some(f), Some(l)) if l > f => Some(l - f),
+			_ => None,
+		}
+	}
+
+	/// Steady state throughput in bytes per second, `None` if the
+	/// window does not contain at least two packets.
+	pub fn steady_rate(&self) -> Option<f64> {
+		self.window()
+			.map(|w| self.steady_bytes as f64 / timespec_secs(&w))
+	}
+
+	/// Throughput of all recorded bytes over the given wall clock
+	/// duration of the run, in bytes per second.
+	pub fn wall_rate(&self, duration: Duration) -> f64 {
+		self.bytes as f64 / duration.as_secs_f64()
+	}
+}
+
+
+/// Summary of a Throughput plus the wall clock duration of the run.
+pub struct ThroughputSummary<'a>(pub &'a Throughput, pub Duration);
+
+impl Display for ThroughputSummary<'_> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let ThroughputSummary(t, duration) = self;
+		write!(f, "sent {} packets, {} bytes; throughput: ", t.packets, t.bytes)?;
+		match (t.steady_rate(), t.window()) {
+			(Some(r), Some(w)) => write!(
+				f, "{:.0} bit/s wire time ({}.{:09}s), ",
+				r * 8.0, w.tv_sec(), w.tv_nsec())?,
+			_ => write!(f, "no steady state window, ")?,
+		}
+		write!(
+			f, "{:.0} bit/s wall clock ({:.3}s)",
+			t.wall_rate(*duration) * 8.0, duration.as_secs_f64())
+	}
+}
+
+
+/// Running counters shared between the client threads, so periodic
+/// snapshots can be read without blocking the send loop. Round trip
+/// times are collected per snapshot interval, each snapshot takes the
+/// samples recorded since the previous one.
+#[derive(Debug, Default)]
+pub struct Live {
+	label: Option<String>,
+	planned: Option<usize>,
+	clock: SendClock,
+	sent: AtomicUsize,
+	received: AtomicUsize,
+	rtt: Mutex<Latency>,
+}
+
+
+impl Live {
+	/// Create new counters, the label, planned packet count (if
+	/// any), and send clock are included in snapshots.
+	pub fn new(label: Option<String>, planned: Option<usize>, clock: SendClock) -> Self {
+		Live { label, planned, clock, ..Default::default() }
+	}
+
+	/// Count a sent packet.
+	pub fn sent(&self) {
+		self.sent.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Count a received echo with the given round trip time.
+	pub fn received(&self, rtt: TimeSpec) {
+		self.received.fetch_add(1, Ordering::Relaxed);
+		self.rtt.lock().unwrap().record(rtt);
+	}
+
+	/// Count a received echo outside the sample, without round trip
+	/// time.
+	pub fn received_unsampled(&self) {
+		self.received.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Create a snapshot as a single line JSON object, `elapsed` is
+	/// the time since the start of the run. Packets that have not
+	/// been echoed (yet) count as lost, so the loss includes packets
+	/// still in flight. Socket queue sizes are included if given.
+	pub fn snapshot(&self, elapsed: Duration, queues: Option<SocketQueues>) -> String {
+		let rtt = std::mem::take(&mut *self.rtt.lock().unwrap());
+		let sent = self.sent.load(Ordering::Relaxed);
+		let received = self.received.load(Ordering::Relaxed);
+		let opt = |v: Option<i64>| v.map(|v| v.to_string())
+			.unwrap_or(String::from("null"));
+		format!(
+			concat!(
+				"{{\"label\": {}, \"clock\": {}, ",
+				"\"elapsed\": {:.9}, \"planned\": {}, \"sent\": {}, \"received\": {}, ",
+				"\"lost\": {}, \"rtt_count\": {}, \"rtt_mean_ns\": {}, ",
+				"\"rtt_p50_ns\": {}, \"rtt_p99_ns\": {}, ",
+				"\"send_queue\": {}, \"receive_queue\": {}, \"receive_buffer\": {}}}"),
+			self.label.as_deref().map(json_string)
+				.unwrap_or(String::from("null")),
+			json_string(&self.clock.to_string()),
+			elapsed.as_secs_f64(),
+			opt(self.planned.map(|p| p as i64)), sent, received,
+			sent.saturating_sub(received), rtt.count(), opt(rtt.mean()),
+			opt(rtt.percentile(50.0)), opt(rtt.percentile(99.0)),
+			opt(queues.map(|q| q.send as i64)), opt(queues.map(|q| q.receive as i64)),
+			opt(queues.map(|q| q.receive_buffer as i64)))
+	}
+}
+
+
+/// Memory budget for the components of a run that allocate memory
+/// depending on run length or options, so a run fails with a clear
+/// error instead of exhausting (possibly locked) memory. Once the
+/// budget has been exceeded all further reservations fail.
+#[derive(Debug, Default)]
+pub struct MemBudget {
+	limit: usize,
+	used: AtomicUsize,
+	exceeded: Mutex<Option<String>>,
+}
+
+
+impl MemBudget {
+	pub fn new(limit: usize) -> Self {
+		MemBudget { limit, ..Default::default() }
+	}
+
+	/// Account for `bytes` of additional memory used by `what`.
+	pub fn reserve(&self, what: &str, bytes: usize) -> Result<(), Error> {
+		let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+		if used > self.limit {
+			let mut e = self.exceeded.lock().unwrap();
+			e.get_or_insert_with(|| format!(
+				"memory budget of {} bytes exceeded by {what} ({used} bytes)",
+				self.limit));
+		}
+		self.check()
+	}
+
+	/// Fail if the budget has been exceeded.
+	pub fn check(&self) -> Result<(), Error> {
+		if self.used() <= self.limit {
+			return Ok(());
+		}
+		match &*self.exceeded.lock().unwrap() {
+			None => Ok(()),
+			Some(e) => Err(Error::new(ErrorKind::OutOfMemory, e.clone())),
+		}
+	}
+
+	/// Number of bytes reserved so far.
+	pub fn used(&self) -> usize {
+		self.used.load(Ordering::Relaxed)
+	}
+}
+
+
+/// Loss split by direction, using the echo sequence numbers the
+/// server stamps into echoes (see [`crate::ECHO_SEQ_FLAG`]). Gaps in
+/// the echo sequence are losses on the way back, packets the server
+/// never echoed were lost on the way to it. Reverse losses after the
+/// last received echo are indistinguishable from forward losses and
+/// counted as such.
+#[derive(Debug, Default)]
+pub struct PathLoss {
+	stamped: usize,
+	range: Option<(u32, u32)>,
+}
+
+
+impl PathLoss {
+	/// Record the echo sequence number of a received echo.
+	pub fn record(&mut self, echo_sequence: u32) {
+		self.stamped += 1;
+		self.range = Some(match self.range {
+			None => (echo_sequence, echo_sequence),
+			Some((min, max)) => (min.min(echo_sequence), max.max(echo_sequence)),
+		});
+	}
+
+	/// Number of received echoes with an echo sequence number.
+	pub fn stamped(&self) -> usize {
+		self.stamped
+	}
+
+	/// Number of echoes the server sent, as far as visible from the
+	/// echo sequence numbers.
+	pub fn echoed(&self) -> usize {
+		self.range.map(|(min, max)| (max - min) as usize + 1).unwrap_or(0)
+	}
+
+	/// Echoes lost on the way back from the server.
+	pub fn reverse(&self) -> usize {
+		self.echoed().saturating_sub(self.stamped)
+	}
+
+	/// Packets lost on the way to the server, out of `requested`
+	/// packets sent with an echo sequence number request.
+	pub fn forward(&self, requested: usize) -> usize {
+		requested.saturating_sub(self.echoed())
+	}
+}
+
+
+/// How a packet arrived, relative to the sequence numbers seen
+/// before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arrival {
+	/// higher sequence number than all before
+	New,
+	/// filled a gap below the highest sequence number
+	Reordered,
+	/// sequence number seen before
+	Duplicate,
+	/// too far below the highest sequence number to tell if it was
+	/// seen before
+	Late,
+}
+
+
+/// Loss, reordering, and duplication seen in the sequence numbers of
+/// received packets, which the sender numbers from 0. A gap below
+/// the highest sequence number counts as lost until the packet
+/// arrives late, then it counts as reordered instead. A sequence
+/// number seen before is a duplicate. Only the missing ranges within
+/// a window below the highest sequence number are stored, so memory
+/// stays bounded however many packets are lost. Gaps that fall out
+/// of the window stay lost, packets arriving from there are counted
+/// as late.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SequenceGaps {
+	received: usize,
+	lost: usize,
+	reordered: usize,
+	duplicated: usize,
+	late: usize,
+	/// one past the highest sequence number seen
+	next: u64,
+	/// missing sequence numbers below `next`, start to end
+	/// (exclusive)
+	missing: BTreeMap<u64, u64>,
+	/// how far below `next` gaps are remembered
+	window: u64,
+}
+
+
+impl Default for SequenceGaps {
+	fn default() -> Self {
+		SequenceGaps::with_window(SequenceGaps::WINDOW)
+	}
+}
+
+
+impl SequenceGaps {
+	/// Default number of sequence numbers below the highest one seen
+	/// that are classified exactly.
+	pub const WINDOW: u32 = 1 << 16;
+
+	pub fn new() -> Self {
+		SequenceGaps::default()
+	}
+
+	/// Remember gaps only within `window` sequence numbers below the
+	/// highest one seen.
+	pub fn with_window(window: u32) -> Self {
+		SequenceGaps {
+			received: 0,
+			lost: 0,
+			reordered: 0,
+			duplicated: 0,
+			late: 0,
+			next: 0,
+			missing: BTreeMap::new(),
+			window: u64::from(window),
+		}
+	}
+
+	/// Record the sequence number of a received packet.
+	pub fn record(&mut self, sequence: u32) -> Arrival {
+		self.received += 1;
+		let s = u64::from(sequence);
+		if s >= self.next {
+			if s > self.next {
+				self.missing.insert(self.next, s);
+				self.lost += (s - self.next) as usize;
+			}
+			self.next = s + 1;
+			self.forget();
+			return Arrival::New;
+		}
+		if s < self.next.saturating_sub(self.window) {
+			self.late += 1;
+			return Arrival::Late;
+		}
+		match self.missing.range(..=s).next_back().map(|(start, end)| (*start, *end)) {
+			Some((start, end)) if s < end => {
+				self.missing.remove(&start);
+				if start < s {
+					self.missing.insert(start, s);
+				}
+				if s + 1 < end {
+					self.missing.insert(s + 1, end);
+				}
+				self.lost -= 1;
+				self.reordered += 1;
+				Arrival::Reordered
+			},
+			_ => {
+				self.duplicated += 1;
+				Arrival::Duplicate
+			},
+		}
+	}
+
+	/// Drop the parts of gaps that are out of the window.
+	fn forget(&mut self) {
+		let floor = self.next.saturating_sub(self.window);
+		while let Some(e) = self.missing.first_entry()
+			&& *e.key() < floor
+		{
+			let (_, end) = e.remove_entry();
+			if end > floor {
+				self.missing.insert(floor, end);
+				break;
+			}
+		}
+	}
+
+	/// Count packets up to `sent` (exclusive) that are missing after
+	/// the highest sequence number seen as lost, e.g. at the end of
+	/// a run. Without this call trailing losses are not visible.
+	pub fn finish(&mut self, sent: u32) {
+		let sent = u64::from(sent);
+		if sent > self.next {
+			self.missing.insert(self.next, sent);
+			self.lost += (sent - self.next) as usize;
+			self.next = sent;
+		}
+	}
+
+	/// Number of packets received, including duplicates.
+	pub fn received(&self) -> usize {
+		self.received
+	}
+
+	/// Sequence numbers missing below the highest one seen (or the
+	/// end given to [`SequenceGaps::finish`]).
+	pub fn lost(&self) -> usize {
+		self.lost
+	}
+
+	/// Packets that arrived after one with a higher sequence number.
+	pub fn reordered(&self) -> usize {
+		self.reordered
+	}
+
+	/// Packets with a sequence number seen before.
+	pub fn duplicated(&self) -> usize {
+		self.duplicated
+	}
+
+	/// Packets too far below the highest sequence number to classify.
+	pub fn late(&self) -> usize {
+		self.late
+	}
+}
+
+
+impl Display for SequenceGaps {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(
+			f, "sequence numbers: {} received, {} lost, {} reordered, {} duplicated",
+			self.received, self.lost, self.reordered, self.duplicated)?;
+		if self.late > 0 {
+			write!(f, ", {} too late to classify", self.late)?;
+		}
+		Ok(())
+	}
+}
+
+
+/// Check that kernel receive timestamps increase with the sequence
+/// number: A packet with a higher sequence number than all before
+/// must not have an earlier receive time than the packet with the
+/// previous highest sequence number. Anything else points to clock
+/// steps or mixed hardware and software timestamps, which make delay
+/// and jitter calculations meaningless. Reordered packets (lower
+/// sequence number than seen before) are not checked.
+#[derive(Clone, Debug, Default)]
+pub struct ReceiveOrder {
+	last: Option<(u32, TimeSpec)>,
+	checked: usize,
+	anomalies: usize,
+}
+
+
+impl ReceiveOrder {
+	pub fn new() -> Self {
+		ReceiveOrder::default()
+	}
+
+	/// Record a packet, returns `true` if its receive time is
+	/// earlier than that of a packet with lower sequence number.
+	pub fn record(&mut self, sequence: u32, receive_time: TimeSpec) -> bool {
+		match self.last {
+			Some((s, _)) if sequence <= s => false,
+			Some((_, t)) => {
+				self.checked += 1;
+				self.last = Some((sequence, receive_time));
+				let anomaly = receive_time < t;
+				if anomaly {
+					self.anomalies += 1;
+				}
+				anomaly
+			},
+			None => {
+				self.last = Some((sequence, receive_time));
+				false
+			},
+		}
+	}
+
+	/// Number of packets with receive time going backwards.
+	pub fn anomalies(&self) -> usize {
+		self.anomalies
+	}
+}
+
+
+impl Display for ReceiveOrder {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(
+			f, "receive timestamps: {} of {} checked packets earlier than a lower sequence number",
+			self.anomalies, self.checked)
+	}
+}
+
+
+/// Round trip time of the first echo of a run, kept apart from the
+/// other round trip times because it often includes path setup
+/// (neighbor discovery, route cache misses) that later packets don't
+/// pay for. The setup cost is estimated as the difference to the
+/// fastest later echo.
+#[derive(Clone, Debug, Default)]
+pub struct FirstEcho {
+	first: Option<(u32, TimeSpec)>,
+	min_later: Option<TimeSpec>,
+}
+
+
+impl FirstEcho {
+	pub fn new() -> Self {
+		FirstEcho::default()
+	}
+
+	/// Record the round trip time of an echo, in the order echoes
+	/// were received. Returns `false` for the first echo, which
+	/// stays out of the round trip time aggregates.
+	pub fn record(&mut self, sequence: u32, rtt: TimeSpec) -> bool {
+		if self.first.is_none() {
+			self.first = Some((sequence, rtt));
+			false
+		} else {
+			self.min_later = Some(self.min_later.map_or(rtt, |m| m.min(rtt)));
+			true
+		}
+	}
+
+	/// Sequence number and round trip time of the first echo.
+	pub fn first(&self) -> Option<(u32, TimeSpec)> {
+		self.first
+	}
+
+	/// Round trip time of the first echo minus the shortest of any
+	/// later echo, `None` with less than two echoes.
+	pub fn setup_cost(&self) -> Option<TimeSpec> {
+		self.first.zip(self.min_later).map(|((_, f), m)| f - m)
+	}
+}
+
+
+impl Display for FirstEcho {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let Some((sequence, rtt)) = self.first else {
+			return write!(f, "first echo: none");
+		};
+		write!(f, "first echo: sequence {sequence}, round trip time ")?;
+		fmt_nanos(f, timespec_nanos(&rtt))?;
+		if let Some(c) = self.setup_cost() {
+			write!(f, ", ")?;
+			fmt_nanos(f, timespec_nanos(&c))?;
+			write!(f, " more than the fastest later echo")?;
+		}
+		Ok(())
+	}
+}
+
+
+/// Resource usage of a thread during a run, calculated from
+/// `getrusage()` results before and after.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+	pub user_time: Duration,
+	pub system_time: Duration,
+	pub major_page_faults: i64,
+	pub minor_page_faults: i64,
+	pub voluntary_context_switches: i64,
+	pub involuntary_context_switches: i64,
+}
+
+
+impl ResourceUsage {
+	pub fn between(pre: &Usage, post: &Usage) -> Self {
+		let time = |pre: TimeVal, post: TimeVal| Duration::from_micros(
+			(post.num_microseconds() - pre.num_microseconds()).max(0) as u64);
+		ResourceUsage {
+			user_time: time(pre.user_time(), post.user_time()),
+			system_time: time(pre.system_time(), post.system_time()),
+			major_page_faults: post.major_page_faults() - pre.major_page_faults(),
+			minor_page_faults: post.minor_page_faults() - pre.minor_page_faults(),
+			voluntary_context_switches:
+				post.voluntary_context_switches() - pre.voluntary_context_switches(),
+			involuntary_context_switches:
+				post.involuntary_context_switches() - pre.involuntary_context_switches(),
+		}
+	}
+}
+
+
+impl Display for ResourceUsage {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		writeln!(
+			f, "major page faults: {}, minor page faults: {}",
+			self.major_page_faults, self.minor_page_faults)?;
+		writeln!(
+			f, "user CPU time: {:.6}s, system CPU time: {:.6}s",
+			self.user_time.as_secs_f64(), self.system_time.as_secs_f64())?;
+		write!(
+			f, "voluntary context switches: {}, involuntary context switches: {}",
+			self.voluntary_context_switches, self.involuntary_context_switches)
+	}
+}
+
+
+/// Resource usage and scheduling of the send or receive thread
+/// during a run, returned by the client and server run functions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunUsage {
+	pub resources: ResourceUsage,
+	/// scheduling of the thread at the end of the run
+	pub scheduling: Scheduling,
+	/// locked memory limit that applied when memory was locked at
+	/// the start of the run
+	pub memlock: MemlockLimit,
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn steady_state() {
+		// 1ms interval, 100 byte packets: 100kB/s
+		let interval = TimeSpec::new(0, 1_000_000);
+		let mut t = Throughput::new(5);
+		let mut time = TimeSpec::new(1000, 0);
+		// warmup packets are bigger and slower, must not count
+		for _ in 0..5 {
+			t.record(time, 1000);
+			time = time + TimeSpec::new(0, 50_000_000);
+		}
+		for _ in 0..100 {
+			t.record(time, 100);
+			time = time + interval;
+		}
+		assert_eq!(t.packets(), 105);
+		assert_eq!(t.bytes(), 15_000);
+		assert_eq!(t.window(), Some(TimeSpec::new(0, 99_000_000)));
+		let rate = t.steady_rate().unwrap();
+		assert!((rate - 100_000.0).abs() < 1e-6, "unexpected rate {rate}");
+		// the wall clock rate includes everything
+		assert_eq!(t.wall_rate(Duration::from_secs(1)), 15_000.0);
+	}
+
+	#[test]
+	fn latency() {
+		let mut l = Latency::new();
+		assert_eq!(l.mean(), None);
+		assert_eq!(l.percentile(99.0), None);
+		for i in 1..=100 {
+			l.record(TimeSpec::new(0, i * 1000));
+		}
+		assert_eq!(l.count(), 100);
+		assert_eq!(l.mean(), Some(50_500));
+		assert_eq!(l.percentile(99.0), Some(99_000));
+		assert_eq!(l.percentile(100.0), Some(100_000));
+		assert_eq!(l.stddev(), Some(28_866));
+		assert_eq!(
+			format!("{l}"),
+			"100 packets, mean 0.000050500s, p99 0.000099000s");
+	}
+
+	#[test]
+	fn burst_rtt() {
+		let mut b = BurstRtt::new(4);
+		assert_eq!(format!("{b}"), "burst of 4 packets: 0 packets");
+		assert!(b.record(0, TimeSpec::new(0, 100_000)));
+		assert!(b.record(2, TimeSpec::new(0, 300_000)));
+		assert!(!b.record(4, TimeSpec::new(0, 1_000)));
+		assert_eq!(
+			b.rtts(),
+			[Some(TimeSpec::new(0, 100_000)), None, Some(TimeSpec::new(0, 300_000)), None]);
+		assert_eq!(
+			format!("{b}"),
+			"burst of 4 packets: 2 packets, mean 0.000200000s, p99 0.000300000s, first 0.000100000s, last 0.000300000s");
+	}
+
+	#[test]
+	fn send_gaps() {
+		let mut g = SendGaps::new();
+		assert_eq!(format!("{g}"), "inter-send gaps: 0");
+		for t in [0, 1_000_000, 3_000_000, 4_000_000, 5_000_000] {
+			g.record(TimeSpec::new(10, t));
+		}
+		assert_eq!(g.gaps().count(), 4);
+		assert_eq!(
+			format!("{g}"),
+			"inter-send gaps: 4, mean 0.001250000s, stddev 0.000433013s, p99 0.002000000s");
+	}
+
+	#[test]
+	fn receive_order() {
+		let mut o = ReceiveOrder::new();
+		assert!(!o.record(0, TimeSpec::new(1, 0)));
+		assert!(!o.record(1, TimeSpec::new(1, 100)));
+		// equal timestamps are fine
+		assert!(!o.record(2, TimeSpec::new(1, 100)));
+		// clock stepped back
+		assert!(o.record(3, TimeSpec::new(0, 900)));
+		// reordered packet, not checked
+		assert!(!o.record(1, TimeSpec::new(0, 0)));
+		// compared against sequence 3, not 2
+		assert!(!o.record(5, TimeSpec::new(0, 950)));
+		assert!(o.record(6, TimeSpec::new(0, 10)));
+		assert_eq!(o.anomalies(), 2);
+		assert_eq!(
+			format!("{o}"),
+			"receive timestamps: 2 of 5 checked packets earlier than a lower sequence number");
+	}
+
+	#[test]
+	fn first_echo() {
+		let mut e = FirstEcho::new();
+		assert_eq!(e.to_string(), "first echo: none");
+		// the first echo is the slow one
+		let (mut all, mut rest) = (Latency::new(), Latency::new());
+		for (s, rtt) in [(1, 5_000_000), (0, 1_200_000), (2, 1_000_000), (3, 1_100_000)] {
+			let rtt = TimeSpec::new(0, rtt);
+			all.record(rtt);
+			if e.record(s, rtt) {
+				rest.record(rtt);
+			}
+			if s == 1 {
+				assert_eq!(e.setup_cost(), None);
+			}
+		}
+		assert_eq!(e.first(), Some((1, TimeSpec::new(0, 5_000_000))));
+		assert_eq!(e.setup_cost(), Some(TimeSpec::new(0, 4_000_000)));
+		// not part of the aggregates of the other echoes
+		assert_eq!(rest.count(), 3);
+		assert_eq!((all.percentile(99.0), all.mean()), (Some(5_000_000), Some(2_075_000)));
+		assert_eq!((rest.percentile(99.0), rest.mean()), (Some(1_200_000), Some(1_100_000)));
+		assert_eq!(
+			e.to_string(),
+			"first echo: sequence 1, round trip time 0.005000000s, 0.004000000s more than the fastest later echo");
+	}
+
+	#[test]
+	fn send_rate() {
+		let mut r = SendRate::new(1);
+		assert_eq!(format!("{r}"), "packet rate: requested unlimited, achieved n/a");
+		// skipped
+		r.record(TimeSpec::new(0, 0), TimeSpec::new(5, 0));
+		for i in 0..SendRate::MIN_PACKETS as i64 {
+			// 1000 packets/s requested, each send takes 2 ms
+			r.record(TimeSpec::new(10, 0) + TimeSpec::new(0, i * 1_000_000),
+				TimeSpec::new(10, 0) + TimeSpec::new(0, i * 2_000_000));
+			if i == 10 {
+				assert_eq!(r.unachievable(), None);
+			}
+		}
+		assert_eq!(r.requested(), Some(1000.0));
+		assert_eq!(r.achieved(), Some(500.0));
+		assert_eq!(r.unachievable(), Some((1000.0, 500.0)));
+		assert_eq!(format!("{r}"), "packet rate: requested 1000.0/s, achieved 500.0/s");
+
+		let mut r = SendRate::new(0);
+		for i in 0..SendRate::MIN_PACKETS as i64 {
+			let t = TimeSpec::new(0, i * 1_000_000);
+			r.record(t, t + TimeSpec::new(0, 50_000));
+		}
+		assert_eq!(r.unachievable(), None);
+	}
+
+	#[test]
+	fn size_buckets() {
+		let mut b = SizeBuckets::new(vec![1000, 100]);
+		for i in 0..50 {
+			b.record(64, TimeSpec::new(0, 100_000 + i));
+			b.record(1400, TimeSpec::new(0, 2_000_000 + i));
+		}
+		let buckets: Vec<_> = b.iter()
+			.map(|(l, u, lat)| (l, u, lat.count(), lat.mean()))
+			.collect();
+		assert_eq!(buckets, vec![
+			(0, Some(100), 50, Some(100_024)),
+			(101, Some(1000), 0, None),
+			(1001, None, 50, Some(2_000_024)),
+		]);
+		// the upper bound is inclusive
+		b.record(100, TimeSpec::new(0, 0));
+		assert_eq!(b.iter().next().unwrap().2.count(), 51);
+		let s = format!("{b}");
+		assert!(s.contains("\n  0-100 bytes: 51 packets"), "{s}");
+		assert!(!s.contains("101-1000"), "{s}");
+		assert!(s.contains("\n  >= 1001 bytes: 50 packets"), "{s}");
+	}
+
+	#[test]
+	fn live_snapshot() {
+		let live = Live::new(None, Some(10), SendClock::Realtime);
+		for _ in 0..4 {
+			live.sent();
+		}
+		live.received(TimeSpec::new(0, 1000));
+		live.received(TimeSpec::new(0, 3000));
+		assert_eq!(
+			live.snapshot(Duration::from_millis(1500), None),
+			concat!(
+				r#"{"label": null, "clock": "realtime", "#,
+				r#""elapsed": 1.500000000, "planned": 10, "sent": 4, "received": 2, "#,
+				r#""lost": 2, "rtt_count": 2, "rtt_mean_ns": 2000, "#,
+				r#""rtt_p50_ns": 1000, "rtt_p99_ns": 3000, "#,
+				r#""send_queue": null, "receive_queue": null, "receive_buffer": null}"#));
+		// RTT samples are reset by the snapshot, counters are not
+		assert_eq!(
+			live.snapshot(
+				Duration::from_secs(3),
+				Some(SocketQueues { send: 10, receive: 20, receive_buffer: 30 })),
+			concat!(
+				r#"{"label": null, "clock": "realtime", "#,
+				r#""elapsed": 3.000000000, "planned": 10, "sent": 4, "received": 2, "#,
+				r#""lost": 2, "rtt_count": 0, "rtt_mean_ns": null, "#,
+				r#""rtt_p50_ns": null, "rtt_p99_ns": null, "#,
+				r#""send_queue": 10, "receive_queue": 20, "receive_buffer": 30}"#));
+
+		let live = Live::new(Some(String::from("before \"change\"")), None, SendClock::Tai);
+		assert!(live.snapshot(Duration::ZERO, None)
+				.starts_with(r#"{"label": "before \"change\"", "clock": "tai", "#));
+	}
+
+	#[test]
+	fn resource_usage() -> Result<(), nix::Error> {
+		let pre = nix::sys::resource::getrusage(
+			nix::sys::resource::UsageWho::RUSAGE_THREAD)?;
+		assert_eq!(ResourceUsage::between(&pre, &pre), ResourceUsage::default());
+		let mut post = pre;
+		let r = post.as_mut();
+		r.ru_utime.tv_usec += 1500;
+		r.ru_stime.tv_sec += 2;
+		r.ru_minflt += 3;
+		r.ru_nvcsw += 4;
+		r.ru_nivcsw += 5;
+		let u = ResourceUsage::between(&pre, &post);
+		assert_eq!(u.user_time, Duration::from_micros(1500));
+		assert_eq!(u.system_time, Duration::from_secs(2));
+		assert_eq!(
+			(u.major_page_faults, u.minor_page_faults,
+			 u.voluntary_context_switches, u.involuntary_context_switches),
+			(0, 3, 4, 5));
+		assert_eq!(
+			u.to_string(),
+			concat!(
+				"major page faults: 0, minor page faults: 3\n",
+				"user CPU time: 0.001500s, system CPU time: 2.000000s\n",
+				"voluntary context switches: 4, involuntary context switches: 5"));
+		Ok(())
+	}
+
+	#[test]
+	fn correlate_logs() -> Result<(), Error> {
+		let server: Vec<ReceivedPacket> = [
+			"10.000300000\t::1\t40000\t1\t10.000100000\t64",
+			"10.000200000\t::1\t40000\t0\t10.000000000\t64",
+			// another client using the same sequence number
+			"10.000250000\t::1\t40001\t0\t10.000050000\t64",
+			// echo lost on the way back
+			"10.000400000\t::1\t40000\t2\t10.000200000\t64",
+		].iter().map(|l| l.parse()).collect::<Result<_, _>>()?;
+		let client: Vec<ReceivedPacket> = [
+			"10.000700000\t::1\t7800\t1\t10.000100000\t64",
+			"10.000500000\t::1\t7800\t0\t10.000000000\t64",
+			// not in the server log
+			"10.000900000\t::1\t7800\t3\t10.000300000\t64",
+		].iter().map(|l| l.parse()).collect::<Result<_, _>>()?;
+
+		let merged = correlate(&server, &client);
+		assert_eq!(merged, vec![
+			Correlated {
+				sequence: 0, size: 64, t1: TimeSpec::new(10, 0),
+				t2: TimeSpec::new(10, 200_000), t4: TimeSpec::new(10, 500_000),
+			},
+			Correlated {
+				sequence: 1, size: 64, t1: TimeSpec::new(10, 100_000),
+				t2: TimeSpec::new(10, 300_000), t4: TimeSpec::new(10, 700_000),
+			},
+		]);
+		assert_eq!(
+			format!("{}", merged[1]),
+			"1\t64\t10.000100000\t10.000300000\t10.000700000\t0.000200000\t0.000400000\t0.000600000");
+		Ok(())
+	}
+
+	#[test]
+	fn clock_offset() {
+		assert_eq!(ClockOffset::estimate(&[]), None);
+		// server clock 1 s behind, 100 µs delay each way, the second
+		// echo queued on the way back
+		let c = |t1: i64, t4: i64| Correlated {
+			sequence: 0, size: 64,
+			t1: TimeSpec::new(10, t1),
+			t2: TimeSpec::new(9, t1 + 100_000),
+			t4: TimeSpec::new(10, t4),
+		};
+		let records = [c(0, 200_000), c(1_000_000, 1_500_000)];
+		let o = ClockOffset::estimate(&records).unwrap();
+		assert_eq!(o, ClockOffset { offset: -1_000_000_000, uncertainty: 100_000, samples: 2 });
+		assert_eq!(
+			format!("{o}"),
+			"clock offset (server - client): -1.000000000s +/- 0.000100000s, best of 2 echoes");
+		let corrected = records[1].corrected(o.offset);
+		assert_eq!(corrected.t2, TimeSpec::new(10, 1_100_000));
+		assert_eq!(corrected.forward(), TimeSpec::new(0, 100_000));
+		assert_eq!(corrected.reverse(), TimeSpec::new(0, 400_000));
+	}
+
+	#[test]
+	fn path_loss() {
+		let mut l = PathLoss::default();
+		assert_eq!((l.echoed(), l.reverse(), l.forward(3)), (0, 0, 3));
+		// echo 2 lost on the way back, reordered 4 and 3
+		for s in [0, 1, 4, 3, 5] {
+			l.record(s);
+		}
+		assert_eq!(l.stamped(), 5);
+		assert_eq!(l.echoed(), 6);
+		assert_eq!(l.reverse(), 1);
+		// two packets never reached the server
+		assert_eq!(l.forward(8), 2);
+	}
+
+	#[test]
+	fn sequence_gaps() {
+		let mut g = SequenceGaps::new();
+		// 2 and 5 missing, 3 late, 1 and 3 twice
+		for s in [0, 1, 4, 3, 1, 6, 3] {
+			g.record(s);
+		}
+		assert_eq!((g.received(), g.lost(), g.reordered(), g.duplicated()), (7, 2, 1, 2));
+		// 5 arrives late, splitting nothing
+		g.record(5);
+		assert_eq!((g.lost(), g.reordered()), (1, 2));
+		// 7 and 8 were sent, but not received
+		g.finish(9);
+		assert_eq!(g.lost(), 3);
+		g.record(8);
+		assert_eq!((g.lost(), g.reordered(), g.duplicated()), (2, 3, 2));
+		// the end of the sequence number range
+		let mut g = SequenceGaps::new();
+		g.record(u32::MAX);
+		g.record(u32::MAX - 1);
+		assert_eq!(g.lost(), u32::MAX as usize - 1);
+		assert_eq!(g.reordered(), 1);
+		assert_eq!(
+			g.to_string(),
+			format!("sequence numbers: 2 received, {} lost, 1 reordered, 0 duplicated", u32::MAX - 1));
+	}
+
+	#[test]
+	fn sequence_window() {
+		let mut g = SequenceGaps::with_window(4);
+		let arrivals: Vec<_> = [0, 2, 2, 5, 3, 9, 4, 7, 0].into_iter().map(|s| g.record(s)).collect();
+		assert_eq!(arrivals, [
+			Arrival::New, Arrival::New, Arrival::Duplicate, Arrival::New, Arrival::Reordered,
+			Arrival::New, Arrival::Late, Arrival::Reordered, Arrival::Late]);
+		// 1, 4 (out of the window when it arrived), 6, 8
+		assert_eq!((g.lost(), g.reordered(), g.duplicated(), g.late()), (4, 2, 1, 2));
+		// only 6 and 8 are remembered
+		assert_eq!(g.missing.len(), 2);
+		assert!(g.to_string().ends_with(", 2 too late to classify"), "{g}");
+	}
+
+	#[test]
+	fn mem_budget() {
+		let b = MemBudget::new(100);
+		assert!(b.reserve("buffer", 60).is_ok());
+		assert!(b.reserve("samples", 40).is_ok());
+		let e = b.reserve("samples", 8).unwrap_err();
+		assert_eq!(e.kind(), ErrorKind::OutOfMemory);
+		assert_eq!(e.to_string(), "memory budget of 100 bytes exceeded by samples (108 bytes)");
+		// the first error sticks
+		assert_eq!(b.check().unwrap_err().to_string(), e.to_string());
+		assert_eq!(b.reserve("buffer", 1).unwrap_err().to_string(), e.to_string());
+		assert_eq!(b.used(), 109);
+	}
+
+	#[test]
+	fn no_window() {
+		let mut t = Throughput::new(1);
+		assert_eq!(t.steady_rate(), None);
+		t.record(TimeSpec::new(1, 0), 100);
+		t.record(TimeSpec::new(2, 0), 100);
+		// only one packet after warmup
+		assert_eq!(t.window(), None);
+		assert_eq!(t.steady_rate(), None);
+	}
+}